@@ -3,7 +3,157 @@
 
 /* auto-generated by NAPI-RS */
 
-export declare function get_artists(): Array<string>
+export interface AlbumDisc {
+  /** 0 if the tracks have no disc number */
+  number: number
+  trackCount: number
+}
+export interface Album {
+  name: string
+  /** Album artist, or the track artist if there is no album artist */
+  artist: string
+  year?: number
+  /** Total duration in seconds */
+  duration: number
+  trackCount: number
+  discs: Array<AlbumDisc>
+  /** The first track, to use for the cover */
+  coverTrackId: TrackID
+  /** Sorted by disc and track number */
+  trackIds: Array<TrackID>
+}
+export declare function get_page_albums(): Array<Album>
+export interface FingerprintJobStatus {
+  fingerprinted: number
+  /** Files that couldn't be fingerprinted, with the reason */
+  errors: Array<string>
+}
+/**
+ * Fingerprints the tracks in the library that don't have an up to date
+ * fingerprint yet, and deletes fingerprints of tracks that don't exist
+ */
+export declare function fingerprint_library(): Promise<FingerprintJobStatus>
+/**
+ * The raw Chromaprint fingerprint of a track, or `None` if it hasn't been
+ * fingerprinted since its file last changed
+ */
+export declare function get_fingerprint(trackId: TrackID): Array<number> | null
+export interface SimilarTrack {
+  trackId: TrackID
+  /** From 0 to 1 */
+  similarity: number
+}
+/**
+ * Finds tracks that sound like the given track, most similar first. Only
+ * tracks that have been fingerprinted are included. `min_similarity`
+ * defaults to 0.75.
+ */
+export declare function find_similar_tracks(trackId: TrackID, minSimilarity?: number | undefined | null): Promise<Array<SimilarTrack>>
+export interface LoudnessJobStatus {
+  analyzed: number
+  /** Files that couldn't be analyzed or tagged, with the reason */
+  errors: Array<string>
+}
+/**
+ * Measures the loudness of tracks. Other tracks of the same albums are
+ * included, so the album loudness is complete. If `write_tags` is set,
+ * ReplayGain tags are written to the files.
+ */
+export declare function analyze_loudness(trackIds: Array<TrackID>, writeTags: boolean): Promise<LoudnessJobStatus>
+export interface SilenceJobStatus {
+  analyzed: number
+  /** Files that couldn't be analyzed, with the reason */
+  errors: Array<string>
+}
+/**
+ * Finds the silence of tracks that haven't been analyzed yet, like newly
+ * imported tracks. Tracks that couldn't be analyzed are skipped until their
+ * file changes.
+ */
+export declare function analyze_silence(): Promise<SilenceJobStatus>
+export interface TempoKeyResult {
+  trackId: TrackID
+  bpm?: number
+  /** From 0 to 1 */
+  bpmConfidence?: number
+  key?: string
+  /** From 0 to 1 */
+  keyConfidence?: number
+  /** Set if the file couldn't be analyzed or tagged */
+  error?: string
+}
+/**
+ * Estimates the BPM and key of tracks. Existing values are kept unless
+ * `overwrite` is set. If `write_tags` is set, the new values are also
+ * written to the files.
+ */
+export declare function analyze_tempo_and_key(trackIds: Array<TrackID>, overwrite: boolean, writeTags: boolean): Promise<Array<TempoKeyResult>>
+/** Sample values from -1 to 1, per bucket. Channels are combined. */
+export interface Waveform {
+  min: Array<number>
+  max: Array<number>
+  rms: Array<number>
+}
+/**
+ * Decodes a track and returns its peaks, divided into `buckets` parts of
+ * equal length
+ */
+export declare function get_waveform(trackId: TrackID, buckets: number): Promise<Waveform>
+export const enum ArtistSortKey {
+  Name = 0,
+  PlayCount = 1
+}
+export interface ArtistInfo {
+  name: string
+  trackCount: number
+  albumCount: number
+  playCount: number
+  /** Milliseconds */
+  playTime: number
+}
+export interface ArtistPage {
+  artists: Array<ArtistInfo>
+  /** Number of artists matching the query */
+  total: number
+}
+export declare function get_artists(query: string, sortKey: ArtistSortKey, desc: boolean, offset: number, limit: number): ArtistPage
+/** How many backups to keep */
+export interface BackupOptions {
+  /** One per hour */
+  hourly: number
+  /** One per day */
+  daily: number
+  /** One per week */
+  weekly: number
+}
+export interface BackupInfo {
+  /** File name, used to restore the backup */
+  name: string
+  date: MsSinceUnixEpoch
+  /** Bytes */
+  size: number
+}
+export declare function list_backups(): Array<BackupInfo>
+/**
+ * Replaces the library with a backup. The current library is backed up
+ * first, so this can be reversed.
+ */
+export declare function restore_backup(name: string): void
+export declare function get_backup_options(): BackupOptions
+export declare function set_backup_options(options: BackupOptions): void
+export const enum BrowseField {
+  Genre = 0,
+  Composer = 1,
+  Grouping = 2,
+  Decade = 3
+}
+export interface BrowseItem {
+  /** Tracklist ID that can be opened */
+  id: string
+  name: string
+  trackCount: number
+}
+export declare function get_browse_items(field: BrowseField): Array<BrowseItem>
 export declare function load_data(isDev: boolean, localDataPath?: string | undefined | null, libraryPath?: string | undefined | null): void
 export interface PathsJs {
   libraryDir: string
@@ -14,12 +164,95 @@ export interface PathsJs {
 }
 export declare function get_paths(): PathsJs
 export declare function save(): void
-export declare function filter_open_playlist(query: string): void
+export interface DuplicateGroup {
+  /** Sorted with the keeper first */
+  trackIds: Array<TrackID>
+  /**
+  * The track that's suggested to keep, with the highest bitrate and the
+  * most plays
+  */
+  keeper: TrackID
+}
+export declare function find_duplicates(): Array<DuplicateGroup>
+/**
+ * Finds tracks that sound the same, regardless of their tags. Only tracks
+ * that have been fingerprinted are included. `min_similarity` defaults to
+ * 0.75.
+ */
+export declare function find_fingerprint_duplicates(minSimilarity?: number | undefined | null): Promise<Array<DuplicateGroup>>
+/**
+ * Merges tracks into the first one. Plays, skips and playlist entries of
+ * the other tracks are moved to it, and their files are moved to the trash
+ */
+export declare function merge_tracks(trackIds: Array<TrackID>): void
+export declare function filter_open_playlist(query: string): QueryError | null
+export interface ImportProgress {
+  path: string
+  /** Number of files handled so far */
+  done: number
+  total: number
+  /** ID of the new track, if the file was imported */
+  trackId?: string
+  /** Whether the file was skipped because it's already in the library */
+  skipped: boolean
+  error?: string
+}
+export interface ImportJobStatus {
+  imported: number
+  skipped: number
+  /** Files that couldn't be imported, with the reason */
+  errors: Array<string>
+  /** Whether the job was cancelled before all files were handled */
+  cancelled: boolean
+}
+export const enum IssueKind {
+  /** A track's file doesn't exist */
+  MissingFile = 0,
+  /** A file in the tracks folder isn't used by any track */
+  OrphanFile = 1,
+  /** A playlist contains track IDs that don't exist */
+  MissingTrack = 2,
+  /** A folder contains a track list ID that doesn't exist */
+  MissingTrackList = 3,
+  /** A track list isn't in root or any folder under root */
+  UnreachableTrackList = 4,
+  /** A track list is in more than one folder, or more than once in a folder */
+  MultipleParents = 5,
+  /** `playCount` doesn't match `plays` and `playsImported` */
+  WrongPlayCount = 6
+}
+export interface LibraryIssue {
+  kind: IssueKind
+  message: string
+  /** What the automatic fix does, if there is one */
+  fix?: string
+  trackId?: TrackID
+  /** The track list with the issue, or the folder for `MissingTrackList` */
+  tracklistId?: TrackListID
+  /** The missing track list for `MissingTrackList` */
+  childId?: TrackListID
+  /** File name in the tracks folder */
+  file?: string
+}
+export declare function check_library(): Array<LibraryIssue>
+/** Fixes issues from `check_library`. Changes to track lists can be undone */
+export declare function fix_library_issues(issues: Array<LibraryIssue>): void
 export interface ImportStatus {
   errors: Array<string>
   tracksCount: number
   playlistsCount: number
 }
+export interface JournalState {
+  /** Name of the action that can be undone */
+  undo?: string
+  /** Name of the action that can be redone */
+  redo?: string
+}
+export declare function get_journal_state(): JournalState
+/** Returns the name of the undone action */
+export declare function undo(): string | null
+/** Returns the name of the redone action */
+export declare function redo(): string | null
 export declare function copyFile(from: string, to: string): void
 export declare function atomicFileSave(filePath: string, content: string): void
 export interface Track {
@@ -34,6 +267,32 @@ export interface Track {
   importedFrom?: string
   /** Imported ID, like iTunes Persistent ID */
   originalId?: string
+  /** File type detected from the content, like "mp3" or "flac" */
+  fileType?: string
+  /** Like "AAC" or "FLAC" */
+  codec?: string
+  channels?: number
+  bitDepth?: number
+  lossless?: boolean
+  /** Software that encoded the file, from the tags */
+  encoder?: string
+  /**
+  * Samples to skip at the start for gapless playback, from the LAME
+  * header or iTunSMPB
+  */
+  encoderDelay?: number
+  /** Samples to skip at the end for gapless playback */
+  encoderPadding?: number
+  /** Seconds of silence at the start, after the encoder delay */
+  silenceStart?: number
+  /** Seconds of silence at the end, before the encoder padding */
+  silenceEnd?: number
+  /** Integrated loudness in LUFS */
+  loudness?: number
+  /** In dBTP */
+  truePeak?: number
+  /** Integrated loudness of the whole album in LUFS */
+  albumLoudness?: number
   artist: string
   composer?: string
   sortName?: string
@@ -43,6 +302,8 @@ export interface Track {
   rating?: PercentInteger
   year?: number
   bpm?: number
+  /** Musical key, like "Ebm" */
+  key?: string
   comments?: string
   grouping?: string
   liked?: boolean
@@ -82,8 +343,18 @@ export interface Playlist {
   originalId?: string
   dateImported?: MsSinceUnixEpoch
   dateCreated?: MsSinceUnixEpoch
+  /**
+  * Set when an imported smart playlist could not be converted, in which
+  * case `tracks` is a snapshot from when it was imported
+  */
+  importedSmartCriteria?: ImportedSmartCriteria
   tracks: Array<TrackID>
 }
+/** Original iTunes smart playlist data, hex encoded */
+export interface ImportedSmartCriteria {
+  info: string
+  criteria: string
+}
 export interface Folder {
   id: TrackListID
   name: string
@@ -114,7 +385,8 @@ export declare function refresh_page(): void
 export declare function get_page_track_ids(): Array<TrackID>
 export const enum ViewAs {
   Songs = 0,
-  Artists = 1
+  Artists = 1,
+  Albums = 2
 }
 export interface PageInfo {
   id: string
@@ -148,8 +420,48 @@ export declare function playlist_filter_duplicates(playlistId: string, ids: Arra
 export declare function remove_from_open_playlist(indexesToRemove: Array<number>): void
 export declare function delete_tracks_in_open(indexesToDelete: Array<number>): void
 export declare function new_playlist(name: string, description: string, isFolder: boolean, parentId: string): void
+export declare function new_smart_playlist(name: string, description: string, rules: SmartRuleGroup, limit: SmartLimit | null, parentId: string): string
+export declare function update_smart_playlist_rules(id: string, rules: SmartRuleGroup, limit: SmartLimit | null): void
 export declare function update_playlist(id: string, name: string, description: string): void
 export declare function move_playlist(id: string, fromId: string, toId: string, toIndex: number): void
+/** A query parse error. `start` and `end` are UTF-16 offsets into the query */
+export interface QueryError {
+  message: string
+  start: number
+  end: number
+}
+export interface RelinkMatch {
+  trackId: TrackID
+  /** Path of the file that was found */
+  path: string
+  /** How well the file matches. Higher is better */
+  score: number
+  /** Whether the file size is exactly the same */
+  sameSize: boolean
+}
+/**
+ * Searches a folder for the files of tracks whose file is missing. Nothing
+ * is changed until the matches are passed to `apply_relinks`.
+ */
+export declare function relink_tracks(searchDir: string): Promise<Array<RelinkMatch>>
+export declare function apply_relinks(matches: Array<RelinkMatch>): void
+export interface FieldChange {
+  field: string
+  /** `null` if the field was not set */
+  old: any
+  new: any
+}
+export interface TrackRescan {
+  trackId: TrackID
+  changes: Array<FieldChange>
+  /** Set if the file couldn't be read, in which case the track is unchanged */
+  error?: string
+}
+/**
+ * Rereads the file properties and tags of tracks. Resolves to the tracks
+ * that changed or couldn't be read.
+ */
+export declare function rescan_tracks(trackIds: Array<TrackID>): Promise<Array<TrackRescan>>
 /** Returns `None` if the file does not have an image */
 export declare function get_modified_timestamp_ms(path: string): number | null
 /** Returns `None` if the file does not have an image */
@@ -170,6 +482,12 @@ export interface TrackMd {
   bpm: string
   comments: string
 }
+/**
+ * Reads the audio properties of tracks that don't have a codec recorded,
+ * like tracks imported before it was. Resolves to the number of tracks
+ * that were updated.
+ */
+export declare function backfill_audio_properties(): Promise<number>
 export declare function get_track(id: string): Track
 export declare function track_exists(id: string): boolean
 export declare function add_play(trackId: string): void
@@ -199,6 +517,24 @@ export declare function shown_playlist_folders(): Array<string>
 export declare function view_folder_set_show(id: string, show: boolean): void
 export declare function load_view_options(): ViewOptions
 export declare function save_view_options(viewOptions: ViewOptions): void
+export declare function get_watch_folders(): Array<string>
+export declare function set_watch_folders(folders: Array<string>): void
+/**
+ * Starts watching the folders. `callback` is called with the IDs of tracks
+ * that were imported.
+ */
+export declare function start_watching(callback: (trackIds: Array<string>) => void): void
+export declare class ImportJob {
+  static new(): ImportJob
+  /**
+  * Imports files, and audio files in folders and their subfolders.
+  * `on_progress` is called after each file. Files that are already in
+  * the library are only skipped if `skip_duplicates` is true.
+  */
+  start(paths: Array<string>, onProgress: (progress: ImportProgress) => void, skipDuplicates?: boolean): Promise<ImportJobStatus>
+  /** Stops importing. Files that are already imported are kept */
+  cancel(): void
+}
 export declare class ItunesImport {
   static new(): ItunesImport
   start(path: string, tracksDir: string): Promise<ImportStatus>
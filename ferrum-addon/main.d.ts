@@ -18,6 +18,60 @@ declare module 'ferrum-addon/addon' {
 	export interface Special {
 		type: 'special'
 	}
+	/** Tracks with a genre, composer, grouping or decade. Not stored in the library */
+	export interface BrowseList {
+		type: 'browse'
+		id: TrackListID
+		field: 'genre' | 'composer' | 'grouping' | 'decade'
+		name: string
+	}
+	/** Tracks that are likely the same song, grouped together. Not stored in the library */
+	export interface DuplicatesList {
+		type: 'duplicates'
+		id: 'duplicates'
+		name: string
+	}
+	export interface SmartPlaylist {
+		type: 'smart'
+		id: TrackListID
+		name: string
+		description?: string
+		liked?: boolean
+		disliked?: boolean
+		importedFrom?: string
+		originalId?: string
+		dateImported?: MsSinceUnixEpoch
+		dateCreated?: MsSinceUnixEpoch
+		rules: SmartRuleGroup
+		limit?: SmartLimit
+	}
+	export type SmartRuleGroup = {
+		match: 'all' | 'any' | 'none'
+		rules: SmartRule[]
+	}
+	export type SmartRule =
+		| ({ type: 'group' } & SmartRuleGroup)
+		| ({ type: 'condition'; field: string } & SmartOperator)
+	export type SmartOperator =
+		| { operator: 'is' | 'isNot'; value: boolean | number | string }
+		| { operator: 'contains' | 'notContains' | 'startsWith' | 'endsWith'; value: string }
+		| { operator: 'greaterThan' | 'lessThan' | 'atLeast' | 'atMost'; value: number }
+		| { operator: 'inRange'; value: [number, number] }
+		/** Milliseconds before now */
+		| { operator: 'inLast' | 'notInLast'; value: number }
+		| { operator: 'isSet' | 'isNotSet' }
+	export type SmartLimit = {
+		amount: number
+		unit: 'items' | 'minutes' | 'megabytes'
+		sortKey: string
+		sortDesc: boolean
+	}
 
-	export type TrackList = Playlist | Folder | Special
+	export type TrackList =
+		| Playlist
+		| Folder
+		| Special
+		| SmartPlaylist
+		| BrowseList
+		| DuplicatesList
 }
@@ -0,0 +1,129 @@
+use crate::data::Data;
+use crate::data_js::get_data;
+use crate::library::TrackField;
+use crate::library_types::{Library, Track, TrackID};
+use crate::sort::compare_track_field;
+use linked_hash_map::LinkedHashMap;
+use napi::{Env, Result};
+use std::cmp::Ordering;
+use std::time::Instant;
+
+#[napi(object)]
+pub struct AlbumDisc {
+	/// 0 if the tracks have no disc number
+	pub number: u32,
+	pub track_count: u32,
+}
+
+#[napi(object)]
+pub struct Album {
+	pub name: String,
+	/// Album artist, or the track artist if there is no album artist
+	pub artist: String,
+	pub year: Option<i64>,
+	/// Total duration in seconds
+	pub duration: f64,
+	pub track_count: u32,
+	pub discs: Vec<AlbumDisc>,
+	/// The first track, to use for the cover
+	pub cover_track_id: TrackID,
+	/// Sorted by disc and track number
+	pub track_ids: Vec<TrackID>,
+}
+
+fn album_key(track: &Track) -> (&str, &str) {
+	let artist = track.albumArtist.as_ref().unwrap_or(&track.artist);
+	let name = track.albumName.as_deref().unwrap_or_default();
+	(artist, name)
+}
+
+fn compare_album_order(a: &Track, b: &Track) -> Ordering {
+	match compare_track_field(a, b, "discNum", &TrackField::U32) {
+		Ordering::Equal => compare_track_field(a, b, "trackNum", &TrackField::U32),
+		order => order,
+	}
+}
+
+/// Groups tracks by album artist and album name. Albums are ordered by
+/// where their first track appears, so they follow the sorting of the page.
+pub fn group_albums(library: &Library, track_ids: &[TrackID]) -> Vec<Album> {
+	let tracks = &library.tracks;
+	let mut groups: LinkedHashMap<(&str, &str), Vec<(&TrackID, &Track)>> = LinkedHashMap::new();
+	for id in track_ids {
+		let track = tracks.get(id).expect("Track ID not found");
+		groups
+			.entry(album_key(track))
+			.or_default()
+			.push((id, track));
+	}
+
+	let mut albums = Vec::with_capacity(groups.len());
+	for ((artist, name), mut album_tracks) in groups {
+		album_tracks.sort_by(|(_, a), (_, b)| compare_album_order(a, b));
+		let mut discs: Vec<AlbumDisc> = Vec::new();
+		for (_, track) in &album_tracks {
+			let number = track.discNum.unwrap_or(0);
+			match discs.last_mut() {
+				Some(disc) if disc.number == number => disc.track_count += 1,
+				_ => discs.push(AlbumDisc {
+					number,
+					track_count: 1,
+				}),
+			}
+		}
+		albums.push(Album {
+			name: name.to_string(),
+			artist: artist.to_string(),
+			year: album_tracks
+				.iter()
+				.filter_map(|(_, track)| track.year)
+				.max(),
+			duration: album_tracks.iter().map(|(_, track)| track.duration).sum(),
+			track_count: album_tracks.len() as u32,
+			discs,
+			cover_track_id: album_tracks[0].0.clone(),
+			track_ids: album_tracks.iter().map(|(id, _)| (*id).clone()).collect(),
+		});
+	}
+	albums
+}
+
+#[napi(js_name = "get_page_albums")]
+#[allow(dead_code)]
+pub fn get_page_albums(env: Env) -> Result<Vec<Album>> {
+	let data: &mut Data = get_data(&env)?;
+	let now = Instant::now();
+	let albums = group_albums(&data.library, data.get_page_tracks());
+	println!("Group albums: {}ms", now.elapsed().as_millis());
+	Ok(albums)
+}
+
+#[test]
+fn group_albums_test() {
+	use crate::library_types::test_library;
+
+	let library = test_library(serde_json::json!([
+		{ "duration": 60.0, "name": "B", "artist": "X", "albumName": "Album", "discNum": 2,
+			"trackNum": 1, "year": 2001 },
+		{ "duration": 30.0, "name": "Single", "artist": "Y" },
+		{ "duration": 60.0, "name": "A", "artist": "Z", "albumArtist": "X", "albumName": "Album",
+			"discNum": 1, "trackNum": 2, "year": 2000 },
+	]));
+	let ids: Vec<TrackID> = library.tracks.keys().cloned().collect();
+	let albums = group_albums(&library, &ids);
+	assert_eq!(albums.len(), 2);
+	assert_eq!(
+		(albums[0].name.as_str(), albums[0].artist.as_str()),
+		("Album", "X")
+	);
+	assert_eq!(albums[0].track_ids, vec!["2", "0"]);
+	assert_eq!(albums[0].duration, 120.0);
+	assert_eq!(albums[0].year, Some(2001));
+	let discs: Vec<_> = albums[0]
+		.discs
+		.iter()
+		.map(|d| (d.number, d.track_count))
+		.collect();
+	assert_eq!(discs, vec![(1, 1), (2, 1)]);
+	assert_eq!(albums[1].cover_track_id, "1");
+}
@@ -0,0 +1,143 @@
+use crate::UniResult;
+use std::fs::File;
+use std::path::Path;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{self, DecoderOptions, CODEC_TYPE_NULL};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatOptions, FormatReader, SeekMode, SeekTo};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+use symphonia::core::units::{Time, TimeBase};
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct Format {
+	pub sample_rate: u32,
+	pub channels: usize,
+}
+
+/// Decodes a file in chunks, so the whole file doesn't need to fit in memory
+pub struct Decoder {
+	format: Box<dyn FormatReader>,
+	decoder: Box<dyn codecs::Decoder>,
+	track_id: u32,
+	time_base: Option<TimeBase>,
+	/// Length in seconds, if the file says
+	pub duration: Option<f64>,
+	buffer: Option<SampleBuffer<f32>>,
+}
+
+fn to_seconds(time: Time) -> f64 {
+	time.seconds as f64 + time.frac
+}
+
+impl Decoder {
+	pub fn open(path: &Path) -> UniResult<Self> {
+		let file = match File::open(path) {
+			Ok(file) => file,
+			Err(e) => throw!("Unable to open file {}: {e}", path.to_string_lossy()),
+		};
+		let stream = MediaSourceStream::new(Box::new(file), Default::default());
+		let mut hint = Hint::new();
+		if let Some(ext) = path.extension() {
+			hint.with_extension(&ext.to_string_lossy());
+		}
+		// Encoder delay and padding are trimmed
+		let format_options = FormatOptions {
+			enable_gapless: true,
+			..Default::default()
+		};
+		let probed = match symphonia::default::get_probe().format(
+			&hint,
+			stream,
+			&format_options,
+			&MetadataOptions::default(),
+		) {
+			Ok(probed) => probed,
+			Err(e) => throw!("Unsupported audio format: {e}"),
+		};
+		let format = probed.format;
+		let track = match format
+			.tracks()
+			.iter()
+			.find(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+		{
+			Some(track) => track,
+			None => throw!("No audio track found"),
+		};
+		let decoder = match symphonia::default::get_codecs()
+			.make(&track.codec_params, &DecoderOptions::default())
+		{
+			Ok(decoder) => decoder,
+			Err(e) => throw!("Unsupported codec: {e}"),
+		};
+		let time_base = track.codec_params.time_base;
+		let duration = match (time_base, track.codec_params.n_frames) {
+			(Some(time_base), Some(n_frames)) => Some(to_seconds(time_base.calc_time(n_frames))),
+			_ => None,
+		};
+		Ok(Decoder {
+			track_id: track.id,
+			time_base,
+			duration,
+			format,
+			decoder,
+			buffer: None,
+		})
+	}
+	/// Seeks to about `seconds`, at or before it. Returns the exact position
+	/// of the next chunk in seconds.
+	pub fn seek(&mut self, seconds: f64) -> UniResult<f64> {
+		let time_base = match self.time_base {
+			Some(time_base) => time_base,
+			None => throw!("Unable to seek without a time base"),
+		};
+		let seek_to = SeekTo::Time {
+			time: Time::from(seconds),
+			track_id: Some(self.track_id),
+		};
+		let seeked_to = match self.format.seek(SeekMode::Accurate, seek_to) {
+			Ok(seeked_to) => seeked_to,
+			Err(e) => throw!("Unable to seek: {e}"),
+		};
+		self.decoder.reset();
+		Ok(to_seconds(time_base.calc_time(seeked_to.actual_ts)))
+	}
+	/// Returns the next chunk of interleaved samples, or `None` at the end
+	pub fn next_chunk(&mut self) -> UniResult<Option<(Format, &[f32])>> {
+		loop {
+			let packet = match self.format.next_packet() {
+				Ok(packet) => packet,
+				Err(Error::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+					return Ok(None)
+				}
+				Err(Error::ResetRequired) => return Ok(None),
+				Err(e) => throw!("Error reading audio: {e}"),
+			};
+			if packet.track_id() != self.track_id {
+				continue;
+			}
+			let decoded = match self.decoder.decode(&packet) {
+				Ok(decoded) => decoded,
+				// Corrupt packets are skipped
+				Err(Error::DecodeError(_)) => continue,
+				Err(e) => throw!("Error decoding audio: {e}"),
+			};
+			if decoded.frames() == 0 {
+				continue;
+			}
+			let spec = *decoded.spec();
+			let format = Format {
+				sample_rate: spec.rate,
+				channels: spec.channels.count(),
+			};
+			let capacity = decoded.capacity() * format.channels;
+			if !matches!(&self.buffer, Some(buffer) if buffer.capacity() >= capacity) {
+				self.buffer = Some(SampleBuffer::new(decoded.capacity() as u64, spec));
+			}
+			let buffer = self.buffer.as_mut().expect("Buffer not created");
+			buffer.copy_interleaved_ref(decoded);
+			return Ok(Some((format, buffer.samples())));
+		}
+	}
+}
@@ -0,0 +1,833 @@
+//! Acoustic fingerprints, computed with Chromaprint's default algorithm, so
+//! they can be compared with fingerprints from fpcalc and AcoustID. Only the
+//! resampling differs slightly, which barely affects the result. They're
+//! stored in `Fingerprints.redb` in the library folder, keyed by track ID,
+//! together with the size and modification time of the file, so they're
+//! recomputed when the file changes.
+//!
+//! A fingerprint is a list of 32-bit values, about 8 per second, from the
+//! first two minutes of a track. Each value describes how the chroma (the
+//! energy per pitch class) changes over a short time.
+
+use super::decode::Decoder;
+use super::spectrum::{Downmix, Stft};
+use crate::data::Data;
+use crate::data_js::get_data;
+use crate::library_types::TrackID;
+use crate::{sys_time_to_timestamp, UniResult};
+use anyhow::Context;
+use lazy_static::lazy_static;
+use napi::{Env, JsObject, Result, Task};
+use rayon::prelude::*;
+use redb::{Database, ReadableTable, TableDefinition};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::f64::consts::PI;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const SAMPLE_RATE: u32 = 11025;
+const FRAME_SIZE: usize = 4096;
+const HOP: usize = FRAME_SIZE / 3;
+const MAX_DURATION: u32 = 120;
+const MIN_FREQUENCY: f64 = 28.0;
+const MAX_FREQUENCY: f64 = 3520.0;
+/// Frequency of pitch class 0, which is A
+const BASE_FREQUENCY: f64 = 440.0 / 16.0;
+/// Smooths the chroma over time
+const CHROMA_FILTER: [f64; 5] = [0.25, 0.75, 1.0, 0.75, 0.25];
+/// Relative to the Nyquist frequency of the output
+const RESAMPLE_CUTOFF: f64 = 0.8;
+/// Zero crossings of the resampling filter on each side
+const RESAMPLE_ZEROS: f64 = 8.0;
+/// Resolution of the resampling filter table, per input sample
+const RESAMPLE_PHASES: usize = 64;
+/// Fingerprints need to overlap by at least this many values, about 5
+/// seconds, to be compared
+const MIN_OVERLAP: usize = 40;
+/// Offsets are found from values whose highest bits match
+const ALIGN_BITS: u32 = 20;
+pub const DEFAULT_MIN_SIMILARITY: f64 = 0.75;
+/// Files fingerprinted between saves
+const BATCH_SIZE: usize = 64;
+
+struct Classifier {
+	kind: u8,
+	/// First pitch class
+	y: usize,
+	/// Number of pitch classes
+	height: usize,
+	/// Number of frames
+	width: usize,
+	thresholds: [f64; 3],
+}
+
+const fn classifier(kind: u8, y: usize, height: usize, width: usize, t: [f64; 3]) -> Classifier {
+	Classifier {
+		kind,
+		y,
+		height,
+		width,
+		thresholds: t,
+	}
+}
+
+/// Chromaprint's classifiers for its default algorithm
+#[rustfmt::skip]
+const CLASSIFIERS: [Classifier; 16] = [
+	classifier(0, 4, 3, 15, [1.98215, 2.35817, 2.63523]),
+	classifier(4, 4, 6, 15, [-1.03809, -0.651211, -0.282167]),
+	classifier(1, 0, 4, 16, [-0.298702, 0.119262, 0.558497]),
+	classifier(3, 8, 2, 12, [-0.105439, 0.0153946, 0.135898]),
+	classifier(3, 4, 4, 8, [-0.142891, 0.0258736, 0.200632]),
+	classifier(4, 0, 3, 5, [-0.826319, -0.590612, -0.368214]),
+	classifier(1, 2, 2, 9, [-0.557409, -0.233035, 0.0534525]),
+	classifier(2, 7, 3, 4, [-0.0646826, 0.00620476, 0.0784847]),
+	classifier(2, 6, 2, 16, [-0.192387, -0.029699, 0.215855]),
+	classifier(2, 1, 3, 2, [-0.0397818, -0.00568076, 0.0292026]),
+	classifier(5, 10, 1, 15, [-0.53823, -0.369934, -0.190235]),
+	classifier(3, 6, 2, 10, [-0.124877, 0.0296483, 0.139239]),
+	classifier(2, 1, 1, 14, [-0.101475, 0.0225617, 0.126971]),
+	classifier(3, 5, 6, 4, [-0.0799915, -0.00729616, 0.116264]),
+	classifier(1, 9, 2, 12, [-0.272556, 0.019424, 0.218035]),
+	classifier(1, 4, 2, 14, [-0.0801607, 0.0197048, 0.129408]),
+];
+/// Widest classifier
+const MAX_WIDTH: usize = 16;
+
+/// Sums of the chroma rows so far. `rows[i][j]` is the sum of the values in
+/// rows before `i` and pitch classes before `j`.
+struct IntegralImage {
+	rows: Vec<[f64; 13]>,
+}
+
+impl IntegralImage {
+	fn len(&self) -> usize {
+		self.rows.len() - 1
+	}
+	fn push(&mut self, row: &[f64; 12]) {
+		let previous = self.rows[self.rows.len() - 1];
+		let mut sums = [0.0; 13];
+		let mut running = 0.0;
+		for (j, value) in row.iter().enumerate() {
+			running += value;
+			sums[j + 1] = previous[j + 1] + running;
+		}
+		self.rows.push(sums);
+	}
+	fn area(&self, x1: usize, y1: usize, x2: usize, y2: usize) -> f64 {
+		self.rows[x2][y2] - self.rows[x1][y2] - self.rows[x2][y1] + self.rows[x1][y1]
+	}
+}
+
+impl Classifier {
+	/// Compares parts of the image, starting at row `x`
+	fn apply(&self, image: &IntegralImage, x: usize) -> f64 {
+		let (y, w, h) = (self.y, self.width, self.height);
+		let area = |x1, y1, x2, y2| image.area(x1, y1, x2, y2);
+		let (a, b) = match self.kind {
+			0 => (area(x, y, x + w, y + h), 0.0),
+			1 => {
+				let h2 = h / 2;
+				(area(x, y + h2, x + w, y + h), area(x, y, x + w, y + h2))
+			}
+			2 => {
+				let w2 = w / 2;
+				(area(x + w2, y, x + w, y + h), area(x, y, x + w2, y + h))
+			}
+			3 => {
+				let (w2, h2) = (w / 2, h / 2);
+				(
+					area(x, y + h2, x + w2, y + h) + area(x + w2, y, x + w, y + h2),
+					area(x, y, x + w2, y + h2) + area(x + w2, y + h2, x + w, y + h),
+				)
+			}
+			4 => {
+				let h3 = h / 3;
+				(
+					area(x, y + h3, x + w, y + 2 * h3),
+					area(x, y, x + w, y + h3) + area(x, y + 2 * h3, x + w, y + h),
+				)
+			}
+			_ => {
+				let w3 = w / 3;
+				(
+					area(x + w3, y, x + 2 * w3, y + h),
+					area(x, y, x + w3, y + h) + area(x + 2 * w3, y, x + w, y + h),
+				)
+			}
+		};
+		((1.0 + a) / (1.0 + b)).ln()
+	}
+	/// 2 bits, Gray coded
+	fn classify(&self, image: &IntegralImage, x: usize) -> u32 {
+		let value = self.apply(image, x);
+		let [t0, t1, t2] = self.thresholds;
+		match value {
+			v if v < t0 => 0,
+			v if v < t1 => 1,
+			v if v < t2 => 3,
+			_ => 2,
+		}
+	}
+}
+
+/// Lowers the sample rate with a windowed sinc filter
+struct Resampler {
+	/// Input samples per output sample
+	step: f64,
+	half_width: usize,
+	/// The filter, sampled `RESAMPLE_PHASES` times per input sample
+	table: Vec<f32>,
+	buffer: Vec<f32>,
+	/// Position of the next output sample in `buffer`
+	position: f64,
+}
+
+impl Resampler {
+	fn new(input_rate: u32, output_rate: u32) -> Self {
+		let step = f64::from(input_rate) / f64::from(output_rate);
+		let cutoff = RESAMPLE_CUTOFF * (1.0 / step).min(1.0);
+		let half_width = (RESAMPLE_ZEROS / cutoff).ceil() as usize;
+		let table = (0..=half_width * RESAMPLE_PHASES)
+			.map(|i| {
+				let x = i as f64 / RESAMPLE_PHASES as f64;
+				let t = PI * x * cutoff;
+				let sinc = if t == 0.0 { 1.0 } else { t.sin() / t };
+				let window = 0.5 + 0.5 * (PI * x / half_width as f64).cos();
+				(cutoff * sinc * window) as f32
+			})
+			.collect();
+		Resampler {
+			step,
+			half_width,
+			table,
+			buffer: Vec::new(),
+			position: 0.0,
+		}
+	}
+	fn add(&mut self, samples: &[f32], output: &mut Vec<f32>) {
+		self.buffer.extend(samples);
+		while self.position.floor() as usize + self.half_width < self.buffer.len() {
+			let center = self.position.floor() as usize;
+			let first = (center + 1).saturating_sub(self.half_width);
+			let mut sum = 0.0;
+			for (k, sample) in self.buffer[first..=center + self.half_width]
+				.iter()
+				.enumerate()
+			{
+				let distance = (self.position - (first + k) as f64).abs();
+				let phase = (distance * RESAMPLE_PHASES as f64).round() as usize;
+				sum += sample * self.table[phase.min(self.table.len() - 1)];
+			}
+			output.push(sum);
+			self.position += self.step;
+		}
+		let consumed = (self.position.floor() as usize).saturating_sub(self.half_width);
+		self.buffer.drain(..consumed.min(self.buffer.len()));
+		self.position -= consumed as f64;
+	}
+}
+
+/// Turns spectrum frames into fingerprint values
+struct Calculator {
+	/// Pitch class of each frequency bin, if it's in range
+	pitch_classes: Vec<Option<usize>>,
+	/// The last chroma vectors, for `CHROMA_FILTER`
+	recent: VecDeque<[f64; 12]>,
+	image: IntegralImage,
+	fingerprint: Vec<u32>,
+}
+
+impl Calculator {
+	fn new() -> Self {
+		let bin_frequency = |bin: usize| bin as f64 * f64::from(SAMPLE_RATE) / FRAME_SIZE as f64;
+		let frequency_bin = |frequency: f64| {
+			(FRAME_SIZE as f64 * frequency / f64::from(SAMPLE_RATE)).round() as usize
+		};
+		let min_bin = frequency_bin(MIN_FREQUENCY).max(1);
+		let max_bin = frequency_bin(MAX_FREQUENCY).min(FRAME_SIZE / 2);
+		let pitch_classes = (0..=FRAME_SIZE / 2)
+			.map(|bin| {
+				if !(min_bin..max_bin).contains(&bin) {
+					return None;
+				}
+				let octave = (bin_frequency(bin) / BASE_FREQUENCY).log2();
+				Some((12.0 * (octave - octave.floor())) as usize)
+			})
+			.collect();
+		Calculator {
+			pitch_classes,
+			recent: VecDeque::with_capacity(CHROMA_FILTER.len()),
+			image: IntegralImage {
+				rows: vec![[0.0; 13]],
+			},
+			fingerprint: Vec::new(),
+		}
+	}
+	fn add(&mut self, magnitudes: &[f32]) {
+		let mut chroma = [0.0; 12];
+		for (magnitude, pitch_class) in magnitudes.iter().zip(&self.pitch_classes) {
+			if let Some(pitch_class) = pitch_class {
+				chroma[*pitch_class] += f64::from(magnitude * magnitude);
+			}
+		}
+		if self.recent.len() == CHROMA_FILTER.len() {
+			self.recent.pop_front();
+		}
+		self.recent.push_back(chroma);
+		if self.recent.len() < CHROMA_FILTER.len() {
+			return;
+		}
+
+		let mut row = [0.0; 12];
+		for (chroma, coefficient) in self.recent.iter().zip(CHROMA_FILTER) {
+			for (value, c) in row.iter_mut().zip(chroma) {
+				*value += coefficient * c;
+			}
+		}
+		let norm = row.iter().map(|value| value * value).sum::<f64>().sqrt();
+		for value in &mut row {
+			*value = match norm < 0.01 {
+				true => 0.0,
+				false => *value / norm,
+			};
+		}
+		self.image.push(&row);
+
+		if self.image.len() >= MAX_WIDTH {
+			let x = self.image.len() - MAX_WIDTH;
+			let bits = CLASSIFIERS.iter().fold(0, |bits, classifier| {
+				(bits << 2) | classifier.classify(&self.image, x)
+			});
+			self.fingerprint.push(bits);
+		}
+	}
+}
+
+pub fn fingerprint(path: &Path) -> UniResult<Vec<u32>> {
+	let mut decoder = Decoder::open(path)?;
+	let mut resampler: Option<(Downmix, Resampler)> = None;
+	let mut stft = Stft::with_window(
+		(0..FRAME_SIZE)
+			.map(|i| (0.54 - 0.46 * (2.0 * PI * i as f64 / (FRAME_SIZE - 1) as f64).cos()) as f32)
+			.collect(),
+		HOP,
+	);
+	let mut calculator = Calculator::new();
+	let max_samples = (SAMPLE_RATE * MAX_DURATION) as usize;
+	let mut samples_done = 0;
+	let mut mono = Vec::new();
+	let mut resampled = Vec::new();
+	while let Some((format, samples)) = decoder.next_chunk()? {
+		let (downmix, resampler) = match &mut resampler {
+			Some(resampler) => resampler,
+			None => resampler.insert((
+				Downmix::new(format.sample_rate, format.sample_rate),
+				Resampler::new(format.sample_rate, SAMPLE_RATE),
+			)),
+		};
+		mono.clear();
+		resampled.clear();
+		downmix.add(format, samples, &mut mono);
+		resampler.add(&mono, &mut resampled);
+		resampled.truncate(max_samples - samples_done);
+		samples_done += resampled.len();
+		stft.add(&resampled, |magnitudes| calculator.add(magnitudes));
+		if samples_done == max_samples {
+			break;
+		}
+	}
+	if calculator.fingerprint.is_empty() {
+		throw!("Not enough audio");
+	}
+	Ok(calculator.fingerprint)
+}
+
+/// Compares one fingerprint with others
+pub struct Matcher<'a> {
+	fingerprint: &'a [u32],
+	/// Positions of values by their highest bits
+	positions: HashMap<u32, Vec<usize>>,
+}
+
+impl<'a> Matcher<'a> {
+	pub fn new(fingerprint: &'a [u32]) -> Self {
+		let mut positions: HashMap<u32, Vec<usize>> = HashMap::new();
+		for (i, value) in fingerprint.iter().enumerate() {
+			positions
+				.entry(value >> (32 - ALIGN_BITS))
+				.or_default()
+				.push(i);
+		}
+		Matcher {
+			fingerprint,
+			positions,
+		}
+	}
+	/// How similar the fingerprints are where they overlap best, from 0 to 1.
+	/// Unrelated audio is around 0.5.
+	pub fn similarity(&self, other: &[u32]) -> f64 {
+		// Offset of `other` that lines up the most values
+		let mut votes: HashMap<isize, u32> = HashMap::new();
+		for (j, value) in other.iter().enumerate() {
+			if let Some(positions) = self.positions.get(&(value >> (32 - ALIGN_BITS))) {
+				for i in positions {
+					*votes.entry(j as isize - *i as isize).or_default() += 1;
+				}
+			}
+		}
+		let offset = votes
+			.into_iter()
+			.max_by(|(a_offset, a), (b_offset, b)| a.cmp(b).then(b_offset.cmp(a_offset)))
+			.map_or(0, |(offset, _)| offset);
+
+		let mut overlap = 0;
+		let mut different_bits = 0;
+		for (i, value) in self.fingerprint.iter().enumerate() {
+			let j = i as isize + offset;
+			if let Some(other_value) = usize::try_from(j).ok().and_then(|j| other.get(j)) {
+				overlap += 1;
+				different_bits += (value ^ other_value).count_ones();
+			}
+		}
+		if overlap < MIN_OVERLAP.min(self.fingerprint.len()).min(other.len()) || overlap == 0 {
+			return 0.0;
+		}
+		1.0 - f64::from(different_bits) / (32 * overlap) as f64
+	}
+}
+
+// track ID -> (path, size, modified_timestamp_ms, encoded fingerprint)
+type Entry<'a> = (&'a str, u64, i64, &'a [u8]);
+
+const FINGERPRINTS_TABLE: TableDefinition<&str, Entry> = TableDefinition::new("fingerprints");
+
+lazy_static! {
+	static ref FINGERPRINTS_DB: Mutex<Option<(PathBuf, Arc<Database>)>> = Mutex::new(None);
+}
+
+pub fn open_db(path: &Path) -> Result<Arc<Database>> {
+	let mut db_mutex = FINGERPRINTS_DB.lock().unwrap();
+	if let Some((db_path, db)) = &*db_mutex {
+		if db_path == path {
+			return Ok(db.clone());
+		}
+	}
+	// The previous library's database is closed first
+	*db_mutex = None;
+	let db = Database::create(path).context("Could not open fingerprints")?;
+	let init_txn = db
+		.begin_write()
+		.context("Could not begin write transaction")?;
+	init_txn
+		.open_table(FINGERPRINTS_TABLE)
+		.context("Could not open table")?;
+	init_txn.commit().context("Could not commit transaction")?;
+	let db = Arc::new(db);
+	*db_mutex = Some((path.to_path_buf(), db.clone()));
+	Ok(db)
+}
+
+fn encode(fingerprint: &[u32]) -> Vec<u8> {
+	fingerprint.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+fn decode(bytes: &[u8]) -> Vec<u32> {
+	bytes
+		.chunks_exact(4)
+		.map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+		.collect()
+}
+
+/// Size and modified timestamp in ms
+fn get_file_state(path: &Path) -> Option<(u64, i64)> {
+	let md = fs::metadata(path).ok()?;
+	Some((md.len(), sys_time_to_timestamp(&md.modified().ok()?)))
+}
+
+/// A fingerprint, and the file it's from
+pub struct Stored {
+	path: String,
+	size: u64,
+	modified_ms: i64,
+	pub fingerprint: Vec<u32>,
+}
+
+impl Stored {
+	pub fn compute(path: &Path) -> UniResult<Self> {
+		let (size, modified_ms) = match get_file_state(path) {
+			Some(state) => state,
+			None => throw!("File not found"),
+		};
+		Ok(Stored {
+			path: path.to_string_lossy().into(),
+			size,
+			modified_ms,
+			fingerprint: fingerprint(path)?,
+		})
+	}
+	fn from_entry(entry: Entry) -> Self {
+		let (path, size, modified_ms, fingerprint) = entry;
+		Stored {
+			path: path.to_string(),
+			size,
+			modified_ms,
+			fingerprint: decode(fingerprint),
+		}
+	}
+	/// Whether it's from the file at `path`, and the file hasn't changed
+	pub fn is_current(&self, path: &Path) -> bool {
+		self.path == path.to_string_lossy()
+			&& get_file_state(path) == Some((self.size, self.modified_ms))
+	}
+}
+
+/// The stored fingerprint of a track. It may be outdated, which
+/// `Stored::is_current` checks
+pub fn get_stored(db: &Database, id: &str) -> Result<Option<Stored>> {
+	Ok(get_stored_many(db, &[id])?.pop().flatten())
+}
+
+/// Like `get_stored`, but for many tracks at once
+pub fn get_stored_many(db: &Database, ids: &[&str]) -> Result<Vec<Option<Stored>>> {
+	let read_txn = db
+		.begin_read()
+		.context("Could not begin read transaction")?;
+	let table = read_txn
+		.open_table(FINGERPRINTS_TABLE)
+		.context("Could not open table")?;
+	let mut stored = Vec::with_capacity(ids.len());
+	for id in ids {
+		let record = table.get(*id).context("Could not get record")?;
+		stored.push(record.map(|entry| Stored::from_entry(entry.value())));
+	}
+	Ok(stored)
+}
+
+fn store(db: &Database, fingerprints: &[(&str, Stored)]) -> Result<()> {
+	let write_txn = db
+		.begin_write()
+		.context("Could not begin write transaction")?;
+	{
+		let mut table = write_txn
+			.open_table(FINGERPRINTS_TABLE)
+			.context("Could not open table")?;
+		for (id, stored) in fingerprints {
+			let fingerprint = encode(&stored.fingerprint);
+			let entry = (
+				stored.path.as_str(),
+				stored.size,
+				stored.modified_ms,
+				fingerprint.as_slice(),
+			);
+			table
+				.insert(*id, entry)
+				.context("Could not insert record")?;
+		}
+	}
+	write_txn.commit().context("Could not commit transaction")?;
+	Ok(())
+}
+
+fn remove(db: &Database, ids: &[&str]) -> Result<()> {
+	let write_txn = db
+		.begin_write()
+		.context("Could not begin write transaction")?;
+	{
+		let mut table = write_txn
+			.open_table(FINGERPRINTS_TABLE)
+			.context("Could not open table")?;
+		for id in ids {
+			table.remove(*id).context("Could not remove record")?;
+		}
+	}
+	write_txn.commit().context("Could not commit transaction")?;
+	Ok(())
+}
+
+/// Deletes the fingerprints of tracks that were removed from the library.
+/// Errors are only logged, since the tracks are already removed, and
+/// leftover fingerprints are deleted by `fingerprint_library`
+pub fn remove_stored(db_path: &Path, ids: &[&str]) {
+	if ids.is_empty() {
+		return;
+	}
+	if let Err(err) = open_db(db_path).and_then(|db| remove(&db, ids)) {
+		println!("Unable to delete fingerprints: {err}");
+	}
+}
+
+#[napi(object)]
+pub struct FingerprintJobStatus {
+	pub fingerprinted: u32,
+	/// Files that couldn't be fingerprinted, with the reason
+	pub errors: Vec<String>,
+}
+
+struct FingerprintTracks {
+	files: Vec<(TrackID, PathBuf)>,
+	db_path: PathBuf,
+}
+impl Task for FingerprintTracks {
+	type Output = FingerprintJobStatus;
+	type JsValue = FingerprintJobStatus;
+	fn compute(&mut self) -> Result<Self::Output> {
+		let db = open_db(&self.db_path)?;
+		let mut outdated = Vec::new();
+		let mut removed = Vec::new();
+		{
+			let read_txn = db
+				.begin_read()
+				.context("Could not begin read transaction")?;
+			let table = read_txn
+				.open_table(FINGERPRINTS_TABLE)
+				.context("Could not open table")?;
+			for (id, path) in &self.files {
+				let record = table.get(id.as_str()).context("Could not get record")?;
+				let stored = record.map(|entry| Stored::from_entry(entry.value()));
+				if !stored.is_some_and(|stored| stored.is_current(path)) {
+					outdated.push((id.as_str(), path));
+				}
+			}
+			// Tracks that were removed while the app wasn't running, for
+			// example when the library was restored from a backup
+			let ids: HashSet<&str> = self.files.iter().map(|(id, _)| id.as_str()).collect();
+			for record in table.iter().context("Could not read table")? {
+				let (id, _) = record.context("Could not get record")?;
+				if !ids.contains(id.value()) {
+					removed.push(id.value().to_string());
+				}
+			}
+		}
+		let removed: Vec<&str> = removed.iter().map(String::as_str).collect();
+		remove(&db, &removed)?;
+		let mut status = FingerprintJobStatus {
+			fingerprinted: 0,
+			errors: Vec::new(),
+		};
+		// Saved in batches, so the progress is kept if the app is closed
+		for batch in outdated.chunks(BATCH_SIZE) {
+			let results: Vec<_> = batch
+				.par_iter()
+				.map(|(id, path)| (*id, *path, Stored::compute(path)))
+				.collect();
+			let mut fingerprints = Vec::new();
+			for (id, path, result) in results {
+				match result {
+					Ok(stored) => fingerprints.push((id, stored)),
+					Err(err) => {
+						status
+							.errors
+							.push(format!("{}: {}", path.to_string_lossy(), err.message))
+					}
+				}
+			}
+			store(&db, &fingerprints)?;
+			status.fingerprinted += fingerprints.len() as u32;
+		}
+		Ok(status)
+	}
+	fn resolve(&mut self, _env: Env, output: Self::Output) -> Result<Self::JsValue> {
+		Ok(output)
+	}
+}
+
+/// Fingerprints the tracks in the library that don't have an up to date
+/// fingerprint yet, and deletes fingerprints of tracks that don't exist
+#[napi(
+	js_name = "fingerprint_library",
+	ts_return_type = "Promise<FingerprintJobStatus>"
+)]
+#[allow(dead_code)]
+pub fn fingerprint_library(env: Env) -> Result<JsObject> {
+	let data: &mut Data = get_data(&env)?;
+	let files = data
+		.library
+		.tracks
+		.iter()
+		.map(|(id, track)| (id.clone(), data.paths.tracks_dir.join(&track.file)))
+		.collect();
+	let task = FingerprintTracks {
+		files,
+		db_path: data.paths.fingerprints_db.clone(),
+	};
+	env.spawn(task).map(|t| t.promise_object())
+}
+
+/// The raw Chromaprint fingerprint of a track, or `None` if it hasn't been
+/// fingerprinted since its file last changed
+#[napi(js_name = "get_fingerprint")]
+#[allow(dead_code)]
+pub fn get_fingerprint(track_id: TrackID, env: Env) -> Result<Option<Vec<u32>>> {
+	let data: &mut Data = get_data(&env)?;
+	let path = data
+		.paths
+		.tracks_dir
+		.join(&data.library.get_track(&track_id)?.file);
+	let db = open_db(&data.paths.fingerprints_db)?;
+	let stored = get_stored(&db, &track_id)?;
+	Ok(stored
+		.filter(|stored| stored.is_current(&path))
+		.map(|stored| stored.fingerprint))
+}
+
+#[napi(object)]
+pub struct SimilarTrack {
+	pub track_id: TrackID,
+	/// From 0 to 1
+	pub similarity: f64,
+}
+
+struct FindSimilarTracks {
+	track_id: TrackID,
+	path: PathBuf,
+	/// Tracks to compare with
+	tracks: Vec<(TrackID, PathBuf)>,
+	min_similarity: f64,
+	db_path: PathBuf,
+}
+impl Task for FindSimilarTracks {
+	type Output = Vec<SimilarTrack>;
+	type JsValue = Vec<SimilarTrack>;
+	fn compute(&mut self) -> Result<Self::Output> {
+		let db = open_db(&self.db_path)?;
+		let fingerprint = match get_stored(&db, &self.track_id)? {
+			Some(stored) if stored.is_current(&self.path) => stored.fingerprint,
+			_ => {
+				let stored = Stored::compute(&self.path)?;
+				let fingerprint = stored.fingerprint.clone();
+				store(&db, &[(&self.track_id, stored)])?;
+				fingerprint
+			}
+		};
+		let matcher = Matcher::new(&fingerprint);
+
+		let ids: Vec<&str> = self.tracks.iter().map(|(id, _)| id.as_str()).collect();
+		let others = get_stored_many(&db, &ids)?;
+		let mut matches = Vec::new();
+		for ((id, path), other) in self.tracks.iter().zip(others) {
+			if *id == self.track_id {
+				continue;
+			}
+			let other = match other {
+				Some(other) if other.is_current(path) => other,
+				_ => continue,
+			};
+			let similarity = matcher.similarity(&other.fingerprint);
+			if similarity >= self.min_similarity {
+				matches.push(SimilarTrack {
+					track_id: id.clone(),
+					similarity,
+				});
+			}
+		}
+		matches.sort_by(|a, b| b.similarity.total_cmp(&a.similarity));
+		Ok(matches)
+	}
+	fn resolve(&mut self, _env: Env, output: Self::Output) -> Result<Self::JsValue> {
+		Ok(output)
+	}
+}
+
+/// Finds tracks that sound like the given track, most similar first. Only
+/// tracks that have been fingerprinted are included. `min_similarity`
+/// defaults to 0.75.
+#[napi(
+	js_name = "find_similar_tracks",
+	ts_return_type = "Promise<Array<SimilarTrack>>"
+)]
+#[allow(dead_code)]
+pub fn find_similar_tracks(
+	track_id: TrackID,
+	min_similarity: Option<f64>,
+	env: Env,
+) -> Result<JsObject> {
+	let data: &mut Data = get_data(&env)?;
+	let track = data.library.get_track(&track_id)?;
+	let tracks_dir = &data.paths.tracks_dir;
+	let task = FindSimilarTracks {
+		path: tracks_dir.join(&track.file),
+		track_id,
+		tracks: data
+			.library
+			.tracks
+			.iter()
+			.map(|(id, track)| (id.clone(), tracks_dir.join(&track.file)))
+			.collect(),
+		min_similarity: min_similarity.unwrap_or(DEFAULT_MIN_SIMILARITY),
+		db_path: data.paths.fingerprints_db.clone(),
+	};
+	env.spawn(task).map(|t| t.promise_object())
+}
+
+#[test]
+fn fingerprint_test() {
+	use std::f32::consts::PI;
+	use std::fs;
+
+	// A melody at 44.1 kHz, and the same melody at 48 kHz and quieter
+	let notes = [261.63, 329.63, 392.0, 440.0, 349.23, 293.66, 493.88, 261.63];
+	let melody = |sample_rate: u32, gain: f32| -> Vec<f32> {
+		(0..sample_rate * 20)
+			.map(|i| {
+				let t = i as f32 / sample_rate as f32;
+				let note = notes[(t * 2.0) as usize % notes.len()];
+				(2.0 * PI * note * t).sin() * gain
+			})
+			.collect()
+	};
+	let other = |sample_rate: u32| -> Vec<f32> {
+		(0..sample_rate * 20)
+			.map(|i| {
+				let t = i as f32 / sample_rate as f32;
+				let note = notes[(t * 3.0) as usize * 5 % notes.len()] * 1.5;
+				(2.0 * PI * note * t).sin() * 0.5
+			})
+			.collect()
+	};
+	let dir = std::env::temp_dir();
+	let id = std::process::id();
+	let paths = [
+		dir.join(format!("ferrum-fingerprint-{id}-a.wav")),
+		dir.join(format!("ferrum-fingerprint-{id}-b.wav")),
+		dir.join(format!("ferrum-fingerprint-{id}-c.wav")),
+	];
+	super::write_test_wav(&paths[0], 44100, 1, &melody(44100, 0.5));
+	super::write_test_wav(&paths[1], 48000, 1, &melody(48000, 0.2));
+	super::write_test_wav(&paths[2], 44100, 1, &other(44100));
+	let fingerprints: Vec<_> = paths.iter().map(|p| fingerprint(p).unwrap()).collect();
+
+	// Stored fingerprints are outdated once the file changes
+	let db_path = dir.join(format!("ferrum-fingerprint-{id}.redb"));
+	let db = Database::create(&db_path).unwrap();
+	let write_txn = db.begin_write().unwrap();
+	write_txn.open_table(FINGERPRINTS_TABLE).unwrap();
+	write_txn.commit().unwrap();
+	store(&db, &[("a", Stored::compute(&paths[0]).unwrap())]).unwrap();
+	let stored = get_stored(&db, "a").unwrap().unwrap();
+	assert_eq!(stored.fingerprint, fingerprints[0]);
+	assert!(stored.is_current(&paths[0]));
+	assert!(!stored.is_current(&paths[1]));
+	super::write_test_wav(&paths[0], 44100, 1, &melody(44100, 0.4)[..44100 * 19]);
+	assert!(!stored.is_current(&paths[0]));
+	remove(&db, &["a"]).unwrap();
+	assert!(get_stored(&db, "a").unwrap().is_none());
+	drop(db);
+	fs::remove_file(&db_path).unwrap();
+	for path in &paths {
+		fs::remove_file(path).unwrap();
+	}
+
+	// About 8 values per second, after the first 2 seconds
+	assert!((130..150).contains(&fingerprints[0].len()));
+	let matcher = Matcher::new(&fingerprints[0]);
+	assert_eq!(matcher.similarity(&fingerprints[0]), 1.0);
+	let same = matcher.similarity(&fingerprints[1]);
+	let different = matcher.similarity(&fingerprints[2]);
+	assert!(same > DEFAULT_MIN_SIMILARITY, "{same}");
+	assert!(different < DEFAULT_MIN_SIMILARITY, "{different}");
+	// Fingerprints that start later are lined up
+	assert_eq!(matcher.similarity(&fingerprints[0][20..]), 1.0);
+	assert_eq!(decode(&encode(&fingerprints[0])), fingerprints[0]);
+}
@@ -0,0 +1,89 @@
+//! Estimates the musical key from how much each pitch class is heard, using
+//! the Krumhansl-Schmuckler key profiles.
+
+const MAJOR_PROFILE: [f64; 12] = [
+	6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88,
+];
+const MINOR_PROFILE: [f64; 12] = [
+	6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17,
+];
+const NOTE_NAMES: [&str; 12] = [
+	"C", "C#", "D", "Eb", "E", "F", "F#", "G", "Ab", "A", "Bb", "B",
+];
+/// Frequencies outside this range are mostly drums and overtones
+const MIN_FREQUENCY: f32 = 55.0;
+const MAX_FREQUENCY: f32 = 5000.0;
+
+/// Pearson correlation of `chroma` and `profile`, with the profile starting
+/// at `tonic`
+fn correlation(chroma: &[f64; 12], profile: &[f64; 12], tonic: usize) -> f64 {
+	let chroma_mean = chroma.iter().sum::<f64>() / 12.0;
+	let profile_mean = profile.iter().sum::<f64>() / 12.0;
+	let mut covariance = 0.0;
+	let mut chroma_variance = 0.0;
+	let mut profile_variance = 0.0;
+	for (i, p) in profile.iter().enumerate() {
+		let c = chroma[(tonic + i) % 12] - chroma_mean;
+		let p = p - profile_mean;
+		covariance += c * p;
+		chroma_variance += c * c;
+		profile_variance += p * p;
+	}
+	let variance = (chroma_variance * profile_variance).sqrt();
+	match variance > 0.0 {
+		true => covariance / variance,
+		false => 0.0,
+	}
+}
+
+/// Sums the spectrum of frames per pitch class
+pub struct Chroma {
+	/// Pitch class of each frequency bin, if it's in range
+	pitch_classes: Vec<Option<usize>>,
+	values: [f64; 12],
+}
+
+impl Chroma {
+	pub fn new(sample_rate: u32, fft_size: usize) -> Self {
+		let pitch_classes = (0..=fft_size / 2)
+			.map(|bin| {
+				let frequency = bin as f32 * sample_rate as f32 / fft_size as f32;
+				if !(MIN_FREQUENCY..=MAX_FREQUENCY).contains(&frequency) {
+					return None;
+				}
+				let midi_note = 69.0 + 12.0 * (frequency / 440.0).log2();
+				Some(midi_note.round() as usize % 12)
+			})
+			.collect();
+		Chroma {
+			pitch_classes,
+			values: [0.0; 12],
+		}
+	}
+	pub fn add(&mut self, magnitudes: &[f32]) {
+		for (magnitude, pitch_class) in magnitudes.iter().zip(&self.pitch_classes) {
+			if let Some(pitch_class) = pitch_class {
+				self.values[*pitch_class] += f64::from(*magnitude);
+			}
+		}
+	}
+	/// The key, like "Ebm", and how well it matches, from 0 to 1. `None` if
+	/// nothing was heard
+	pub fn estimate(&self) -> Option<(String, f64)> {
+		if self.values.iter().all(|value| *value == 0.0) {
+			return None;
+		}
+		let mut best = (String::new(), f64::MIN);
+		for (tonic, note) in NOTE_NAMES.iter().enumerate() {
+			let major = correlation(&self.values, &MAJOR_PROFILE, tonic);
+			if major > best.1 {
+				best = (note.to_string(), major);
+			}
+			let minor = correlation(&self.values, &MINOR_PROFILE, tonic);
+			if minor > best.1 {
+				best = (format!("{note}m"), minor);
+			}
+		}
+		Some((best.0, best.1.clamp(0.0, 1.0)))
+	}
+}
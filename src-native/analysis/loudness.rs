@@ -0,0 +1,448 @@
+//! Loudness analysis per EBU R128 (ITU-R BS.1770), so tracks can be played
+//! at the same perceived volume. Gains are relative to the ReplayGain 2
+//! reference of -18 LUFS.
+
+use super::decode::Decoder;
+use crate::data::Data;
+use crate::data_js::get_data;
+use crate::get_now_timestamp;
+use crate::library_types::{Track, TrackID};
+use crate::tracks::Tag;
+use crate::UniResult;
+use napi::{Env, JsObject, Result, Task};
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::f64::consts::PI;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+pub const REFERENCE_LOUDNESS: f64 = -18.0;
+/// Blocks quieter than this are ignored
+const ABSOLUTE_GATE: f64 = -70.0;
+/// Blocks this much quieter than the ungated loudness are ignored
+const RELATIVE_GATE: f64 = -10.0;
+const STEPS_PER_BLOCK: usize = 4;
+/// True peak is measured by interpolating this many points per sample
+const OVERSAMPLING: usize = 4;
+const INTERPOLATION_TAPS: usize = 12;
+
+#[derive(Clone, Copy)]
+struct Biquad {
+	b: [f64; 3],
+	a: [f64; 2],
+}
+
+impl Biquad {
+	fn process(&self, state: &mut [f64; 2], x: f64) -> f64 {
+		let y = self.b[0] * x + state[0];
+		state[0] = self.b[1] * x - self.a[0] * y + state[1];
+		state[1] = self.b[2] * x - self.a[1] * y;
+		y
+	}
+}
+
+/// The K-weighting pre-filter and high-pass filter, for any sample rate
+fn k_weighting(sample_rate: f64) -> [Biquad; 2] {
+	let k = (PI * 1681.974450955533 / sample_rate).tan();
+	let q = 0.7071752369554196;
+	let vh = 10f64.powf(3.999843853973347 / 20.0);
+	let vb = vh.powf(0.4996667741545416);
+	let a0 = 1.0 + k / q + k * k;
+	let shelf = Biquad {
+		b: [
+			(vh + vb * k / q + k * k) / a0,
+			2.0 * (k * k - vh) / a0,
+			(vh - vb * k / q + k * k) / a0,
+		],
+		a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+	};
+	let k = (PI * 38.13547087602444 / sample_rate).tan();
+	let q = 0.5003270373238773;
+	let a0 = 1.0 + k / q + k * k;
+	let high_pass = Biquad {
+		b: [1.0, -2.0, 1.0],
+		a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+	};
+	[shelf, high_pass]
+}
+
+/// Windowed sinc coefficients for each interpolated point between samples
+fn interpolation_filter() -> [[f64; INTERPOLATION_TAPS]; OVERSAMPLING] {
+	let center = (INTERPOLATION_TAPS / 2 - 1) as f64;
+	let half_width = (INTERPOLATION_TAPS / 2) as f64;
+	let mut filter = [[0.0; INTERPOLATION_TAPS]; OVERSAMPLING];
+	for (phase, coefficients) in filter.iter_mut().enumerate() {
+		let offset = phase as f64 / OVERSAMPLING as f64;
+		for (i, coefficient) in coefficients.iter_mut().enumerate() {
+			let t = center + offset - i as f64;
+			let sinc = if t == 0.0 {
+				1.0
+			} else {
+				(PI * t).sin() / (PI * t)
+			};
+			let window = 0.5 * (1.0 + (PI * t / half_width).cos());
+			*coefficient = sinc * window;
+		}
+	}
+	filter
+}
+
+fn block_loudness(mean_square: f64) -> f64 {
+	-0.691 + 10.0 * mean_square.log10()
+}
+
+fn mean(values: &[f64]) -> f64 {
+	values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// Gated loudness of blocks, in LUFS. Blocks of several tracks can be
+/// combined to get the loudness of an album. `None` if everything is silent
+pub fn integrated_loudness(blocks: &[f64]) -> Option<f64> {
+	let blocks: Vec<f64> = blocks
+		.iter()
+		.copied()
+		.filter(|block| block_loudness(*block) > ABSOLUTE_GATE)
+		.collect();
+	if blocks.is_empty() {
+		return None;
+	}
+	let gate = block_loudness(mean(&blocks)) + RELATIVE_GATE;
+	let blocks: Vec<f64> = blocks
+		.into_iter()
+		.filter(|block| block_loudness(*block) > gate)
+		.collect();
+	match blocks.is_empty() {
+		true => None,
+		false => Some(block_loudness(mean(&blocks))),
+	}
+}
+
+pub struct Loudness {
+	/// Weighted mean square of each 400 ms block, overlapping by 75%
+	pub blocks: Vec<f64>,
+	/// Linear
+	pub true_peak: f64,
+}
+
+/// Measures the loudness of interleaved samples
+pub struct Meter {
+	channels: usize,
+	weights: Vec<f64>,
+	filters: [Biquad; 2],
+	filter_states: Vec<[[f64; 2]; 2]>,
+	/// Frames per 100 ms step
+	step_frames: usize,
+	step_frame: usize,
+	step_sum: f64,
+	/// Sums of the last steps
+	steps: VecDeque<f64>,
+	blocks: Vec<f64>,
+	interpolation: [[f64; INTERPOLATION_TAPS]; OVERSAMPLING],
+	/// Last samples of each channel, for interpolation
+	history: Vec<VecDeque<f64>>,
+	peak: f64,
+}
+
+impl Meter {
+	pub fn new(sample_rate: u32, channels: usize) -> Self {
+		let weights = (0..channels)
+			.map(|channel| match (channels, channel) {
+				// Surround channels of 5.1 are weighted up, and LFE is ignored
+				(6, 3) => 0.0,
+				(6, 4 | 5) => 1.41,
+				_ => 1.0,
+			})
+			.collect();
+		Meter {
+			channels,
+			weights,
+			filters: k_weighting(sample_rate.into()),
+			filter_states: vec![[[0.0; 2]; 2]; channels],
+			step_frames: (sample_rate as usize / 10).max(1),
+			step_frame: 0,
+			step_sum: 0.0,
+			steps: VecDeque::with_capacity(STEPS_PER_BLOCK),
+			blocks: Vec::new(),
+			interpolation: interpolation_filter(),
+			history: vec![VecDeque::from([0.0; INTERPOLATION_TAPS]); channels],
+			peak: 0.0,
+		}
+	}
+	fn add_to_peak(&mut self, channel: usize, sample: f64) {
+		let history = &mut self.history[channel];
+		history.pop_front();
+		history.push_back(sample);
+		for coefficients in &self.interpolation {
+			let value: f64 = history.iter().zip(coefficients).map(|(x, c)| x * c).sum();
+			self.peak = self.peak.max(value.abs());
+		}
+	}
+	pub fn add(&mut self, samples: &[f32]) {
+		for frame in samples.chunks_exact(self.channels) {
+			for (channel, sample) in frame.iter().enumerate() {
+				let sample = f64::from(*sample);
+				self.add_to_peak(channel, sample);
+				let state = &mut self.filter_states[channel];
+				let filtered = self.filters[0].process(&mut state[0], sample);
+				let filtered = self.filters[1].process(&mut state[1], filtered);
+				self.step_sum += self.weights[channel] * filtered * filtered;
+			}
+			self.step_frame += 1;
+			if self.step_frame == self.step_frames {
+				if self.steps.len() == STEPS_PER_BLOCK {
+					self.steps.pop_front();
+				}
+				self.steps.push_back(self.step_sum);
+				if self.steps.len() == STEPS_PER_BLOCK {
+					let block_frames = (self.step_frames * STEPS_PER_BLOCK) as f64;
+					self.blocks
+						.push(self.steps.iter().sum::<f64>() / block_frames);
+				}
+				self.step_frame = 0;
+				self.step_sum = 0.0;
+			}
+		}
+	}
+	pub fn finish(mut self) -> Loudness {
+		// Flush the interpolation history, so the last samples are included
+		for channel in 0..self.channels {
+			for _ in 0..INTERPOLATION_TAPS {
+				self.add_to_peak(channel, 0.0);
+			}
+		}
+		Loudness {
+			blocks: self.blocks,
+			true_peak: self.peak,
+		}
+	}
+}
+
+pub fn measure(path: &Path) -> UniResult<Loudness> {
+	let mut decoder = Decoder::open(path)?;
+	let mut meter: Option<Meter> = None;
+	while let Some((format, samples)) = decoder.next_chunk()? {
+		let meter = match &mut meter {
+			Some(meter) => meter,
+			None => meter.insert(Meter::new(format.sample_rate, format.channels)),
+		};
+		if format.channels != meter.channels {
+			throw!("Number of channels changed while decoding");
+		}
+		meter.add(samples);
+	}
+	match meter {
+		Some(meter) => Ok(meter.finish()),
+		None => throw!("No audio found"),
+	}
+}
+
+fn to_decibels(linear: f64) -> f64 {
+	20.0 * linear.log10()
+}
+
+struct TrackLoudness {
+	loudness: Option<f64>,
+	/// Linear
+	true_peak: f64,
+	album_loudness: Option<f64>,
+	/// Linear
+	album_peak: Option<f64>,
+}
+
+fn write_tags(path: &Path, result: &TrackLoudness) -> UniResult<()> {
+	let loudness = match result.loudness {
+		Some(loudness) => loudness,
+		None => throw!("Track is silent"),
+	};
+	let mut tag = Tag::read_from_path(path)?;
+	let album = match (result.album_loudness, result.album_peak) {
+		(Some(loudness), Some(peak)) => Some((REFERENCE_LOUDNESS - loudness, peak)),
+		_ => None,
+	};
+	tag.set_replay_gain(REFERENCE_LOUDNESS - loudness, result.true_peak, album);
+	tag.write_to_path(path)
+}
+
+/// Identifies the album of a track, for album gain
+fn album_key(track: &Track) -> Option<(String, String)> {
+	let album_name = track.albumName.as_deref().unwrap_or_default();
+	if album_name.is_empty() {
+		return None;
+	}
+	let album_artist = track.albumArtist.as_ref().unwrap_or(&track.artist);
+	Some((album_artist.clone(), album_name.to_string()))
+}
+
+struct AnalyzeFile {
+	id: TrackID,
+	path: PathBuf,
+	album: Option<(String, String)>,
+}
+
+#[napi(object)]
+pub struct LoudnessJobStatus {
+	pub analyzed: u32,
+	/// Files that couldn't be analyzed or tagged, with the reason
+	pub errors: Vec<String>,
+}
+
+struct AnalyzeLoudness {
+	files: Vec<AnalyzeFile>,
+	write_tags: bool,
+}
+impl Task for AnalyzeLoudness {
+	type Output = (Vec<(TrackID, TrackLoudness, bool)>, Vec<String>);
+	type JsValue = LoudnessJobStatus;
+	fn compute(&mut self) -> Result<Self::Output> {
+		let measured: Vec<_> = self
+			.files
+			.par_iter()
+			.map(|file| (file, measure(&file.path)))
+			.collect();
+
+		let mut errors = Vec::new();
+		let mut albums: HashMap<&(String, String), (Vec<f64>, f64)> = HashMap::new();
+		for (file, result) in &measured {
+			match (&file.album, result) {
+				(Some(album), Ok(loudness)) => {
+					let (blocks, peak) = albums.entry(album).or_default();
+					blocks.extend(&loudness.blocks);
+					*peak = peak.max(loudness.true_peak);
+				}
+				(_, Err(err)) => {
+					errors.push(format!("{}: {}", file.path.to_string_lossy(), err.message))
+				}
+				_ => {}
+			}
+		}
+		let albums: HashMap<_, _> = albums
+			.into_iter()
+			.map(|(album, (blocks, peak))| (album, (integrated_loudness(&blocks), peak)))
+			.collect();
+
+		let results: Vec<_> = measured
+			.iter()
+			.filter_map(|(file, result)| {
+				let loudness = result.as_ref().ok()?;
+				let album = file.album.as_ref().map(|album| albums[album]);
+				let result = TrackLoudness {
+					loudness: integrated_loudness(&loudness.blocks),
+					true_peak: loudness.true_peak,
+					album_loudness: album.and_then(|(loudness, _)| loudness),
+					album_peak: album.map(|(_, peak)| peak),
+				};
+				Some((file, result))
+			})
+			.collect();
+		let tag_errors = std::sync::Mutex::new(Vec::new());
+		let output = results
+			.into_par_iter()
+			.map(|(file, result)| {
+				let mut tagged = false;
+				if self.write_tags {
+					match write_tags(&file.path, &result) {
+						Ok(()) => tagged = true,
+						Err(err) => tag_errors.lock().unwrap().push(format!(
+							"{}: {}",
+							file.path.to_string_lossy(),
+							err.message
+						)),
+					}
+				}
+				(file.id.clone(), result, tagged)
+			})
+			.collect();
+		errors.extend(tag_errors.into_inner().unwrap());
+		Ok((output, errors))
+	}
+	fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+		let data: &mut Data = get_data(&env)?;
+		let (results, errors) = output;
+		let now = get_now_timestamp();
+		let mut analyzed = 0;
+		for (id, result, tagged) in results {
+			// The track may have been deleted in the meantime
+			let track = match data.library.tracks.get_mut(&id) {
+				Some(track) => track,
+				None => continue,
+			};
+			track.loudness = result.loudness;
+			track.truePeak = Some(to_decibels(result.true_peak));
+			track.albumLoudness = result.album_loudness;
+			if tagged {
+				let path = data.paths.tracks_dir.join(&track.file);
+				if let Ok(md) = fs::metadata(path) {
+					track.size = md.len() as i64;
+				}
+				track.dateModified = now;
+			}
+			data.track_changed(&id);
+			analyzed += 1;
+		}
+		Ok(LoudnessJobStatus { analyzed, errors })
+	}
+}
+
+/// Measures the loudness of tracks. Other tracks of the same albums are
+/// included, so the album loudness is complete. If `write_tags` is set,
+/// ReplayGain tags are written to the files.
+#[napi(
+	js_name = "analyze_loudness",
+	ts_return_type = "Promise<LoudnessJobStatus>"
+)]
+#[allow(dead_code)]
+pub fn analyze_loudness(track_ids: Vec<TrackID>, write_tags: bool, env: Env) -> Result<JsObject> {
+	let data: &mut Data = get_data(&env)?;
+	let mut albums = HashSet::new();
+	let mut ids = HashSet::new();
+	for id in &track_ids {
+		let track = data.library.get_track(id)?;
+		if let Some(album) = album_key(track) {
+			albums.insert(album);
+		}
+		ids.insert(id);
+	}
+	let files = data
+		.library
+		.tracks
+		.iter()
+		.map(|(id, track)| (id, track, album_key(track)))
+		.filter(|(id, _, album)| {
+			ids.contains(id) || album.as_ref().is_some_and(|album| albums.contains(album))
+		})
+		.map(|(id, track, album)| AnalyzeFile {
+			id: id.clone(),
+			path: data.paths.tracks_dir.join(&track.file),
+			album,
+		})
+		.collect();
+	let task = AnalyzeLoudness { files, write_tags };
+	env.spawn(task).map(|t| t.promise_object())
+}
+
+#[test]
+fn loudness_test() {
+	// A 997 Hz sine at -20 dBFS measures -20 LUFS per channel, plus 3 LU
+	// for two channels
+	let sample_rate = 48000;
+	let amplitude = 10f64.powf(-20.0 / 20.0) * 2f64.sqrt();
+	let samples: Vec<f32> = (0..sample_rate * 5)
+		.flat_map(|i| {
+			let t = i as f64 / sample_rate as f64;
+			let value = (amplitude * (2.0 * PI * 997.0 * t).sin()) as f32;
+			[value, value]
+		})
+		.collect();
+	let path = std::env::temp_dir().join(format!("ferrum-loudness-{}.wav", std::process::id()));
+	super::write_test_wav(&path, sample_rate as u32, 2, &samples);
+	let loudness = measure(&path).unwrap();
+	fs::remove_file(&path).unwrap();
+	let integrated = integrated_loudness(&loudness.blocks).unwrap();
+	assert!((integrated - -17.0).abs() < 0.1, "{integrated}");
+	assert!((to_decibels(loudness.true_peak) - -17.0).abs() < 0.1);
+
+	// Silence is gated away
+	let mut meter = Meter::new(44100, 1);
+	meter.add(&[0.0; 44100]);
+	assert_eq!(integrated_loudness(&meter.finish().blocks), None);
+}
@@ -0,0 +1,33 @@
+//! Analysis of the decoded audio of tracks
+
+mod decode;
+pub mod fingerprint;
+mod key;
+pub mod loudness;
+pub mod silence;
+mod spectrum;
+pub mod tempo;
+pub mod waveform;
+
+/// Writes interleaved samples to a 16-bit WAV file
+#[cfg(test)]
+pub fn write_test_wav(path: &std::path::Path, sample_rate: u32, channels: u16, samples: &[f32]) {
+	let data_size = samples.len() as u32 * 2;
+	let mut wav = Vec::new();
+	wav.extend(b"RIFF");
+	wav.extend((36 + data_size).to_le_bytes());
+	wav.extend(b"WAVEfmt ");
+	wav.extend(16u32.to_le_bytes());
+	wav.extend(1u16.to_le_bytes()); // PCM
+	wav.extend(channels.to_le_bytes());
+	wav.extend(sample_rate.to_le_bytes());
+	wav.extend((sample_rate * u32::from(channels) * 2).to_le_bytes());
+	wav.extend((channels * 2).to_le_bytes());
+	wav.extend(16u16.to_le_bytes());
+	wav.extend(b"data");
+	wav.extend(data_size.to_le_bytes());
+	for sample in samples {
+		wav.extend(((sample.clamp(-1.0, 1.0) * 32767.0) as i16).to_le_bytes());
+	}
+	std::fs::write(path, wav).unwrap();
+}
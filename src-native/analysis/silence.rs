@@ -0,0 +1,218 @@
+//! Finds silence at the start and end of tracks, so playback can skip it.
+//! Positions are after the encoder delay and padding are removed. Tracks are
+//! analyzed in the background after they're added, since it decodes them.
+
+use super::decode::Decoder;
+use crate::data::Data;
+use crate::data_js::get_data;
+use crate::library_types::TrackID;
+use crate::UniResult;
+use napi::{Env, JsObject, Result, Task};
+use rayon::prelude::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// About -60 dBFS
+const SILENCE_THRESHOLD: f32 = 0.001;
+/// Only this much of the end of the track is decoded, unless it's all silent
+const TAIL_DURATION: f64 = 30.0;
+
+/// Seconds of silence
+#[derive(Debug, PartialEq)]
+pub struct Silence {
+	pub start: f64,
+	pub end: f64,
+}
+
+fn is_sound(frame: &[f32]) -> bool {
+	frame.iter().any(|sample| sample.abs() >= SILENCE_THRESHOLD)
+}
+
+/// Decodes until the end, starting at `position` seconds. Returns where the
+/// last sound ends, and where the audio ends.
+fn find_last_sound(
+	decoder: &mut Decoder,
+	mut position: f64,
+	mut last_sound: Option<f64>,
+) -> UniResult<(Option<f64>, f64)> {
+	while let Some((format, samples)) = decoder.next_chunk()? {
+		let mut frames = samples.chunks_exact(format.channels);
+		let length = frames.len() as f64 / f64::from(format.sample_rate);
+		if let Some(i) = frames.rposition(is_sound) {
+			last_sound = Some(position + (i + 1) as f64 / f64::from(format.sample_rate));
+		}
+		position += length;
+	}
+	Ok((last_sound, position))
+}
+
+pub fn detect(path: &Path) -> UniResult<Silence> {
+	let mut decoder = Decoder::open(path)?;
+	let mut position = 0.0;
+	let mut first_sound = None;
+	let mut last_sound = None;
+	while first_sound.is_none() {
+		let (format, samples) = match decoder.next_chunk()? {
+			Some(chunk) => chunk,
+			None => break,
+		};
+		let sample_rate = f64::from(format.sample_rate);
+		let mut frames = samples.chunks_exact(format.channels);
+		let length = frames.len() as f64 / sample_rate;
+		if let Some(i) = frames.position(is_sound) {
+			first_sound = Some(position + i as f64 / sample_rate);
+			let last = frames.rposition(is_sound).map_or(i, |j| i + 1 + j);
+			last_sound = Some(position + (last + 1) as f64 / sample_rate);
+		}
+		position += length;
+	}
+	let start = match first_sound {
+		Some(start) => start,
+		None => {
+			return Ok(Silence {
+				start: position,
+				end: 0.0,
+			})
+		}
+	};
+
+	let tail_start = decoder
+		.duration
+		.map(|duration| duration - TAIL_DURATION)
+		.filter(|tail_start| *tail_start > position);
+	let mut seeked = false;
+	if let Some(tail_start) = tail_start {
+		if let Ok(tail_position) = decoder.seek(tail_start) {
+			position = tail_position;
+			last_sound = None;
+			seeked = true;
+		}
+	}
+	let (last_sound, end) = match find_last_sound(&mut decoder, position, last_sound)? {
+		// The whole tail is silent, so the rest needs to be decoded too
+		(None, _) if seeked => find_last_sound(&mut Decoder::open(path)?, 0.0, None)?,
+		result => result,
+	};
+	Ok(Silence {
+		start,
+		end: last_sound.map_or(0.0, |last_sound| end - last_sound),
+	})
+}
+
+fn get_modified(path: &Path) -> Option<SystemTime> {
+	fs::metadata(path).ok()?.modified().ok()
+}
+
+#[napi(object)]
+pub struct SilenceJobStatus {
+	pub analyzed: u32,
+	/// Files that couldn't be analyzed, with the reason
+	pub errors: Vec<String>,
+}
+
+struct AnalyzeSilence {
+	files: Vec<(TrackID, PathBuf)>,
+}
+impl Task for AnalyzeSilence {
+	type Output = Vec<(TrackID, PathBuf, UniResult<Silence>)>;
+	type JsValue = SilenceJobStatus;
+	fn compute(&mut self) -> Result<Self::Output> {
+		let silences = self
+			.files
+			.par_iter()
+			.map(|(id, path)| (id.clone(), path.clone(), detect(path)))
+			.collect();
+		Ok(silences)
+	}
+	fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+		let data: &mut Data = get_data(&env)?;
+		let mut status = SilenceJobStatus {
+			analyzed: 0,
+			errors: Vec::new(),
+		};
+		for (id, path, result) in output {
+			// The track may have been deleted in the meantime
+			let track = match data.library.tracks.get_mut(&id) {
+				Some(track) => track,
+				None => continue,
+			};
+			match result {
+				Ok(silence) => {
+					track.silenceStart = Some(silence.start);
+					track.silenceEnd = Some(silence.end);
+					data.track_changed(&id);
+					status.analyzed += 1;
+				}
+				// Not all formats can be decoded, like Opus
+				Err(err) => {
+					let path_str = path.to_string_lossy();
+					status.errors.push(format!("{}: {}", path_str, err.message));
+					data.silence_failed.insert(id, get_modified(&path));
+				}
+			}
+		}
+		Ok(status)
+	}
+}
+
+/// Finds the silence of tracks that haven't been analyzed yet, like newly
+/// imported tracks. Tracks that couldn't be analyzed are skipped until their
+/// file changes.
+#[napi(
+	js_name = "analyze_silence",
+	ts_return_type = "Promise<SilenceJobStatus>"
+)]
+#[allow(dead_code)]
+pub fn analyze_silence(env: Env) -> Result<JsObject> {
+	let data: &mut Data = get_data(&env)?;
+	let tracks_dir = &data.paths.tracks_dir;
+	let silence_failed = &data.silence_failed;
+	let files = data
+		.library
+		.tracks
+		.iter()
+		.filter(|(_, track)| track.silenceStart.is_none())
+		.map(|(id, track)| (id.clone(), tracks_dir.join(&track.file)))
+		.filter(|(id, path)| match silence_failed.get(id) {
+			Some(modified) => get_modified(path) != *modified,
+			None => true,
+		})
+		.collect();
+	let task = AnalyzeSilence { files };
+	env.spawn(task).map(|t| t.promise_object())
+}
+
+#[test]
+fn silence_test() {
+	use std::fs;
+
+	// 1.5 seconds of silence, 40 seconds of sound and 2 seconds of silence
+	let sample_rate = 8000;
+	let samples: Vec<f32> = (0..sample_rate * 87)
+		.map(|i| match i / 2 {
+			frame if frame < sample_rate * 3 / 2 => 0.0,
+			frame if frame < sample_rate * 83 / 2 => 0.5,
+			_ => 0.0,
+		})
+		.collect();
+	let path = std::env::temp_dir().join(format!("ferrum-silence-{}.wav", std::process::id()));
+	super::write_test_wav(&path, sample_rate as u32, 2, &samples);
+	let silence = detect(&path).unwrap();
+	fs::remove_file(&path).unwrap();
+
+	assert!((silence.start - 1.5).abs() < 0.001, "{silence:?}");
+	assert!((silence.end - 2.0).abs() < 0.001, "{silence:?}");
+
+	// Only silence
+	super::write_test_wav(&path, 8000, 1, &[0.0; 2]);
+	let silence = detect(&path).unwrap();
+	fs::remove_file(&path).unwrap();
+	assert_eq!(
+		silence,
+		Silence {
+			start: 2.0 / 8000.0,
+			end: 0.0
+		}
+	);
+}
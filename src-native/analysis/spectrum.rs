@@ -0,0 +1,90 @@
+use super::decode::Format;
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use std::f32::consts::PI;
+use std::sync::Arc;
+
+/// Mixes interleaved samples down to mono, and lowers the sample rate by
+/// averaging, since the analysis doesn't need high frequencies
+pub struct Downmix {
+	factor: usize,
+	pub sample_rate: u32,
+	sum: f32,
+	count: usize,
+}
+
+impl Downmix {
+	pub fn new(sample_rate: u32, max_sample_rate: u32) -> Self {
+		let factor = (sample_rate / max_sample_rate).max(1) as usize;
+		Downmix {
+			factor,
+			sample_rate: sample_rate / factor as u32,
+			sum: 0.0,
+			count: 0,
+		}
+	}
+	pub fn add(&mut self, format: Format, samples: &[f32], output: &mut Vec<f32>) {
+		for frame in samples.chunks_exact(format.channels) {
+			self.sum += frame.iter().sum::<f32>() / format.channels as f32;
+			self.count += 1;
+			if self.count == self.factor {
+				output.push(self.sum / self.factor as f32);
+				self.sum = 0.0;
+				self.count = 0;
+			}
+		}
+	}
+}
+
+/// Short-time Fourier transform, computed as samples arrive
+pub struct Stft {
+	size: usize,
+	hop: usize,
+	window: Vec<f32>,
+	buffer: Vec<f32>,
+	fft: Arc<dyn Fft<f32>>,
+	frame: Vec<Complex<f32>>,
+	magnitudes: Vec<f32>,
+}
+
+impl Stft {
+	/// Uses a Hann window
+	pub fn new(size: usize, hop: usize) -> Self {
+		let window = (0..size)
+			.map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / size as f32).cos())
+			.collect();
+		Self::with_window(window, hop)
+	}
+	/// The frame size is the length of `window`
+	pub fn with_window(window: Vec<f32>, hop: usize) -> Self {
+		let size = window.len();
+		Stft {
+			size,
+			hop,
+			window,
+			buffer: Vec::with_capacity(size * 2),
+			fft: FftPlanner::new().plan_fft_forward(size),
+			frame: vec![Complex::default(); size],
+			magnitudes: vec![0.0; size / 2 + 1],
+		}
+	}
+	/// Calls `on_frame` with the magnitude of each frequency bin, for each
+	/// frame that's complete
+	pub fn add(&mut self, samples: &[f32], mut on_frame: impl FnMut(&[f32])) {
+		self.buffer.extend(samples);
+		let mut start = 0;
+		while self.buffer.len() - start >= self.size {
+			let input = &self.buffer[start..start + self.size];
+			for ((value, sample), window) in self.frame.iter_mut().zip(input).zip(&self.window) {
+				*value = Complex::new(sample * window, 0.0);
+			}
+			self.fft.process(&mut self.frame);
+			for (magnitude, value) in self.magnitudes.iter_mut().zip(&self.frame) {
+				*magnitude = value.norm();
+			}
+			on_frame(&self.magnitudes);
+			start += self.hop;
+		}
+		self.buffer.drain(..start);
+	}
+}
@@ -0,0 +1,329 @@
+//! Estimates the tempo from how regularly the sound gets louder, and the key.
+//! Tracks that already have a BPM or key keep it unless `overwrite` is set.
+
+use super::decode::Decoder;
+use super::key::Chroma;
+use super::spectrum::{Downmix, Stft};
+use crate::data::Data;
+use crate::data_js::get_data;
+use crate::get_now_timestamp;
+use crate::library_types::TrackID;
+use crate::tracks::Tag;
+use crate::UniResult;
+use napi::{Env, JsObject, Result, Task};
+use rayon::prelude::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Higher frequencies aren't needed, so audio is analyzed at about this rate
+const MAX_SAMPLE_RATE: u32 = 22050;
+const ONSET_FFT_SIZE: usize = 1024;
+const ONSET_HOP: usize = 256;
+/// Long frames, so low notes can be told apart
+const KEY_FFT_SIZE: usize = 8192;
+const KEY_HOP: usize = 4096;
+const MIN_BPM: f64 = 60.0;
+const MAX_BPM: f64 = 200.0;
+/// Tempos near this are preferred, to choose between half and double tempo
+const PREFERRED_BPM: f64 = 120.0;
+
+/// Measures how much louder each frame gets compared to the previous one,
+/// summed over frequencies
+#[derive(Default)]
+struct OnsetDetector {
+	previous: Vec<f32>,
+	strengths: Vec<f32>,
+}
+
+impl OnsetDetector {
+	fn add(&mut self, magnitudes: &[f32]) {
+		self.previous.resize(magnitudes.len(), 0.0);
+		let mut flux = 0.0;
+		for (magnitude, previous) in magnitudes.iter().zip(&mut self.previous) {
+			let value = (1.0 + 1000.0 * magnitude).ln();
+			flux += (value - *previous).max(0.0);
+			*previous = value;
+		}
+		self.strengths.push(flux);
+	}
+}
+
+fn tempo_weight(bpm: f64) -> f64 {
+	let octaves = (bpm / PREFERRED_BPM).log2();
+	(-0.5 * octaves * octaves).exp()
+}
+
+/// BPM and confidence from 0 to 1, from the autocorrelation of onset
+/// strengths. `None` if there's not enough audio or no rhythm.
+fn estimate_tempo(strengths: &[f32], frame_rate: f64) -> Option<(f64, f64)> {
+	// Only increases above the average of the last second are onsets
+	let window = frame_rate.round() as usize;
+	let mut onsets = Vec::with_capacity(strengths.len());
+	let mut window_sum = 0.0;
+	for (i, strength) in strengths.iter().enumerate() {
+		window_sum += f64::from(*strength);
+		if i >= window {
+			window_sum -= f64::from(strengths[i - window]);
+		}
+		let mean = window_sum / (i + 1).min(window) as f64;
+		onsets.push((f64::from(*strength) - mean).max(0.0));
+	}
+
+	let min_lag = (frame_rate * 60.0 / MAX_BPM).floor() as usize;
+	let max_lag = (frame_rate * 60.0 / MIN_BPM).ceil() as usize;
+	if onsets.len() < max_lag * 2 {
+		return None;
+	}
+	let autocorrelation = |lag: usize| {
+		let sum: f64 = onsets.iter().zip(&onsets[lag..]).map(|(a, b)| a * b).sum();
+		sum / (onsets.len() - lag) as f64
+	};
+	let energy = autocorrelation(0);
+	if energy == 0.0 {
+		return None;
+	}
+	let values: Vec<f64> = (min_lag - 1..=max_lag + 1).map(autocorrelation).collect();
+	let value = |lag: usize| values[lag + 1 - min_lag];
+	let lag_bpm = |lag: f64| 60.0 * frame_rate / lag;
+	let best_lag = (min_lag..=max_lag).max_by(|a, b| {
+		let a = value(*a) * tempo_weight(lag_bpm(*a as f64));
+		let b = value(*b) * tempo_weight(lag_bpm(*b as f64));
+		a.total_cmp(&b)
+	})?;
+	// Parabolic interpolation between lags
+	let (before, peak, after) = (value(best_lag - 1), value(best_lag), value(best_lag + 1));
+	let curvature = before - 2.0 * peak + after;
+	let offset = match curvature < 0.0 {
+		true => (0.5 * (before - after) / curvature).clamp(-0.5, 0.5),
+		false => 0.0,
+	};
+	let bpm = lag_bpm(best_lag as f64 + offset);
+	let confidence = (peak / energy).clamp(0.0, 1.0);
+	Some(((bpm * 10.0).round() / 10.0, confidence))
+}
+
+pub struct TempoAndKey {
+	/// BPM and confidence
+	pub tempo: Option<(f64, f64)>,
+	/// Key and confidence
+	pub key: Option<(String, f64)>,
+}
+
+pub fn analyze(path: &Path) -> UniResult<TempoAndKey> {
+	let mut decoder = Decoder::open(path)?;
+	let mut downmix: Option<Downmix> = None;
+	let mut onset_stft = Stft::new(ONSET_FFT_SIZE, ONSET_HOP);
+	let mut key_stft = Stft::new(KEY_FFT_SIZE, KEY_HOP);
+	let mut onsets = OnsetDetector::default();
+	let mut chroma: Option<Chroma> = None;
+	let mut mono = Vec::new();
+	while let Some((format, samples)) = decoder.next_chunk()? {
+		let downmix = match &mut downmix {
+			Some(downmix) => downmix,
+			None => downmix.insert(Downmix::new(format.sample_rate, MAX_SAMPLE_RATE)),
+		};
+		let chroma = match &mut chroma {
+			Some(chroma) => chroma,
+			None => chroma.insert(Chroma::new(downmix.sample_rate, KEY_FFT_SIZE)),
+		};
+		mono.clear();
+		downmix.add(format, samples, &mut mono);
+		onset_stft.add(&mono, |magnitudes| onsets.add(magnitudes));
+		key_stft.add(&mono, |magnitudes| chroma.add(magnitudes));
+	}
+	let sample_rate = match downmix {
+		Some(downmix) => downmix.sample_rate,
+		None => throw!("No audio found"),
+	};
+	let frame_rate = f64::from(sample_rate) / ONSET_HOP as f64;
+	Ok(TempoAndKey {
+		tempo: estimate_tempo(&onsets.strengths, frame_rate),
+		key: chroma.and_then(|chroma| chroma.estimate()),
+	})
+}
+
+#[napi(object)]
+pub struct TempoKeyResult {
+	pub track_id: TrackID,
+	pub bpm: Option<f64>,
+	/// From 0 to 1
+	pub bpm_confidence: Option<f64>,
+	pub key: Option<String>,
+	/// From 0 to 1
+	pub key_confidence: Option<f64>,
+	/// Set if the file couldn't be analyzed or tagged
+	pub error: Option<String>,
+}
+
+struct AnalyzeFile {
+	id: TrackID,
+	path: PathBuf,
+	set_bpm: bool,
+	set_key: bool,
+}
+
+fn write_tags(file: &AnalyzeFile, result: &TempoAndKey) -> UniResult<bool> {
+	let bpm = result.tempo.filter(|_| file.set_bpm);
+	let key = result.key.as_ref().filter(|_| file.set_key);
+	if bpm.is_none() && key.is_none() {
+		return Ok(false);
+	}
+	let mut tag = Tag::read_from_path(&file.path)?;
+	if let Some((bpm, _)) = bpm {
+		tag.set_bpm(bpm.round() as u16);
+	}
+	if let Some((key, _)) = key {
+		tag.set_key(key);
+	}
+	tag.write_to_path(&file.path)?;
+	Ok(true)
+}
+
+struct TrackResult {
+	id: TrackID,
+	set_bpm: bool,
+	set_key: bool,
+	analysis: Option<TempoAndKey>,
+	error: Option<String>,
+}
+
+struct AnalyzeTempoAndKey {
+	files: Vec<AnalyzeFile>,
+	write_tags: bool,
+}
+impl Task for AnalyzeTempoAndKey {
+	type Output = Vec<(TrackResult, bool)>;
+	type JsValue = Vec<TempoKeyResult>;
+	fn compute(&mut self) -> Result<Self::Output> {
+		let output = self
+			.files
+			.par_iter()
+			.map(|file| {
+				let mut result = TrackResult {
+					id: file.id.clone(),
+					set_bpm: file.set_bpm,
+					set_key: file.set_key,
+					analysis: None,
+					error: None,
+				};
+				let mut tagged = false;
+				match analyze(&file.path) {
+					Ok(analysis) => {
+						if self.write_tags {
+							match write_tags(file, &analysis) {
+								Ok(written) => tagged = written,
+								Err(err) => result.error = Some(err.message),
+							}
+						}
+						result.analysis = Some(analysis);
+					}
+					Err(err) => result.error = Some(err.message),
+				}
+				(result, tagged)
+			})
+			.collect();
+		Ok(output)
+	}
+	fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+		let data: &mut Data = get_data(&env)?;
+		let now = get_now_timestamp();
+		let mut results = Vec::new();
+		for (result, tagged) in output {
+			// The track may have been deleted in the meantime
+			let track = match data.library.tracks.get_mut(&result.id) {
+				Some(track) => track,
+				None => continue,
+			};
+			let (tempo, key) = match result.analysis {
+				Some(analysis) => (analysis.tempo, analysis.key),
+				None => (None, None),
+			};
+			if let (Some((bpm, _)), true) = (tempo, result.set_bpm) {
+				track.bpm = Some(bpm);
+			}
+			if let (Some((key, _)), true) = (&key, result.set_key) {
+				track.key = Some(key.clone());
+			}
+			if tagged {
+				let path = data.paths.tracks_dir.join(&track.file);
+				if let Ok(md) = fs::metadata(path) {
+					track.size = md.len() as i64;
+				}
+				track.dateModified = now;
+			}
+			data.track_changed(&result.id);
+			results.push(TempoKeyResult {
+				track_id: result.id,
+				bpm: tempo.map(|(bpm, _)| bpm),
+				bpm_confidence: tempo.map(|(_, confidence)| confidence),
+				key_confidence: key.as_ref().map(|(_, confidence)| *confidence),
+				key: key.map(|(key, _)| key),
+				error: result.error,
+			});
+		}
+		Ok(results)
+	}
+}
+
+/// Estimates the BPM and key of tracks. Existing values are kept unless
+/// `overwrite` is set. If `write_tags` is set, the new values are also
+/// written to the files.
+#[napi(
+	js_name = "analyze_tempo_and_key",
+	ts_return_type = "Promise<Array<TempoKeyResult>>"
+)]
+#[allow(dead_code)]
+pub fn analyze_tempo_and_key(
+	track_ids: Vec<TrackID>,
+	overwrite: bool,
+	write_tags: bool,
+	env: Env,
+) -> Result<JsObject> {
+	let data: &mut Data = get_data(&env)?;
+	let mut files = Vec::new();
+	for id in track_ids {
+		let track = data.library.get_track(&id)?;
+		files.push(AnalyzeFile {
+			path: data.paths.tracks_dir.join(&track.file),
+			set_bpm: overwrite || track.bpm.is_none(),
+			set_key: overwrite || track.key.is_none(),
+			id,
+		});
+	}
+	let task = AnalyzeTempoAndKey { files, write_tags };
+	env.spawn(task).map(|t| t.promise_object())
+}
+
+#[test]
+fn tempo_test() {
+	use std::f32::consts::PI;
+
+	// A C major chord with a click at 128 BPM
+	let sample_rate = 44100;
+	let beat = (sample_rate as f32 * 60.0 / 128.0) as usize;
+	let samples: Vec<f32> = (0..sample_rate * 20)
+		.map(|i| {
+			let t = i as f32 / sample_rate as f32;
+			let chord: f32 = [261.63, 329.63, 392.0]
+				.iter()
+				.map(|frequency| (2.0 * PI * frequency * t).sin() * 0.1)
+				.sum();
+			let click = match i % beat < 200 {
+				true => ((i * 7919) % 200) as f32 / 200.0 - 0.5,
+				false => 0.0,
+			};
+			chord + click
+		})
+		.collect();
+	let path = std::env::temp_dir().join(format!("ferrum-tempo-{}.wav", std::process::id()));
+	super::write_test_wav(&path, sample_rate as u32, 1, &samples);
+	let result = analyze(&path).unwrap();
+	fs::remove_file(&path).unwrap();
+	let (bpm, confidence) = result.tempo.unwrap();
+	assert!((bpm - 128.0).abs() < 1.0, "{bpm}");
+	assert!(confidence > 0.0);
+	let (key, _) = result.key.unwrap();
+	assert_eq!(key, "C");
+
+	assert!(estimate_tempo(&[0.0; 1000], 86.0).is_none());
+}
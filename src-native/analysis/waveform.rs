@@ -0,0 +1,250 @@
+//! Peaks for drawing a track's waveform. They're cached in `Cache.redb`
+//! together with the cover thumbnails.
+
+use super::decode::Decoder;
+use crate::data::Data;
+use crate::data_js::get_data;
+use crate::library_types::TrackID;
+use crate::tracks::cover::{get_modified_timestamp_ms, init_cache_db, CACHE_DB};
+use crate::UniResult;
+use anyhow::Context;
+use napi::{Env, JsObject, Result, Task};
+use redb::{Database, TableDefinition, TableError};
+use std::path::{Path, PathBuf};
+
+/// Samples are first summarized in blocks of this many frames, which are
+/// then combined into the requested number of buckets
+const BLOCK_SIZE: usize = 256;
+const MAX_BUCKETS: u32 = 10_000;
+
+// (path, buckets) -> (modified_timestamp_ms, encoded waveform)
+type CacheKey<'a> = (&'a str, u32);
+type CacheEntry = (i64, Vec<u8>);
+
+const WAVEFORM_CACHE_TABLE: TableDefinition<CacheKey, CacheEntry> =
+	TableDefinition::new("waveform_cache");
+
+/// Sample values from -1 to 1, per bucket. Channels are combined.
+#[napi(object)]
+#[derive(Debug, PartialEq)]
+pub struct Waveform {
+	pub min: Vec<f64>,
+	pub max: Vec<f64>,
+	pub rms: Vec<f64>,
+}
+
+impl Waveform {
+	fn encode(&self) -> Vec<u8> {
+		let values = self.min.iter().chain(&self.max).chain(&self.rms);
+		values.flat_map(|v| (*v as f32).to_le_bytes()).collect()
+	}
+	fn decode(bytes: &[u8], buckets: usize) -> Option<Self> {
+		if bytes.len() != buckets * 3 * 4 {
+			return None;
+		}
+		let values: Vec<f64> = bytes
+			.chunks_exact(4)
+			.map(|b| f64::from(f32::from_le_bytes([b[0], b[1], b[2], b[3]])))
+			.collect();
+		Some(Waveform {
+			min: values[..buckets].to_vec(),
+			max: values[buckets..buckets * 2].to_vec(),
+			rms: values[buckets * 2..].to_vec(),
+		})
+	}
+}
+
+#[derive(Clone, Copy)]
+struct Block {
+	min: f32,
+	max: f32,
+	sum_squares: f64,
+	count: usize,
+}
+
+impl Block {
+	const EMPTY: Block = Block {
+		min: 0.0,
+		max: 0.0,
+		sum_squares: 0.0,
+		count: 0,
+	};
+	fn add(&mut self, sample: f32) {
+		self.min = self.min.min(sample);
+		self.max = self.max.max(sample);
+		self.sum_squares += f64::from(sample * sample);
+		self.count += 1;
+	}
+	fn combine(&mut self, other: &Block) {
+		self.min = self.min.min(other.min);
+		self.max = self.max.max(other.max);
+		self.sum_squares += other.sum_squares;
+		self.count += other.count;
+	}
+}
+
+fn compute_waveform(path: &Path, buckets: usize) -> UniResult<Waveform> {
+	let mut decoder = Decoder::open(path)?;
+	let mut blocks = Vec::new();
+	let mut block = Block::EMPTY;
+	let mut frames = 0;
+	while let Some((format, samples)) = decoder.next_chunk()? {
+		for frame in samples.chunks_exact(format.channels) {
+			for sample in frame {
+				block.add(*sample);
+			}
+			frames += 1;
+			if frames == BLOCK_SIZE {
+				blocks.push(block);
+				block = Block::EMPTY;
+				frames = 0;
+			}
+		}
+	}
+	if frames > 0 {
+		blocks.push(block);
+	}
+
+	let mut waveform = Waveform {
+		min: Vec::with_capacity(buckets),
+		max: Vec::with_capacity(buckets),
+		rms: Vec::with_capacity(buckets),
+	};
+	for i in 0..buckets {
+		let start = i * blocks.len() / buckets;
+		let end = ((i + 1) * blocks.len() / buckets).max(start + 1);
+		let mut bucket = Block::EMPTY;
+		for block in blocks.get(start..end).unwrap_or_default() {
+			bucket.combine(block);
+		}
+		waveform.min.push(f64::from(bucket.min));
+		waveform.max.push(f64::from(bucket.max));
+		let rms = match bucket.count {
+			0 => 0.0,
+			count => (bucket.sum_squares / count as f64).sqrt() as f32,
+		};
+		waveform.rms.push(f64::from(rms));
+	}
+	Ok(waveform)
+}
+
+fn get_cached_waveform(
+	cache_db: &Database,
+	path: &str,
+	buckets: u32,
+	date_modified_ms: i64,
+) -> Result<Option<Waveform>> {
+	let read_txn = cache_db
+		.begin_read()
+		.context("Could not begin read transaction")?;
+	let table = match read_txn.open_table(WAVEFORM_CACHE_TABLE) {
+		Ok(table) => table,
+		// Created on the first write
+		Err(TableError::TableDoesNotExist(_)) => return Ok(None),
+		Err(e) => return Err(e).context("Could not open table")?,
+	};
+	let cache_entry = match table.get((path, buckets)).context("Could not get record")? {
+		Some(cache_entry) => cache_entry.value(),
+		None => return Ok(None),
+	};
+	if date_modified_ms != cache_entry.0 {
+		return Ok(None);
+	}
+	Ok(Waveform::decode(&cache_entry.1, buckets as usize))
+}
+
+fn write_to_cache(cache_db: &Database, key: CacheKey, value: CacheEntry) -> Result<()> {
+	let write_txn = cache_db
+		.begin_write()
+		.context("Could not begin write transaction")?;
+	{
+		let mut table = write_txn
+			.open_table(WAVEFORM_CACHE_TABLE)
+			.context("Could not open table")?;
+		table
+			.insert(key, value)
+			.context("Could not insert record")?;
+	}
+	write_txn.commit().context("Could not commit transaction")?;
+	Ok(())
+}
+
+struct GetWaveform {
+	path: PathBuf,
+	buckets: u32,
+	cache_db_path: String,
+}
+impl Task for GetWaveform {
+	type Output = Waveform;
+	type JsValue = Waveform;
+	fn compute(&mut self) -> Result<Self::Output> {
+		let path = self.path.to_string_lossy();
+		let date_modified_ms = get_modified_timestamp_ms(&path)?.map(|ms| ms as i64);
+		init_cache_db(self.cache_db_path.clone())?;
+		let cache_db_mutex = CACHE_DB.read().unwrap();
+		let cache_db = cache_db_mutex.as_ref().unwrap();
+
+		if let Some(date_modified_ms) = date_modified_ms {
+			let cached = get_cached_waveform(cache_db, &path, self.buckets, date_modified_ms)?;
+			if let Some(waveform) = cached {
+				return Ok(waveform);
+			}
+		}
+
+		let waveform = compute_waveform(&self.path, self.buckets as usize)?;
+
+		if let Some(date_modified_ms) = date_modified_ms {
+			let value = (date_modified_ms, waveform.encode());
+			write_to_cache(cache_db, (&path, self.buckets), value)?;
+		}
+		Ok(waveform)
+	}
+	fn resolve(&mut self, _env: Env, output: Self::Output) -> Result<Self::JsValue> {
+		Ok(output)
+	}
+}
+
+/// Decodes a track and returns its peaks, divided into `buckets` parts of
+/// equal length
+#[napi(js_name = "get_waveform", ts_return_type = "Promise<Waveform>")]
+#[allow(dead_code)]
+pub fn get_waveform(track_id: TrackID, buckets: u32, env: Env) -> Result<JsObject> {
+	if buckets == 0 || buckets > MAX_BUCKETS {
+		throw!("buckets must be between 1 and {MAX_BUCKETS}");
+	}
+	let data: &mut Data = get_data(&env)?;
+	let track = data.library.get_track(&track_id)?;
+	let task = GetWaveform {
+		path: data.paths.tracks_dir.join(&track.file),
+		buckets,
+		cache_db_path: data.paths.cache_db.to_string_lossy().into(),
+	};
+	env.spawn(task).map(|t| t.promise_object())
+}
+
+#[test]
+fn waveform_test() {
+	use std::fs;
+
+	// A second of silence, then a square wave
+	let sample_rate = 8000;
+	let samples: Vec<f32> = (0..sample_rate * 2)
+		.map(|i| match (i < sample_rate, i % 20 < 10) {
+			(true, _) => 0.0,
+			(false, true) => 0.5,
+			(false, false) => -0.5,
+		})
+		.collect();
+	let path = std::env::temp_dir().join(format!("ferrum-waveform-{}.wav", std::process::id()));
+	super::write_test_wav(&path, sample_rate as u32, 1, &samples);
+	let waveform = compute_waveform(&path, 4).unwrap();
+	fs::remove_file(&path).unwrap();
+
+	assert_eq!(waveform.max.len(), 4);
+	assert_eq!(waveform.max[0], 0.0);
+	assert_eq!(waveform.rms[1], 0.0);
+	assert!((waveform.max[3] - 0.5).abs() < 0.01);
+	assert!((waveform.min[3] + 0.5).abs() < 0.01);
+	assert!((waveform.rms[2] - 0.5).abs() < 0.01);
+	assert_eq!(Waveform::decode(&waveform.encode(), 4), Some(waveform));
+}
@@ -1,27 +1,329 @@
 use crate::data::Data;
 use crate::data_js::get_data;
-use crate::library_types::Library;
+use crate::filter::find_match;
+use crate::library_types::{Library, Track, TrackID};
+use alphanumeric_sort::compare_str;
 use napi::{Env, Result};
-use std::collections::HashSet;
+use std::cmp::Ordering;
+use std::collections::HashMap;
 use std::time::Instant;
+use unicode_normalization::UnicodeNormalization;
 
-pub fn load_artists(library: &Library) -> HashSet<String> {
-	let now = Instant::now();
-	let mut artists = HashSet::new();
-	for (_, track) in &library.tracks {
-		if !artists.contains(&track.artist) {
-			artists.insert(track.artist.clone());
+/// Separators between artists, in lowercase. Bracketed ones are for
+/// names like "Song (feat. Artist)", and end with a closing bracket
+const SEPARATORS: [(&str, bool); 10] = [
+	(" (feat. ", true),
+	(" [feat. ", true),
+	(" (ft. ", true),
+	(" [ft. ", true),
+	(" featuring ", false),
+	(" feat. ", false),
+	(" feat ", false),
+	(" ft. ", false),
+	(" & ", false),
+	(";", false),
+];
+
+/// Splits a string like "A feat. B & C" into separate artists
+pub fn split_artists(artists: &str) -> Vec<&str> {
+	let lowercase = artists.to_ascii_lowercase();
+	let mut result = Vec::new();
+	let mut start = 0;
+	let mut in_brackets = false;
+	let mut i = 0;
+	while i < artists.len() {
+		if !artists.is_char_boundary(i) {
+			i += 1;
+			continue;
+		}
+		let separator = SEPARATORS
+			.iter()
+			.find(|(separator, _)| lowercase[i..].starts_with(separator));
+		match separator {
+			Some((separator, bracketed)) => {
+				result.push(&artists[start..i]);
+				i += separator.len();
+				start = i;
+				in_brackets |= bracketed;
+			}
+			None => i += 1,
+		}
+	}
+	let mut last = &artists[start..];
+	if in_brackets {
+		last = last.trim_end().trim_end_matches([')', ']']);
+	}
+	result.push(last);
+	let result: Vec<&str> = result
+		.into_iter()
+		.map(|artist| artist.trim())
+		.filter(|artist| !artist.is_empty())
+		.collect();
+	match result.is_empty() {
+		true => vec![""],
+		false => result,
+	}
+}
+
+fn artist_key(name: &str) -> String {
+	name.nfc().flat_map(char::to_lowercase).collect()
+}
+
+struct TrackEntry {
+	artist_keys: Vec<String>,
+	album: Option<String>,
+	play_count: u32,
+	play_time: i64,
+}
+
+struct Artist {
+	name: String,
+	sort_name: Option<String>,
+	track_count: u32,
+	/// Number of tracks in each album
+	albums: HashMap<String, u32>,
+	play_count: i64,
+	play_time: i64,
+}
+
+/// Artists of all tracks, including album artists and featured artists
+#[derive(Default)]
+pub struct ArtistIndex {
+	artists: HashMap<String, Artist>,
+	tracks: HashMap<TrackID, TrackEntry>,
+}
+
+impl ArtistIndex {
+	pub fn build(library: &Library) -> Self {
+		let now = Instant::now();
+		let mut play_times: HashMap<&str, i64> = HashMap::new();
+		for (track_id, _, duration) in &library.playTime {
+			*play_times.entry(track_id).or_default() += duration;
+		}
+		let mut index = ArtistIndex::default();
+		for (id, track) in &library.tracks {
+			let play_time = play_times.get(id.as_str()).copied().unwrap_or(0);
+			index.insert(id, track, play_time);
+		}
+		println!("Get artists: {}ms", now.elapsed().as_millis());
+		index
+	}
+	fn insert(&mut self, id: &TrackID, track: &Track, play_time: i64) {
+		let mut names: Vec<(&str, Option<&String>)> = Vec::new();
+		let artists = split_artists(&track.artist);
+		let sort_artist = if artists.len() == 1 {
+			track.sortArtist.as_ref()
+		} else {
+			None
+		};
+		names.extend(artists.into_iter().map(|name| (name, sort_artist)));
+		if let Some(album_artist) = &track.albumArtist {
+			let album_artists = split_artists(album_artist);
+			let sort_album_artist = if album_artists.len() == 1 {
+				track.sortAlbumArtist.as_ref()
+			} else {
+				None
+			};
+			names.extend(
+				album_artists
+					.into_iter()
+					.map(|name| (name, sort_album_artist)),
+			);
+		}
+
+		let album = track.albumName.clone().filter(|album| !album.is_empty());
+		let play_count = track.playCount.unwrap_or(0);
+		let mut artist_keys = Vec::new();
+		for (name, sort_name) in names {
+			let key = artist_key(name);
+			if artist_keys.contains(&key) {
+				continue;
+			}
+			let artist = self.artists.entry(key.clone()).or_insert_with(|| Artist {
+				name: name.to_string(),
+				sort_name: None,
+				track_count: 0,
+				albums: HashMap::new(),
+				play_count: 0,
+				play_time: 0,
+			});
+			if artist.sort_name.is_none() {
+				artist.sort_name = sort_name.filter(|s| !s.is_empty()).cloned();
+			}
+			artist.track_count += 1;
+			if let Some(album) = &album {
+				*artist.albums.entry(album.clone()).or_default() += 1;
+			}
+			artist.play_count += i64::from(play_count);
+			artist.play_time += play_time;
+			artist_keys.push(key);
+		}
+		let entry = TrackEntry {
+			artist_keys,
+			album,
+			play_count,
+			play_time,
+		};
+		self.tracks.insert(id.clone(), entry);
+	}
+	/// Returns the play time of the removed track
+	fn remove(&mut self, id: &str) -> Option<i64> {
+		let entry = self.tracks.remove(id)?;
+		for key in &entry.artist_keys {
+			let artist = match self.artists.get_mut(key) {
+				Some(artist) => artist,
+				None => continue,
+			};
+			artist.track_count -= 1;
+			if artist.track_count == 0 {
+				self.artists.remove(key);
+				continue;
+			}
+			if let Some(album) = &entry.album {
+				if let Some(count) = artist.albums.get_mut(album) {
+					*count -= 1;
+					if *count == 0 {
+						artist.albums.remove(album);
+					}
+				}
+			}
+			artist.play_count -= i64::from(entry.play_count);
+			artist.play_time -= entry.play_time;
+		}
+		Some(entry.play_time)
+	}
+	/// Adds or updates a track
+	pub fn update_track(&mut self, id: &TrackID, track: &Track) {
+		let play_time = self.remove(id).unwrap_or(0);
+		self.insert(id, track, play_time);
+	}
+	pub fn remove_track(&mut self, id: &str) {
+		self.remove(id);
+	}
+	pub fn add_play_time(&mut self, id: &str, duration: i64) {
+		let entry = match self.tracks.get_mut(id) {
+			Some(entry) => entry,
+			None => return,
+		};
+		entry.play_time += duration;
+		for key in &entry.artist_keys {
+			if let Some(artist) = self.artists.get_mut(key) {
+				artist.play_time += duration;
+			}
 		}
 	}
-	println!("Get artists: {}ms", now.elapsed().as_millis());
-	return artists;
+}
+
+#[napi]
+pub enum ArtistSortKey {
+	Name,
+	PlayCount,
+}
+
+#[napi(object)]
+pub struct ArtistInfo {
+	pub name: String,
+	pub track_count: u32,
+	pub album_count: u32,
+	pub play_count: i64,
+	/// Milliseconds
+	pub play_time: i64,
+}
+
+#[napi(object)]
+pub struct ArtistPage {
+	pub artists: Vec<ArtistInfo>,
+	/// Number of artists matching the query
+	pub total: u32,
+}
+
+fn compare_names(a: &Artist, b: &Artist) -> Ordering {
+	let name_a = a.sort_name.as_ref().unwrap_or(&a.name);
+	let name_b = b.sort_name.as_ref().unwrap_or(&b.name);
+	compare_str(name_a, name_b)
 }
 
 #[napi(js_name = "get_artists")]
 #[allow(dead_code)]
-pub fn get_artists(env: Env) -> Result<Vec<String>> {
+pub fn get_artists(
+	query: String,
+	sort_key: ArtistSortKey,
+	desc: bool,
+	offset: u32,
+	limit: u32,
+	env: Env,
+) -> Result<ArtistPage> {
 	let data: &mut Data = get_data(&env)?;
-	let mut artists: Vec<String> = data.artists.clone().into_iter().collect();
-	artists.sort();
-	Ok(artists)
+	let query: String = query.nfc().collect();
+	let keywords: Vec<&str> = query.split(' ').collect();
+	let mut artists: Vec<&Artist> = data
+		.artists
+		.artists
+		.values()
+		.filter(|artist| {
+			keywords
+				.iter()
+				.all(|keyword| find_match(&artist.name, keyword))
+		})
+		.collect();
+	artists.sort_by(|a, b| {
+		let order = match sort_key {
+			ArtistSortKey::Name => compare_names(a, b),
+			ArtistSortKey::PlayCount => a.play_count.cmp(&b.play_count),
+		};
+		let order = match desc {
+			true => order.reverse(),
+			false => order,
+		};
+		order.then_with(|| compare_names(a, b))
+	});
+	let total = artists.len() as u32;
+	let artists = artists
+		.into_iter()
+		.skip(offset as usize)
+		.take(limit as usize)
+		.map(|artist| ArtistInfo {
+			name: artist.name.clone(),
+			track_count: artist.track_count,
+			album_count: artist.albums.len() as u32,
+			play_count: artist.play_count,
+			play_time: artist.play_time,
+		})
+		.collect();
+	Ok(ArtistPage { artists, total })
+}
+
+#[test]
+fn artist_index_test() {
+	use crate::library_types::test_library;
+
+	assert_eq!(split_artists("A feat. B & C"), vec!["A", "B", "C"]);
+	assert_eq!(split_artists("A (Feat. B)"), vec!["A", "B"]);
+	assert_eq!(split_artists("Sunn O)))"), vec!["Sunn O)))"]);
+	assert_eq!(
+		split_artists("Björk; Thom Yorke"),
+		vec!["Björk", "Thom Yorke"]
+	);
+	assert_eq!(split_artists(""), vec![""]);
+
+	let mut library = test_library(serde_json::json!([
+		{ "name": "1", "artist": "The Beatles", "sortArtist": "Beatles",
+			"albumName": "Abbey Road", "playCount": 2 },
+		{ "name": "2", "artist": "the beatles feat. Billy Preston",
+			"albumArtist": "The Beatles", "albumName": "Let It Be", "playCount": 1 },
+	]));
+	library.playTime.push(("1".to_string(), 0, 1000));
+	let mut index = ArtistIndex::build(&library);
+	assert_eq!(index.artists.len(), 2);
+	let beatles = &index.artists["the beatles"];
+	assert_eq!(beatles.name, "The Beatles");
+	assert_eq!(beatles.sort_name.as_deref(), Some("Beatles"));
+	assert_eq!((beatles.track_count, beatles.albums.len()), (2, 2));
+	assert_eq!((beatles.play_count, beatles.play_time), (3, 1000));
+
+	index.add_play_time("1", 500);
+	assert_eq!(index.artists["billy preston"].play_time, 1500);
+	index.remove_track("1");
+	assert_eq!(index.artists.len(), 1);
+	assert_eq!(index.artists["the beatles"].track_count, 1);
 }
@@ -0,0 +1,249 @@
+//! Timestamped copies of the library, kept in `Paths::backups_dir`. A backup
+//! is made when saving if the last one is more than an hour old, and old
+//! backups are thinned out to a number of hourly, daily and weekly ones.
+
+use crate::data::Data;
+use crate::data_js::get_data;
+use crate::library::{parse_library, Paths};
+use crate::library_types::{Library, MsSinceUnixEpoch};
+use crate::{get_now_timestamp, path_to_json, UniResult};
+use atomicwrites::{AllowOverwrite, AtomicFile};
+use napi::{Env, Result};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+const HOUR: i64 = 60 * 60 * 1000;
+const DAY: i64 = 24 * HOUR;
+const WEEK: i64 = 7 * DAY;
+
+/// How many backups to keep
+#[derive(Serialize, Deserialize, Clone)]
+#[napi(object)]
+pub struct BackupOptions {
+	/// One per hour
+	pub hourly: u32,
+	/// One per day
+	pub daily: u32,
+	/// One per week
+	pub weekly: u32,
+}
+impl Default for BackupOptions {
+	fn default() -> Self {
+		BackupOptions {
+			hourly: 12,
+			daily: 7,
+			weekly: 8,
+		}
+	}
+}
+
+#[napi(object)]
+pub struct BackupInfo {
+	/// File name, used to restore the backup
+	pub name: String,
+	pub date: MsSinceUnixEpoch,
+	/// Bytes
+	pub size: i64,
+}
+
+fn backup_name(date: MsSinceUnixEpoch) -> String {
+	format!("Library-{date}.json")
+}
+
+fn parse_backup_name(name: &str) -> Option<MsSinceUnixEpoch> {
+	let date = name.strip_prefix("Library-")?.strip_suffix(".json")?;
+	if !date.chars().all(|c| c.is_ascii_digit()) {
+		return None;
+	}
+	date.parse().ok()
+}
+
+/// Dates of the backups to keep. `dates` must be sorted newest first
+fn backups_to_keep(dates: &[MsSinceUnixEpoch], options: &BackupOptions) -> HashSet<i64> {
+	let mut keep = HashSet::new();
+	let periods = [
+		(HOUR, options.hourly),
+		(DAY, options.daily),
+		(WEEK, options.weekly),
+	];
+	for (period, count) in periods {
+		let mut last_bucket = None;
+		let mut kept = 0;
+		for date in dates {
+			if kept >= count {
+				break;
+			}
+			let bucket = date.div_euclid(period);
+			if last_bucket != Some(bucket) {
+				keep.insert(*date);
+				last_bucket = Some(bucket);
+				kept += 1;
+			}
+		}
+	}
+	keep
+}
+
+pub struct Backups {
+	dir: PathBuf,
+	options_path: PathBuf,
+	pub options: BackupOptions,
+	/// Date of the newest backup, once it's known
+	latest: Option<Option<MsSinceUnixEpoch>>,
+}
+
+impl Backups {
+	pub fn load(paths: &Paths) -> Self {
+		let options_path = paths.local_data_dir.join("backups.json");
+		Backups {
+			dir: paths.backups_dir.clone(),
+			options: path_to_json(options_path.clone()).unwrap_or_default(),
+			options_path,
+			latest: None,
+		}
+	}
+	pub fn save_options(&self) -> UniResult<()> {
+		let json_str = match serde_json::to_string(&self.options) {
+			Ok(json_str) => json_str,
+			Err(_) => throw!("Error saving backups.json"),
+		};
+		let af = AtomicFile::new(&self.options_path, AllowOverwrite);
+		match af.write(|f| f.write_all(json_str.as_bytes())) {
+			Ok(_) => Ok(()),
+			Err(_) => throw!("Error writing backups.json"),
+		}
+	}
+	/// Sorted newest first
+	pub fn list(&self) -> UniResult<Vec<BackupInfo>> {
+		let entries = match fs::read_dir(&self.dir) {
+			Ok(entries) => entries,
+			Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+			Err(err) => throw!("Error reading backups folder: {err}"),
+		};
+		let mut backups = Vec::new();
+		for entry in entries.flatten() {
+			let name = entry.file_name().to_string_lossy().to_string();
+			let date = match parse_backup_name(&name) {
+				Some(date) => date,
+				None => continue,
+			};
+			let size = entry.metadata().map(|m| m.len() as i64).unwrap_or(0);
+			backups.push(BackupInfo { name, date, size });
+		}
+		backups.sort_by_key(|backup| Reverse(backup.date));
+		Ok(backups)
+	}
+	pub fn is_due(&mut self, now: MsSinceUnixEpoch) -> UniResult<bool> {
+		let latest = match self.latest {
+			Some(latest) => latest,
+			None => {
+				let latest = self.list()?.first().map(|backup| backup.date);
+				self.latest = Some(latest);
+				latest
+			}
+		};
+		Ok(latest.is_none_or(|latest| now - latest >= HOUR))
+	}
+	/// Saves a backup, and deletes old backups according to the options
+	pub fn create(&mut self, library_json: &[u8], now: MsSinceUnixEpoch) -> UniResult<()> {
+		if let Err(err) = fs::create_dir_all(&self.dir) {
+			throw!("Error creating backups folder: {err}");
+		}
+		let af = AtomicFile::new(self.dir.join(backup_name(now)), AllowOverwrite);
+		if let Err(err) = af.write(|f| f.write_all(library_json)) {
+			throw!("Error writing backup: {err}");
+		}
+		self.latest = Some(Some(now));
+		println!("Created backup {}", backup_name(now));
+		self.prune()
+	}
+	fn prune(&self) -> UniResult<()> {
+		let backups = self.list()?;
+		let dates: Vec<_> = backups.iter().map(|backup| backup.date).collect();
+		let keep = backups_to_keep(&dates, &self.options);
+		for backup in backups {
+			if !keep.contains(&backup.date) {
+				if let Err(err) = fs::remove_file(self.dir.join(&backup.name)) {
+					throw!("Error deleting backup {}: {err}", backup.name);
+				}
+			}
+		}
+		Ok(())
+	}
+	/// Reads and validates a backup
+	pub fn read(&self, name: &str) -> UniResult<Library> {
+		if parse_backup_name(name).is_none() {
+			throw!("Invalid backup name: {name}");
+		}
+		let json_str = match fs::read_to_string(self.dir.join(name)) {
+			Ok(json_str) => json_str,
+			Err(err) => throw!("Error reading backup: {err}"),
+		};
+		parse_library(&json_str)
+	}
+}
+
+#[napi(js_name = "list_backups")]
+#[allow(dead_code)]
+pub fn list_backups(env: Env) -> Result<Vec<BackupInfo>> {
+	let data: &mut Data = get_data(&env)?;
+	Ok(data.backups.list()?)
+}
+
+/// Replaces the library with a backup. The current library is backed up
+/// first, so this can be reversed.
+#[napi(js_name = "restore_backup")]
+#[allow(dead_code)]
+pub fn restore_backup(name: String, env: Env) -> Result<()> {
+	let data: &mut Data = get_data(&env)?;
+	let library = data.backups.read(&name)?;
+	let json = data.stringify()?;
+	data.backups.create(&json, get_now_timestamp())?;
+	data.library = library;
+	data.library_replaced();
+	data.save()?;
+	data.open_playlist("root".to_string(), None)?;
+	Ok(())
+}
+
+#[napi(js_name = "get_backup_options")]
+#[allow(dead_code)]
+pub fn get_backup_options(env: Env) -> Result<BackupOptions> {
+	let data: &mut Data = get_data(&env)?;
+	Ok(data.backups.options.clone())
+}
+
+#[napi(js_name = "set_backup_options")]
+#[allow(dead_code)]
+pub fn set_backup_options(options: BackupOptions, env: Env) -> Result<()> {
+	let data: &mut Data = get_data(&env)?;
+	data.backups.options = options;
+	data.backups.save_options()?;
+	Ok(())
+}
+
+#[test]
+fn backups_test() {
+	assert_eq!(parse_backup_name(&backup_name(1234)), Some(1234));
+	assert_eq!(parse_backup_name("Library-../x.json"), None);
+	assert_eq!(parse_backup_name("Library-+1.json"), None);
+
+	// Every 20 minutes for 3 days, newest first, starting at 12:30
+	let minute = 60 * 1000;
+	let now = 10 * WEEK + 12 * HOUR + 30 * minute;
+	let dates: Vec<i64> = (0..3 * 24 * 3).map(|i| now - i * 20 * minute).collect();
+	let options = BackupOptions {
+		hourly: 4,
+		daily: 2,
+		weekly: 1,
+	};
+	let mut keep: Vec<i64> = backups_to_keep(&dates, &options).into_iter().collect();
+	keep.sort_unstable_by(|a, b| b.cmp(a));
+	let minutes_ago: Vec<i64> = keep.iter().map(|date| (now - date) / minute).collect();
+	// Newest in each of the last 4 hours, and the newest of the previous day
+	assert_eq!(minutes_ago, vec![0, 40, 100, 160, 760]);
+}
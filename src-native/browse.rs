@@ -0,0 +1,180 @@
+//! Browsing tracks by genre, composer, grouping or decade. Each value can be
+//! opened as a virtual tracklist with an ID like `browse:genre:Jazz`.
+
+use crate::data::Data;
+use crate::data_js::get_data;
+use crate::library_types::{Library, Track, TrackID};
+use alphanumeric_sort::compare_str;
+use napi::{Env, Result};
+use serde_json::json;
+use std::collections::HashMap;
+use unicode_normalization::UnicodeNormalization;
+
+const ID_PREFIX: &str = "browse:";
+
+#[napi]
+#[derive(PartialEq)]
+pub enum BrowseField {
+	Genre,
+	Composer,
+	Grouping,
+	Decade,
+}
+
+impl BrowseField {
+	fn name(&self) -> &'static str {
+		match self {
+			BrowseField::Genre => "genre",
+			BrowseField::Composer => "composer",
+			BrowseField::Grouping => "grouping",
+			BrowseField::Decade => "decade",
+		}
+	}
+	fn from_name(name: &str) -> Option<Self> {
+		let field = match name {
+			"genre" => BrowseField::Genre,
+			"composer" => BrowseField::Composer,
+			"grouping" => BrowseField::Grouping,
+			"decade" => BrowseField::Decade,
+			_ => return None,
+		};
+		Some(field)
+	}
+	/// The track's value, and the value to sort by
+	fn get_value<'a>(&self, track: &'a Track) -> Option<(String, Option<&'a String>)> {
+		let value = match self {
+			BrowseField::Genre => (track.genre.clone()?, None),
+			BrowseField::Composer => (track.composer.clone()?, track.sortComposer.as_ref()),
+			BrowseField::Grouping => (track.grouping.clone()?, None),
+			BrowseField::Decade => (format!("{}s", track.year? / 10 * 10), None),
+		};
+		match value.0.trim() {
+			"" => None,
+			trimmed => Some((trimmed.to_string(), value.1)),
+		}
+	}
+}
+
+fn value_key(value: &str) -> String {
+	value.nfc().flat_map(char::to_lowercase).collect()
+}
+
+pub fn get_id(field: &BrowseField, value: &str) -> String {
+	format!("{ID_PREFIX}{}:{value}", field.name())
+}
+
+/// Returns the field and value of a browse tracklist ID
+pub fn parse_id(id: &str) -> Option<(BrowseField, &str)> {
+	let (field, value) = id.strip_prefix(ID_PREFIX)?.split_once(':')?;
+	Some((BrowseField::from_name(field)?, value))
+}
+
+pub fn get_track_ids(library: &Library, field: &BrowseField, value: &str) -> Vec<TrackID> {
+	let key = value_key(value);
+	library
+		.tracks
+		.iter()
+		.filter(|(_, track)| match field.get_value(track) {
+			Some((track_value, _)) => value_key(&track_value) == key,
+			None => false,
+		})
+		.map(|(id, _)| id.clone())
+		.collect()
+}
+
+/// Tracklist object for a browse ID, in the same shape as other tracklists
+pub fn get_tracklist_json(id: &str) -> Option<serde_json::Value> {
+	let (field, value) = parse_id(id)?;
+	Some(json!({
+		"type": "browse",
+		"id": id,
+		"field": field.name(),
+		"name": value,
+	}))
+}
+
+#[napi(object)]
+pub struct BrowseItem {
+	/// Tracklist ID that can be opened
+	pub id: String,
+	pub name: String,
+	pub track_count: u32,
+}
+
+struct Item {
+	name: String,
+	sort_name: Option<String>,
+	track_count: u32,
+}
+
+pub fn get_items(library: &Library, field: &BrowseField) -> Vec<BrowseItem> {
+	let mut items: HashMap<String, Item> = HashMap::new();
+	for track in library.tracks.values() {
+		let (value, sort_value) = match field.get_value(track) {
+			Some(value) => value,
+			None => continue,
+		};
+		let item = items.entry(value_key(&value)).or_insert_with(|| Item {
+			name: value,
+			sort_name: None,
+			track_count: 0,
+		});
+		if item.sort_name.is_none() {
+			item.sort_name = sort_value.filter(|s| !s.is_empty()).cloned();
+		}
+		item.track_count += 1;
+	}
+	let mut items: Vec<Item> = items.into_values().collect();
+	items.sort_by(|a, b| {
+		let name_a = a.sort_name.as_ref().unwrap_or(&a.name);
+		let name_b = b.sort_name.as_ref().unwrap_or(&b.name);
+		compare_str(name_a, name_b)
+	});
+	items
+		.into_iter()
+		.map(|item| BrowseItem {
+			id: get_id(field, &item.name),
+			name: item.name,
+			track_count: item.track_count,
+		})
+		.collect()
+}
+
+#[napi(js_name = "get_browse_items")]
+#[allow(dead_code)]
+pub fn get_browse_items(field: BrowseField, env: Env) -> Result<Vec<BrowseItem>> {
+	let data: &mut Data = get_data(&env)?;
+	Ok(get_items(&data.library, &field))
+}
+
+#[test]
+fn browse_test() {
+	use crate::library_types::test_library;
+
+	let library = test_library(serde_json::json!([
+		{ "name": "1", "artist": "", "composer": "Johann Sebastian Bach",
+			"sortComposer": "Bach", "year": 1999 },
+		{ "name": "2", "artist": "", "composer": "Antonín Dvořák", "year": 1990 },
+		{ "name": "3", "artist": "", "composer": "johann sebastian bach ", "year": 2001 },
+	]));
+	let items = get_items(&library, &BrowseField::Composer);
+	let names: Vec<_> = items
+		.iter()
+		.map(|i| (i.name.as_str(), i.track_count))
+		.collect();
+	assert_eq!(
+		names,
+		vec![("Antonín Dvořák", 1), ("Johann Sebastian Bach", 2)]
+	);
+
+	let (field, value) = parse_id(&items[1].id).unwrap();
+	assert_eq!(get_track_ids(&library, &field, value), vec!["0", "2"]);
+
+	let items = get_items(&library, &BrowseField::Decade);
+	let names: Vec<_> = items
+		.iter()
+		.map(|i| (i.name.as_str(), i.track_count))
+		.collect();
+	assert_eq!(names, vec![("1990s", 2), ("2000s", 1)]);
+	assert!(parse_id("browse:unknown:x").is_none());
+}
@@ -0,0 +1,358 @@
+//! Append-only log of library changes, stored next to `Library.json`. Saving
+//! appends the current state of whatever changed since the last save, and
+//! loading replays the log on top of `Library.json`. Every so often the
+//! library is saved as a whole and the log is cleared.
+//!
+//! The log starts with a header that has the same generation as the
+//! `Library.json` it belongs to. Saving the library as a whole increments
+//! the generation, so if the app quits before the log is cleared, the old
+//! log is skipped instead of replayed on the newer library.
+
+use crate::library_types::{Library, PlayTime, Track, TrackID, TrackList, TrackListID};
+use crate::UniResult;
+use atomicwrites::{AllowOverwrite, AtomicFile};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::PathBuf;
+use std::time::Instant;
+
+/// Number of records after which the library is saved as a whole
+const MAX_RECORDS: usize = 10_000;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Record<'a> {
+	/// The first record. Logs without one have generation 0
+	Header { generation: u64 },
+	/// `None` if the track was deleted
+	Track {
+		id: Cow<'a, str>,
+		track: Option<Box<Cow<'a, Track>>>,
+	},
+	/// `None` if the track list was deleted
+	TrackList {
+		id: Cow<'a, str>,
+		list: Option<Box<Cow<'a, TrackList>>>,
+	},
+	PlayTime {
+		/// Position in `Library::playTime`
+		index: usize,
+		play_time: Cow<'a, PlayTime>,
+	},
+}
+
+impl Record<'_> {
+	fn apply(self, library: &mut Library) {
+		match self {
+			Record::Header { .. } => {}
+			Record::Track { id, track } => match track {
+				Some(track) => match library.tracks.get_mut(id.as_ref()) {
+					Some(existing) => *existing = track.into_owned(),
+					None => {
+						library.tracks.insert(id.into_owned(), track.into_owned());
+					}
+				},
+				None => {
+					library.tracks.remove(id.as_ref());
+				}
+			},
+			Record::TrackList { id, list } => match list {
+				Some(list) => match library.trackLists.get_mut(id.as_ref()) {
+					Some(existing) => *existing = list.into_owned(),
+					None => {
+						library
+							.trackLists
+							.insert(id.into_owned(), list.into_owned());
+					}
+				},
+				None => {
+					library.trackLists.remove(id.as_ref());
+				}
+			},
+			Record::PlayTime { index, play_time } => {
+				if index >= library.playTime.len() {
+					library.playTime.push(play_time.into_owned());
+				}
+			}
+		}
+	}
+}
+
+pub struct ChangeLog {
+	path: PathBuf,
+	/// Generation in the header
+	generation: u64,
+	/// Number of records in the file
+	records: usize,
+	/// Changed since the last save
+	tracks: HashSet<TrackID>,
+	/// Changed since the last save
+	track_lists: HashSet<TrackListID>,
+	/// Number of `Library::playTime` entries that are saved
+	saved_play_time: usize,
+	/// Whether the library needs to be saved as a whole
+	snapshot_needed: bool,
+}
+
+impl ChangeLog {
+	/// Replays the log file on the library, if it belongs to it
+	pub fn open(path: PathBuf, library: &mut Library) -> UniResult<Self> {
+		let now = Instant::now();
+		let mut records = 0;
+		let mut generation = None;
+		match OpenOptions::new().read(true).write(true).open(&path) {
+			Ok(file) => {
+				let mut reader = BufReader::new(&file);
+				let mut line = String::new();
+				let mut valid_len = 0;
+				let mut line_number = 0;
+				loop {
+					line.clear();
+					let len = match reader.read_line(&mut line) {
+						Ok(0) => break,
+						Ok(len) => len,
+						Err(err) => throw!("Error reading library log: {err}"),
+					};
+					line_number += 1;
+					let at_end = match reader.fill_buf() {
+						Ok(buf) => buf.is_empty(),
+						Err(err) => throw!("Error reading library log: {err}"),
+					};
+					let record = match line.ends_with('\n') {
+						true => serde_json::from_str::<Record>(&line).ok(),
+						false => None,
+					};
+					let record = match record {
+						Some(record) => record,
+						// The last line is incomplete if the app quit while it
+						// was being written. It's removed so new records
+						// start on a new line
+						None if at_end => {
+							println!("Removing incomplete library log record");
+							if let Err(err) = file.set_len(valid_len) {
+								throw!("Error repairing library log: {err}");
+							}
+							break;
+						}
+						None => throw!("Error parsing library log at line {line_number}"),
+					};
+					if generation.is_none() {
+						let log_generation = match record {
+							Record::Header { generation } => generation,
+							_ => 0,
+						};
+						generation = Some(log_generation);
+						if log_generation != library.logGeneration {
+							// Left over from before the library was saved
+							// as a whole
+							println!("Skipping outdated library log");
+							break;
+						}
+					}
+					if !matches!(record, Record::Header { .. }) {
+						record.apply(library);
+						records += 1;
+					}
+					valid_len += len as u64;
+				}
+			}
+			Err(err) if err.kind() == ErrorKind::NotFound => {}
+			Err(err) => throw!("Error opening library log: {err}"),
+		}
+		println!("Replay library log: {}ms", now.elapsed().as_millis());
+		let mut change_log = ChangeLog {
+			path,
+			generation: library.logGeneration,
+			records,
+			tracks: HashSet::new(),
+			track_lists: HashSet::new(),
+			saved_play_time: library.playTime.len(),
+			snapshot_needed: false,
+		};
+		// Outdated, empty or missing
+		if generation != Some(library.logGeneration) {
+			change_log.clear(library)?;
+		}
+		Ok(change_log)
+	}
+	/// Generation for the next time the library is saved as a whole
+	pub fn next_generation(&self) -> u64 {
+		self.generation + 1
+	}
+	pub fn track_changed(&mut self, id: &str) {
+		self.tracks.insert(id.to_string());
+	}
+	pub fn track_list_changed(&mut self, id: &str) {
+		self.track_lists.insert(id.to_string());
+	}
+	/// For changes that aren't tracked, like when the library is replaced
+	pub fn snapshot_needed(&mut self) {
+		self.snapshot_needed = true;
+	}
+	/// Whether the library should be saved as a whole instead of appending
+	/// to the log
+	pub fn should_compact(&self) -> bool {
+		self.snapshot_needed || self.records >= MAX_RECORDS
+	}
+	/// Appends the changes since the last save
+	pub fn append(&mut self, library: &Library) -> UniResult<()> {
+		let mut records = Vec::new();
+		for id in &self.tracks {
+			records.push(Record::Track {
+				id: Cow::Borrowed(id),
+				track: library
+					.tracks
+					.get(id)
+					.map(|track| Box::new(Cow::Borrowed(track))),
+			});
+		}
+		for id in &self.track_lists {
+			records.push(Record::TrackList {
+				id: Cow::Borrowed(id),
+				list: library
+					.trackLists
+					.get(id)
+					.map(|list| Box::new(Cow::Borrowed(list))),
+			});
+		}
+		let new_play_time = library.playTime.iter().enumerate();
+		for (index, play_time) in new_play_time.skip(self.saved_play_time) {
+			records.push(Record::PlayTime {
+				index,
+				play_time: Cow::Borrowed(play_time),
+			});
+		}
+		if records.is_empty() {
+			return Ok(());
+		}
+
+		let mut bytes = Vec::new();
+		for record in &records {
+			if let Err(err) = serde_json::to_writer(&mut bytes, record) {
+				throw!("Error serializing library log: {err}");
+			}
+			bytes.push(b'\n');
+		}
+		let file = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&self.path);
+		let result = file.and_then(|mut file| {
+			file.write_all(&bytes)?;
+			file.sync_data()
+		});
+		if let Err(err) = result {
+			throw!("Error writing library log: {err}");
+		}
+
+		self.records += records.len();
+		self.tracks.clear();
+		self.track_lists.clear();
+		self.saved_play_time = library.playTime.len();
+		Ok(())
+	}
+	/// Clears the log, after the library has been saved as a whole
+	pub fn clear(&mut self, library: &Library) -> UniResult<()> {
+		let header = Record::Header {
+			generation: library.logGeneration,
+		};
+		let mut bytes = match serde_json::to_vec(&header) {
+			Ok(bytes) => bytes,
+			Err(err) => throw!("Error serializing library log: {err}"),
+		};
+		bytes.push(b'\n');
+		let af = AtomicFile::new(&self.path, AllowOverwrite);
+		if let Err(err) = af.write(|f| f.write_all(&bytes)) {
+			throw!("Error clearing library log: {err}");
+		}
+		self.generation = library.logGeneration;
+		self.records = 0;
+		self.tracks.clear();
+		self.track_lists.clear();
+		self.saved_play_time = library.playTime.len();
+		self.snapshot_needed = false;
+		Ok(())
+	}
+}
+
+#[test]
+fn change_log_test() {
+	use crate::library_types::test_track;
+
+	let path = std::env::temp_dir().join(format!("ferrum-change-log-{}.log", std::process::id()));
+	let _ = std::fs::remove_file(&path);
+
+	let mut library = Library::new();
+	let mut log = ChangeLog::open(path.clone(), &mut library).unwrap();
+	let track = test_track(serde_json::json!({ "name": "A" }));
+	library.tracks.insert("a".to_string(), track.clone());
+	library.tracks.insert("b".to_string(), track);
+	log.track_changed("a");
+	log.track_changed("b");
+	let playlist = library.new_playlist("P".to_string(), None);
+	let playlist_id = playlist.id.clone();
+	library
+		.trackLists
+		.insert(playlist_id.clone(), TrackList::Playlist(playlist));
+	log.track_list_changed(&playlist_id);
+	library.playTime.push(("a".to_string(), 0, 1000));
+	log.append(&library).unwrap();
+
+	library.tracks.remove("b");
+	log.track_changed("b");
+	library.tracks.get_mut("a").unwrap().name = "A2".to_string();
+	log.track_changed("a");
+	library.playTime.push(("a".to_string(), 1000, 500));
+	log.append(&library).unwrap();
+	assert_eq!(log.records, 7);
+
+	// Incomplete last record
+	let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+	file.write_all(b"{\"type\":\"track\",\"id\":\"c\",\"tra")
+		.unwrap();
+
+	let mut replayed = Library::new();
+	ChangeLog::open(path.clone(), &mut replayed).unwrap();
+	let log_text = std::fs::read_to_string(&path).unwrap();
+	assert!(log_text.ends_with('\n'));
+	assert_eq!(replayed.tracks.keys().collect::<Vec<_>>(), vec!["a"]);
+	assert_eq!(replayed.tracks["a"].name, "A2");
+	assert!(replayed.trackLists.contains_key(&playlist_id));
+	assert_eq!(replayed.playTime.len(), 2);
+
+	// Replaying on a library that already has the changes
+	let mut replayed_again = replayed.clone();
+	ChangeLog::open(path.clone(), &mut replayed_again).unwrap();
+	assert_eq!(replayed_again.playTime.len(), 2);
+
+	// Library saved as a whole, but the app quit before the log was cleared
+	let mut snapshot = replayed.clone();
+	snapshot.logGeneration = log.next_generation();
+	snapshot.tracks.get_mut("a").unwrap().name = "A3".to_string();
+	snapshot.tracks.remove("b");
+	let mut reopened = snapshot.clone();
+	let mut log = ChangeLog::open(path.clone(), &mut reopened).unwrap();
+	assert_eq!(log.records, 0);
+	assert_eq!(reopened.tracks.keys().collect::<Vec<_>>(), vec!["a"]);
+	assert_eq!(reopened.tracks["a"].name, "A3");
+	let log_text = std::fs::read_to_string(&path).unwrap();
+	assert_eq!(log_text, "{\"type\":\"header\",\"generation\":1}\n");
+
+	// New records are replayed on that snapshot
+	reopened.tracks.get_mut("a").unwrap().name = "A4".to_string();
+	log.track_changed("a");
+	log.append(&reopened).unwrap();
+	let mut replayed = snapshot.clone();
+	ChangeLog::open(path.clone(), &mut replayed).unwrap();
+	assert_eq!(replayed.tracks["a"].name, "A4");
+
+	log.clear(&snapshot).unwrap();
+	let mut empty = snapshot.clone();
+	empty.tracks.clear();
+	ChangeLog::open(path.clone(), &mut empty).unwrap();
+	assert!(empty.tracks.is_empty());
+	std::fs::remove_file(&path).unwrap();
+}
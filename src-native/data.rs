@@ -1,20 +1,25 @@
-use crate::artists::load_artists;
+use crate::artists::ArtistIndex;
+use crate::backups::Backups;
+use crate::change_log::ChangeLog;
+use crate::journal::{Change, Journal};
 use crate::library::{load_library, Paths};
 use crate::library_types::{Library, TrackID, TrackList, TrackListID};
 use crate::page::{get_track_ids, ViewAs};
+use crate::search_index::SearchIndex;
 use crate::sort::sort;
 use crate::tracks::Tag;
 use crate::view_options::ViewOptions;
-use crate::{page, UniResult};
+use crate::watch_folders::WatchFolders;
+use crate::{browse, duplicates, get_now_timestamp, page, UniResult};
 use atomicwrites::{AllowOverwrite, AtomicFile};
 use dirs_next;
 use napi::Result;
 use serde::Serialize;
-use std::collections::HashSet;
+use std::collections::HashMap;
 use std::env;
 use std::io::Write;
 use std::path::PathBuf;
-use std::time::Instant;
+use std::time::{Instant, SystemTime};
 
 pub struct Data {
 	pub paths: Paths,
@@ -32,20 +37,52 @@ pub struct Data {
 	pub group_album_tracks: bool,
 	/// Current tag being edited
 	pub current_tag: Option<Tag>,
-	pub artists: HashSet<String>,
+	pub artists: ArtistIndex,
+	pub search_index: SearchIndex,
+	pub journal: Journal,
+	pub change_log: ChangeLog,
+	pub backups: Backups,
+	pub watch_folders: WatchFolders,
+	/// Tracks whose silence couldn't be detected, with the file's modified
+	/// time, so they're only retried if the file changes
+	pub silence_failed: HashMap<TrackID, Option<SystemTime>>,
 }
 
 impl Data {
+	/// Appends the unsaved changes to the change log, or saves the whole
+	/// library if the log is due for compaction. Also makes a backup if the
+	/// last one is more than an hour old
 	pub fn save(&mut self) -> Result<()> {
-		let mut now = Instant::now();
+		let timestamp = get_now_timestamp();
+		if self.backups.is_due(timestamp)? {
+			let json = self.stringify()?;
+			self.backups.create(&json, timestamp)?;
+		}
+		if !self.change_log.should_compact() {
+			let now = Instant::now();
+			self.change_log.append(&self.library)?;
+			println!("Write changes: {}ms", now.elapsed().as_millis());
+			return Ok(());
+		}
+		self.library.logGeneration = self.change_log.next_generation();
+		self.save_snapshot()?;
+		self.change_log.clear(&self.library)?;
+		Ok(())
+	}
+	pub fn stringify(&self) -> Result<Vec<u8>> {
+		let now = Instant::now();
 		let formatter = serde_json::ser::PrettyFormatter::with_indent(b"	"); // tab
 
 		let mut json = Vec::new();
 		let mut ser = serde_json::Serializer::with_formatter(&mut json, formatter);
 		self.library.versioned().serialize(&mut ser)?;
 		println!("Stringify: {}ms", now.elapsed().as_millis());
+		Ok(json)
+	}
+	fn save_snapshot(&mut self) -> Result<()> {
+		let json = self.stringify()?;
 
-		now = Instant::now();
+		let now = Instant::now();
 		let file_path = &self.paths.library_json;
 		let af = AtomicFile::new(file_path, AllowOverwrite);
 		let result = af.write(|f| f.write_all(&json));
@@ -56,6 +93,40 @@ impl Data {
 		println!("Write: {}ms", now.elapsed().as_millis());
 		Ok(())
 	}
+	/// Keeps indexes in sync after a track is added or changed
+	pub fn track_changed(&mut self, id: &TrackID) {
+		self.change_log.track_changed(id);
+		if let Some(track) = self.library.tracks.get(id) {
+			self.search_index.update_track(id, track);
+			self.artists.update_track(id, track);
+		}
+	}
+	/// Keeps indexes in sync after a track is deleted
+	pub fn track_removed(&mut self, id: &str) {
+		self.change_log.track_changed(id);
+		self.search_index.remove_track(id);
+		self.artists.remove_track(id);
+	}
+	/// Rebuilds indexes and clears undo history after the whole library
+	/// is replaced. The library is saved as a whole next time
+	pub fn library_replaced(&mut self) {
+		self.search_index.reset();
+		self.artists = ArtistIndex::build(&self.library);
+		self.journal.clear();
+		self.change_log.snapshot_needed();
+	}
+	/// Records an action that can be undone, and marks the track lists it
+	/// changed as unsaved
+	pub fn record(&mut self, name: &str, changes: Vec<Change>) {
+		for change in &changes {
+			if let Change::TrackLists(lists) = change {
+				for (id, _) in lists {
+					self.change_log.track_list_changed(id);
+				}
+			}
+		}
+		self.journal.push(name, changes);
+	}
 	pub fn get_page_tracks(&self) -> &Vec<String> {
 		match &self.page_track_ids {
 			Some(ids) => ids,
@@ -97,6 +168,9 @@ impl Data {
 			},
 			tracks_dir: library_dir.join("Tracks"),
 			library_json: library_dir.join("Library.json"),
+			library_log: library_dir.join("Library.log"),
+			backups_dir: library_dir.join("Backups"),
+			fingerprints_db: library_dir.join("Fingerprints.redb"),
 			cache_dir: cache_dir.clone(),
 			cache_db: cache_dir.join("Cache.redb"),
 			local_data_dir: match local_data_path {
@@ -105,14 +179,23 @@ impl Data {
 			},
 		};
 
-		let loaded_library = load_library(&paths)?;
+		let mut loaded_library = load_library(&paths)?;
+		let change_log = ChangeLog::open(paths.library_log.clone(), &mut loaded_library)?;
 		let loaded_cache = ViewOptions::load(&paths);
-		let artists = load_artists(&loaded_library);
+		let backups = Backups::load(&paths);
+		let watch_folders = WatchFolders::load(&paths);
+		let artists = ArtistIndex::build(&loaded_library);
 
 		let mut data = Data {
 			paths,
 			library: loaded_library,
 			artists,
+			search_index: SearchIndex::new(),
+			journal: Journal::new(),
+			change_log,
+			backups,
+			watch_folders,
+			silence_failed: HashMap::new(),
 			view_options: loaded_cache,
 			open_playlist_id: "root".to_string(),
 			open_playlist_track_ids: vec![],
@@ -133,6 +216,15 @@ impl Data {
 		self.view_as = view_as.unwrap_or_default();
 		self.open_playlist_track_ids = get_track_ids(self)?;
 		self.page_track_ids = None;
+		if browse::parse_id(&self.open_playlist_id).is_some() {
+			sort(self, "dateAdded", true)?;
+			return Ok(());
+		}
+		if self.open_playlist_id == duplicates::TRACKLIST_ID {
+			self.sort_key = "index".to_string();
+			self.sort_desc = true;
+			return Ok(());
+		}
 		match self.library.get_tracklist(&self.open_playlist_id)? {
 			TrackList::Special(_) => {
 				sort(self, "dateAdded", true)?;
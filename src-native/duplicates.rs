@@ -0,0 +1,531 @@
+//! Finds tracks that are likely the same song, by artist, title and
+//! duration. The groups can be opened as a virtual tracklist with the ID
+//! `duplicates`, which lists the tracks of each group next to each other.
+//! Tracks can also be grouped by fingerprint, which finds duplicates with
+//! different tags.
+
+use crate::analysis::fingerprint::{
+	get_stored_many, open_db, remove_stored, Matcher, DEFAULT_MIN_SIMILARITY,
+};
+use crate::artists::ArtistIndex;
+use crate::data::Data;
+use crate::data_js::get_data;
+use crate::filter::fold_char;
+use crate::journal::Change;
+use crate::library_types::{Library, Track, TrackID, TrackList};
+use crate::playlists::{delete_file, restore_file, TrashedFile};
+use crate::UniResult;
+use napi::{Env, JsObject, Result, Task};
+use rayon::prelude::*;
+use serde_json::json;
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use unicode_normalization::UnicodeNormalization;
+
+pub const TRACKLIST_ID: &str = "duplicates";
+
+/// Durations within this many seconds of the shortest track in a group
+/// are considered the same
+const DURATION_TOLERANCE: f64 = 3.0;
+
+#[napi(object)]
+pub struct DuplicateGroup {
+	/// Sorted with the keeper first
+	pub track_ids: Vec<TrackID>,
+	/// The track that's suggested to keep, with the highest bitrate and the
+	/// most plays
+	pub keeper: TrackID,
+}
+
+/// Folds text like filtering does, so differences in case, accents,
+/// punctuation and spacing are ignored
+fn fold(text: &str) -> String {
+	text.nfc()
+		.filter_map(fold_char)
+		.filter(|c| !c.is_whitespace())
+		.collect()
+}
+
+/// Orders tracks from least to most worth keeping
+fn compare_keeper(a: &Track, b: &Track) -> Ordering {
+	let bitrate = a.bitrate.total_cmp(&b.bitrate);
+	let plays = a.playCount.unwrap_or(0).cmp(&b.playCount.unwrap_or(0));
+	// The track that was added first is preferred
+	let date_added = b.dateAdded.cmp(&a.dateAdded);
+	bitrate.then(plays).then(date_added)
+}
+
+fn new_group(mut tracks: Vec<(&TrackID, &Track)>) -> DuplicateGroup {
+	tracks.sort_by(|(_, a), (_, b)| compare_keeper(b, a));
+	DuplicateGroup {
+		keeper: tracks[0].0.clone(),
+		track_ids: tracks.into_iter().map(|(id, _)| id.clone()).collect(),
+	}
+}
+
+/// Stable order, so the tracklist doesn't jump around
+fn sort_groups(library: &Library, groups: &mut [DuplicateGroup]) {
+	groups.sort_by(|a, b| {
+		let track_a = &library.tracks[&a.keeper];
+		let track_b = &library.tracks[&b.keeper];
+		let artist = track_a.artist.cmp(&track_b.artist);
+		artist
+			.then(track_a.name.cmp(&track_b.name))
+			.then(a.keeper.cmp(&b.keeper))
+	});
+}
+
+pub fn find_duplicates(library: &Library) -> Vec<DuplicateGroup> {
+	let mut by_name: HashMap<(String, String), Vec<(&TrackID, &Track)>> = HashMap::new();
+	for (id, track) in &library.tracks {
+		let name = fold(&track.name);
+		if name.is_empty() {
+			continue;
+		}
+		let key = (fold(&track.artist), name);
+		by_name.entry(key).or_default().push((id, track));
+	}
+
+	let mut groups = Vec::new();
+	for mut tracks in by_name.into_values() {
+		if tracks.len() < 2 {
+			continue;
+		}
+		tracks.sort_by(|(_, a), (_, b)| a.duration.total_cmp(&b.duration));
+		let mut start = 0;
+		for i in 1..=tracks.len() {
+			let ends_group = i == tracks.len()
+				|| tracks[i].1.duration - tracks[start].1.duration > DURATION_TOLERANCE;
+			if !ends_group {
+				continue;
+			}
+			let group = tracks[start..i].to_vec();
+			start = i;
+			if group.len() >= 2 {
+				groups.push(new_group(group));
+			}
+		}
+	}
+	sort_groups(library, &mut groups);
+	groups
+}
+
+/// Index of the group that `i` is in, for grouping with a union-find
+fn root(parents: &mut [usize], mut i: usize) -> usize {
+	while parents[i] != i {
+		parents[i] = parents[parents[i]];
+		i = parents[i];
+	}
+	i
+}
+
+/// Groups tracks whose durations are close and whose fingerprints are
+/// similar. `tracks` are `(id, duration, fingerprint)`, sorted by duration.
+fn group_by_fingerprint(
+	tracks: &[(TrackID, f64, Vec<u32>)],
+	min_similarity: f64,
+) -> Vec<Vec<TrackID>> {
+	let pairs: Vec<(usize, usize)> = (0..tracks.len())
+		.into_par_iter()
+		.flat_map_iter(|i| {
+			let (_, duration, fingerprint) = &tracks[i];
+			let matcher = Matcher::new(fingerprint);
+			tracks[i + 1..]
+				.iter()
+				.take_while(|(_, other_duration, _)| {
+					other_duration - duration <= DURATION_TOLERANCE
+				})
+				.enumerate()
+				.filter(|(_, (_, _, other))| matcher.similarity(other) >= min_similarity)
+				.map(|(j, _)| (i, i + 1 + j))
+				.collect::<Vec<_>>()
+		})
+		.collect();
+
+	// Tracks that match through another track are in the same group
+	let mut parents: Vec<usize> = (0..tracks.len()).collect();
+	for (i, j) in pairs {
+		let (i, j) = (root(&mut parents, i), root(&mut parents, j));
+		parents[j] = i;
+	}
+	let mut groups: HashMap<usize, Vec<TrackID>> = HashMap::new();
+	for (i, (id, _, _)) in tracks.iter().enumerate() {
+		groups
+			.entry(root(&mut parents, i))
+			.or_default()
+			.push(id.clone());
+	}
+	groups.into_values().filter(|ids| ids.len() >= 2).collect()
+}
+
+/// Track IDs of all groups, one group after another
+pub fn get_track_ids(library: &Library) -> Vec<TrackID> {
+	find_duplicates(library)
+		.into_iter()
+		.flat_map(|group| group.track_ids)
+		.collect()
+}
+
+/// Tracklist object for the duplicates list, in the same shape as other
+/// tracklists
+pub fn get_tracklist_json() -> serde_json::Value {
+	json!({
+		"type": "duplicates",
+		"id": TRACKLIST_ID,
+		"name": "Duplicates",
+	})
+}
+
+#[napi(js_name = "find_duplicates")]
+#[allow(dead_code)]
+pub fn find_duplicates_js(env: Env) -> Result<Vec<DuplicateGroup>> {
+	let data: &mut Data = get_data(&env)?;
+	Ok(find_duplicates(&data.library))
+}
+
+struct FindFingerprintDuplicates {
+	/// Tracks and their files, sorted by duration
+	tracks: Vec<(TrackID, f64, PathBuf)>,
+	min_similarity: f64,
+	db_path: PathBuf,
+}
+impl Task for FindFingerprintDuplicates {
+	type Output = Vec<Vec<TrackID>>;
+	type JsValue = Vec<DuplicateGroup>;
+	fn compute(&mut self) -> Result<Self::Output> {
+		let db = open_db(&self.db_path)?;
+		let ids: Vec<&str> = self.tracks.iter().map(|(id, _, _)| id.as_str()).collect();
+		let fingerprints = get_stored_many(&db, &ids)?;
+		let tracks: Vec<_> = self
+			.tracks
+			.iter()
+			.zip(fingerprints)
+			.filter_map(|((id, duration, path), stored)| match stored {
+				Some(stored) if stored.is_current(path) => {
+					Some((id.clone(), *duration, stored.fingerprint))
+				}
+				_ => None,
+			})
+			.collect();
+		Ok(group_by_fingerprint(&tracks, self.min_similarity))
+	}
+	fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+		let data: &mut Data = get_data(&env)?;
+		let mut groups = Vec::new();
+		for ids in output {
+			// Tracks may have been deleted in the meantime
+			let tracks: Vec<_> = ids
+				.iter()
+				.filter_map(|id| Some((id, data.library.tracks.get(id)?)))
+				.collect();
+			if tracks.len() >= 2 {
+				groups.push(new_group(tracks));
+			}
+		}
+		sort_groups(&data.library, &mut groups);
+		Ok(groups)
+	}
+}
+
+/// Finds tracks that sound the same, regardless of their tags. Only tracks
+/// that have been fingerprinted are included. `min_similarity` defaults to
+/// 0.75.
+#[napi(
+	js_name = "find_fingerprint_duplicates",
+	ts_return_type = "Promise<Array<DuplicateGroup>>"
+)]
+#[allow(dead_code)]
+pub fn find_fingerprint_duplicates(min_similarity: Option<f64>, env: Env) -> Result<JsObject> {
+	let data: &mut Data = get_data(&env)?;
+	let tracks_dir = &data.paths.tracks_dir;
+	let mut tracks: Vec<(TrackID, f64, PathBuf)> = data
+		.library
+		.tracks
+		.iter()
+		.map(|(id, track)| (id.clone(), track.duration, tracks_dir.join(&track.file)))
+		.collect();
+	tracks.sort_by(|(_, a, _), (_, b, _)| a.total_cmp(b));
+	let task = FindFingerprintDuplicates {
+		tracks,
+		min_similarity: min_similarity.unwrap_or(DEFAULT_MIN_SIMILARITY),
+		db_path: data.paths.fingerprints_db.clone(),
+	};
+	env.spawn(task).map(|t| t.promise_object())
+}
+
+fn add_counts(a: Option<u32>, b: Option<u32>) -> Option<u32> {
+	match (a, b) {
+		(None, None) => None,
+		(a, b) => Some(a.unwrap_or(0) + b.unwrap_or(0)),
+	}
+}
+
+fn append<T>(a: &mut Option<Vec<T>>, b: Option<Vec<T>>) {
+	if let Some(b) = b {
+		a.get_or_insert_with(Vec::new).extend(b);
+	}
+}
+
+/// Merges the play and skip history of `other` into `keeper`
+fn merge_history(keeper: &mut Track, other: Track) {
+	keeper.playCount = add_counts(keeper.playCount, other.playCount);
+	keeper.skipCount = add_counts(keeper.skipCount, other.skipCount);
+	append(&mut keeper.plays, other.plays);
+	append(&mut keeper.skips, other.skips);
+	append(&mut keeper.playsImported, other.playsImported);
+	append(&mut keeper.skipsImported, other.skipsImported);
+	if let Some(plays) = &mut keeper.plays {
+		plays.sort_unstable();
+	}
+	if let Some(skips) = &mut keeper.skips {
+		skips.sort_unstable();
+	}
+	keeper.dateAdded = keeper.dateAdded.min(other.dateAdded);
+}
+
+fn check_merge(library: &Library, keeper_id: &str, other_ids: &[TrackID]) -> UniResult<()> {
+	library.get_track(keeper_id)?;
+	let mut seen = HashSet::from([keeper_id]);
+	for id in other_ids {
+		if !seen.insert(id) {
+			throw!("Track ID listed more than once: {id}");
+		}
+		library.get_track(id)?;
+	}
+	Ok(())
+}
+
+/// A merge, with what's needed to undo it
+pub struct Merged {
+	keeper_id: TrackID,
+	/// The keeper as it was before the merge
+	keeper: Box<Track>,
+	/// Removed tracks, with their play time in ms
+	removed: Vec<(TrackID, Track, i64)>,
+	/// Indexes of play time entries that were moved to the keeper, and the
+	/// track they belonged to
+	play_time: Vec<(usize, TrackID)>,
+	/// Files of removed tracks that were moved to the trash
+	trashed: Vec<TrashedFile>,
+}
+
+/// Merges tracks into `keeper_id`, and points playlists and play time to it
+fn merge_in_library(
+	library: &mut Library,
+	keeper_id: &str,
+	other_ids: &[TrackID],
+) -> UniResult<Merged> {
+	check_merge(library, keeper_id, other_ids)?;
+
+	let mut play_times: HashMap<&str, i64> = HashMap::new();
+	let mut moved_play_time = Vec::new();
+	for (i, (track_id, _, duration)) in library.playTime.iter_mut().enumerate() {
+		if let Some(other_id) = other_ids.iter().find(|id| *id == track_id) {
+			*play_times.entry(other_id).or_default() += *duration;
+			moved_play_time.push((i, other_id.clone()));
+			*track_id = keeper_id.to_string();
+		}
+	}
+	for (_, tracklist) in library.trackLists.iter_mut() {
+		if let TrackList::Playlist(playlist) = tracklist {
+			// The keeper is only added where it isn't already
+			let mut has_keeper = playlist.tracks.iter().any(|id| id == keeper_id);
+			playlist.tracks.retain_mut(|track_id| {
+				if !other_ids.contains(track_id) {
+					return true;
+				}
+				if has_keeper {
+					return false;
+				}
+				*track_id = keeper_id.to_string();
+				has_keeper = true;
+				true
+			});
+		}
+	}
+	let keeper_before = Box::new(library.get_track(keeper_id)?.clone());
+	let mut removed = Vec::new();
+	for id in other_ids {
+		let other = library.tracks.remove(id).expect("Track ID not found");
+		let keeper = library
+			.tracks
+			.get_mut(keeper_id)
+			.expect("Track ID not found");
+		merge_history(keeper, other.clone());
+		let play_time = play_times.get(id.as_str()).copied().unwrap_or(0);
+		removed.push((id.clone(), other, play_time));
+	}
+	Ok(Merged {
+		keeper_id: keeper_id.to_string(),
+		keeper: keeper_before,
+		removed,
+		play_time: moved_play_time,
+		trashed: Vec::new(),
+	})
+}
+
+/// Merges tracks into `keeper_id`. The files of the other tracks are moved
+/// to the trash afterwards by `trash_files`
+pub fn merge(data: &mut Data, keeper_id: &str, other_ids: &[TrackID]) -> UniResult<Merged> {
+	let merged = merge_in_library(&mut data.library, keeper_id, other_ids)?;
+	let ids: Vec<&str> = other_ids.iter().map(String::as_str).collect();
+	remove_stored(&data.paths.fingerprints_db, &ids);
+	for (id, _, play_time) in &merged.removed {
+		data.track_removed(id);
+		data.artists.add_play_time(keeper_id, *play_time);
+	}
+	data.track_changed(&keeper_id.to_string());
+	// Play time entries were changed, which the change log doesn't track
+	data.change_log.snapshot_needed();
+	Ok(merged)
+}
+
+/// Moves the files of merged tracks to the trash. Stops at the first file
+/// that can't be moved
+pub fn trash_files(data: &Data, merged: &mut Merged) -> UniResult<()> {
+	for (_, track, _) in &merged.removed {
+		let path = data.paths.tracks_dir.join(&track.file);
+		if path.exists() {
+			merged.trashed.push(delete_file(&path)?);
+		}
+	}
+	Ok(())
+}
+
+/// Undoes a merge. Returns the keeper ID and the IDs of the restored tracks
+pub fn unmerge(data: &mut Data, merged: Merged) -> UniResult<(TrackID, Vec<TrackID>)> {
+	data.library.get_track(&merged.keeper_id)?;
+	for (id, _, _) in &merged.removed {
+		if data.library.tracks.contains_key(id) {
+			throw!("Track ID already exists: {id}");
+		}
+	}
+	for trashed in &merged.trashed {
+		restore_file(trashed)?;
+	}
+	for (i, id) in merged.play_time {
+		match data.library.playTime.get_mut(i) {
+			Some(entry) if entry.0 == merged.keeper_id => entry.0 = id,
+			_ => {}
+		}
+	}
+	data.library
+		.tracks
+		.insert(merged.keeper_id.clone(), *merged.keeper);
+	data.track_changed(&merged.keeper_id);
+	let mut other_ids = Vec::new();
+	for (id, track, _) in merged.removed {
+		data.library.tracks.insert(id.clone(), track);
+		data.track_changed(&id);
+		other_ids.push(id);
+	}
+	// Play time can't be subtracted from the keeper's artists
+	data.artists = ArtistIndex::build(&data.library);
+	data.change_log.snapshot_needed();
+	Ok((merged.keeper_id, other_ids))
+}
+
+/// Merges tracks into the first one. Plays, skips and playlist entries of
+/// the other tracks are moved to it, and their files are moved to the trash
+#[napi(js_name = "merge_tracks")]
+#[allow(dead_code)]
+pub fn merge_tracks(track_ids: Vec<TrackID>, env: Env) -> Result<()> {
+	let data: &mut Data = get_data(&env)?;
+	let (keeper_id, other_ids) = match track_ids.split_first() {
+		Some((keeper_id, other_ids)) => (keeper_id.clone(), other_ids),
+		None => throw!("No tracks to merge"),
+	};
+	check_merge(&data.library, &keeper_id, other_ids)?;
+	let playlist_ids: Vec<&str> = data
+		.library
+		.trackLists
+		.iter()
+		.filter(|(_, tracklist)| match tracklist {
+			TrackList::Playlist(playlist) => {
+				playlist.tracks.iter().any(|id| other_ids.contains(id))
+			}
+			_ => false,
+		})
+		.map(|(id, _)| id.as_str())
+		.collect();
+	let playlists_change = Change::track_lists(&data.library, playlist_ids);
+	let mut merged = merge(data, &keeper_id, other_ids)?;
+	let result = trash_files(data, &mut merged);
+	let changes = vec![playlists_change, Change::MergedTracks(Box::new(merged))];
+	data.record("Merge Tracks", changes);
+	result?;
+	Ok(())
+}
+
+#[test]
+fn duplicates_test() {
+	use crate::library_types::test_library;
+
+	let mut library = test_library(serde_json::json!([
+		{ "duration": 200.0, "bitrate": 128000.0, "file": "a.mp3", "name": "Don't Stop",
+			"artist": "Band" },
+		{ "duration": 201.5, "bitrate": 320000.0, "file": "b.mp3", "dateAdded": 5,
+			"name": "dont stop", "artist": "BAND" },
+		{ "duration": 199.0, "bitrate": 320000.0, "file": "c.mp3", "dateAdded": 9,
+			"name": "Don’t  Stop", "artist": "Band", "playCount": 4 },
+		{ "duration": 320.0, "bitrate": 320000.0, "file": "d.mp3", "name": "Don't Stop",
+			"artist": "Band" },
+		{ "duration": 200.0, "bitrate": 320000.0, "file": "e.mp3", "name": "Other",
+			"artist": "Band" },
+	]));
+	let groups = find_duplicates(&library);
+	assert_eq!(groups.len(), 1);
+	assert_eq!(groups[0].keeper, "2");
+	assert_eq!(groups[0].track_ids, vec!["2", "1", "0"]);
+	assert_eq!(get_track_ids(&library), vec!["2", "1", "0"]);
+
+	let mut playlist = library.new_playlist("P".to_string(), None);
+	playlist.tracks = vec!["0".to_string(), "4".to_string(), "1".to_string()];
+	let playlist_id = playlist.id.clone();
+	library
+		.trackLists
+		.insert(playlist_id.clone(), TrackList::Playlist(playlist));
+	library.tracks.get_mut("0").unwrap().plays = Some(vec![30, 10]);
+	library.tracks.get_mut("0").unwrap().playCount = Some(2);
+	library.tracks.get_mut("2").unwrap().plays = Some(vec![20]);
+	library.playTime.push(("0".to_string(), 10, 1000));
+	library.playTime.push(("4".to_string(), 10, 500));
+	let others = ["0".to_string(), "1".to_string()];
+	let merged = merge_in_library(&mut library, "2", &others).unwrap();
+	let removed: Vec<_> = merged
+		.removed
+		.iter()
+		.map(|(id, _, ms)| (id.as_str(), *ms))
+		.collect();
+	assert_eq!(removed, vec![("0", 1000), ("1", 0)]);
+	assert_eq!(merged.play_time, vec![(0, "0".to_string())]);
+	assert_eq!(merged.keeper.playCount, Some(4));
+	let keeper = &library.tracks["2"];
+	assert_eq!(keeper.playCount, Some(6));
+	assert_eq!(keeper.plays, Some(vec![10, 20, 30]));
+	assert_eq!(keeper.dateAdded, 0);
+	assert!(!library.tracks.contains_key("0"));
+	assert_eq!(library.playTime[0].0, "2");
+	assert_eq!(library.playTime[1].0, "4");
+	match &library.trackLists[&playlist_id] {
+		TrackList::Playlist(playlist) => assert_eq!(playlist.tracks, vec!["2", "4"]),
+		_ => panic!(),
+	}
+	assert!(merge_in_library(&mut library, "2", &["4".to_string(), "4".to_string()]).is_err());
+
+	let fingerprint = |seed: u32| -> Vec<u32> {
+		(0..100)
+			.map(|i: u32| (i ^ seed).wrapping_mul(2654435761))
+			.collect()
+	};
+	let tracks = vec![
+		("a".to_string(), 100.0, fingerprint(1)),
+		("b".to_string(), 101.0, fingerprint(2)),
+		("c".to_string(), 102.0, fingerprint(1)),
+		("d".to_string(), 104.0, fingerprint(1)),
+		("e".to_string(), 110.0, fingerprint(1)),
+	];
+	let groups = group_by_fingerprint(&tracks, DEFAULT_MIN_SIMILARITY);
+	assert_eq!(groups, vec![vec!["a", "c", "d"]]);
+}
@@ -1,18 +1,21 @@
 use crate::data::Data;
 use crate::data_js::get_data;
 use crate::library_types::TrackID;
+use crate::query::{self, QueryError};
+use crate::smart_playlists::TrackMatcher;
+use lazy_static::lazy_static;
 use napi::{Env, Result};
 use rayon::prelude::*;
+use std::collections::HashMap;
 use std::str::Chars;
 use std::time::Instant;
 use unicode_normalization::{Recompositions, UnicodeNormalization};
 
 #[napi(js_name = "filter_open_playlist")]
 #[allow(dead_code)]
-pub fn filter_js(query: String, env: Env) -> Result<()> {
+pub fn filter_js(query: String, env: Env) -> Result<Option<QueryError>> {
 	let data: &mut Data = get_data(&env)?;
-	filter(data, query);
-	return Ok(());
+	return Ok(filter(data, query));
 }
 
 fn match_at_start(mut text: Recompositions<Chars>, keyword: Chars) -> bool {
@@ -41,7 +44,7 @@ fn match_at_start(mut text: Recompositions<Chars>, keyword: Chars) -> bool {
 	return true;
 }
 
-fn find_match(text: &str, keyword: &str) -> bool {
+pub fn find_match(text: &str, keyword: &str) -> bool {
 	let mut keyword_chars = keyword.chars();
 	let first_keyword_char = match keyword_chars.next() {
 		Some(x) => x,
@@ -61,64 +64,119 @@ fn find_match(text: &str, keyword: &str) -> bool {
 	return false;
 }
 
-fn find_match_opt(text: &Option<String>, keyword: &str) -> bool {
-	match text {
-		Some(text) => find_match(text, keyword),
-		None => return false,
-	}
-}
-
-fn filter_keyword(data: &Data, ids: &Vec<TrackID>, keyword: &str) -> Vec<TrackID> {
-	let tracks = &data.library.tracks;
-	let filtered_tracks: Vec<_> = ids
-		.into_par_iter()
-		.with_min_len(2000)
-		.filter(|id| {
-			let track = tracks.get(*id).expect("Track ID not found");
-			let is_match = find_match(&track.name, keyword)
-				|| find_match(&track.artist, keyword)
-				|| find_match_opt(&track.albumName, keyword)
-				|| find_match_opt(&track.comments, keyword)
-				|| find_match_opt(&track.genre, keyword);
-			is_match
-		})
-		.map(|id| id.clone())
-		.collect();
-	filtered_tracks
+/// Like `find_match`, but only matches at the start of `text`
+pub fn find_match_at_start(text: &str, keyword: &str) -> bool {
+	match_at_start(text.nfc(), keyword.chars())
 }
 
-pub fn filter(data: &mut Data, query: String) {
+/// Filters the open playlist. If the query is invalid, the previous filter
+/// is kept and the error is returned
+pub fn filter(data: &mut Data, query: String) -> Option<QueryError> {
 	let now = Instant::now();
-	data.page_track_ids = if query == "" {
-		None
-	} else {
-		let query: String = query.nfc().collect();
-		let mut keywords_iter = query.split(' ');
-		let mut filtered_tracks = match keywords_iter.next() {
-			Some(keyword) => filter_keyword(data, &data.open_playlist_track_ids, keyword),
-			None => return,
-		};
-		for keyword in keywords_iter {
-			filtered_tracks = filter_keyword(data, &filtered_tracks, keyword);
+	let rules = match query::parse(&query) {
+		Ok(rules) => rules,
+		Err(err) => return Some(err),
+	};
+	data.page_track_ids = match rules {
+		None => None,
+		Some(rules) => {
+			let matcher = match TrackMatcher::new(&data.library, &rules) {
+				Ok(matcher) => matcher,
+				Err(err) => {
+					return Some(QueryError {
+						message: err.message,
+						start: 0,
+						end: query.encode_utf16().count() as u32,
+					})
+				}
+			};
+			let candidates = data.search_index.candidates(&data.library, &rules);
+			let tracks = &data.library.tracks;
+			let filtered_tracks: Vec<TrackID> = data
+				.open_playlist_track_ids
+				.par_iter()
+				.with_min_len(2000)
+				.filter(|id| {
+					if let Some(candidates) = &candidates {
+						if !candidates.contains(id) {
+							return false;
+						}
+					}
+					let track = tracks.get(*id).expect("Track ID not found");
+					matcher.matches(id, track)
+				})
+				.cloned()
+				.collect();
+			Some(filtered_tracks)
 		}
-		Some(filtered_tracks)
 	};
 	data.filter = query;
 	println!("Filter: {}ms", now.elapsed().as_millis());
+	return None;
 }
 
-enum Eq {
-	True,
-	False,
-	Skip,
+/// Ranges containing every char that `check()` has special rules for
+const FOLD_RANGES: [(u32, u32); 5] = [
+	(0x0020, 0x0600),
+	(0x13A0, 0x1400),
+	(0x1E00, 0x1F00),
+	(0x2000, 0x2100),
+	(0xFF00, 0xFF70),
+];
+
+lazy_static! {
+	/// Chars that `check()` treats as equal, mapped to the same char
+	static ref FOLD_TABLE: HashMap<char, char> = build_fold_table();
 }
-fn check(user_char: char, data_char: char) -> Eq {
-	if user_char == data_char {
-		return Eq::True;
+
+fn build_fold_table() -> HashMap<char, char> {
+	let chars: Vec<char> = FOLD_RANGES
+		.iter()
+		.flat_map(|(start, end)| (*start..*end).filter_map(char::from_u32))
+		.collect();
+	// union-find, where each set is represented by its lowest char
+	let mut parents: HashMap<char, char> = HashMap::new();
+	fn find(parents: &HashMap<char, char>, mut c: char) -> char {
+		while let Some(parent) = parents.get(&c) {
+			c = *parent;
+		}
+		return c;
+	}
+	for user_char in &chars {
+		for data_char in &chars {
+			if user_char == data_char || !matches!(check(*user_char, *data_char), Eq::True) {
+				continue;
+			}
+			let a = find(&parents, *user_char);
+			let b = find(&parents, *data_char);
+			if a != b {
+				parents.insert(a.max(b), a.min(b));
+			}
+		}
 	}
+	let mut table = HashMap::new();
+	for c in chars {
+		let root = find(&parents, c);
+		if root != c {
+			table.insert(c, root);
+		}
+	}
+	return table;
+}
+
+/// Folds a char so that chars `check()` matches with each other fold to the
+/// same char. Returns `None` for symbols that can be skipped in the text.
+pub fn fold_char(c: char) -> Option<char> {
+	if is_skip(c) {
+		return None;
+	}
+	return Some(*FOLD_TABLE.get(&c).unwrap_or(&c));
+}
+
+/// Symbols that can be skipped in the text
+fn is_skip(data_char: char) -> bool {
 	// unicode list: https://en.wikipedia.org/wiki/List_of_Unicode_characters
-	// double char matches todo: æ ae, ß ss, œ oe
-	let is_skip = match data_char {
+	return match data_char {
 		// ascii symbols, \u{0021} to \u{002F}
 		'!' | '"' | '#' | '$' | '%' | '&' | '\'' | '(' | ')' => true,
 		'*' | '+' | ',' | '-' | '.' | '/' => true,
@@ -164,9 +222,21 @@ fn check(user_char: char, data_char: char) -> Eq {
 
 		_ => false,
 	};
-	if is_skip {
+}
+
+enum Eq {
+	True,
+	False,
+	Skip,
+}
+fn check(user_char: char, data_char: char) -> Eq {
+	if user_char == data_char {
+		return Eq::True;
+	}
+	if is_skip(data_char) {
 		return Eq::Skip;
 	}
+	// double char matches todo: æ ae, ß ss, œ oe
 	fn one_of(c: &char, a: char, b: char) -> bool {
 		return c == &a || c == &b;
 	}
@@ -0,0 +1,270 @@
+//! Imports many files and folders in the background. Files are read and
+//! copied in parallel, and each imported track is added to the library on
+//! the JS thread as soon as it's ready.
+
+use crate::data::Data;
+use crate::data_js::get_data;
+use crate::get_now_timestamp;
+use crate::library_types::{Library, Track};
+use crate::tracks::import::{self, find_audio_files};
+use napi::threadsafe_function::{
+	ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
+};
+use napi::{Env, JsFunction, JsObject, Result, Task};
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use unicode_normalization::UnicodeNormalization;
+
+/// Tracks with the same key are considered the same song
+#[derive(PartialEq, Eq, Hash)]
+struct DuplicateKey {
+	size: i64,
+	/// Tenths of a second
+	duration: i64,
+	name: String,
+	artist: String,
+	album: String,
+}
+
+impl DuplicateKey {
+	fn new(track: &Track) -> Self {
+		let normalize = |s: &str| s.trim().nfc().flat_map(char::to_lowercase).collect();
+		DuplicateKey {
+			size: track.size,
+			duration: (track.duration * 10.0).round() as i64,
+			name: normalize(&track.name),
+			artist: normalize(&track.artist),
+			album: normalize(track.albumName.as_deref().unwrap_or_default()),
+		}
+	}
+}
+
+fn get_duplicate_keys(library: &Library) -> HashSet<DuplicateKey> {
+	library.tracks.values().map(DuplicateKey::new).collect()
+}
+
+enum Outcome {
+	Imported(Box<Track>),
+	Skipped,
+	Error(String),
+}
+
+/// Sent from the import threads to the JS thread
+struct FileDone {
+	path: PathBuf,
+	done: u32,
+	total: u32,
+	outcome: Outcome,
+}
+
+#[napi(object)]
+pub struct ImportProgress {
+	pub path: String,
+	/// Number of files handled so far
+	pub done: u32,
+	pub total: u32,
+	/// ID of the new track, if the file was imported
+	pub track_id: Option<String>,
+	/// Whether the file was skipped because it's already in the library
+	pub skipped: bool,
+	pub error: Option<String>,
+}
+
+#[napi(object)]
+pub struct ImportJobStatus {
+	pub imported: u32,
+	pub skipped: u32,
+	/// Files that couldn't be imported, with the reason
+	pub errors: Vec<String>,
+	/// Whether the job was cancelled before all files were handled
+	pub cancelled: bool,
+}
+
+type ProgressCallback = ThreadsafeFunction<FileDone, ErrorStrategy::Fatal>;
+
+fn import_file(
+	path: &Path,
+	tracks_dir: &Path,
+	now: i64,
+	existing: Option<&Mutex<HashSet<DuplicateKey>>>,
+) -> Outcome {
+	let read_file = match import::read(path, now) {
+		Ok(read_file) => read_file,
+		Err(err) => return Outcome::Error(err.message),
+	};
+	if let Some(existing) = existing {
+		// Also catches the same file being in the selection twice
+		let key = DuplicateKey::new(&read_file.track);
+		if !existing.lock().unwrap().insert(key) {
+			return Outcome::Skipped;
+		}
+	}
+	match read_file.copy_to(tracks_dir, now) {
+		Ok(track) => Outcome::Imported(Box::new(track)),
+		Err(err) => Outcome::Error(err.message),
+	}
+}
+
+struct ImportFiles {
+	paths: Vec<PathBuf>,
+	tracks_dir: PathBuf,
+	now: i64,
+	/// `None` if duplicates are imported
+	existing: Option<Mutex<HashSet<DuplicateKey>>>,
+	callback: ProgressCallback,
+	cancelled: Arc<AtomicBool>,
+}
+impl Task for ImportFiles {
+	type Output = ImportJobStatus;
+	type JsValue = ImportJobStatus;
+	fn compute(&mut self) -> Result<Self::Output> {
+		let mut files = Vec::new();
+		let mut errors = Vec::new();
+		for path in &self.paths {
+			if !path.is_dir() {
+				files.push(path.clone());
+			} else if let Err(err) = find_audio_files(path, &mut files) {
+				errors.push(err.message);
+			}
+		}
+		let total = files.len() as u32;
+		let done = AtomicU32::new(0);
+		let imported = AtomicU32::new(0);
+		let skipped = AtomicU32::new(0);
+		let file_errors = Mutex::new(Vec::new());
+		files.par_iter().for_each(|path| {
+			if self.cancelled.load(Ordering::Relaxed) {
+				return;
+			}
+			let outcome = import_file(path, &self.tracks_dir, self.now, self.existing.as_ref());
+			match &outcome {
+				Outcome::Imported(_) => imported.fetch_add(1, Ordering::Relaxed),
+				Outcome::Skipped => skipped.fetch_add(1, Ordering::Relaxed),
+				Outcome::Error(err) => {
+					let message = format!("{}: {err}", path.to_string_lossy());
+					file_errors.lock().unwrap().push(message);
+					0
+				}
+			};
+			let file_done = FileDone {
+				path: path.clone(),
+				done: done.fetch_add(1, Ordering::Relaxed) + 1,
+				total,
+				outcome,
+			};
+			self.callback
+				.call(file_done, ThreadsafeFunctionCallMode::Blocking);
+		});
+		errors.extend(file_errors.into_inner().unwrap());
+		Ok(ImportJobStatus {
+			imported: imported.into_inner(),
+			skipped: skipped.into_inner(),
+			errors,
+			cancelled: done.into_inner() < total,
+		})
+	}
+	fn resolve(&mut self, _env: Env, output: Self::Output) -> Result<Self::JsValue> {
+		Ok(output)
+	}
+}
+
+/// Adds an imported track to the library, on the JS thread
+fn add_to_library(env: &Env, file_done: FileDone) -> Result<ImportProgress> {
+	let data: &mut Data = get_data(env)?;
+	let mut progress = ImportProgress {
+		path: file_done.path.to_string_lossy().to_string(),
+		done: file_done.done,
+		total: file_done.total,
+		track_id: None,
+		skipped: false,
+		error: None,
+	};
+	match file_done.outcome {
+		Outcome::Imported(track) => {
+			let id = data.library.generate_id();
+			data.library.tracks.insert(id.clone(), *track);
+			data.track_changed(&id);
+			progress.track_id = Some(id);
+		}
+		Outcome::Skipped => progress.skipped = true,
+		Outcome::Error(err) => progress.error = Some(err),
+	}
+	Ok(progress)
+}
+
+#[napi]
+#[derive(Default)]
+pub struct ImportJob {
+	cancelled: Arc<AtomicBool>,
+}
+#[napi]
+impl ImportJob {
+	#[napi(factory)]
+	pub fn new() -> Self {
+		Self::default()
+	}
+	/// Imports files, and audio files in folders and their subfolders.
+	/// `on_progress` is called after each file. Files that are already in
+	/// the library are only skipped if `skip_duplicates` is true.
+	#[napi(
+		ts_args_type = "paths: Array<string>, onProgress: (progress: ImportProgress) => void, skipDuplicates?: boolean",
+		ts_return_type = "Promise<ImportJobStatus>"
+	)]
+	pub fn start(
+		&self,
+		paths: Vec<String>,
+		on_progress: JsFunction,
+		skip_duplicates: Option<bool>,
+		env: Env,
+	) -> Result<JsObject> {
+		let data: &mut Data = get_data(&env)?;
+		self.cancelled.store(false, Ordering::Relaxed);
+		let callback: ProgressCallback = on_progress
+			.create_threadsafe_function(0, |ctx: ThreadSafeCallContext<FileDone>| {
+				Ok(vec![add_to_library(&ctx.env, ctx.value)?])
+			})?;
+		let task = ImportFiles {
+			paths: paths.into_iter().map(PathBuf::from).collect(),
+			tracks_dir: data.paths.tracks_dir.clone(),
+			now: get_now_timestamp(),
+			existing: match skip_duplicates.unwrap_or(false) {
+				true => Some(Mutex::new(get_duplicate_keys(&data.library))),
+				false => None,
+			},
+			callback,
+			cancelled: self.cancelled.clone(),
+		};
+		env.spawn(task).map(|t| t.promise_object())
+	}
+	/// Stops importing. Files that are already imported are kept
+	#[napi]
+	pub fn cancel(&self) {
+		self.cancelled.store(true, Ordering::Relaxed);
+	}
+}
+
+#[test]
+fn import_job_test() {
+	use crate::library_types::test_track;
+
+	let track = |name: &str, duration: f64| -> Track {
+		test_track(serde_json::json!({
+			"size": 1000, "duration": duration, "name": name, "artist": "X"
+		}))
+	};
+	let mut library = Library::new();
+	library
+		.tracks
+		.insert("a".to_string(), track("Song", 180.02));
+	let keys = get_duplicate_keys(&library);
+	assert!(keys.contains(&DuplicateKey::new(&track(" song", 180.0))));
+	assert!(!keys.contains(&DuplicateKey::new(&track("Song", 181.0))));
+	assert!(!keys.contains(&DuplicateKey::new(&track("Song 2", 180.0))));
+
+	let existing = Mutex::new(keys);
+	let outcome = import_file(Path::new("missing.txt"), Path::new("."), 0, Some(&existing));
+	assert!(matches!(outcome, Outcome::Error(_)));
+}
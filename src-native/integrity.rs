@@ -0,0 +1,467 @@
+//! Finds inconsistencies in the library, like missing files or track lists
+//! that aren't in any folder, and fixes them.
+
+use crate::data::Data;
+use crate::data_js::get_data;
+use crate::get_now_timestamp;
+use crate::journal::Change;
+use crate::library_types::{Library, Track, TrackID, TrackList, TrackListID};
+use crate::playlists::delete_file;
+use crate::tracks::import::{self, FileType};
+use crate::UniResult;
+use napi::{Env, Result};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+
+#[napi]
+#[derive(PartialEq)]
+pub enum IssueKind {
+	/// A track's file doesn't exist
+	MissingFile,
+	/// A file in the tracks folder isn't used by any track
+	OrphanFile,
+	/// A playlist contains track IDs that don't exist
+	MissingTrack,
+	/// A folder contains a track list ID that doesn't exist
+	MissingTrackList,
+	/// A track list isn't in root or any folder under root
+	UnreachableTrackList,
+	/// A track list is in more than one folder, or more than once in a folder
+	MultipleParents,
+	/// `playCount` doesn't match `plays` and `playsImported`
+	WrongPlayCount,
+}
+
+#[napi(object)]
+pub struct LibraryIssue {
+	pub kind: IssueKind,
+	pub message: String,
+	/// What the automatic fix does, if there is one
+	pub fix: Option<String>,
+	pub track_id: Option<TrackID>,
+	/// The track list with the issue, or the folder for `MissingTrackList`
+	pub tracklist_id: Option<TrackListID>,
+	/// The missing track list for `MissingTrackList`
+	pub child_id: Option<TrackListID>,
+	/// File name in the tracks folder
+	pub file: Option<String>,
+}
+
+impl LibraryIssue {
+	fn new(kind: IssueKind, message: String, fix: Option<&str>) -> Self {
+		LibraryIssue {
+			kind,
+			message,
+			fix: fix.map(str::to_string),
+			track_id: None,
+			tracklist_id: None,
+			child_id: None,
+			file: None,
+		}
+	}
+}
+
+fn expected_play_count(track: &Track) -> u32 {
+	let plays = track.plays.as_ref().map_or(0, |plays| plays.len() as u32);
+	let imported: i64 = match &track.playsImported {
+		Some(imported) => imported.iter().map(|count| count.count).sum(),
+		None => 0,
+	};
+	plays + imported.max(0) as u32
+}
+
+fn get_children(tracklist: &TrackList) -> &[TrackListID] {
+	match tracklist {
+		TrackList::Folder(folder) => &folder.children,
+		TrackList::Special(special) => &special.children,
+		TrackList::Playlist(_) | TrackList::Smart(_) => &[],
+	}
+}
+
+fn get_children_mut(tracklist: &mut TrackList) -> Option<&mut Vec<TrackListID>> {
+	match tracklist {
+		TrackList::Folder(folder) => Some(&mut folder.children),
+		TrackList::Special(special) => Some(&mut special.children),
+		TrackList::Playlist(_) | TrackList::Smart(_) => None,
+	}
+}
+
+fn get_name(library: &Library, id: &str) -> String {
+	match library.trackLists.get(id) {
+		Some(TrackList::Playlist(playlist)) => playlist.name.clone(),
+		Some(TrackList::Folder(folder)) => folder.name.clone(),
+		Some(TrackList::Smart(smart)) => smart.name.clone(),
+		Some(TrackList::Special(_)) => "Library".to_string(),
+		None => id.to_string(),
+	}
+}
+
+/// Adds `id` and the track lists under it to `reached`
+fn reach<'a>(library: &'a Library, id: &'a str, reached: &mut HashSet<&'a str>) {
+	let mut stack = vec![id];
+	while let Some(id) = stack.pop() {
+		if !reached.insert(id) {
+			continue;
+		}
+		if let Some(tracklist) = library.trackLists.get(id) {
+			stack.extend(get_children(tracklist).iter().map(String::as_str));
+		}
+	}
+}
+
+/// Track lists that aren't under root. Only the top ones of each unreachable
+/// tree are returned, since fixing those also fixes the ones under them.
+fn find_unreachable(library: &Library) -> Vec<&str> {
+	let mut parents: HashMap<&str, Vec<&str>> = HashMap::new();
+	for (id, tracklist) in &library.trackLists {
+		for child_id in get_children(tracklist) {
+			parents.entry(child_id).or_default().push(id);
+		}
+	}
+	let mut reached = HashSet::new();
+	reach(library, "root", &mut reached);
+	let mut unreachable = Vec::new();
+	// Track lists without a parent first, then ones in a cycle of folders
+	for without_parent in [true, false] {
+		for id in library.trackLists.keys() {
+			let has_parent = parents
+				.get(id.as_str())
+				.is_some_and(|parents| parents.iter().any(|p| library.trackLists.contains_key(*p)));
+			if !reached.contains(id.as_str()) && has_parent != without_parent {
+				unreachable.push(id.as_str());
+				reach(library, id, &mut reached);
+			}
+		}
+	}
+	unreachable
+}
+
+pub fn check(library: &Library, tracks_dir: &Path) -> UniResult<Vec<LibraryIssue>> {
+	let mut issues = Vec::new();
+
+	let entries = match fs::read_dir(tracks_dir) {
+		Ok(entries) => entries,
+		Err(err) => throw!("Error reading tracks folder: {err}"),
+	};
+	let mut files: Vec<String> = entries
+		.flatten()
+		.filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+		.map(|entry| entry.file_name().to_string_lossy().to_string())
+		.collect();
+	files.sort();
+	let file_set: HashSet<&str> = files.iter().map(String::as_str).collect();
+
+	let mut used_files = HashSet::new();
+	for (id, track) in &library.tracks {
+		used_files.insert(track.file.as_str());
+		if !file_set.contains(track.file.as_str()) && !tracks_dir.join(&track.file).exists() {
+			let message = format!("File of \"{}\" is missing: {}", track.name, track.file);
+			let mut issue = LibraryIssue::new(IssueKind::MissingFile, message, None);
+			issue.track_id = Some(id.clone());
+			issue.file = Some(track.file.clone());
+			issues.push(issue);
+		}
+		let expected = expected_play_count(track);
+		if track.playCount.unwrap_or(0) != expected {
+			let message = format!(
+				"\"{}\" has a play count of {}, but {} plays",
+				track.name,
+				track.playCount.unwrap_or(0),
+				expected
+			);
+			let fix = format!("Set the play count to {expected}");
+			let mut issue = LibraryIssue::new(IssueKind::WrongPlayCount, message, Some(&fix));
+			issue.track_id = Some(id.clone());
+			issues.push(issue);
+		}
+	}
+	for file in &files {
+		let is_audio = FileType::from_path(Path::new(file)).is_ok();
+		if is_audio && !used_files.contains(file.as_str()) {
+			let message = format!("File is not in the library: {file}");
+			let fix = Some("Add it to the library");
+			let mut issue = LibraryIssue::new(IssueKind::OrphanFile, message, fix);
+			issue.file = Some(file.clone());
+			issues.push(issue);
+		}
+	}
+
+	let mut parents: HashMap<&str, Vec<&str>> = HashMap::new();
+	for (id, tracklist) in &library.trackLists {
+		if let TrackList::Playlist(playlist) = tracklist {
+			let missing: HashSet<&TrackID> = playlist
+				.tracks
+				.iter()
+				.filter(|track_id| !library.tracks.contains_key(*track_id))
+				.collect();
+			if !missing.is_empty() {
+				let message = format!(
+					"Playlist \"{}\" contains {} songs that don't exist",
+					playlist.name,
+					missing.len()
+				);
+				let fix = Some("Remove them from the playlist");
+				let mut issue = LibraryIssue::new(IssueKind::MissingTrack, message, fix);
+				issue.tracklist_id = Some(id.clone());
+				issues.push(issue);
+			}
+		}
+		for child_id in get_children(tracklist) {
+			parents.entry(child_id).or_default().push(id);
+			if !library.trackLists.contains_key(child_id) {
+				let message = format!(
+					"\"{}\" contains a playlist that doesn't exist: {child_id}",
+					get_name(library, id)
+				);
+				let fix = Some("Remove it from the folder");
+				let mut issue = LibraryIssue::new(IssueKind::MissingTrackList, message, fix);
+				issue.tracklist_id = Some(id.clone());
+				issue.child_id = Some(child_id.clone());
+				issues.push(issue);
+			}
+		}
+	}
+
+	for id in library.trackLists.keys() {
+		let parent_ids = match parents.get(id.as_str()) {
+			Some(parent_ids) if parent_ids.len() > 1 => parent_ids,
+			_ => continue,
+		};
+		let parent_names: Vec<String> = parent_ids
+			.iter()
+			.map(|parent_id| format!("\"{}\"", get_name(library, parent_id)))
+			.collect();
+		let message = format!(
+			"\"{}\" is in more than one folder: {}",
+			get_name(library, id),
+			parent_names.join(", ")
+		);
+		let fix = format!("Keep it only in {}", parent_names[0]);
+		let mut issue = LibraryIssue::new(IssueKind::MultipleParents, message, Some(&fix));
+		issue.tracklist_id = Some(id.clone());
+		issues.push(issue);
+	}
+
+	for id in find_unreachable(library) {
+		let message = format!("\"{}\" is not in the sidebar", get_name(library, id));
+		let fix = Some("Move it to the top level");
+		let mut issue = LibraryIssue::new(IssueKind::UnreachableTrackList, message, fix);
+		issue.tracklist_id = Some(id.to_string());
+		issues.push(issue);
+	}
+
+	Ok(issues)
+}
+
+/// Fixes issues that only involve the library itself. Does nothing if the
+/// issue no longer exists.
+fn fix_in_library(library: &mut Library, issue: &LibraryIssue) -> UniResult<()> {
+	let tracklist_id = issue.tracklist_id.as_deref().unwrap_or_default();
+	match issue.kind {
+		IssueKind::MissingFile => throw!("No automatic fix for missing files"),
+		IssueKind::OrphanFile => throw!("Orphan files are not fixed in the library"),
+		IssueKind::WrongPlayCount => {
+			let track_id = issue.track_id.as_deref().unwrap_or_default();
+			let track = match library.tracks.get_mut(track_id) {
+				Some(track) => track,
+				None => return Ok(()),
+			};
+			let expected = expected_play_count(track);
+			track.playCount = if expected == 0 && track.playCount.is_none() {
+				None
+			} else {
+				Some(expected)
+			};
+		}
+		IssueKind::MissingTrack => {
+			let tracks = &library.tracks;
+			if let Some(TrackList::Playlist(playlist)) = library.trackLists.get_mut(tracklist_id) {
+				playlist.tracks.retain(|id| tracks.contains_key(id));
+			}
+		}
+		IssueKind::MissingTrackList => {
+			let child_id = issue.child_id.as_deref().unwrap_or_default();
+			if library.trackLists.contains_key(child_id) {
+				return Ok(());
+			}
+			if let Some(tracklist) = library.trackLists.get_mut(tracklist_id) {
+				if let Some(children) = get_children_mut(tracklist) {
+					children.retain(|id| id != child_id);
+				}
+			}
+		}
+		IssueKind::MultipleParents => {
+			let mut kept = false;
+			for (_, tracklist) in library.trackLists.iter_mut() {
+				if let Some(children) = get_children_mut(tracklist) {
+					children.retain(|id| {
+						if id != tracklist_id {
+							return true;
+						}
+						let keep = !kept;
+						kept = true;
+						keep
+					});
+				}
+			}
+		}
+		IssueKind::UnreachableTrackList => {
+			if !find_unreachable(library).contains(&tracklist_id) || tracklist_id == "root" {
+				return Ok(());
+			}
+			for (_, tracklist) in library.trackLists.iter_mut() {
+				if let Some(children) = get_children_mut(tracklist) {
+					children.retain(|id| id != tracklist_id);
+				}
+			}
+			let root = library.get_root_tracklist_mut()?;
+			root.children.push(tracklist_id.to_string());
+		}
+	}
+	Ok(())
+}
+
+/// Adds an orphan file to the library. The file is copied like any other
+/// import, and the original is moved to the trash.
+fn fix_orphan_file(data: &mut Data, file: &str) -> UniResult<()> {
+	if data.library.tracks.values().any(|track| track.file == file) {
+		return Ok(());
+	}
+	let path = data.paths.tracks_dir.join(file);
+	let track = import::import(data, &path, get_now_timestamp())?;
+	let id = data.library.generate_id();
+	data.library.tracks.insert(id.clone(), track);
+	data.track_changed(&id);
+	delete_file(&path)?;
+	Ok(())
+}
+
+#[napi(js_name = "check_library")]
+#[allow(dead_code)]
+pub fn check_library(env: Env) -> Result<Vec<LibraryIssue>> {
+	let data: &mut Data = get_data(&env)?;
+	Ok(check(&data.library, &data.paths.tracks_dir)?)
+}
+
+/// Fixes issues from `check_library`. Changes to track lists can be undone
+#[napi(js_name = "fix_library_issues")]
+#[allow(dead_code)]
+pub fn fix_library_issues(issues: Vec<LibraryIssue>, env: Env) -> Result<()> {
+	let data: &mut Data = get_data(&env)?;
+	let all_ids: Vec<TrackListID> = data.library.trackLists.keys().cloned().collect();
+	let change = Change::track_lists(&data.library, all_ids.iter().map(String::as_str));
+	let mut tracklists_changed = false;
+	let mut result = Ok(());
+	for issue in &issues {
+		result = match issue.kind {
+			IssueKind::MissingFile => continue,
+			IssueKind::OrphanFile => match &issue.file {
+				Some(file) => fix_orphan_file(data, file),
+				None => continue,
+			},
+			IssueKind::WrongPlayCount => {
+				let fixed = fix_in_library(&mut data.library, issue);
+				if let Some(track_id) = &issue.track_id {
+					data.track_changed(track_id);
+				}
+				fixed
+			}
+			_ => {
+				tracklists_changed = true;
+				fix_in_library(&mut data.library, issue)
+			}
+		};
+		if result.is_err() {
+			break;
+		}
+	}
+	if tracklists_changed {
+		data.record("Repair Library", vec![change]);
+	}
+	Ok(result?)
+}
+
+#[test]
+fn integrity_test() {
+	use crate::library_types::test_library;
+
+	let dir = std::env::temp_dir().join(format!("ferrum-integrity-{}", std::process::id()));
+	let _ = fs::remove_dir_all(&dir);
+	fs::create_dir_all(&dir).unwrap();
+	fs::write(dir.join("a.mp3"), b"").unwrap();
+	fs::write(dir.join("orphan.mp3"), b"").unwrap();
+	fs::write(dir.join(".DS_Store"), b"").unwrap();
+
+	let mut library = test_library(serde_json::json!([
+		{ "file": "a.mp3", "name": "A", "playCount": 3, "plays": [1, 2],
+			"playsImported": [{ "count": 1, "fromDate": 0, "toDate": 0 }] },
+		{ "file": "b.mp3", "name": "B", "playCount": 2, "plays": [1] },
+	]));
+	let mut playlist = library.new_playlist("P".to_string(), None);
+	playlist.tracks = vec!["0".to_string(), "x".to_string()];
+	let playlist_id = playlist.id.clone();
+	let mut folder = library.new_folder("F".to_string(), None);
+	folder.children = vec![playlist_id.clone(), "y".to_string()];
+	let folder_id = folder.id.clone();
+	let lost = library.new_playlist("Lost".to_string(), None);
+	let lost_id = lost.id.clone();
+	library
+		.trackLists
+		.insert(playlist_id.clone(), TrackList::Playlist(playlist));
+	library
+		.trackLists
+		.insert(folder_id.clone(), TrackList::Folder(folder));
+	library
+		.trackLists
+		.insert(lost_id.clone(), TrackList::Playlist(lost));
+	let root = library.get_root_tracklist_mut().unwrap();
+	root.children = vec![folder_id.clone(), playlist_id.clone()];
+
+	let issues = check(&library, &dir).unwrap();
+	let mut kinds: Vec<_> = issues
+		.iter()
+		.map(|issue| match issue.kind {
+			IssueKind::MissingFile => "missing file",
+			IssueKind::OrphanFile => "orphan file",
+			IssueKind::MissingTrack => "missing track",
+			IssueKind::MissingTrackList => "missing track list",
+			IssueKind::UnreachableTrackList => "unreachable",
+			IssueKind::MultipleParents => "multiple parents",
+			IssueKind::WrongPlayCount => "play count",
+		})
+		.collect();
+	kinds.sort();
+	assert_eq!(
+		kinds,
+		vec![
+			"missing file",
+			"missing track",
+			"missing track list",
+			"multiple parents",
+			"orphan file",
+			"play count",
+			"unreachable"
+		]
+	);
+
+	for issue in &issues {
+		if issue.kind != IssueKind::MissingFile && issue.kind != IssueKind::OrphanFile {
+			fix_in_library(&mut library, issue).unwrap();
+		}
+	}
+	let remaining = check(&library, &dir).unwrap();
+	assert!(remaining
+		.iter()
+		.all(|issue| issue.kind == IssueKind::MissingFile || issue.kind == IssueKind::OrphanFile));
+	assert_eq!(library.tracks["1"].playCount, Some(1));
+	match &library.trackLists[&folder_id] {
+		TrackList::Folder(folder) => assert!(folder.children.is_empty()),
+		_ => panic!(),
+	}
+	assert_eq!(
+		library.get_root_tracklist_mut().unwrap().children,
+		vec![folder_id, playlist_id, lost_id]
+	);
+	fs::remove_dir_all(&dir).unwrap();
+}
@@ -1,9 +1,12 @@
 use crate::data_js::get_data;
-use crate::get_now_timestamp;
-use crate::library_types::{CountObject, Folder, Library, Playlist, Track, TrackList};
+use crate::library_types::{
+	CountObject, Folder, ImportedSmartCriteria, Library, Playlist, SmartPlaylist, Track, TrackList,
+};
 use crate::tracks::generate_filename;
-use crate::tracks::import::{read_file_metadata, FileType};
-use lofty::file::{AudioFile, TaggedFileExt};
+use crate::tracks::import::{self, read_file_metadata, FileType};
+use crate::tracks::properties::AudioProperties;
+use crate::{get_now_timestamp, itunes_smart, smart_playlists};
+use lofty::file::TaggedFileExt;
 use napi::{Env, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
@@ -350,20 +353,15 @@ fn parse_track(
 	// this will also checks if the file exists
 	let file_md = read_file_metadata(&xml_track_path)?;
 
-	let tagged_file = match lofty::read_from_path(&xml_track_path) {
+	let tagged_file = match import::probe(&xml_track_path)?.read() {
 		Ok(tagged_file) => tagged_file,
 		Err(e) => throw!("Failed to read file information: {}", e),
 	};
-	let audio_properties = tagged_file.properties();
-
-	let file_type = FileType::from_path(&xml_track_path)?;
-	{
-		// check that file type matches file extension
-		let lofty_file_type = FileType::from_lofty_file_type(tagged_file.file_type())?;
-		if file_type != lofty_file_type {
-			println!("File type mismatch: {} vs {}", file_type, lofty_file_type);
-		}
-	}
+	let audio_properties = AudioProperties::read(&tagged_file, &xml_track_path)?;
+
+	// The file type is detected from the content, so files with the wrong
+	// extension get the right one when copied
+	let file_type = FileType::from_lofty_file_type(tagged_file.file_type())?;
 
 	let name = xml_track.name.unwrap_or_default();
 	let artist = xml_track.artist.unwrap_or_default();
@@ -371,21 +369,28 @@ fn parse_track(
 
 	let track = Track {
 		size: file_md.len() as i64,
-		duration: audio_properties.duration().as_secs_f64(),
-		bitrate: audio_properties
-			.audio_bitrate()
-			.ok_or(nerr!("Unknown bitrate"))?
-			.into(),
-		sampleRate: audio_properties
-			.audio_bitrate()
-			.ok_or(nerr!("Unknown sample rate"))?
-			.into(),
+		duration: audio_properties.duration,
+		bitrate: audio_properties.bitrate,
+		sampleRate: audio_properties.sample_rate,
 		file: filename,
 		dateModified: datetime_to_timestamp_millis(xml_track.date_modified),
 		dateAdded: datetime_to_timestamp_millis(xml_track.date_added),
 		name,
 		importedFrom: Some("itunes".to_string()),
 		originalId: Some(xml_track.persistent_id),
+		fileType: Some(file_type.file_extension().to_string()),
+		codec: audio_properties.codec,
+		channels: audio_properties.channels,
+		bitDepth: audio_properties.bit_depth,
+		lossless: audio_properties.lossless,
+		encoder: audio_properties.encoder,
+		encoderDelay: audio_properties.encoder_delay,
+		encoderPadding: audio_properties.encoder_padding,
+		silenceStart: None,
+		silenceEnd: None,
+		loudness: None,
+		truePeak: None,
+		albumLoudness: None,
 		artist,
 		composer: keep_filled(xml_track.composer),
 		sortName: keep_filled(xml_track.sort_name),
@@ -395,6 +400,7 @@ fn parse_track(
 		rating: xml_track.rating,
 		year: xml_track.year,
 		bpm: xml_track.bpm.map(|bpm| bpm.into()),
+		key: None,
 		comments: keep_filled(xml_track.comments),
 		grouping: keep_filled(xml_track.grouping),
 		liked: keep_true(xml_track.loved),
@@ -464,6 +470,9 @@ struct XmlPlaylist {
 	#[serde(rename = "Smart Info")]
 	smart_info: Option<plist::Value>,
 
+	#[serde(rename = "Smart Criteria")]
+	smart_criteria: Option<plist::Value>,
+
 	#[serde(rename = "Playlist Persistent ID")]
 	playlist_persistent_id: String,
 
@@ -487,10 +496,6 @@ impl XmlPlaylist {
 		if self.visible == Some(false) {
 			return false;
 		};
-		// smart playlists
-		if self.smart_info.is_some() {
-			return false;
-		};
 		match self.distinguished_kind {
 			None | Some(1) => return true,
 			Some(_) => return false, // ignore special iTunes playlists
@@ -551,36 +556,192 @@ fn import_playlist(
 		// immediately insert into library so new generated ids are unique
 		library.trackLists.insert(id.clone(), folder);
 	} else {
-		let mut track_ids = Vec::new();
-		for playlist_item in xml_playlist.playlist_items.as_ref().unwrap() {
-			let track_id = xml_track_id_map.get(&playlist_item.track_id.to_string());
-			match track_id {
-				Some(track_id) => track_ids.push(track_id.clone()),
-				None => errors.push(format!(
-					"Track with id {} not found in playlist {}",
-					playlist_item.track_id, xml_playlist.name
-				)),
+		tracklist = match parse_smart_playlist(xml_playlist) {
+			Some(Ok(smart)) => TrackList::Smart(SmartPlaylist {
+				id: id.clone(),
+				name: xml_playlist.name.clone(),
+				description: xml_playlist.description.clone(),
+				liked: xml_playlist.loved.unwrap_or_default(),
+				disliked: xml_playlist.disliked.unwrap_or_default(),
+				importedFrom: Some("itunes".to_string()),
+				originalId: Some(xml_playlist.playlist_persistent_id.clone()),
+				dateImported: Some(start_time),
+				dateCreated: None,
+				rules: smart.rules,
+				limit: smart.limit,
+			}),
+			Some(Err(e)) => {
+				errors.push(format!(
+					"Smart playlist \"{}\" imported as a regular playlist: {e}",
+					xml_playlist.name
+				));
+				let playlist = import_static_playlist(
+					id.clone(),
+					xml_playlist,
+					start_time,
+					errors,
+					xml_track_id_map,
+				);
+				TrackList::Playlist(playlist)
 			}
-		}
-
-		tracklist = TrackList::Playlist(Playlist {
-			id: id.clone(),
-			name: xml_playlist.name.clone(),
-			description: xml_playlist.description.clone(),
-			liked: xml_playlist.loved.unwrap_or_default(),
-			disliked: xml_playlist.disliked.unwrap_or_default(),
-			importedFrom: Some("itunes".to_string()),
-			originalId: Some(xml_playlist.playlist_persistent_id.clone()),
-			dateImported: Some(start_time),
-			dateCreated: None,
-			tracks: track_ids,
-		});
+			None => {
+				let playlist = import_static_playlist(
+					id.clone(),
+					xml_playlist,
+					start_time,
+					errors,
+					xml_track_id_map,
+				);
+				TrackList::Playlist(playlist)
+			}
+		};
 		// immediately insert into library so new generated ids are unique
 		library.trackLists.insert(id.clone(), tracklist);
 	}
 	id
 }
 
+/// Imports the playlist's items. For smart playlists, this is a snapshot of
+/// what the playlist contained in iTunes.
+fn import_static_playlist(
+	id: String,
+	xml_playlist: &XmlPlaylist,
+	start_time: i64,
+	errors: &mut Vec<String>,
+	xml_track_id_map: &HashMap<String, String>,
+) -> Playlist {
+	let mut track_ids = Vec::new();
+	for playlist_item in xml_playlist.playlist_items.as_ref().unwrap() {
+		let track_id = xml_track_id_map.get(&playlist_item.track_id.to_string());
+		match track_id {
+			Some(track_id) => track_ids.push(track_id.clone()),
+			None => errors.push(format!(
+				"Track with id {} not found in playlist {}",
+				playlist_item.track_id, xml_playlist.name
+			)),
+		}
+	}
+	let imported_smart_criteria = match (&xml_playlist.smart_info, &xml_playlist.smart_criteria) {
+		(Some(info), Some(criteria)) => Some(ImportedSmartCriteria {
+			info: to_hex(info.as_data().unwrap_or_default()),
+			criteria: to_hex(criteria.as_data().unwrap_or_default()),
+		}),
+		_ => None,
+	};
+
+	Playlist {
+		id,
+		name: xml_playlist.name.clone(),
+		description: xml_playlist.description.clone(),
+		liked: xml_playlist.loved.unwrap_or_default(),
+		disliked: xml_playlist.disliked.unwrap_or_default(),
+		importedFrom: Some("itunes".to_string()),
+		originalId: Some(xml_playlist.playlist_persistent_id.clone()),
+		dateImported: Some(start_time),
+		dateCreated: None,
+		importedSmartCriteria: imported_smart_criteria,
+		tracks: track_ids,
+	}
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+	bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Returns `None` if it's not a smart playlist
+fn parse_smart_playlist(
+	xml_playlist: &XmlPlaylist,
+) -> Option<std::result::Result<itunes_smart::ItunesSmartPlaylist, String>> {
+	let info = xml_playlist.smart_info.as_ref()?;
+	let result = match (info.as_data(), &xml_playlist.smart_criteria) {
+		(Some(info), Some(criteria)) => match criteria.as_data() {
+			Some(criteria) => itunes_smart::parse(info, criteria),
+			None => Err("Invalid Smart Criteria".to_string()),
+		},
+		(Some(_), None) => Err("Missing Smart Criteria".to_string()),
+		(None, _) => Err("Invalid Smart Info".to_string()),
+	};
+	Some(result)
+}
+
+/// Smart playlist rules reference playlists by iTunes Persistent ID, so this
+/// replaces them with Ferrum IDs once every playlist is imported. Smart
+/// playlists that can't be resolved are turned into regular playlists.
+fn resolve_smart_playlists(
+	library: &mut Library,
+	xml_playlists: &[XmlPlaylist],
+	start_time: i64,
+	errors: &mut Vec<String>,
+	xml_track_id_map: &HashMap<String, String>,
+) {
+	let mut persistent_id_map = HashMap::new();
+	for (id, tracklist) in &library.trackLists {
+		let original_id = match tracklist {
+			TrackList::Playlist(list) => &list.originalId,
+			TrackList::Folder(list) => &list.originalId,
+			TrackList::Smart(list) => &list.originalId,
+			TrackList::Special(_) => continue,
+		};
+		if let Some(original_id) = original_id {
+			persistent_id_map.insert(original_id.clone(), id.clone());
+		}
+	}
+
+	let imported_smart_ids: Vec<String> = library
+		.trackLists
+		.values()
+		.filter_map(|tracklist| match tracklist {
+			TrackList::Smart(smart) if smart.dateImported == Some(start_time) => {
+				Some(smart.id.clone())
+			}
+			_ => None,
+		})
+		.collect();
+	for id in imported_smart_ids {
+		let mut smart = match library.trackLists.get(&id) {
+			Some(TrackList::Smart(smart)) => smart.clone(),
+			_ => continue,
+		};
+		let mut unresolved = Vec::new();
+		itunes_smart::map_playlist_ids(
+			&mut smart.rules,
+			&mut |playlist_id| match persistent_id_map.get(playlist_id) {
+				Some(ferrum_id) => *playlist_id = ferrum_id.clone(),
+				None => unresolved.push(playlist_id.clone()),
+			},
+		);
+		let error = match unresolved.first() {
+			Some(playlist_id) => Some(format!("References missing playlist {playlist_id}")),
+			None => match smart_playlists::validate(library, &smart) {
+				Ok(()) => None,
+				Err(e) => Some(e.message),
+			},
+		};
+		let tracklist = match error {
+			None => TrackList::Smart(smart),
+			Some(e) => {
+				errors.push(format!(
+					"Smart playlist \"{}\" imported as a regular playlist: {e}",
+					smart.name
+				));
+				let xml_playlist = xml_playlists
+					.iter()
+					.find(|p| Some(&p.playlist_persistent_id) == smart.originalId.as_ref())
+					.expect("Imported smart playlist not found");
+				let playlist = import_static_playlist(
+					id.clone(),
+					xml_playlist,
+					start_time,
+					errors,
+					xml_track_id_map,
+				);
+				TrackList::Playlist(playlist)
+			}
+		};
+		library.trackLists.insert(id, tracklist);
+	}
+}
+
 #[napi(object)]
 pub struct ImportStatus {
 	pub errors: Vec<String>,
@@ -618,6 +779,7 @@ impl ItunesImport {
 		}
 		let new_library = &mut self.new_library.lock().unwrap();
 		data.library = new_library.take().ok_or(nerr!("Not initialized"))?;
+		data.library_replaced();
 		Ok(())
 	}
 }
@@ -764,6 +926,13 @@ pub async fn import_itunes(
 		let root = library.get_root_tracklist_mut()?;
 		root.children.push(playlist_id);
 	}
+	resolve_smart_playlists(
+		library,
+		&importable_xml_playlists,
+		start_time,
+		&mut errors,
+		&xml_track_id_map,
+	);
 
 	Ok(ImportStatus {
 		errors,
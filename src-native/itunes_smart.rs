@@ -0,0 +1,381 @@
+//! Decoding of the binary `Smart Info` and `Smart Criteria` values of iTunes
+//! smart playlists. The format is undocumented, so this is based on the
+//! tables reverse engineered by the itunes_smartplaylist project
+//! (https://github.com/cvzi/itunes_smartplaylist). The field and operator
+//! codes are shared with the iTunesDB format, but the limit and sort codes
+//! are specific to `Library.xml`.
+
+use crate::library_types::{
+	SmartCondition, SmartLimit, SmartLimitUnit, SmartMatch, SmartOperator, SmartRule,
+	SmartRuleGroup, SmartValue,
+};
+
+/// Seconds between 1904-01-01 (Mac HFS+ epoch) and 1970-01-01
+const HFS_EPOCH_OFFSET: i64 = 2082844800;
+
+const CRITERIA_HEADER_LEN: usize = 136;
+const RULE_HEADER_LEN: usize = 56;
+
+const ACTION_NOT: u32 = 0x02000000;
+const ACTION_IS: u32 = 0x01;
+const ACTION_CONTAINS: u32 = 0x02;
+const ACTION_STARTS_WITH: u32 = 0x04;
+const ACTION_ENDS_WITH: u32 = 0x08;
+const ACTION_GREATER_THAN: u32 = 0x10;
+const ACTION_LESS_THAN: u32 = 0x40;
+const ACTION_IN_RANGE: u32 = 0x100;
+const ACTION_IN_THE_LAST: u32 = 0x200;
+const ACTION_BINARY_AND: u32 = 0x400;
+
+const MEDIA_KIND_MUSIC: u64 = 1;
+
+pub struct ItunesSmartPlaylist {
+	pub rules: SmartRuleGroup,
+	pub limit: Option<SmartLimit>,
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32, String> {
+	match data.get(offset..offset + 4) {
+		Some(bytes) => Ok(u32::from_be_bytes(bytes.try_into().unwrap())),
+		None => Err("Unexpected end of data".to_string()),
+	}
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Result<u64, String> {
+	match data.get(offset..offset + 8) {
+		Some(bytes) => Ok(u64::from_be_bytes(bytes.try_into().unwrap())),
+		None => Err("Unexpected end of data".to_string()),
+	}
+}
+
+fn read_utf16(data: &[u8]) -> Result<String, String> {
+	let units: Vec<u16> = data
+		.chunks_exact(2)
+		.map(|pair| u16::from_be_bytes([pair[0], pair[1]]))
+		.collect();
+	String::from_utf16(&units).or(Err("Invalid UTF-16 string".to_string()))
+}
+
+fn string_field(field: u32) -> Option<&'static str> {
+	let field = match field {
+		0x02 => "name",
+		0x03 => "albumName",
+		0x04 => "artist",
+		0x08 => "genre",
+		0x0e => "comments",
+		0x12 => "composer",
+		0x27 => "grouping",
+		0x47 => "albumArtist",
+		0x4e => "sortName",
+		0x4f => "sortAlbumName",
+		0x50 => "sortArtist",
+		0x51 => "sortAlbumArtist",
+		0x52 => "sortComposer",
+		_ => return None,
+	};
+	Some(field)
+}
+
+/// Field name and the factor to convert the iTunes value to Ferrum's unit
+fn number_field(field: u32) -> Option<(&'static str, f64)> {
+	let field = match field {
+		0x05 => ("bitrate", 1000.0), // kbps to bps
+		0x06 => ("sampleRate", 1.0),
+		0x07 => ("year", 1.0),
+		0x0b => ("trackNum", 1.0),
+		0x0c => ("size", 1.0),
+		0x0d => ("duration", 0.001), // ms to s
+		0x16 => ("playCount", 1.0),
+		0x18 => ("discNum", 1.0),
+		0x19 => ("rating", 1.0),
+		0x23 => ("bpm", 1.0),
+		0x44 => ("skipCount", 1.0),
+		_ => return None,
+	};
+	Some(field)
+}
+
+fn date_field(field: u32) -> Option<&'static str> {
+	let field = match field {
+		0x0a => "dateModified",
+		0x10 => "dateAdded",
+		0x17 => "lastPlayed",
+		0x45 => "lastSkipped",
+		_ => return None,
+	};
+	Some(field)
+}
+
+fn condition(field: &str, operator: SmartOperator) -> SmartRule {
+	SmartRule::Condition(SmartCondition {
+		field: field.to_string(),
+		operator,
+	})
+}
+
+/// Wraps the rule so that it matches the opposite
+fn negate(rule: SmartRule) -> SmartRule {
+	SmartRule::Group(SmartRuleGroup {
+		match_: SmartMatch::None,
+		rules: vec![rule],
+	})
+}
+
+fn hfs_to_timestamp(seconds: u64) -> f64 {
+	((seconds as i64 - HFS_EPOCH_OFFSET) * 1000) as f64
+}
+
+/// Returns `None` for rules that are always true in Ferrum
+fn parse_rule(field: u32, action: u32, value: &[u8]) -> Result<Option<SmartRule>, String> {
+	let is_not = action & ACTION_NOT != 0;
+	let kind = action & 0xffff;
+
+	if field == 0 && value.starts_with(b"SLst") {
+		let group = parse_criteria(value)?;
+		return Ok(Some(SmartRule::Group(group)));
+	}
+
+	if let Some(field) = string_field(field) {
+		let text = read_utf16(value)?;
+		let rule = match (kind, is_not) {
+			(ACTION_IS, false) => condition(field, SmartOperator::Is(SmartValue::String(text))),
+			(ACTION_IS, true) => condition(field, SmartOperator::IsNot(SmartValue::String(text))),
+			(ACTION_CONTAINS, false) => condition(field, SmartOperator::Contains(text)),
+			(ACTION_CONTAINS, true) => condition(field, SmartOperator::NotContains(text)),
+			(ACTION_STARTS_WITH, _) => condition(field, SmartOperator::StartsWith(text)),
+			(ACTION_ENDS_WITH, _) => condition(field, SmartOperator::EndsWith(text)),
+			_ => return Err(format!("Unsupported {field} condition 0x{action:08x}")),
+		};
+		let rule = match (kind, is_not) {
+			(ACTION_STARTS_WITH | ACTION_ENDS_WITH, true) => negate(rule),
+			_ => rule,
+		};
+		return Ok(Some(rule));
+	}
+
+	let from = read_u64(value, 0)?;
+	if let Some((field, factor)) = number_field(field) {
+		let from = from as f64 * factor;
+		let rule = match kind {
+			ACTION_IS => condition(field, SmartOperator::Is(SmartValue::Number(from))),
+			ACTION_GREATER_THAN => condition(field, SmartOperator::GreaterThan(from)),
+			ACTION_LESS_THAN => condition(field, SmartOperator::LessThan(from)),
+			ACTION_IN_RANGE => {
+				let to = read_u64(value, 24)? as f64 * factor;
+				condition(field, SmartOperator::InRange(from, to))
+			}
+			_ => return Err(format!("Unsupported {field} condition 0x{action:08x}")),
+		};
+		return Ok(Some(if is_not { negate(rule) } else { rule }));
+	}
+
+	if let Some(field) = date_field(field) {
+		let rule = match kind {
+			ACTION_IN_THE_LAST => {
+				// the value is a negative amount of units, like -2 weeks
+				let amount = read_u64(value, 8)? as i64;
+				let unit_seconds = read_u64(value, 16)? as i64;
+				let ms = -amount * unit_seconds * 1000;
+				match is_not {
+					false => condition(field, SmartOperator::InLast(ms)),
+					true => condition(field, SmartOperator::NotInLast(ms)),
+				}
+			}
+			ACTION_IS => {
+				let day_start = hfs_to_timestamp(from);
+				let day_end = day_start + (24 * 60 * 60 * 1000 - 1) as f64;
+				condition(field, SmartOperator::InRange(day_start, day_end))
+			}
+			ACTION_GREATER_THAN => {
+				condition(field, SmartOperator::GreaterThan(hfs_to_timestamp(from)))
+			}
+			ACTION_LESS_THAN => condition(field, SmartOperator::LessThan(hfs_to_timestamp(from))),
+			ACTION_IN_RANGE => {
+				let to = read_u64(value, 24)?;
+				let range = SmartOperator::InRange(hfs_to_timestamp(from), hfs_to_timestamp(to));
+				condition(field, range)
+			}
+			_ => return Err(format!("Unsupported {field} condition 0x{action:08x}")),
+		};
+		return match (kind, is_not) {
+			(ACTION_IN_THE_LAST, _) | (_, false) => Ok(Some(rule)),
+			(_, true) => Ok(Some(negate(rule))),
+		};
+	}
+
+	let rule = match field {
+		// Compilation
+		0x1f if kind == ACTION_IS => condition(
+			"compilation",
+			SmartOperator::Is(SmartValue::Bool(from != 0)),
+		),
+		// Checked
+		0x1d if kind == ACTION_IS => {
+			condition("disabled", SmartOperator::Is(SmartValue::Bool(from == 0)))
+		}
+		// Love
+		0x9a if kind == ACTION_IS && from == 2 => {
+			condition("liked", SmartOperator::Is(SmartValue::Bool(true)))
+		}
+		0x9a if kind == ACTION_IS && from == 3 => {
+			condition("disliked", SmartOperator::Is(SmartValue::Bool(true)))
+		}
+		// Playlist, referenced by persistent ID
+		0x28 if kind == ACTION_IS => {
+			let persistent_id = format!("{:016X}", from);
+			condition(
+				"playlist",
+				SmartOperator::Is(SmartValue::String(persistent_id)),
+			)
+		}
+		// Media Kind. Only music is imported, so "is Music" is always true
+		0x3c if kind == ACTION_BINARY_AND && from == MEDIA_KIND_MUSIC && !is_not => {
+			return Ok(None);
+		}
+		0x3c => return Err("Unsupported Media Kind condition".to_string()),
+		_ => return Err(format!("Unsupported field 0x{field:02x}")),
+	};
+	Ok(Some(if is_not { negate(rule) } else { rule }))
+}
+
+fn parse_criteria(data: &[u8]) -> Result<SmartRuleGroup, String> {
+	if !data.starts_with(b"SLst") {
+		return Err("Invalid Smart Criteria header".to_string());
+	}
+	let rule_count = read_u32(data, 8)?;
+	let match_ = match read_u32(data, 12)? {
+		1 => SmartMatch::Any,
+		_ => SmartMatch::All,
+	};
+	let mut rules = Vec::new();
+	let mut offset = CRITERIA_HEADER_LEN;
+	for _ in 0..rule_count {
+		let field = read_u32(data, offset)?;
+		let action = read_u32(data, offset + 4)?;
+		let length = read_u32(data, offset + 52)? as usize;
+		let value_start = offset + RULE_HEADER_LEN;
+		let value = match data.get(value_start..value_start + length) {
+			Some(value) => value,
+			None => return Err("Unexpected end of data".to_string()),
+		};
+		if let Some(rule) = parse_rule(field, action, value)? {
+			rules.push(rule);
+		}
+		offset = value_start + length;
+	}
+	Ok(SmartRuleGroup { match_, rules })
+}
+
+fn parse_limit(info: &[u8]) -> Result<Option<SmartLimit>, String> {
+	if info.get(2) != Some(&1) {
+		return Ok(None);
+	}
+	let limit_type = info[3];
+	let selected_by = read_u32(info, 4)?;
+	let amount = read_u32(info, 8)? as f64;
+	let reverse = info.get(13) == Some(&1);
+
+	let (amount, unit) = match limit_type {
+		1 => (amount, SmartLimitUnit::Minutes),
+		2 => (amount, SmartLimitUnit::Megabytes),
+		3 => (amount, SmartLimitUnit::Items),
+		4 => (amount * 60.0, SmartLimitUnit::Minutes),
+		5 => (amount * 1000.0, SmartLimitUnit::Megabytes),
+		_ => return Err(format!("Unsupported limit type {limit_type}")),
+	};
+	// (sort key, descending)
+	let (sort_key, sort_desc) = match selected_by {
+		0x02 => ("random", false),
+		0x05 => ("name", false),
+		0x06 => ("albumName", false),
+		0x07 => ("artist", false),
+		0x09 => ("genre", false),
+		0x01 => ("rating", false),
+		0x1c => ("rating", true),
+		0x1a => ("lastPlayed", true),
+		0x19 => ("playCount", true),
+		0x15 => ("dateAdded", true),
+		_ => return Err(format!("Unsupported limit selection 0x{selected_by:02x}")),
+	};
+	Ok(Some(SmartLimit {
+		amount,
+		unit,
+		sortKey: sort_key.to_string(),
+		sortDesc: sort_desc != reverse,
+	}))
+}
+
+/// Errors describe the criteria that could not be converted
+pub fn parse(info: &[u8], criteria: &[u8]) -> Result<ItunesSmartPlaylist, String> {
+	if info.len() < 14 {
+		return Err("Invalid Smart Info".to_string());
+	}
+	let check_rules = info[1] == 1;
+	let match_checked_only = info[12] == 1;
+
+	let mut rules = match check_rules {
+		true => parse_criteria(criteria)?,
+		false => SmartRuleGroup {
+			match_: SmartMatch::All,
+			rules: Vec::new(),
+		},
+	};
+	if match_checked_only {
+		let checked = condition("disabled", SmartOperator::IsNot(SmartValue::Bool(true)));
+		rules = SmartRuleGroup {
+			match_: SmartMatch::All,
+			rules: vec![checked, SmartRule::Group(rules)],
+		};
+	}
+	Ok(ItunesSmartPlaylist {
+		rules,
+		limit: parse_limit(info)?,
+	})
+}
+
+/// Calls `f` with every playlist ID referenced in the rules
+pub fn map_playlist_ids(group: &mut SmartRuleGroup, f: &mut impl FnMut(&mut String)) {
+	for rule in &mut group.rules {
+		match rule {
+			SmartRule::Group(group) => map_playlist_ids(group, f),
+			SmartRule::Condition(condition) if condition.field == "playlist" => {
+				match &mut condition.operator {
+					SmartOperator::Is(SmartValue::String(id))
+					| SmartOperator::IsNot(SmartValue::String(id)) => f(id),
+					_ => {}
+				}
+			}
+			SmartRule::Condition(_) => {}
+		}
+	}
+}
+
+#[test]
+fn parse_smart_playlist_test() {
+	let library_xml_path = "src-native/tests/Library.xml";
+	let value = plist::Value::from_file(library_xml_path).unwrap();
+	let playlists = value.as_dictionary().unwrap()["Playlists"]
+		.as_array()
+		.unwrap();
+	let playlist = playlists
+		.iter()
+		.map(|p| p.as_dictionary().unwrap())
+		.find(|p| p.contains_key("Smart Info"))
+		.unwrap();
+	let info = playlist["Smart Info"].as_data().unwrap();
+	let criteria = playlist["Smart Criteria"].as_data().unwrap();
+
+	let smart = parse(info, criteria).unwrap();
+	assert_eq!(smart.rules.match_, SmartMatch::All);
+	match &smart.rules.rules[..] {
+		[SmartRule::Condition(condition)] => {
+			assert_eq!(condition.field, "artist");
+			assert_eq!(condition.operator, SmartOperator::Contains("M".to_string()));
+		}
+		_ => panic!("Unexpected rules"),
+	}
+	let limit = smart.limit.unwrap();
+	assert_eq!(limit.amount, 25.0);
+	assert_eq!(limit.unit, SmartLimitUnit::Items);
+	assert_eq!(limit.sortKey, "random");
+}
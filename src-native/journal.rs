@@ -0,0 +1,327 @@
+//! Undo and redo for changes to the library. Each change stores the state it
+//! replaced, so reverting it means swapping that state back in. The state
+//! that gets swapped out is what the redo reverts to.
+
+use crate::analysis::fingerprint;
+use crate::data::Data;
+use crate::data_js::get_data;
+use crate::library_types::{Library, Track, TrackID, TrackList, TrackListID};
+use crate::playlists::{delete_file, restore_file, TrashedFile};
+use crate::tracks::{md, Tag};
+use crate::{browse, duplicates, UniResult};
+use lofty::picture::Picture;
+use napi::{Env, Result};
+use std::fs;
+
+/// Number of actions that can be undone
+const MAX_ENTRIES: usize = 100;
+
+pub enum Change {
+	/// Track lists as they were, or `None` if they didn't exist
+	TrackLists(Vec<(TrackListID, Option<TrackList>)>),
+	/// The edited fields of a track as they were, and the images of its
+	/// file if they were edited
+	TrackInfo {
+		id: TrackID,
+		info: Box<md::TrackInfo>,
+		images: Option<Vec<Picture>>,
+	},
+	/// Tracks whose files were moved to the trash
+	DeletedTracks(Vec<(TrackID, Track, TrashedFile)>),
+	/// Tracks whose files were restored from the trash
+	RestoredTracks(Vec<TrackID>),
+	/// Tracks that were merged into another track
+	MergedTracks(Box<duplicates::Merged>),
+	/// Tracks that were split from a track they were merged into, by the
+	/// keeper ID and the other IDs
+	UnmergedTracks(TrackID, Vec<TrackID>),
+}
+
+impl Change {
+	/// Records the current state of track lists, before they are changed
+	pub fn track_lists<'a>(library: &Library, ids: impl IntoIterator<Item = &'a str>) -> Self {
+		let mut lists: Vec<(TrackListID, Option<TrackList>)> = Vec::new();
+		for id in ids {
+			if lists.iter().all(|(existing_id, _)| existing_id != id) {
+				lists.push((id.to_string(), library.trackLists.get(id).cloned()));
+			}
+		}
+		Change::TrackLists(lists)
+	}
+	/// Records the current state of a track, before it's updated with the
+	/// tag being edited
+	pub fn track_info(data: &Data, id: &str) -> UniResult<Self> {
+		let track = data.library.get_track(id)?;
+		let tag = Tag::read_from_path(&data.paths.tracks_dir.join(&track.file))?;
+		let images = match &data.current_tag {
+			Some(current_tag) if current_tag.pictures() != tag.pictures() => {
+				Some(tag.pictures().to_vec())
+			}
+			_ => None,
+		};
+		Ok(Change::TrackInfo {
+			id: id.to_string(),
+			info: Box::new(md::TrackInfo::from_track(track)),
+			images,
+		})
+	}
+	/// Reverts the change, and returns the change that reverts it back
+	fn revert(self, data: &mut Data) -> UniResult<Change> {
+		match self {
+			Change::TrackLists(lists) => {
+				for (id, _) in &lists {
+					data.change_log.track_list_changed(id);
+				}
+				Ok(swap_track_lists(&mut data.library, lists))
+			}
+			Change::TrackInfo { id, info, images } => {
+				let current = data.library.get_track(&id)?;
+				let current_path = data.paths.tracks_dir.join(&current.file);
+				let mut tag = Tag::read_from_path(&current_path)?;
+				let inverse = Change::TrackInfo {
+					id: id.clone(),
+					info: Box::new(md::TrackInfo::from_track(current)),
+					images: images.as_ref().map(|_| tag.pictures().to_vec()),
+				};
+				info.write_to_tag(&mut tag)?;
+				if let Some(images) = images {
+					tag.set_pictures(images);
+				}
+				tag.write_to_path(&current_path)?;
+				if current.file != info.file {
+					let path = data.paths.tracks_dir.join(&info.file);
+					if path.exists() {
+						throw!("File already exists: {}", path.to_string_lossy());
+					}
+					if let Err(err) = fs::rename(&current_path, &path) {
+						throw!("Error renaming file: {err}");
+					}
+				}
+				let current = data
+					.library
+					.tracks
+					.get_mut(&id)
+					.expect("Track ID not found");
+				info.restore(current);
+				data.track_changed(&id);
+				Ok(inverse)
+			}
+			Change::DeletedTracks(tracks) => {
+				let mut ids = Vec::new();
+				for (id, track, trashed) in tracks {
+					if data.library.tracks.contains_key(&id) {
+						throw!("Track ID already exists: {id}");
+					}
+					restore_file(&trashed)?;
+					data.library.tracks.insert(id.clone(), track);
+					data.track_changed(&id);
+					ids.push(id);
+				}
+				Ok(Change::RestoredTracks(ids))
+			}
+			Change::RestoredTracks(ids) => {
+				let mut tracks = Vec::new();
+				for id in ids {
+					let file_path = data
+						.paths
+						.tracks_dir
+						.join(&data.library.get_track(&id)?.file);
+					let trashed = delete_file(&file_path)?;
+					let track = data.library.tracks.remove(&id).expect("Track ID not found");
+					data.track_removed(&id);
+					tracks.push((id, track, trashed));
+				}
+				let ids: Vec<&str> = tracks.iter().map(|(id, _, _)| id.as_str()).collect();
+				fingerprint::remove_stored(&data.paths.fingerprints_db, &ids);
+				Ok(Change::DeletedTracks(tracks))
+			}
+			Change::MergedTracks(merged) => {
+				let (keeper_id, other_ids) = duplicates::unmerge(data, *merged)?;
+				Ok(Change::UnmergedTracks(keeper_id, other_ids))
+			}
+			Change::UnmergedTracks(keeper_id, other_ids) => {
+				let mut merged = duplicates::merge(data, &keeper_id, &other_ids)?;
+				duplicates::trash_files(data, &mut merged)?;
+				Ok(Change::MergedTracks(Box::new(merged)))
+			}
+		}
+	}
+}
+
+fn swap_track_lists(library: &mut Library, lists: Vec<(TrackListID, Option<TrackList>)>) -> Change {
+	let mut replaced = Vec::new();
+	for (id, list) in lists {
+		let current = match list {
+			Some(list) => library.trackLists.insert(id.clone(), list),
+			None => library.trackLists.remove(&id),
+		};
+		replaced.push((id, current));
+	}
+	Change::TrackLists(replaced)
+}
+
+struct Entry {
+	/// Name of the action, like "Delete Playlist"
+	name: String,
+	/// In the order they were made
+	changes: Vec<Change>,
+}
+
+impl Entry {
+	/// Reverts the changes in reverse order. Returns the entry that
+	/// reverts them back
+	fn revert(self, data: &mut Data) -> UniResult<Entry> {
+		let mut inverse = Vec::with_capacity(self.changes.len());
+		for change in self.changes.into_iter().rev() {
+			inverse.push(change.revert(data)?);
+		}
+		inverse.reverse();
+		Ok(Entry {
+			name: self.name,
+			changes: inverse,
+		})
+	}
+}
+
+#[derive(Default)]
+pub struct Journal {
+	undo: Vec<Entry>,
+	redo: Vec<Entry>,
+}
+
+impl Journal {
+	pub fn new() -> Self {
+		Self::default()
+	}
+	/// Records an action so it can be undone
+	pub fn push(&mut self, name: &str, changes: Vec<Change>) {
+		self.redo.clear();
+		self.undo.push(Entry {
+			name: name.to_string(),
+			changes,
+		});
+		if self.undo.len() > MAX_ENTRIES {
+			self.undo.remove(0);
+		}
+	}
+	/// Forgets all actions, for when the library is replaced
+	pub fn clear(&mut self) {
+		self.undo.clear();
+		self.redo.clear();
+	}
+}
+
+enum Direction {
+	Undo,
+	Redo,
+}
+
+/// Returns the name of the action that was undone or redone
+fn revert(data: &mut Data, direction: Direction) -> UniResult<Option<String>> {
+	let entry = match direction {
+		Direction::Undo => data.journal.undo.pop(),
+		Direction::Redo => data.journal.redo.pop(),
+	};
+	let entry = match entry {
+		Some(entry) => entry,
+		None => return Ok(None),
+	};
+	let inverse = match entry.revert(data) {
+		Ok(inverse) => inverse,
+		Err(err) => {
+			// The library may be partially reverted, so the journal no
+			// longer matches it
+			data.journal.clear();
+			return Err(err);
+		}
+	};
+	let name = inverse.name.clone();
+	match direction {
+		Direction::Undo => data.journal.redo.push(inverse),
+		Direction::Redo => data.journal.undo.push(inverse),
+	}
+	if !data.library.trackLists.contains_key(&data.open_playlist_id)
+		&& browse::parse_id(&data.open_playlist_id).is_none()
+		&& data.open_playlist_id != duplicates::TRACKLIST_ID
+	{
+		data.open_playlist("root".to_string(), None)?;
+	}
+	Ok(Some(name))
+}
+
+#[napi(object)]
+pub struct JournalState {
+	/// Name of the action that can be undone
+	pub undo: Option<String>,
+	/// Name of the action that can be redone
+	pub redo: Option<String>,
+}
+
+#[napi(js_name = "get_journal_state")]
+#[allow(dead_code)]
+pub fn get_journal_state(env: Env) -> Result<JournalState> {
+	let data: &mut Data = get_data(&env)?;
+	Ok(JournalState {
+		undo: data.journal.undo.last().map(|entry| entry.name.clone()),
+		redo: data.journal.redo.last().map(|entry| entry.name.clone()),
+	})
+}
+
+/// Returns the name of the undone action
+#[napi(js_name = "undo")]
+#[allow(dead_code)]
+pub fn undo(env: Env) -> Result<Option<String>> {
+	let data: &mut Data = get_data(&env)?;
+	Ok(revert(data, Direction::Undo)?)
+}
+
+/// Returns the name of the redone action
+#[napi(js_name = "redo")]
+#[allow(dead_code)]
+pub fn redo(env: Env) -> Result<Option<String>> {
+	let data: &mut Data = get_data(&env)?;
+	Ok(revert(data, Direction::Redo)?)
+}
+
+#[test]
+fn journal_test() {
+	let mut library = Library::new();
+	let playlist = library.new_playlist("A".to_string(), None);
+	let id = playlist.id.clone();
+	library
+		.trackLists
+		.insert(id.clone(), TrackList::Playlist(playlist));
+
+	let before = Change::track_lists(&library, [id.as_str(), id.as_str()]);
+	match &before {
+		Change::TrackLists(lists) => assert_eq!(lists.len(), 1),
+		_ => panic!(),
+	}
+	library.trackLists.remove(&id);
+
+	let inverse = match before {
+		Change::TrackLists(lists) => swap_track_lists(&mut library, lists),
+		_ => panic!(),
+	};
+	assert!(library.trackLists.contains_key(&id));
+	match inverse {
+		Change::TrackLists(lists) => {
+			assert!(lists[0].1.is_none());
+			swap_track_lists(&mut library, lists);
+		}
+		_ => panic!(),
+	}
+	assert!(!library.trackLists.contains_key(&id));
+
+	let mut journal = Journal::new();
+	for _ in 0..MAX_ENTRIES + 1 {
+		journal.push("Edit", Vec::new());
+	}
+	assert_eq!(journal.undo.len(), MAX_ENTRIES);
+	journal.redo.push(Entry {
+		name: "Edit".to_string(),
+		changes: Vec::new(),
+	});
+	journal.push("Edit", Vec::new());
+	assert!(journal.redo.is_empty());
+}
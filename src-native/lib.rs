@@ -20,19 +20,35 @@ macro_rules! throw {
 #[macro_use]
 extern crate napi_derive;
 
+mod albums;
+mod analysis;
 mod artists;
+mod backups;
+mod browse;
+mod change_log;
 mod data;
 mod data_js;
+mod duplicates;
 mod filter;
+mod import_job;
+mod integrity;
 mod itunes_import;
+mod itunes_smart;
+mod journal;
 mod js;
 mod library;
 mod library_types;
 mod page;
 mod playlists;
+mod query;
+mod relink;
+mod rescan;
+mod search_index;
+mod smart_playlists;
 mod sort;
 mod tracks;
 mod view_options;
+mod watch_folders;
 
 fn get_now_timestamp() -> i64 {
 	let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
@@ -59,6 +75,7 @@ fn str_to_option(s: String) -> Option<String> {
 
 pub type UniResult<T> = std::result::Result<T, UniError>;
 
+#[derive(Debug)]
 pub struct UniError {
 	pub message: String,
 }
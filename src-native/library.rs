@@ -12,6 +12,10 @@ pub struct Paths {
 	pub library_dir: PathBuf,
 	pub tracks_dir: PathBuf,
 	pub library_json: PathBuf,
+	/// Changes since `library_json` was saved
+	pub library_log: PathBuf,
+	pub backups_dir: PathBuf,
+	pub fingerprints_db: PathBuf,
 	pub cache_dir: PathBuf,
 	pub cache_db: PathBuf,
 	pub local_data_dir: PathBuf,
@@ -38,7 +42,7 @@ pub fn load_library(paths: &Paths) -> UniResult<Library> {
 		paths.library_dir.to_string_lossy()
 	);
 
-	let versioned_library = match File::open(&paths.library_json) {
+	let library = match File::open(&paths.library_json) {
 		Ok(mut file) => {
 			let mut json_str = String::new();
 			match file.read_to_string(&mut json_str) {
@@ -47,31 +51,9 @@ pub fn load_library(paths: &Paths) -> UniResult<Library> {
 			};
 			println!("Read library: {}ms", now.elapsed().as_millis());
 			now = Instant::now();
-
-			let mut value: Value = match serde_json::from_str(&mut json_str) {
-				Ok(library) => library,
-				Err(err) => throw!("Error parsing library file: {:?}", err),
-			};
-			// Migrate version number to string
-			if let Some(obj) = value.as_object_mut() {
-				if let Some(version_field) = obj.get_mut("version") {
-					if let Some(version) = version_field.as_number() {
-						if version.as_u64() == Some(1) {
-							*version_field = json!("1");
-						} else if version.as_u64() == Some(2) {
-							*version_field = json!("2");
-						}
-					}
-				}
-			}
-
-			let versioned_library: VersionedLibrary = match serde_json::from_value(value) {
-				Ok(library) => library,
-				Err(err) => throw!("Error parsing library file: {:?}", err),
-			};
-
+			let library = parse_library(&json_str)?;
 			println!("Parse library: {}ms", now.elapsed().as_millis());
-			versioned_library
+			library
 		}
 		Err(err) => match err.kind() {
 			ErrorKind::NotFound => {
@@ -81,6 +63,32 @@ pub fn load_library(paths: &Paths) -> UniResult<Library> {
 		},
 	};
 
+	Ok(library)
+}
+
+/// Parses and upgrades a library file
+pub fn parse_library(json_str: &str) -> UniResult<Library> {
+	let mut value: Value = match serde_json::from_str(json_str) {
+		Ok(library) => library,
+		Err(err) => throw!("Error parsing library file: {:?}", err),
+	};
+	// Migrate version number to string
+	if let Some(obj) = value.as_object_mut() {
+		if let Some(version_field) = obj.get_mut("version") {
+			if let Some(version) = version_field.as_number() {
+				if version.as_u64() == Some(1) {
+					*version_field = json!("1");
+				} else if version.as_u64() == Some(2) {
+					*version_field = json!("2");
+				}
+			}
+		}
+	}
+
+	let versioned_library: VersionedLibrary = match serde_json::from_value(value) {
+		Ok(library) => library,
+		Err(err) => throw!("Error parsing library file: {:?}", err),
+	};
 	Ok(versioned_library.upgrade())
 }
 
@@ -106,6 +114,19 @@ pub fn get_track_field_type(field: &str) -> Option<TrackField> {
 		"name" => TrackField::String,
 		"importedFrom" => TrackField::String,
 		"originalId" => TrackField::String,
+		"fileType" => TrackField::String,
+		"codec" => TrackField::String,
+		"channels" => TrackField::U8,
+		"bitDepth" => TrackField::U8,
+		"lossless" => TrackField::Bool,
+		"encoder" => TrackField::String,
+		"encoderDelay" => TrackField::U32,
+		"encoderPadding" => TrackField::U32,
+		"silenceStart" => TrackField::F64,
+		"silenceEnd" => TrackField::F64,
+		"loudness" => TrackField::F64,
+		"truePeak" => TrackField::F64,
+		"albumLoudness" => TrackField::F64,
 		"artist" => TrackField::String,
 		"composer" => TrackField::String,
 		"sortName" => TrackField::String,
@@ -115,6 +136,7 @@ pub fn get_track_field_type(field: &str) -> Option<TrackField> {
 		"rating" => TrackField::U8,
 		"year" => TrackField::I64,
 		"bpm" => TrackField::F64,
+		"key" => TrackField::String,
 		"comments" => TrackField::String,
 		"grouping" => TrackField::String,
 		"liked" => TrackField::Bool,
@@ -131,7 +153,9 @@ pub fn get_track_field_type(field: &str) -> Option<TrackField> {
 		"discCount" => TrackField::U32,
 		"dateImported" => TrackField::I64,
 		"playCount" => TrackField::U32,
+		"lastPlayed" => TrackField::I64,
 		"skipCount" => TrackField::U32,
+		"lastSkipped" => TrackField::I64,
 		"volume" => TrackField::I8,
 		_ => return None,
 	};
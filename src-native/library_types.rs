@@ -16,6 +16,10 @@ pub struct Library {
 	/// - timestamps aren't updated after pausing
 	pub v1PlayTime: Vec<PlayTime>,
 	pub playTime: Vec<PlayTime>,
+	/// Generation of the change log that belongs to this file. The log is
+	/// only replayed if it has the same generation
+	#[serde(default)]
+	pub logGeneration: u64,
 }
 impl Library {
 	pub fn versioned(&self) -> VersionedLibrary {
@@ -54,6 +58,7 @@ impl V1Library {
 			trackLists: self.trackLists,
 			v1PlayTime: self.playTime,
 			playTime: Vec::new(),
+			logGeneration: 0,
 		}
 	}
 }
@@ -73,6 +78,7 @@ impl Library {
 			playTime: Vec::new(),
 			tracks: LinkedHashMap::new(),
 			trackLists: track_lists,
+			logGeneration: 0,
 		}
 	}
 	pub fn generate_id(&self) -> String {
@@ -99,9 +105,31 @@ impl Library {
 			originalId: None,
 			dateImported: None,
 			dateCreated: Some(get_now_timestamp()),
+			importedSmartCriteria: None,
 			tracks: Vec::new(),
 		}
 	}
+	pub fn new_smart_playlist(
+		&self,
+		name: String,
+		description: Option<String>,
+		rules: SmartRuleGroup,
+		limit: Option<SmartLimit>,
+	) -> SmartPlaylist {
+		SmartPlaylist {
+			id: self.generate_id(),
+			name,
+			description,
+			liked: false,
+			disliked: false,
+			importedFrom: None,
+			originalId: None,
+			dateImported: None,
+			dateCreated: Some(get_now_timestamp()),
+			rules,
+			limit,
+		}
+	}
 	pub fn new_folder(&self, name: String, description: Option<String>) -> Folder {
 		Folder {
 			id: self.generate_id(),
@@ -142,7 +170,7 @@ impl Library {
 	pub fn get_parent_id(&self, id: &str) -> Option<String> {
 		for (parent_id, tracklist) in &self.trackLists {
 			let children = match tracklist {
-				TrackList::Playlist(_) => continue,
+				TrackList::Playlist(_) | TrackList::Smart(_) => continue,
 				TrackList::Folder(list) => &list.children,
 				TrackList::Special(list) => &list.children,
 			};
@@ -182,6 +210,43 @@ pub struct Track {
 	/// Imported ID, like iTunes Persistent ID
 	#[serde(default, skip_serializing_if = "Option::is_none")]
 	pub originalId: Option<String>,
+	/// File type detected from the content, like "mp3" or "flac"
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub fileType: Option<String>,
+	/// Like "AAC" or "FLAC"
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub codec: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub channels: Option<u8>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub bitDepth: Option<u8>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub lossless: Option<bool>,
+	/// Software that encoded the file, from the tags
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub encoder: Option<String>,
+	/// Samples to skip at the start for gapless playback, from the LAME
+	/// header or iTunSMPB
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub encoderDelay: Option<u32>,
+	/// Samples to skip at the end for gapless playback
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub encoderPadding: Option<u32>,
+	/// Seconds of silence at the start, after the encoder delay
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub silenceStart: Option<f64>,
+	/// Seconds of silence at the end, before the encoder padding
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub silenceEnd: Option<f64>,
+	/// Integrated loudness in LUFS
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub loudness: Option<f64>,
+	/// In dBTP
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub truePeak: Option<f64>,
+	/// Integrated loudness of the whole album in LUFS
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub albumLoudness: Option<f64>,
 	#[serde(default)]
 	pub artist: String,
 	#[serde(default, skip_serializing_if = "Option::is_none")]
@@ -200,6 +265,9 @@ pub struct Track {
 	pub year: Option<i64>,
 	#[serde(default, skip_serializing_if = "Option::is_none")]
 	pub bpm: Option<f64>,
+	/// Musical key, like "Ebm"
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub key: Option<String>,
 	#[serde(default, skip_serializing_if = "Option::is_none")]
 	pub comments: Option<String>,
 	#[serde(default, skip_serializing_if = "Option::is_none")]
@@ -247,6 +315,33 @@ pub struct Track {
 	pub volume: Option<i8>,
 }
 
+/// A track with placeholder values for the required fields, overridden by
+/// `fields`
+#[cfg(test)]
+pub fn test_track(fields: serde_json::Value) -> Track {
+	let mut track = serde_json::json!({
+		"size": 1, "duration": 1.0, "bitrate": 1.0, "sampleRate": 1.0, "file": "a.mp3",
+		"dateModified": 0, "dateAdded": 0, "name": ""
+	});
+	if let (Some(track), serde_json::Value::Object(fields)) = (track.as_object_mut(), fields) {
+		track.extend(fields);
+	}
+	serde_json::from_value(track).unwrap()
+}
+
+/// A library with a `test_track` for each of `tracks`, with the IDs "0",
+/// "1" and so on
+#[cfg(test)]
+pub fn test_library(tracks: serde_json::Value) -> Library {
+	let mut library = Library::new();
+	if let serde_json::Value::Array(tracks) = tracks {
+		for (i, fields) in tracks.into_iter().enumerate() {
+			library.tracks.insert(i.to_string(), test_track(fields));
+		}
+	}
+	library
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[napi(object)]
 pub struct CountObject {
@@ -262,6 +357,8 @@ pub enum TrackList {
 	Playlist(Playlist),
 	#[serde(rename = "folder")]
 	Folder(Folder),
+	#[serde(rename = "smart")]
+	Smart(SmartPlaylist),
 	#[serde(rename = "special")]
 	Special(Special),
 }
@@ -271,6 +368,7 @@ impl TrackList {
 		match self {
 			TrackList::Playlist(list) => &list.id,
 			TrackList::Folder(list) => &list.id,
+			TrackList::Smart(list) => &list.id,
 			TrackList::Special(list) => &list.id,
 		}
 	}
@@ -299,9 +397,21 @@ pub struct Playlist {
 	pub dateImported: Option<MsSinceUnixEpoch>,
 	#[serde(default, skip_serializing_if = "Option::is_none")]
 	pub dateCreated: Option<MsSinceUnixEpoch>,
+	/// Set when an imported smart playlist could not be converted, in which
+	/// case `tracks` is a snapshot from when it was imported
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub importedSmartCriteria: Option<ImportedSmartCriteria>,
 	pub tracks: Vec<TrackID>,
 }
 
+/// Original iTunes smart playlist data, hex encoded
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[napi(object)]
+pub struct ImportedSmartCriteria {
+	pub info: String,
+	pub criteria: String,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[napi(object)]
 pub struct Folder {
@@ -326,6 +436,116 @@ pub struct Folder {
 	pub children: Vec<TrackListID>,
 }
 
+/// A playlist whose tracks are evaluated live from `rules`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SmartPlaylist {
+	pub id: TrackListID,
+	pub name: String,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub description: Option<String>,
+	#[serde(default, skip_serializing_if = "is_false")]
+	pub liked: bool,
+	#[serde(default, skip_serializing_if = "is_false")]
+	pub disliked: bool,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub importedFrom: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub originalId: Option<String>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub dateImported: Option<MsSinceUnixEpoch>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub dateCreated: Option<MsSinceUnixEpoch>,
+	pub rules: SmartRuleGroup,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub limit: Option<SmartLimit>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum SmartMatch {
+	/// Every rule must match
+	All,
+	/// At least one rule must match
+	Any,
+	/// No rule may match
+	None,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SmartRuleGroup {
+	#[serde(rename = "match")]
+	pub match_: SmartMatch,
+	pub rules: Vec<SmartRule>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type")]
+pub enum SmartRule {
+	#[serde(rename = "group")]
+	Group(SmartRuleGroup),
+	#[serde(rename = "condition")]
+	Condition(SmartCondition),
+}
+
+/// A rule on a single track field. `field` is any field from
+/// `library::get_track_field_type`, or `"playlist"` to match membership of
+/// another tracklist.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SmartCondition {
+	pub field: String,
+	#[serde(flatten)]
+	pub operator: SmartOperator,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "operator", content = "value", rename_all = "camelCase")]
+pub enum SmartOperator {
+	Is(SmartValue),
+	IsNot(SmartValue),
+	Contains(String),
+	NotContains(String),
+	StartsWith(String),
+	EndsWith(String),
+	GreaterThan(f64),
+	LessThan(f64),
+	AtLeast(f64),
+	AtMost(f64),
+	/// Inclusive range
+	InRange(f64, f64),
+	/// For timestamp fields. Milliseconds before now
+	InLast(i64),
+	/// For timestamp fields. Milliseconds before now
+	NotInLast(i64),
+	IsSet,
+	IsNotSet,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum SmartValue {
+	Bool(bool),
+	Number(f64),
+	String(String),
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum SmartLimitUnit {
+	Items,
+	Minutes,
+	Megabytes,
+}
+
+/// Keep only the first tracks when sorted by `sortKey`. `sortKey` can also be
+/// `"random"`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SmartLimit {
+	pub amount: f64,
+	pub unit: SmartLimitUnit,
+	pub sortKey: String,
+	pub sortDesc: bool,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[napi(object)]
 pub struct Special {
@@ -1,10 +1,11 @@
 use crate::data::Data;
 use crate::data_js::get_data;
+use crate::journal::Change;
 use crate::js::nerr;
 use crate::library::{get_track_field_type, TrackField};
 use crate::library_types::{SpecialTrackListName, Track, TrackID, TrackList};
 use crate::sort::sort;
-use crate::{filter, UniResult};
+use crate::{browse, duplicates, filter, smart_playlists, UniResult};
 use napi::{Env, JsString, JsUndefined, JsUnknown, Result};
 use std::collections::HashSet;
 use std::time::Instant;
@@ -64,6 +65,12 @@ pub fn get_page_track_ids(env: Env) -> Result<Vec<TrackID>> {
 }
 
 fn get_tracklist_track_ids(data: &Data, playlist_id: &str) -> UniResult<Vec<TrackID>> {
+	if let Some((field, value)) = browse::parse_id(playlist_id) {
+		return Ok(browse::get_track_ids(&data.library, &field, value));
+	}
+	if playlist_id == duplicates::TRACKLIST_ID {
+		return Ok(duplicates::get_track_ids(&data.library));
+	}
 	match data.library.get_tracklist(playlist_id)? {
 		TrackList::Playlist(playlist) => {
 			let ids = playlist
@@ -73,6 +80,7 @@ fn get_tracklist_track_ids(data: &Data, playlist_id: &str) -> UniResult<Vec<Trac
 				.collect();
 			Ok(ids)
 		}
+		TrackList::Smart(smart) => smart_playlists::get_track_ids(&data.library, smart),
 		TrackList::Folder(folder) => {
 			let mut ids: HashSet<TrackID> = HashSet::new();
 			for child in &folder.children {
@@ -101,6 +109,7 @@ pub fn get_track_ids(data: &Data) -> UniResult<Vec<TrackID>> {
 pub enum ViewAs {
 	Songs,
 	Artists,
+	Albums,
 }
 impl Default for ViewAs {
 	fn default() -> Self {
@@ -123,12 +132,18 @@ pub struct PageInfo {
 #[napi(js_name = "get_page_info")]
 pub fn get_page_info(env: Env) -> Result<PageInfo> {
 	let data = get_data(&env)?;
-	let tracklist = data.library.get_tracklist(&data.open_playlist_id)?;
+	let tracklist = match browse::get_tracklist_json(&data.open_playlist_id) {
+		Some(browse_list) => env.to_js_value(&browse_list)?,
+		None if data.open_playlist_id == duplicates::TRACKLIST_ID => {
+			env.to_js_value(&duplicates::get_tracklist_json())?
+		}
+		None => env.to_js_value(data.library.get_tracklist(&data.open_playlist_id)?)?,
+	};
 
 	Ok(PageInfo {
 		id: data.open_playlist_id.clone(),
 		view_as: data.view_as,
-		tracklist: env.to_js_value(tracklist)?,
+		tracklist,
 		sort_key: data.sort_key.clone(),
 		sort_desc: data.sort_desc,
 		length: data.get_page_tracks().len().try_into().expect("Too long"),
@@ -184,6 +199,7 @@ pub fn move_tracks(
 	let data: &mut Data = get_data(&env)?;
 	indexes_to_move.sort_unstable();
 	indexes_to_move.dedup();
+	let change = Change::track_lists(&data.library, [data.open_playlist_id.as_str()]);
 	let tracklist = data
 		.library
 		.trackLists
@@ -192,6 +208,7 @@ pub fn move_tracks(
 	let playlist = match tracklist {
 		TrackList::Playlist(playlist) => playlist,
 		TrackList::Folder(_) => return Err(nerr!("Cannot rearrange tracks in folder")),
+		TrackList::Smart(_) => return Err(nerr!("Cannot rearrange tracks in smart playlist")),
 		TrackList::Special(_) => return Err(nerr!("Cannot rearrange tracks in special playlist")),
 	};
 	if data.sort_key != "index" || !data.sort_desc {
@@ -222,6 +239,7 @@ pub fn move_tracks(
 	start_ids.append(&mut moved_ids);
 	start_ids.append(&mut end_ids);
 	playlist.tracks = start_ids;
+	data.record("Rearrange Songs", vec![change]);
 	Ok(SelectionInfo {
 		from: new_from,
 		to: new_to,
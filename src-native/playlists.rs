@@ -1,11 +1,19 @@
+use crate::analysis::fingerprint;
 use crate::data::Data;
 use crate::data_js::get_data;
-use crate::library_types::{Library, SpecialTrackListName, TrackID, TrackList};
-use crate::{str_to_option, UniResult};
+use crate::journal::Change;
+use crate::library_types::{
+	Library, SmartLimit, SmartRuleGroup, SpecialTrackListName, Track, TrackID, TrackList,
+};
+use crate::{smart_playlists, str_to_option, UniResult};
 use napi::{Env, JsUnknown, Result};
 use std::collections::{HashMap, HashSet};
+use std::fs;
 use std::path::PathBuf;
+use std::time::SystemTime;
 
+#[cfg(target_os = "macos")]
+use std::path::Path;
 #[cfg(target_os = "macos")]
 use trash::macos::TrashContextExtMacos;
 
@@ -35,11 +43,13 @@ pub fn get_track_lists_details(env: Env) -> Result<HashMap<String, TrackListDeta
 						TrackList::Special(_) => "special".to_string(),
 						TrackList::Folder(_) => "folder".to_string(),
 						TrackList::Playlist(_) => "playlist".to_string(),
+						TrackList::Smart(_) => "smart".to_string(),
 					},
 					name: match tracklist {
 						TrackList::Special(tracklist) => tracklist.name.to_string(),
 						TrackList::Folder(tracklist) => tracklist.name.clone(),
 						TrackList::Playlist(tracklist) => tracklist.name.clone(),
+						TrackList::Smart(tracklist) => tracklist.name.clone(),
 					},
 					children: match tracklist {
 						TrackList::Special(tracklist) => Some(tracklist.children.clone()),
@@ -66,7 +76,7 @@ fn get_child_ids_recursive(
 	ids: &mut HashSet<String>,
 ) -> Result<()> {
 	let folder_children = match library.trackLists.get(id) {
-		Some(TrackList::Playlist(_)) => return Ok(()),
+		Some(TrackList::Playlist(_) | TrackList::Smart(_)) => return Ok(()),
 		Some(TrackList::Folder(folder)) => folder.children.clone(),
 		Some(TrackList::Special(_)) => throw!("Cannot delete special track list"),
 		None => throw!("No track list with id {}", id),
@@ -89,7 +99,7 @@ fn remove_child_id(library: &mut Library, parent_id: &String, child_id: &String)
 		None => throw!("Parent id {parent_id} not found"),
 	};
 	let children = match parent {
-		TrackList::Playlist(_) => throw!("Parent id {parent_id} not found"),
+		TrackList::Playlist(_) | TrackList::Smart(_) => throw!("Parent id {parent_id} not found"),
 		TrackList::Folder(folder) => folder.children.clone(),
 		TrackList::Special(special) => match special.name {
 			SpecialTrackListName::Root => special.children.clone(),
@@ -106,7 +116,7 @@ fn remove_child_id(library: &mut Library, parent_id: &String, child_id: &String)
 		_ => throw!("Child id {child_id} found multiple times"),
 	};
 	match parent {
-		TrackList::Playlist(_) => panic!(),
+		TrackList::Playlist(_) | TrackList::Smart(_) => panic!(),
 		TrackList::Folder(folder) => folder.children = new_children,
 		TrackList::Special(special) => match special.name {
 			SpecialTrackListName::Root => special.children = new_children,
@@ -128,7 +138,31 @@ fn delete_track_list(data: &mut Data, id: String) -> Result<()> {
 	if ids.contains(&parent_id) {
 		throw!("Parent id {parent_id} contains itself");
 	}
+	let referencing: Vec<&str> = data
+		.library
+		.trackLists
+		.values()
+		.filter_map(|list| match list {
+			TrackList::Smart(smart) if !ids.contains(&smart.id) => Some(smart),
+			_ => None,
+		})
+		.filter(|smart| smart_playlists::references_any(&smart.rules, &ids))
+		.map(|smart| smart.name.as_str())
+		.collect();
+	if !referencing.is_empty() {
+		throw!(
+			"Cannot delete because it's used in the rules of {}",
+			referencing.join(", ")
+		);
+	}
+	let change = Change::track_lists(
+		&data.library,
+		[parent_id.as_str()]
+			.into_iter()
+			.chain(ids.iter().map(String::as_str)),
+	);
 	remove_child_id(&mut data.library, &parent_id, &id)?;
+	data.record("Delete Playlist", vec![change]);
 	for id in &ids {
 		data.library.trackLists.remove(id);
 	}
@@ -150,12 +184,15 @@ pub fn delete_track_list_js(id: String, env: Env) -> Result<()> {
 #[allow(dead_code)]
 pub fn add_tracks(playlist_id: String, mut track_ids: Vec<String>, env: Env) -> Result<()> {
 	let data: &mut Data = get_data(&env)?;
+	let change = Change::track_lists(&data.library, [playlist_id.as_str()]);
 	let playlist = match data.library.get_tracklist_mut(&playlist_id)? {
 		TrackList::Playlist(playlist) => playlist,
 		TrackList::Folder(_) => throw!("Cannot add track to folder"),
+		TrackList::Smart(_) => throw!("Cannot add track to smart playlist"),
 		TrackList::Special(_) => throw!("Cannot add track to special playlist"),
 	};
 	playlist.tracks.append(&mut track_ids);
+	data.record("Add to Playlist", vec![change]);
 	return Ok(());
 }
 
@@ -183,9 +220,11 @@ pub fn remove_from_open(mut indexes_to_remove: Vec<u32>, env: Env) -> Result<()>
 	let data: &mut Data = get_data(&env)?;
 	indexes_to_remove.sort_unstable();
 	indexes_to_remove.dedup();
+	let change = Change::track_lists(&data.library, [data.open_playlist_id.as_str()]);
 	let playlist = match data.library.get_tracklist_mut(&data.open_playlist_id)? {
 		TrackList::Playlist(playlist) => playlist,
 		TrackList::Folder(_) => throw!("Cannot remove track from folder"),
+		TrackList::Smart(_) => throw!("Cannot remove track from smart playlist"),
 		TrackList::Special(_) => throw!("Cannot remove track from special playlist"),
 	};
 	if data.sort_key != "index" || !data.sort_desc {
@@ -206,6 +245,7 @@ pub fn remove_from_open(mut indexes_to_remove: Vec<u32>, env: Env) -> Result<()>
 		}
 	}
 	playlist.tracks = new_list;
+	data.record("Remove from Playlist", vec![change]);
 	return Ok(());
 }
 
@@ -232,7 +272,39 @@ fn get_page_ids(data: &mut Data, indexes: Vec<u32>) -> UniResult<Vec<String>> {
 	Ok(ids)
 }
 
-fn delete_file(path: &PathBuf) -> UniResult<()> {
+/// A file that was moved to the trash
+pub struct TrashedFile {
+	/// Where the file was deleted from
+	pub path: PathBuf,
+	#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+	size: u64,
+	#[cfg_attr(not(target_os = "macos"), allow(dead_code))]
+	modified: Option<SystemTime>,
+}
+
+impl TrashedFile {
+	/// Whether a file in the trash is this one. Moving a file to the trash
+	/// keeps its size and modification time.
+	#[cfg(target_os = "macos")]
+	fn matches(&self, path: &Path) -> bool {
+		match fs::metadata(path) {
+			Ok(md) => md.is_file() && md.len() == self.size && md.modified().ok() == self.modified,
+			Err(_) => false,
+		}
+	}
+}
+
+pub fn delete_file(path: &PathBuf) -> UniResult<TrashedFile> {
+	let md = match fs::metadata(path) {
+		Ok(md) => md,
+		Err(_) => throw!("File does not exist: {}", path.to_string_lossy()),
+	};
+	let trashed = TrashedFile {
+		path: path.clone(),
+		size: md.len(),
+		modified: md.modified().ok(),
+	};
+
 	#[allow(unused_mut)]
 	let mut trash_context = trash::TrashContext::new();
 
@@ -240,11 +312,129 @@ fn delete_file(path: &PathBuf) -> UniResult<()> {
 	trash_context.set_delete_method(trash::macos::DeleteMethod::NsFileManager);
 
 	match trash_context.delete(&path) {
-		Ok(_) => Ok(()),
+		Ok(_) => Ok(trashed),
 		Err(_) => throw!("Failed moving file to trash: {}", path.to_string_lossy()),
 	}
 }
 
+/// Moves a file back from the trash to where it was deleted from
+#[cfg(any(
+	target_os = "windows",
+	all(
+		unix,
+		not(target_os = "macos"),
+		not(target_os = "ios"),
+		not(target_os = "android")
+	)
+))]
+pub fn restore_file(trashed: &TrashedFile) -> UniResult<()> {
+	let path = &trashed.path;
+	let items = match trash::os_limited::list() {
+		Ok(items) => items,
+		Err(_) => throw!("Failed reading trash"),
+	};
+	let item = items
+		.into_iter()
+		.filter(|item| item.original_path() == *path)
+		.max_by_key(|item| item.time_deleted);
+	let item = match item {
+		Some(item) => item,
+		None => throw!("File not found in trash: {}", path.to_string_lossy()),
+	};
+	match trash::os_limited::restore_all([item]) {
+		Ok(_) => Ok(()),
+		Err(_) => throw!(
+			"Failed restoring file from trash: {}",
+			path.to_string_lossy()
+		),
+	}
+}
+
+/// Moves a file back from the trash to where it was deleted from. macOS
+/// doesn't tell where trashed files end up, so this looks in the user's
+/// trash folder and in the trash folder of the file's volume.
+#[cfg(target_os = "macos")]
+pub fn restore_file(trashed: &TrashedFile) -> UniResult<()> {
+	use std::os::unix::fs::MetadataExt;
+
+	let path = &trashed.path;
+	if path.exists() {
+		throw!("File already exists: {}", path.to_string_lossy());
+	}
+	let home_dir = dirs_next::home_dir().ok_or("Home folder not found")?;
+	let mut trash_dirs = vec![home_dir.join(".Trash")];
+	let mut components = path.components();
+	if let (Some(root), Some(volumes), Some(volume)) =
+		(components.next(), components.next(), components.next())
+	{
+		if volumes.as_os_str() == "Volumes" {
+			if let Ok(home_md) = fs::metadata(&home_dir) {
+				let volume_dir = PathBuf::from_iter([root, volumes, volume]);
+				trash_dirs.push(volume_dir.join(".Trashes").join(home_md.uid().to_string()));
+			}
+		}
+	}
+	let trashed_path = trash_dirs
+		.iter()
+		.find_map(|trash_dir| find_in_trash(trash_dir, trashed));
+	let trashed_path = match trashed_path {
+		Some(trashed_path) => trashed_path,
+		None => throw!("File not found in trash: {}", path.to_string_lossy()),
+	};
+	match fs::rename(&trashed_path, path) {
+		Ok(_) => Ok(()),
+		Err(_) => throw!(
+			"Failed restoring file from trash: {}",
+			path.to_string_lossy()
+		),
+	}
+}
+
+/// When the trash already has a file with the same name, Finder adds a
+/// number or the time to the name, like `Song 2.mp3` or `Song 10.41.12.mp3`
+#[cfg(target_os = "macos")]
+fn find_in_trash(trash_dir: &Path, trashed: &TrashedFile) -> Option<PathBuf> {
+	let file_name = trashed.path.file_name()?;
+	let stem = trashed.path.file_stem()?.to_string_lossy();
+	let extension = trashed.path.extension();
+	let exact_path = trash_dir.join(file_name);
+	if trashed.matches(&exact_path) {
+		return Some(exact_path);
+	}
+	fs::read_dir(trash_dir)
+		.ok()?
+		.filter_map(|entry| Some(entry.ok()?.path()))
+		.filter(|path| path.extension() == extension)
+		.filter(|path| match path.file_stem() {
+			Some(s) => s.to_string_lossy().starts_with(&*stem),
+			None => false,
+		})
+		.find(|path| trashed.matches(path))
+}
+
+fn delete_tracks(
+	data: &mut Data,
+	ids: &[TrackID],
+	deleted: &mut Vec<(TrackID, Track, TrashedFile)>,
+) -> UniResult<()> {
+	for id_to_delete in ids {
+		let file_path = {
+			let track = data.library.get_track(id_to_delete)?;
+			data.paths.tracks_dir.join(&track.file)
+		};
+		let trashed = delete_file(&file_path)?;
+		remove_from_all_playlists(&mut data.library, id_to_delete);
+		let track = data
+			.library
+			.tracks
+			.remove(id_to_delete)
+			.expect("Track ID not found when deleting");
+		data.track_removed(id_to_delete);
+		deleted.push((id_to_delete.clone(), track, trashed));
+	}
+	Ok(())
+}
+
 #[napi(js_name = "delete_tracks_in_open")]
 #[allow(dead_code)]
 pub fn delete_tracks_in_open(mut indexes_to_delete: Vec<u32>, env: Env) -> Result<()> {
@@ -252,24 +442,30 @@ pub fn delete_tracks_in_open(mut indexes_to_delete: Vec<u32>, env: Env) -> Resul
 	indexes_to_delete.sort_unstable();
 	indexes_to_delete.dedup();
 	let ids_to_delete = get_page_ids(data, indexes_to_delete)?;
-	let library = &mut data.library;
 
-	for id_to_delete in &ids_to_delete {
-		let file_path = {
-			let track = library.get_track(id_to_delete)?;
-			data.paths.tracks_dir.join(&track.file)
-		};
-		if !file_path.exists() {
-			throw!("File does not exist: {}", file_path.to_string_lossy());
-		}
-
-		remove_from_all_playlists(library, &id_to_delete);
-		library
-			.tracks
-			.remove(id_to_delete)
-			.expect("Track ID not found when deleting");
-		delete_file(&file_path)?;
+	let playlist_ids: Vec<&str> = data
+		.library
+		.trackLists
+		.iter()
+		.filter(|(_, tracklist)| match tracklist {
+			TrackList::Playlist(playlist) => {
+				playlist.tracks.iter().any(|id| ids_to_delete.contains(id))
+			}
+			_ => false,
+		})
+		.map(|(id, _)| id.as_str())
+		.collect();
+	let playlists_change = Change::track_lists(&data.library, playlist_ids);
+
+	let mut deleted = Vec::new();
+	let result = delete_tracks(data, &ids_to_delete, &mut deleted);
+	let deleted_ids: Vec<&str> = deleted.iter().map(|(id, _, _)| id.as_str()).collect();
+	fingerprint::remove_stored(&data.paths.fingerprints_db, &deleted_ids);
+	if !deleted.is_empty() {
+		let changes = vec![playlists_change, Change::DeletedTracks(deleted)];
+		data.record("Delete from Library", changes);
 	}
+	result?;
 	return Ok(());
 }
 
@@ -296,13 +492,21 @@ pub fn new_playlist(
 		}
 	};
 
-	let parent = match library.trackLists.get_mut(&parent_id) {
+	let change = Change::track_lists(library, [parent_id.as_str(), list.id()]);
+	insert_tracklist(library, list, &parent_id)?;
+	data.record("New Playlist", vec![change]);
+	Ok(())
+}
+
+fn insert_tracklist(library: &mut Library, list: TrackList, parent_id: &str) -> Result<()> {
+	let parent = match library.trackLists.get_mut(parent_id) {
 		Some(parent) => parent,
 		None => throw!("Parent not found"),
 	};
 
 	match parent {
 		TrackList::Playlist(_) => throw!("Parent cannot be playlist"),
+		TrackList::Smart(_) => throw!("Parent cannot be smart playlist"),
 		TrackList::Folder(folder) => {
 			folder.children.push(list.id().to_string());
 			library.trackLists.insert(list.id().to_string(), list);
@@ -318,10 +522,60 @@ pub fn new_playlist(
 	return Ok(());
 }
 
+#[napi(js_name = "new_smart_playlist")]
+#[allow(dead_code)]
+pub fn new_smart_playlist(
+	name: String,
+	description: String,
+	#[napi(ts_arg_type = "SmartRuleGroup")] rules: JsUnknown,
+	#[napi(ts_arg_type = "SmartLimit | null")] limit: JsUnknown,
+	parent_id: String,
+	env: Env,
+) -> Result<String> {
+	let data: &mut Data = get_data(&env)?;
+	let rules: SmartRuleGroup = env.from_js_value(rules)?;
+	let limit: Option<SmartLimit> = env.from_js_value(limit)?;
+	let library = &mut data.library;
+
+	let smart = library.new_smart_playlist(name, str_to_option(description), rules, limit);
+	smart_playlists::validate(library, &smart)?;
+	let id = smart.id.clone();
+	let change = Change::track_lists(library, [parent_id.as_str(), id.as_str()]);
+	insert_tracklist(library, TrackList::Smart(smart), &parent_id)?;
+	data.record("New Smart Playlist", vec![change]);
+	Ok(id)
+}
+
+#[napi(js_name = "update_smart_playlist_rules")]
+#[allow(dead_code)]
+pub fn update_smart_playlist_rules(
+	id: String,
+	#[napi(ts_arg_type = "SmartRuleGroup")] rules: JsUnknown,
+	#[napi(ts_arg_type = "SmartLimit | null")] limit: JsUnknown,
+	env: Env,
+) -> Result<()> {
+	let data: &mut Data = get_data(&env)?;
+	let rules: SmartRuleGroup = env.from_js_value(rules)?;
+	let limit: Option<SmartLimit> = env.from_js_value(limit)?;
+
+	let mut smart = match data.library.get_tracklist(&id)? {
+		TrackList::Smart(smart) => smart.clone(),
+		_ => throw!("Not a smart playlist"),
+	};
+	smart.rules = rules;
+	smart.limit = limit;
+	smart_playlists::validate(&data.library, &smart)?;
+	let change = Change::track_lists(&data.library, [id.as_str()]);
+	data.library.trackLists.insert(id, TrackList::Smart(smart));
+	data.record("Edit Smart Playlist", vec![change]);
+	Ok(())
+}
+
 #[napi(js_name = "update_playlist")]
 #[allow(dead_code)]
 pub fn update_playlist(id: String, name: String, description: String, env: Env) -> Result<()> {
 	let data: &mut Data = get_data(&env)?;
+	let change = Change::track_lists(&data.library, [id.as_str()]);
 
 	match data.library.trackLists.get_mut(&id) {
 		Some(TrackList::Special(_)) => throw!("Cannot edit special playlists"),
@@ -333,8 +587,13 @@ pub fn update_playlist(id: String, name: String, description: String, env: Env)
 			folder.name = name;
 			folder.description = str_to_option(description);
 		}
+		Some(TrackList::Smart(smart)) => {
+			smart.name = name;
+			smart.description = str_to_option(description);
+		}
 		None => throw!("Playlist not found"),
 	};
+	data.record("Edit Playlist", vec![change]);
 
 	return Ok(());
 }
@@ -343,13 +602,13 @@ fn get_all_tracklist_children(data: &Data, playlist_id: &str) -> UniResult<Vec<T
 	let direct_children = match data.library.get_tracklist(playlist_id)? {
 		TrackList::Folder(folder) => &folder.children,
 		TrackList::Special(special) => &special.children,
-		TrackList::Playlist(_) => return Ok(Vec::new()),
+		TrackList::Playlist(_) | TrackList::Smart(_) => return Ok(Vec::new()),
 	};
 	let mut all_children = Vec::new();
 	for child_id in direct_children {
 		all_children.push(child_id.clone());
 		match data.library.get_tracklist(child_id)? {
-			TrackList::Playlist(_) => {}
+			TrackList::Playlist(_) | TrackList::Smart(_) => {}
 			TrackList::Folder(folder) => {
 				all_children.extend(get_all_tracklist_children(data, &folder.id)?)
 			}
@@ -405,6 +664,7 @@ pub fn move_playlist(
 		throw!("Cannot move playlist to a child of itself");
 	}
 
+	let change = Change::track_lists(&data.library, [from_id.as_str(), to_id.as_str()]);
 	let children = get_children_if_user_editable(&mut data.library, &from_id)?;
 	let i = match children.iter().position(|child_id| child_id == &id) {
 		None => throw!("Could not find playlist"),
@@ -420,6 +680,7 @@ pub fn move_playlist(
 	} else {
 		to_folder_children.insert(to_index, id);
 	}
+	data.record("Move Playlist", vec![change]);
 
 	Ok(())
 }
@@ -0,0 +1,626 @@
+//! Filter query language. A query like
+//! `artist:"Miles Davis" year:1955..1965 rating:>=80 -genre:live added:<30d`
+//! is parsed into smart playlist rules, so it's evaluated the same way.
+//!
+//! - Words without a field match name, artist, album, comments or genre.
+//!   Words with an unknown field, like `Re:Zero` or `12:30`, are plain words
+//! - Terms are combined with AND, or with OR when separated by `OR` or `|`
+//! - `-` negates a term or a group, and `(...)` groups terms
+//! - Numbers support `>`, `>=`, `<`, `<=`, `=` and ranges like `1955..1965`
+//! - Dates support relative values like `<30d` (h, d, w, m, y) and absolute
+//!   dates like `2020`, `2020-06` or `>=2020-06-01`
+
+use crate::library::{get_track_field_type, TrackField};
+use crate::library_types::{
+	SmartCondition, SmartMatch, SmartOperator, SmartRule, SmartRuleGroup, SmartValue,
+};
+use time::{Date, Month};
+
+/// A query parse error. `start` and `end` are UTF-16 offsets into the query
+#[napi(object)]
+#[derive(Debug)]
+pub struct QueryError {
+	pub message: String,
+	pub start: u32,
+	pub end: u32,
+}
+
+/// Fields that words without a field are matched against
+const KEYWORD_FIELDS: [&str; 5] = ["name", "artist", "albumName", "comments", "genre"];
+
+fn resolve_field(name: &str) -> Option<&'static str> {
+	let field = match name.to_lowercase().as_str() {
+		"title" | "name" => "name",
+		"artist" => "artist",
+		"album" => "albumName",
+		"albumartist" => "albumArtist",
+		"composer" => "composer",
+		"genre" => "genre",
+		"comment" | "comments" => "comments",
+		"grouping" => "grouping",
+		"file" => "file",
+		"year" => "year",
+		"rating" => "rating",
+		"bpm" => "bpm",
+		"key" => "key",
+		"plays" | "playcount" => "playCount",
+		"skips" | "skipcount" => "skipCount",
+		"played" | "lastplayed" => "lastPlayed",
+		"skipped" | "lastskipped" => "lastSkipped",
+		"added" | "dateadded" => "dateAdded",
+		"modified" | "datemodified" => "dateModified",
+		"imported" | "dateimported" => "dateImported",
+		"duration" | "time" => "duration",
+		"bitrate" => "bitrate",
+		"samplerate" => "sampleRate",
+		"codec" => "codec",
+		"channels" => "channels",
+		"bitdepth" => "bitDepth",
+		"lossless" => "lossless",
+		"encoder" => "encoder",
+		"loudness" => "loudness",
+		"truepeak" | "peak" => "truePeak",
+		"size" => "size",
+		"track" | "tracknum" => "trackNum",
+		"disc" | "discnum" => "discNum",
+		"liked" | "loved" => "liked",
+		"disliked" => "disliked",
+		"compilation" => "compilation",
+		"disabled" => "disabled",
+		_ => return None,
+	};
+	Some(field)
+}
+
+fn is_date_field(field: &str) -> bool {
+	matches!(
+		field,
+		"dateAdded" | "dateModified" | "dateImported" | "lastPlayed" | "lastSkipped"
+	)
+}
+
+/// Tracks that have never been played or skipped have no count
+fn is_count_field(field: &str) -> bool {
+	matches!(field, "playCount" | "skipCount")
+}
+
+enum TokenKind {
+	Open,
+	Close,
+	Or,
+	Not,
+	Term {
+		field: Option<&'static str>,
+		/// Comparison operator in front of the value, like `>=`
+		op: String,
+		value: String,
+		quoted: bool,
+	},
+}
+
+struct Token {
+	kind: TokenKind,
+	start: u32,
+	end: u32,
+}
+
+struct Lexer {
+	chars: Vec<char>,
+	/// UTF-16 offset of each char, plus the end offset
+	offsets: Vec<u32>,
+	i: usize,
+	depth: usize,
+}
+
+impl Lexer {
+	fn new(query: &str) -> Self {
+		let chars: Vec<char> = query.chars().collect();
+		let mut offsets = Vec::with_capacity(chars.len() + 1);
+		let mut offset = 0;
+		for c in &chars {
+			offsets.push(offset);
+			offset += c.len_utf16() as u32;
+		}
+		offsets.push(offset);
+		Lexer {
+			chars,
+			offsets,
+			i: 0,
+			depth: 0,
+		}
+	}
+	fn peek(&self) -> Option<char> {
+		self.chars.get(self.i).copied()
+	}
+	fn is_word_end(&self, c: char) -> bool {
+		c.is_whitespace() || (c == ')' && self.depth > 0)
+	}
+	fn error(&self, message: String, start: usize, end: usize) -> QueryError {
+		QueryError {
+			message,
+			start: self.offsets[start],
+			end: self.offsets[end],
+		}
+	}
+	/// Reads a quoted string, starting at the opening quote
+	fn read_quoted(&mut self) -> Result<String, QueryError> {
+		let start = self.i;
+		self.i += 1;
+		let mut value = String::new();
+		loop {
+			match self.peek() {
+				Some('"') => {
+					self.i += 1;
+					return Ok(value);
+				}
+				Some(c) => {
+					value.push(c);
+					self.i += 1;
+				}
+				None => {
+					let message = "Missing closing quote".to_string();
+					return Err(self.error(message, start, self.i));
+				}
+			}
+		}
+	}
+	fn read_word(&mut self) -> String {
+		let mut word = String::new();
+		while let Some(c) = self.peek() {
+			if self.is_word_end(c) {
+				break;
+			}
+			word.push(c);
+			self.i += 1;
+		}
+		word
+	}
+	fn read_term(&mut self) -> Result<TokenKind, QueryError> {
+		let start = self.i;
+		if self.peek() == Some('"') {
+			return Ok(TokenKind::Term {
+				field: None,
+				op: String::new(),
+				value: self.read_quoted()?,
+				quoted: true,
+			});
+		}
+		let mut name = String::new();
+		while let Some(c) = self.peek() {
+			if !c.is_ascii_alphabetic() {
+				break;
+			}
+			name.push(c);
+			self.i += 1;
+		}
+		let has_value = match (self.peek(), self.chars.get(self.i + 1)) {
+			(Some(':'), Some(c)) => !self.is_word_end(*c),
+			_ => false,
+		};
+		// Unknown fields are plain words, like "Re:Zero"
+		let field = match has_value {
+			true => resolve_field(&name),
+			false => None,
+		};
+		let field = match field {
+			Some(field) => field,
+			None => {
+				self.i = start;
+				let word = self.read_word();
+				if word == "OR" {
+					return Ok(TokenKind::Or);
+				}
+				return Ok(TokenKind::Term {
+					field: None,
+					op: String::new(),
+					value: word,
+					quoted: false,
+				});
+			}
+		};
+		self.i += 1; // colon
+		let mut op = String::new();
+		while let Some(c @ ('<' | '>' | '=')) = self.peek() {
+			op.push(c);
+			self.i += 1;
+		}
+		let (value, quoted) = match self.peek() {
+			Some('"') => (self.read_quoted()?, true),
+			_ => (self.read_word(), false),
+		};
+		Ok(TokenKind::Term {
+			field: Some(field),
+			op,
+			value,
+			quoted,
+		})
+	}
+	fn tokenize(mut self) -> Result<(Vec<Token>, Vec<u32>), QueryError> {
+		let mut tokens = Vec::new();
+		while let Some(c) = self.peek() {
+			let start = self.i;
+			let kind = match c {
+				c if c.is_whitespace() => {
+					self.i += 1;
+					continue;
+				}
+				'(' => {
+					self.i += 1;
+					self.depth += 1;
+					TokenKind::Open
+				}
+				')' if self.depth > 0 => {
+					self.i += 1;
+					self.depth -= 1;
+					TokenKind::Close
+				}
+				'|' => {
+					self.i += 1;
+					TokenKind::Or
+				}
+				'-' if matches!(self.chars.get(self.i + 1), Some(c) if !self.is_word_end(*c)) => {
+					self.i += 1;
+					TokenKind::Not
+				}
+				_ => self.read_term()?,
+			};
+			tokens.push(Token {
+				kind,
+				start: self.offsets[start],
+				end: self.offsets[self.i],
+			});
+		}
+		Ok((tokens, self.offsets))
+	}
+}
+
+fn condition(field: &str, operator: SmartOperator) -> SmartRule {
+	SmartRule::Condition(SmartCondition {
+		field: field.to_string(),
+		operator,
+	})
+}
+
+fn group(match_: SmartMatch, rules: Vec<SmartRule>) -> SmartRule {
+	SmartRule::Group(SmartRuleGroup { match_, rules })
+}
+
+fn parse_number(value: &str) -> Result<f64, String> {
+	match value.parse::<f64>() {
+		Ok(n) if n.is_finite() => Ok(n),
+		_ => Err(format!("Invalid number \"{value}\"")),
+	}
+}
+
+/// Parses relative durations like `30d` into milliseconds
+fn parse_relative(value: &str) -> Option<i64> {
+	let unit_index = value.find(|c: char| c.is_ascii_alphabetic())?;
+	let (amount, unit) = value.split_at(unit_index);
+	let amount: f64 = amount.parse().ok()?;
+	let hour = 60.0 * 60.0 * 1000.0;
+	let unit_ms = match unit {
+		"h" => hour,
+		"d" => 24.0 * hour,
+		"w" => 7.0 * 24.0 * hour,
+		"m" => 30.0 * 24.0 * hour,
+		"y" => 365.0 * 24.0 * hour,
+		_ => return None,
+	};
+	Some((amount * unit_ms) as i64)
+}
+
+fn date_to_ms(date: Date) -> f64 {
+	(date.midnight().assume_utc().unix_timestamp() * 1000) as f64
+}
+
+/// Parses `YYYY`, `YYYY-MM` or `YYYY-MM-DD` into the first and last
+/// millisecond of that period, in UTC
+fn parse_date(value: &str) -> Option<(f64, f64)> {
+	let parts: Vec<&str> = value.split('-').collect();
+	let year: i32 = parts.first()?.parse().ok()?;
+	let (start, next) = match parts[1..] {
+		[] => (
+			Date::from_calendar_date(year, Month::January, 1).ok()?,
+			Date::from_calendar_date(year + 1, Month::January, 1).ok()?,
+		),
+		[month] => {
+			let month = Month::try_from(month.parse::<u8>().ok()?).ok()?;
+			let next_year = if month == Month::December {
+				year + 1
+			} else {
+				year
+			};
+			(
+				Date::from_calendar_date(year, month, 1).ok()?,
+				Date::from_calendar_date(next_year, month.next(), 1).ok()?,
+			)
+		}
+		[month, day] => {
+			let month = Month::try_from(month.parse::<u8>().ok()?).ok()?;
+			let date = Date::from_calendar_date(year, month, day.parse().ok()?).ok()?;
+			(date, date.next_day()?)
+		}
+		_ => return None,
+	};
+	Some((date_to_ms(start), date_to_ms(next) - 1.0))
+}
+
+fn compile_range(field: &str, from: &str, to: &str) -> Result<SmartOperator, String> {
+	let is_date = is_date_field(field);
+	let parse_bound = |value: &str, is_end: bool| -> Result<Option<f64>, String> {
+		if value.is_empty() {
+			return Ok(None);
+		}
+		if !is_date {
+			return parse_number(value).map(Some);
+		}
+		match parse_date(value) {
+			Some((start, end)) => Ok(Some(if is_end { end } else { start })),
+			None => Err(format!("Invalid date \"{value}\"")),
+		}
+	};
+	let operator = match (parse_bound(from, false)?, parse_bound(to, true)?) {
+		(Some(from), Some(to)) => SmartOperator::InRange(from, to),
+		(Some(from), None) => SmartOperator::AtLeast(from),
+		(None, Some(to)) => SmartOperator::AtMost(to),
+		(None, None) => return Err("Range is missing a start or end".to_string()),
+	};
+	Ok(operator)
+}
+
+fn compile_date(op: &str, value: &str) -> Result<SmartOperator, String> {
+	if let Some(ms) = parse_relative(value) {
+		let operator = match op {
+			"" | "<" | "<=" => SmartOperator::InLast(ms),
+			">" | ">=" => SmartOperator::NotInLast(ms),
+			_ => return Err(format!("Operator {op} cannot be used with relative dates")),
+		};
+		return Ok(operator);
+	}
+	let (start, end) = match parse_date(value) {
+		Some(range) => range,
+		None => return Err(format!("Invalid date \"{value}\"")),
+	};
+	let operator = match op {
+		"" | "=" => SmartOperator::InRange(start, end),
+		">" => SmartOperator::GreaterThan(end),
+		">=" => SmartOperator::AtLeast(start),
+		"<" => SmartOperator::LessThan(start),
+		"<=" => SmartOperator::AtMost(end),
+		_ => return Err(format!("Invalid operator {op}")),
+	};
+	Ok(operator)
+}
+
+fn compile_number(op: &str, value: &str) -> Result<SmartOperator, String> {
+	let n = parse_number(value)?;
+	let operator = match op {
+		"" | "=" => SmartOperator::Is(SmartValue::Number(n)),
+		">" => SmartOperator::GreaterThan(n),
+		">=" => SmartOperator::AtLeast(n),
+		"<" => SmartOperator::LessThan(n),
+		"<=" => SmartOperator::AtMost(n),
+		_ => return Err(format!("Invalid operator {op}")),
+	};
+	Ok(operator)
+}
+
+fn compile_field(field: &str, op: &str, value: &str, quoted: bool) -> Result<SmartRule, String> {
+	let field_type = get_track_field_type(field).expect("Unknown query field");
+	let operator = match field_type {
+		TrackField::String => match op {
+			"" if quoted && value.is_empty() => SmartOperator::IsNotSet,
+			"" => SmartOperator::Contains(value.to_string()),
+			"=" => SmartOperator::Is(SmartValue::String(value.to_string())),
+			_ => return Err(format!("Operator {op} cannot be used with text")),
+		},
+		TrackField::Bool => match (op, value.to_lowercase().as_str()) {
+			("" | "=", "true" | "yes" | "1") => SmartOperator::Is(SmartValue::Bool(true)),
+			("" | "=", "false" | "no" | "0") => SmartOperator::Is(SmartValue::Bool(false)),
+			_ => return Err(format!("Expected {field}:true or {field}:false")),
+		},
+		_ if op.is_empty() && value.contains("..") => {
+			let (from, to) = value.split_once("..").unwrap();
+			compile_range(field, from, to)?
+		}
+		_ if is_date_field(field) => compile_date(op, value)?,
+		_ => compile_number(op, value)?,
+	};
+	if is_count_field(field) && matches_zero(&operator) {
+		let rules = vec![
+			condition(field, operator),
+			condition(field, SmartOperator::IsNotSet),
+		];
+		return Ok(group(SmartMatch::Any, rules));
+	}
+	Ok(condition(field, operator))
+}
+
+fn matches_zero(operator: &SmartOperator) -> bool {
+	match operator {
+		SmartOperator::Is(SmartValue::Number(n)) => *n == 0.0,
+		SmartOperator::LessThan(n) => *n > 0.0,
+		SmartOperator::AtMost(n) => *n >= 0.0,
+		SmartOperator::InRange(from, to) => *from <= 0.0 && *to >= 0.0,
+		_ => false,
+	}
+}
+
+struct Parser {
+	tokens: Vec<Token>,
+	pos: usize,
+	/// UTF-16 length of the query
+	end: u32,
+}
+
+impl Parser {
+	fn peek(&self) -> Option<&Token> {
+		self.tokens.get(self.pos)
+	}
+	fn error_at_next(&self, message: &str) -> QueryError {
+		let (start, end) = match self.peek() {
+			Some(token) => (token.start, token.end),
+			None => (self.end, self.end),
+		};
+		QueryError {
+			message: message.to_string(),
+			start,
+			end,
+		}
+	}
+	fn parse_or(&mut self) -> Result<SmartRule, QueryError> {
+		let mut rules = vec![self.parse_and()?];
+		while let Some(Token {
+			kind: TokenKind::Or,
+			..
+		}) = self.peek()
+		{
+			self.pos += 1;
+			rules.push(self.parse_and()?);
+		}
+		Ok(match rules.len() {
+			1 => rules.pop().unwrap(),
+			_ => group(SmartMatch::Any, rules),
+		})
+	}
+	fn parse_and(&mut self) -> Result<SmartRule, QueryError> {
+		let mut rules = Vec::new();
+		while let Some(token) = self.peek() {
+			match token.kind {
+				TokenKind::Or | TokenKind::Close => break,
+				_ => rules.push(self.parse_unary()?),
+			}
+		}
+		Ok(match rules.len() {
+			0 => return Err(self.error_at_next("Expected a search term")),
+			1 => rules.pop().unwrap(),
+			_ => group(SmartMatch::All, rules),
+		})
+	}
+	fn parse_unary(&mut self) -> Result<SmartRule, QueryError> {
+		let token = match self.tokens.get(self.pos) {
+			Some(token) => token,
+			None => return Err(self.error_at_next("Expected a search term")),
+		};
+		self.pos += 1;
+		match &token.kind {
+			TokenKind::Not => {
+				let rule = self.parse_unary()?;
+				Ok(group(SmartMatch::None, vec![rule]))
+			}
+			TokenKind::Open => {
+				let (start, end) = (token.start, token.end);
+				let rule = self.parse_or()?;
+				match self.peek() {
+					Some(Token {
+						kind: TokenKind::Close,
+						..
+					}) => {
+						self.pos += 1;
+						Ok(rule)
+					}
+					_ => Err(QueryError {
+						message: "Missing closing parenthesis".to_string(),
+						start,
+						end,
+					}),
+				}
+			}
+			TokenKind::Term {
+				field: None, value, ..
+			} => {
+				let rules = KEYWORD_FIELDS
+					.iter()
+					.map(|field| condition(field, SmartOperator::Contains(value.clone())))
+					.collect();
+				Ok(group(SmartMatch::Any, rules))
+			}
+			TokenKind::Term {
+				field: Some(field),
+				op,
+				value,
+				quoted,
+			} => compile_field(field, op, value, *quoted).map_err(|message| QueryError {
+				message,
+				start: token.start,
+				end: token.end,
+			}),
+			TokenKind::Or | TokenKind::Close => {
+				self.pos -= 1;
+				Err(self.error_at_next("Expected a search term"))
+			}
+		}
+	}
+}
+
+/// Parses a filter query into rules. Returns `None` if the query is empty
+pub fn parse(query: &str) -> Result<Option<SmartRuleGroup>, QueryError> {
+	let (tokens, offsets) = Lexer::new(query).tokenize()?;
+	if tokens.is_empty() {
+		return Ok(None);
+	}
+	let mut parser = Parser {
+		tokens,
+		pos: 0,
+		end: *offsets.last().unwrap(),
+	};
+	let rule = parser.parse_or()?;
+	if parser.peek().is_some() {
+		return Err(parser.error_at_next("Unexpected closing parenthesis"));
+	}
+	let rules = match rule {
+		SmartRule::Group(group) => group,
+		rule => SmartRuleGroup {
+			match_: SmartMatch::All,
+			rules: vec![rule],
+		},
+	};
+	Ok(Some(rules))
+}
+
+#[test]
+fn query_test() {
+	use crate::library_types::test_library;
+	use crate::smart_playlists::TrackMatcher;
+
+	let library = test_library(serde_json::json!([
+		{ "duration": 60.0, "name": "So What", "artist": "Miles Davis", "genre": "Jazz",
+			"year": 1959, "rating": 100, "playCount": 3 },
+		{ "duration": 60.0, "name": "So What (Live)", "artist": "Miles Davis",
+			"genre": "Jazz Live", "year": 1964, "rating": 80 },
+		{ "duration": 60.0, "dateAdded": 1262304000000i64, "name": "Café", "artist": "Davis",
+			"year": 2010 },
+		{ "duration": 60.0, "name": "Re:Zero at 12:30" },
+	]));
+	let run = |query: &str| -> Vec<String> {
+		let rules = parse(query).unwrap().unwrap();
+		let matcher = TrackMatcher::new(&library, &rules).unwrap();
+		let mut ids: Vec<String> = library
+			.tracks
+			.iter()
+			.filter(|(id, track)| matcher.matches(id, track))
+			.map(|(id, _)| id.clone())
+			.collect();
+		ids.sort();
+		ids
+	};
+	assert_eq!(
+		run(r#"artist:"Miles Davis" year:1955..1965 rating:>=80 -genre:live"#),
+		vec!["0"]
+	);
+	assert_eq!(run("plays:0"), vec!["1", "2", "3"]);
+	assert_eq!(run("cafe OR genre:=jazz"), vec!["0", "2"]);
+	assert_eq!(run("-(so | genre:jazz) added:2010"), vec!["2"]);
+	assert_eq!(run("what (live)"), vec!["1"]);
+	assert_eq!(run("added:<30d"), Vec::<String>::new());
+	assert_eq!(run("re:zero 12:30"), vec!["3"]);
+	assert_eq!(run("so:what"), Vec::<String>::new());
+	assert!(parse("  ").unwrap().is_none());
+
+	let err = parse("rating:>=high").err().unwrap();
+	assert_eq!((err.start, err.end), (0, 13));
+	let err = parse("(a b").err().unwrap();
+	assert_eq!((err.start, err.end), (0, 1));
+	assert!(parse("a OR").is_err());
+	assert!(parse("\"a b").is_err());
+}
@@ -0,0 +1,311 @@
+//! Finds files for tracks whose file is missing, for example after the files
+//! were renamed or moved outside the app. Files in a folder are matched by
+//! size, duration and tags, and by fingerprint for tracks that were
+//! fingerprinted. The matches can then be copied into the tracks folder.
+
+use crate::analysis::fingerprint::{
+	fingerprint, get_stored, open_db, Matcher, DEFAULT_MIN_SIMILARITY,
+};
+use crate::data::Data;
+use crate::data_js::get_data;
+use crate::library_types::{Track, TrackID};
+use crate::tracks::generate_filename;
+use crate::tracks::import::{self, find_audio_files, FileType};
+use crate::tracks::properties::AudioProperties;
+use crate::UniResult;
+use lofty::file::{AudioFile, TaggedFileExt};
+use lofty::tag::Accessor;
+use napi::{Env, JsObject, Result, Task};
+use rayon::prelude::*;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use unicode_normalization::UnicodeNormalization;
+
+/// Durations within this many seconds are considered equal
+const DURATION_TOLERANCE: f64 = 1.0;
+/// Minimum score for a file to be proposed
+const MIN_SCORE: u32 = 4;
+
+#[napi(object)]
+pub struct RelinkMatch {
+	pub track_id: TrackID,
+	/// Path of the file that was found
+	pub path: String,
+	/// How well the file matches. Higher is better
+	pub score: u32,
+	/// Whether the file size is exactly the same
+	pub same_size: bool,
+}
+
+/// What's compared between a track and a file
+struct Info {
+	size: i64,
+	duration: f64,
+	name: String,
+	artist: String,
+	album: String,
+	/// For missing tracks that were fingerprinted, and files that may be one
+	/// of them
+	fingerprint: Option<Vec<u32>>,
+}
+
+fn normalize(s: &str) -> String {
+	s.trim().nfc().flat_map(char::to_lowercase).collect()
+}
+
+impl Info {
+	fn from_track(track: &Track) -> Self {
+		Info {
+			size: track.size,
+			duration: track.duration,
+			name: normalize(&track.name),
+			artist: normalize(&track.artist),
+			album: normalize(track.albumName.as_deref().unwrap_or_default()),
+			fingerprint: None,
+		}
+	}
+	fn read(path: &Path) -> Option<Self> {
+		let size = fs::metadata(path).ok()?.len() as i64;
+		let tagged_file = import::probe(path).ok()?.read().ok()?;
+		let duration = tagged_file.properties().duration().as_secs_f64();
+		let tag = tagged_file.primary_tag().or(tagged_file.first_tag());
+		let get = |value: Option<std::borrow::Cow<str>>| normalize(&value.unwrap_or_default());
+		Some(Info {
+			size,
+			duration,
+			name: get(tag.and_then(|tag| tag.title())),
+			artist: get(tag.and_then(|tag| tag.artist())),
+			album: get(tag.and_then(|tag| tag.album())),
+			fingerprint: None,
+		})
+	}
+	fn same_duration(&self, file: &Info) -> bool {
+		(self.duration - file.duration).abs() <= DURATION_TOLERANCE
+	}
+	/// `None` if the file can't be the track
+	fn score(&self, file: &Info) -> Option<u32> {
+		if !self.same_duration(file) {
+			return None;
+		}
+		let mut score = 2;
+		if let (Some(track_fp), Some(file_fp)) = (&self.fingerprint, &file.fingerprint) {
+			if Matcher::new(track_fp).similarity(file_fp) < DEFAULT_MIN_SIMILARITY {
+				return None;
+			}
+			score += 3;
+		}
+		if self.size == file.size {
+			score += 3;
+		}
+		if !self.name.is_empty() && self.name == file.name {
+			score += 2;
+		}
+		if !self.artist.is_empty() && self.artist == file.artist {
+			score += 1;
+		}
+		if !self.album.is_empty() && self.album == file.album {
+			score += 1;
+		}
+		Some(score)
+	}
+}
+
+/// Picks the best file for each track. Each file is used at most once, and
+/// the best scoring pairs are picked first.
+fn find_matches(missing: &[(TrackID, Info)], files: &[(PathBuf, Info)]) -> Vec<RelinkMatch> {
+	let mut pairs = Vec::new();
+	for (track_index, (_, track)) in missing.iter().enumerate() {
+		for (file_index, (_, file)) in files.iter().enumerate() {
+			match track.score(file) {
+				Some(score) if score >= MIN_SCORE => pairs.push((score, track_index, file_index)),
+				_ => {}
+			}
+		}
+	}
+	pairs.sort_by_key(|(score, _, _)| Reverse(*score));
+	let mut used_tracks = HashSet::new();
+	let mut used_files = HashSet::new();
+	let mut matches = Vec::new();
+	for (score, track_index, file_index) in pairs {
+		if used_tracks.contains(&track_index) || used_files.contains(&file_index) {
+			continue;
+		}
+		used_tracks.insert(track_index);
+		used_files.insert(file_index);
+		let (track_id, track) = &missing[track_index];
+		let (path, file) = &files[file_index];
+		matches.push(RelinkMatch {
+			track_id: track_id.clone(),
+			path: path.to_string_lossy().to_string(),
+			score,
+			same_size: track.size == file.size,
+		});
+	}
+	matches
+}
+
+struct FindRelinks {
+	search_dir: PathBuf,
+	missing: Vec<(TrackID, Info)>,
+	fingerprints_db: PathBuf,
+}
+impl Task for FindRelinks {
+	type Output = Vec<RelinkMatch>;
+	type JsValue = Vec<RelinkMatch>;
+	fn compute(&mut self) -> Result<Self::Output> {
+		if self.missing.is_empty() {
+			return Ok(Vec::new());
+		}
+		let db = open_db(&self.fingerprints_db)?;
+		for (id, track) in &mut self.missing {
+			// The file is missing, so the fingerprint is from its last file
+			track.fingerprint = get_stored(&db, id)?.map(|stored| stored.fingerprint);
+		}
+		let missing = &self.missing;
+		let mut paths = Vec::new();
+		find_audio_files(&self.search_dir, &mut paths)?;
+		let files: Vec<(PathBuf, Info)> = paths
+			.into_par_iter()
+			.filter_map(|path| {
+				let mut file = Info::read(&path)?;
+				// Fingerprinting is slow, so only files that may be a
+				// fingerprinted track are fingerprinted
+				let is_candidate = missing
+					.iter()
+					.any(|(_, track)| track.fingerprint.is_some() && track.same_duration(&file));
+				if is_candidate {
+					file.fingerprint = fingerprint(&path).ok();
+				}
+				Some((path, file))
+			})
+			.collect();
+		Ok(find_matches(&self.missing, &files))
+	}
+	fn resolve(&mut self, _env: Env, output: Self::Output) -> Result<Self::JsValue> {
+		Ok(output)
+	}
+}
+
+/// Searches a folder for the files of tracks whose file is missing. Nothing
+/// is changed until the matches are passed to `apply_relinks`.
+#[napi(
+	js_name = "relink_tracks",
+	ts_return_type = "Promise<Array<RelinkMatch>>"
+)]
+#[allow(dead_code)]
+pub fn relink_tracks(search_dir: String, env: Env) -> Result<JsObject> {
+	let data: &mut Data = get_data(&env)?;
+	let tracks_dir = &data.paths.tracks_dir;
+	let missing = data
+		.library
+		.tracks
+		.iter()
+		.filter(|(_, track)| !tracks_dir.join(&track.file).exists())
+		.map(|(id, track)| (id.clone(), Info::from_track(track)))
+		.collect();
+	let task = FindRelinks {
+		search_dir: search_dir.into(),
+		missing,
+		fingerprints_db: data.paths.fingerprints_db.clone(),
+	};
+	env.spawn(task).map(|t| t.promise_object())
+}
+
+/// Copies the file into the tracks folder and points the track to it. The
+/// file's properties replace the track's, since it may be encoded differently
+fn relink(data: &mut Data, track_id: &str, path: &Path) -> UniResult<()> {
+	let tracks_dir = &data.paths.tracks_dir;
+	let track = data.library.get_track(track_id)?;
+	if tracks_dir.join(&track.file).exists() {
+		throw!("File of \"{}\" is not missing", track.name);
+	}
+	let file_md = match fs::metadata(path) {
+		Ok(file_md) => file_md,
+		Err(err) => throw!("Unable to access file {}: {err}", path.to_string_lossy()),
+	};
+	let file_type = FileType::detect(path)?;
+	let properties = AudioProperties::read_from_path(path)?;
+	let filename = generate_filename(
+		tracks_dir,
+		&track.artist,
+		&track.name,
+		file_type.file_extension(),
+	);
+	if let Err(err) = fs::copy(path, tracks_dir.join(&filename)) {
+		throw!("Error copying file: {err}");
+	}
+	let track = data
+		.library
+		.tracks
+		.get_mut(track_id)
+		.expect("Track ID not found");
+	track.file = filename;
+	track.size = file_md.len() as i64;
+	track.fileType = Some(file_type.file_extension().to_string());
+	properties.apply_to(track);
+	// Found again by `analyze_silence`
+	track.silenceStart = None;
+	track.silenceEnd = None;
+	data.track_changed(&track_id.to_string());
+	Ok(())
+}
+
+#[napi(js_name = "apply_relinks")]
+#[allow(dead_code)]
+pub fn apply_relinks(matches: Vec<RelinkMatch>, env: Env) -> Result<()> {
+	let data: &mut Data = get_data(&env)?;
+	for relink_match in matches {
+		relink(data, &relink_match.track_id, Path::new(&relink_match.path))?;
+	}
+	Ok(())
+}
+
+#[test]
+fn relink_test() {
+	let info = |size, duration, name: &str, artist: &str| Info {
+		size,
+		duration,
+		name: normalize(name),
+		artist: normalize(artist),
+		album: String::new(),
+		fingerprint: None,
+	};
+	let fingerprinted = |size, name: &str, seed: u32| Info {
+		fingerprint: Some(
+			(0..100)
+				.map(|i: u32| (i ^ seed).wrapping_mul(2654435761))
+				.collect(),
+		),
+		..info(size, 30.0, name, "")
+	};
+	let missing = vec![
+		("a".to_string(), info(100, 200.0, "Song", "Artist")),
+		("b".to_string(), info(300, 100.0, "Other", "Artist")),
+		("c".to_string(), info(500, 50.0, "Lost", "")),
+		("d".to_string(), fingerprinted(700, "Live", 1)),
+		("e".to_string(), fingerprinted(800, "Demo", 2)),
+	];
+	let files = vec![
+		(PathBuf::from("x.mp3"), info(999, 200.4, "song ", "ARTIST")),
+		(PathBuf::from("y.mp3"), info(100, 200.0, "Song", "Artist")),
+		(PathBuf::from("z.mp3"), info(300, 100.2, "Renamed", "")),
+		(PathBuf::from("w.mp3"), info(500, 80.0, "Lost", "")),
+		(PathBuf::from("v.mp3"), fingerprinted(123, "Re-encoded", 1)),
+		(PathBuf::from("u.mp3"), fingerprinted(800, "Demo", 3)),
+	];
+	let matches = find_matches(&missing, &files);
+	let pairs: Vec<_> = matches
+		.iter()
+		.map(|m| (m.track_id.as_str(), m.path.as_str(), m.same_size))
+		.collect();
+	assert_eq!(
+		pairs,
+		vec![
+			("a", "y.mp3", true),
+			("b", "z.mp3", true),
+			("d", "v.mp3", false)
+		]
+	);
+}
@@ -0,0 +1,231 @@
+//! Rereads file properties and tags of tracks from their files, for example
+//! after the files were edited with another tagger. Play history and other
+//! info that's only stored in the library is kept.
+
+use crate::data::Data;
+use crate::data_js::get_data;
+use crate::get_now_timestamp;
+use crate::library_types::{Track, TrackID};
+use crate::tracks::import;
+use crate::UniResult;
+use napi::{Env, JsObject, Result, Task};
+use rayon::prelude::*;
+use serde_json::Value;
+use std::path::PathBuf;
+
+/// Fields that are read from the file's properties
+const PROPERTY_FIELDS: [&str; 13] = [
+	"size",
+	"duration",
+	"bitrate",
+	"sampleRate",
+	"dateModified",
+	"fileType",
+	"codec",
+	"channels",
+	"bitDepth",
+	"lossless",
+	"encoder",
+	"encoderDelay",
+	"encoderPadding",
+];
+
+/// Fields that are read from the file's tag
+const TAG_FIELDS: [&str; 20] = [
+	"name",
+	"artist",
+	"composer",
+	"sortName",
+	"sortArtist",
+	"sortComposer",
+	"genre",
+	"year",
+	"bpm",
+	"key",
+	"comments",
+	"grouping",
+	"albumName",
+	"albumArtist",
+	"sortAlbumName",
+	"sortAlbumArtist",
+	"trackNum",
+	"trackCount",
+	"discNum",
+	"discCount",
+];
+
+/// Tag fields that are kept if the tag doesn't have them. The BPM and key
+/// may be set by analysis without writing the tag, and tracks always have a
+/// name
+const KEPT_FIELDS: [&str; 3] = ["name", "bpm", "key"];
+
+#[napi(object)]
+pub struct FieldChange {
+	pub field: String,
+	/// `null` if the field was not set
+	pub old: Value,
+	pub new: Value,
+}
+
+#[napi(object)]
+pub struct TrackRescan {
+	pub track_id: TrackID,
+	pub changes: Vec<FieldChange>,
+	/// Set if the file couldn't be read, in which case the track is unchanged
+	pub error: Option<String>,
+}
+
+/// Copies the file fields of `scanned` into `track`, and returns what changed
+fn apply_scan(track: &mut Track, scanned: &Track) -> UniResult<Vec<FieldChange>> {
+	let mut track_json = match serde_json::to_value(&*track) {
+		Ok(Value::Object(map)) => map,
+		_ => throw!("Unable to serialize track"),
+	};
+	let scanned_json = match serde_json::to_value(scanned) {
+		Ok(Value::Object(map)) => map,
+		_ => throw!("Unable to serialize track"),
+	};
+	let mut changes = Vec::new();
+	for field in PROPERTY_FIELDS.iter().chain(&TAG_FIELDS) {
+		let old = track_json.get(*field).cloned().unwrap_or(Value::Null);
+		let mut new = scanned_json.get(*field).cloned().unwrap_or(Value::Null);
+		if TAG_FIELDS.contains(field) && new == "" {
+			new = Value::Null;
+		}
+		if old == new || (KEPT_FIELDS.contains(field) && new == Value::Null) {
+			continue;
+		}
+		match &new {
+			Value::Null => track_json.remove(*field),
+			new => track_json.insert(field.to_string(), new.clone()),
+		};
+		changes.push(FieldChange {
+			field: field.to_string(),
+			old,
+			new,
+		});
+	}
+	if !changes.is_empty() {
+		*track = match serde_json::from_value(Value::Object(track_json)) {
+			Ok(track) => track,
+			Err(e) => throw!("Unable to update track: {e}"),
+		};
+	}
+	Ok(changes)
+}
+
+struct RescanTracks {
+	files: Vec<(TrackID, PathBuf)>,
+	now: i64,
+}
+impl Task for RescanTracks {
+	type Output = Vec<(TrackID, UniResult<Track>)>;
+	type JsValue = Vec<TrackRescan>;
+	fn compute(&mut self) -> Result<Self::Output> {
+		let scanned = self
+			.files
+			.par_iter()
+			.map(|(id, path)| {
+				let read_file = import::read(path, self.now);
+				let track = read_file.map(|read_file| {
+					let mut track = read_file.track;
+					// Not a name from the tag
+					if read_file.untitled {
+						track.name = String::new();
+					}
+					track
+				});
+				(id.clone(), track)
+			})
+			.collect();
+		Ok(scanned)
+	}
+	fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+		let data: &mut Data = get_data(&env)?;
+		let mut results = Vec::new();
+		for (id, scanned) in output {
+			// The track may have been deleted in the meantime
+			let track = match data.library.tracks.get_mut(&id) {
+				Some(track) => track,
+				None => continue,
+			};
+			let changes = scanned.and_then(|scanned| apply_scan(track, &scanned));
+			let result = match changes {
+				Ok(changes) if changes.is_empty() => continue,
+				Ok(changes) => {
+					data.track_changed(&id);
+					TrackRescan {
+						track_id: id,
+						changes,
+						error: None,
+					}
+				}
+				Err(err) => TrackRescan {
+					track_id: id,
+					changes: Vec::new(),
+					error: Some(err.message),
+				},
+			};
+			results.push(result);
+		}
+		Ok(results)
+	}
+}
+
+/// Rereads the file properties and tags of tracks. Resolves to the tracks
+/// that changed or couldn't be read.
+#[napi(
+	js_name = "rescan_tracks",
+	ts_return_type = "Promise<Array<TrackRescan>>"
+)]
+#[allow(dead_code)]
+pub fn rescan_tracks(track_ids: Vec<TrackID>, env: Env) -> Result<JsObject> {
+	let data: &mut Data = get_data(&env)?;
+	let mut files = Vec::new();
+	for id in track_ids {
+		let track = data.library.get_track(&id)?;
+		files.push((id, data.paths.tracks_dir.join(&track.file)));
+	}
+	let task = RescanTracks {
+		files,
+		now: get_now_timestamp(),
+	};
+	env.spawn(task).map(|t| t.promise_object())
+}
+
+#[test]
+fn rescan_test() {
+	use crate::library_types::test_track;
+
+	let mut track = test_track(serde_json::json!({
+		"duration": 200.0, "dateAdded": 5, "name": "Old", "artist": "X", "genre": "Rock",
+		"bpm": 120.0, "playCount": 3, "rating": 80
+	}));
+	let scanned = test_track(serde_json::json!({
+		"size": 2, "duration": 200.0, "file": "", "dateAdded": 9, "name": "New", "artist": "X",
+		"year": 1999
+	}));
+	let changes = apply_scan(&mut track, &scanned).unwrap();
+	let fields: Vec<_> = changes.iter().map(|c| c.field.as_str()).collect();
+	assert_eq!(fields, vec!["size", "name", "genre", "year"]);
+	assert_eq!(changes[1].old, Value::from("Old"));
+	assert_eq!(changes[1].new, Value::from("New"));
+	assert_eq!(track.name, "New");
+	// Removed from the file's tag
+	assert_eq!(changes[2].old, Value::from("Rock"));
+	assert_eq!(changes[2].new, Value::Null);
+	assert_eq!(track.genre, None);
+	// Not written to the file's tag
+	assert_eq!(track.bpm, Some(120.0));
+	assert_eq!(track.year, Some(1999));
+	assert_eq!(track.file, "a.mp3");
+	assert_eq!(track.dateAdded, 5);
+	assert_eq!(track.playCount, Some(3));
+	assert_eq!(track.rating, Some(80));
+	assert!(apply_scan(&mut track, &scanned).unwrap().is_empty());
+	let untitled = test_track(serde_json::json!({
+		"size": 2, "duration": 200.0, "file": "", "artist": "X", "year": 1999
+	}));
+	assert!(apply_scan(&mut track, &untitled).unwrap().is_empty());
+	assert_eq!(track.name, "New");
+}
@@ -0,0 +1,305 @@
+//! Trigram index over track text, used to narrow down which tracks need to be
+//! checked when filtering. Text is folded with `filter::fold_char`, so every
+//! track that `find_match` would match is a candidate. Candidates still need
+//! to be checked with the real matcher.
+
+use crate::filter::fold_char;
+use crate::library_types::{
+	Library, SmartMatch, SmartOperator, SmartRule, SmartRuleGroup, Track, TrackID,
+};
+use std::collections::{HashMap, HashSet};
+use std::time::Instant;
+use unicode_normalization::UnicodeNormalization;
+
+/// Fields that are indexed. `Contains` rules on these fields use the index
+const INDEXED_FIELDS: [&str; 8] = [
+	"name",
+	"artist",
+	"albumName",
+	"albumArtist",
+	"composer",
+	"comments",
+	"genre",
+	"grouping",
+];
+
+type Trigram = u64;
+/// Position of a track in the index
+type Slot = u32;
+
+fn pack(chars: &[char]) -> Trigram {
+	((chars[0] as u64) << 42) | ((chars[1] as u64) << 21) | chars[2] as u64
+}
+
+/// Trigrams of text, skipping symbols like `find_match` does
+fn text_trigrams(text: &str, trigrams: &mut HashSet<Trigram>) {
+	let chars: Vec<char> = text.nfc().filter_map(fold_char).collect();
+	for window in chars.windows(3) {
+		trigrams.insert(pack(window));
+	}
+}
+
+/// Trigrams a match for the keyword must contain. Symbols in the keyword
+/// only match the exact same symbol, so trigrams don't span across them.
+fn keyword_trigrams(keyword: &str) -> HashSet<Trigram> {
+	let mut trigrams = HashSet::new();
+	let mut chars = Vec::new();
+	for c in keyword.chars().chain([' ']) {
+		match fold_char(c) {
+			Some(c) if !c.is_whitespace() => chars.push(c),
+			_ => {
+				for window in chars.windows(3) {
+					trigrams.insert(pack(window));
+				}
+				chars.clear();
+			}
+		}
+	}
+	trigrams
+}
+
+fn track_trigrams(track: &Track) -> HashSet<Trigram> {
+	let mut trigrams = HashSet::new();
+	let fields = [
+		Some(&track.name),
+		Some(&track.artist),
+		track.albumName.as_ref(),
+		track.albumArtist.as_ref(),
+		track.composer.as_ref(),
+		track.comments.as_ref(),
+		track.genre.as_ref(),
+		track.grouping.as_ref(),
+	];
+	for text in fields.into_iter().flatten() {
+		text_trigrams(text, &mut trigrams);
+	}
+	trigrams
+}
+
+fn intersect(a: &[Slot], b: &[Slot]) -> Vec<Slot> {
+	let mut result = Vec::new();
+	let (mut i, mut j) = (0, 0);
+	while i < a.len() && j < b.len() {
+		if a[i] < b[j] {
+			i += 1;
+		} else if a[i] > b[j] {
+			j += 1;
+		} else {
+			result.push(a[i]);
+			i += 1;
+			j += 1;
+		}
+	}
+	result
+}
+
+fn union(a: &[Slot], b: &[Slot]) -> Vec<Slot> {
+	let mut result: Vec<Slot> = a.iter().chain(b).copied().collect();
+	result.sort_unstable();
+	result.dedup();
+	result
+}
+
+#[derive(Default)]
+struct Index {
+	/// Track ID of each slot. Removed tracks leave an empty slot
+	slots: Vec<Option<TrackID>>,
+	track_slots: HashMap<TrackID, Slot>,
+	/// Sorted slots containing each trigram. May contain empty slots
+	postings: HashMap<Trigram, Vec<Slot>>,
+	empty_slots: usize,
+}
+
+impl Index {
+	fn insert(&mut self, id: &TrackID, track: &Track) {
+		let slot = self.slots.len() as Slot;
+		self.slots.push(Some(id.clone()));
+		self.track_slots.insert(id.clone(), slot);
+		for trigram in track_trigrams(track) {
+			self.postings.entry(trigram).or_default().push(slot);
+		}
+	}
+	fn remove(&mut self, id: &str) {
+		if let Some(slot) = self.track_slots.remove(id) {
+			self.slots[slot as usize] = None;
+			self.empty_slots += 1;
+		}
+		if self.empty_slots > 1000 && self.empty_slots > self.slots.len() / 2 {
+			self.compact();
+		}
+	}
+	/// Removes empty slots
+	fn compact(&mut self) {
+		let mut new_slots: Vec<Option<Slot>> = Vec::with_capacity(self.slots.len());
+		let mut slots = Vec::new();
+		for id in self.slots.drain(..) {
+			match id {
+				Some(id) => {
+					let slot = slots.len() as Slot;
+					new_slots.push(Some(slot));
+					self.track_slots.insert(id.clone(), slot);
+					slots.push(Some(id));
+				}
+				None => new_slots.push(None),
+			}
+		}
+		self.slots = slots;
+		self.postings.retain(|_, posting| {
+			*posting = posting
+				.iter()
+				.filter_map(|slot| new_slots[*slot as usize])
+				.collect();
+			!posting.is_empty()
+		});
+		self.empty_slots = 0;
+	}
+	/// Returns `None` if the rule can't be narrowed down using the index
+	fn rule_candidates(&self, rule: &SmartRule) -> Option<Vec<Slot>> {
+		match rule {
+			SmartRule::Group(group) => self.group_candidates(group),
+			SmartRule::Condition(condition) => {
+				let keyword = match &condition.operator {
+					SmartOperator::Contains(keyword) => keyword,
+					_ => return None,
+				};
+				if !INDEXED_FIELDS.contains(&condition.field.as_str()) {
+					return None;
+				}
+				let mut postings = Vec::new();
+				for trigram in keyword_trigrams(&keyword.nfc().collect::<String>()) {
+					match self.postings.get(&trigram) {
+						Some(posting) => postings.push(posting),
+						None => return Some(Vec::new()),
+					}
+				}
+				postings.sort_by_key(|posting| posting.len());
+				let (first, rest) = postings.split_first()?;
+				let mut slots = first.to_vec();
+				for posting in rest {
+					slots = intersect(&slots, posting);
+				}
+				Some(slots)
+			}
+		}
+	}
+	fn group_candidates(&self, group: &SmartRuleGroup) -> Option<Vec<Slot>> {
+		let mut rules = group.rules.iter().map(|rule| self.rule_candidates(rule));
+		match group.match_ {
+			SmartMatch::All => rules
+				.flatten()
+				.reduce(|slots, other| intersect(&slots, &other)),
+			SmartMatch::Any => {
+				rules.try_fold(Vec::new(), |slots, other| Some(union(&slots, &other?)))
+			}
+			SmartMatch::None => None,
+		}
+	}
+}
+
+/// Built the first time it's used, and then kept in sync with the library
+#[derive(Default)]
+pub struct SearchIndex {
+	index: Option<Index>,
+}
+
+impl SearchIndex {
+	pub fn new() -> Self {
+		Self::default()
+	}
+	fn get_or_build(&mut self, library: &Library) -> &Index {
+		self.index.get_or_insert_with(|| {
+			let now = Instant::now();
+			let mut index = Index::default();
+			for (id, track) in &library.tracks {
+				index.insert(id, track);
+			}
+			println!("Build search index: {}ms", now.elapsed().as_millis());
+			index
+		})
+	}
+	/// Adds or updates a track
+	pub fn update_track(&mut self, id: &TrackID, track: &Track) {
+		if let Some(index) = &mut self.index {
+			index.remove(id);
+			index.insert(id, track);
+		}
+	}
+	pub fn remove_track(&mut self, id: &str) {
+		if let Some(index) = &mut self.index {
+			index.remove(id);
+		}
+	}
+	/// Clears the index, for when the whole library is replaced
+	pub fn reset(&mut self) {
+		self.index = None;
+	}
+	/// Tracks that might match the rules. Returns `None` if the rules
+	/// can't be narrowed down using the index
+	pub fn candidates(
+		&mut self,
+		library: &Library,
+		rules: &SmartRuleGroup,
+	) -> Option<HashSet<&TrackID>> {
+		let index = self.get_or_build(library);
+		let slots = index.group_candidates(rules)?;
+		let ids = slots
+			.into_iter()
+			.filter_map(|slot| index.slots[slot as usize].as_ref())
+			.collect();
+		Some(ids)
+	}
+}
+
+#[test]
+fn search_index_test() {
+	use crate::filter::find_match;
+	use crate::library_types::{test_track, SmartCondition};
+
+	let names = [
+		"Café del Mar",
+		"AC/DC - Back in Black",
+		"ＦＵＬＬＷＩＤＴＨ ５",
+		"Ёлка (Live)",
+		"Don't Stop Me Now",
+		"Straße",
+		"naïve",
+		"GROẞ",
+	];
+	let keywords = [
+		"cafe", "café", "acdc", "ac/dc", "ack in", "fullw", "5", "ёлка", "ЁЛКА", "dont", "don't",
+		"stop me", "traß", "traẞ", "groß", "naive", "ïve", "xyz", "live)",
+	];
+	let mut library = Library::new();
+	for (i, name) in names.iter().enumerate() {
+		let track = test_track(serde_json::json!({ "name": name }));
+		library.tracks.insert(i.to_string(), track);
+	}
+	let rules = |keyword: &str| SmartRuleGroup {
+		match_: SmartMatch::All,
+		rules: vec![SmartRule::Condition(SmartCondition {
+			field: "name".to_string(),
+			operator: SmartOperator::Contains(keyword.to_string()),
+		})],
+	};
+	let mut search_index = SearchIndex::new();
+	for keyword in keywords {
+		let candidates = search_index.candidates(&library, &rules(keyword));
+		for (id, track) in &library.tracks {
+			if find_match(&track.name, keyword) {
+				let is_candidate = candidates.as_ref().is_none_or(|c| c.contains(id));
+				assert!(is_candidate, "{keyword} should match {}", track.name);
+			}
+		}
+	}
+	let candidates = search_index.candidates(&library, &rules("xyz")).unwrap();
+	assert!(candidates.is_empty());
+
+	let mut track = library.tracks.get("0").unwrap().clone();
+	track.name = "Xyzzy".to_string();
+	search_index.update_track(&"0".to_string(), &track);
+	let candidates = search_index.candidates(&library, &rules("xyz")).unwrap();
+	assert_eq!(candidates.into_iter().collect::<Vec<_>>(), vec!["0"]);
+	search_index.remove_track("0");
+	let candidates = search_index.candidates(&library, &rules("xyz")).unwrap();
+	assert!(candidates.is_empty());
+}
@@ -0,0 +1,419 @@
+use crate::filter::{find_match, find_match_at_start};
+use crate::get_now_timestamp;
+use crate::library::{get_track_field_type, TrackField};
+use crate::library_types::{
+	Library, SmartLimit, SmartLimitUnit, SmartMatch, SmartOperator, SmartPlaylist, SmartRule,
+	SmartRuleGroup, SmartValue, SpecialTrackListName, Track, TrackID, TrackList,
+};
+use crate::sort::{
+	compare_track_field, get_field_bool, get_field_f64, get_field_i64, get_field_i8, get_field_str,
+	get_field_u32, get_field_u8,
+};
+use crate::UniResult;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use unicode_normalization::UnicodeNormalization;
+
+enum Matcher<'a> {
+	Group(SmartMatch, Vec<Matcher<'a>>),
+	Field {
+		field: &'a str,
+		field_type: TrackField,
+		operator: &'a SmartOperator,
+		/// NFC-normalized text value, for string operators
+		text: String,
+	},
+	Membership {
+		track_ids: HashSet<TrackID>,
+		is_member: bool,
+	},
+}
+
+impl<'a> Matcher<'a> {
+	fn matches(&self, track_id: &str, track: &Track, now: i64) -> bool {
+		match self {
+			Matcher::Group(SmartMatch::All, matchers) => {
+				matchers.iter().all(|m| m.matches(track_id, track, now))
+			}
+			Matcher::Group(SmartMatch::Any, matchers) => {
+				matchers.iter().any(|m| m.matches(track_id, track, now))
+			}
+			Matcher::Group(SmartMatch::None, matchers) => {
+				!matchers.iter().any(|m| m.matches(track_id, track, now))
+			}
+			Matcher::Membership {
+				track_ids,
+				is_member,
+			} => track_ids.contains(track_id) == *is_member,
+			Matcher::Field {
+				field,
+				field_type,
+				operator,
+				text,
+			} => match field_type {
+				TrackField::String => {
+					let value = get_field_str(track, field).map(|s| s.as_str());
+					match_str(value, operator, text)
+				}
+				TrackField::Bool => match_bool(get_field_bool(track, field), operator),
+				TrackField::I64 => {
+					let value = get_field_i64(track, field).map(|n| n as f64);
+					match_number(value, operator, now)
+				}
+				TrackField::F64 => match_number(get_field_f64(track, field), operator, now),
+				TrackField::U32 => {
+					let value = get_field_u32(track, field).map(f64::from);
+					match_number(value, operator, now)
+				}
+				TrackField::I8 => {
+					let value = get_field_i8(track, field).map(f64::from);
+					match_number(value, operator, now)
+				}
+				TrackField::U8 => {
+					let value = get_field_u8(track, field).map(f64::from);
+					match_number(value, operator, now)
+				}
+			},
+		}
+	}
+}
+
+fn match_str(value: Option<&str>, operator: &SmartOperator, text: &str) -> bool {
+	let value = value.unwrap_or_default();
+	match operator {
+		SmartOperator::Is(_) => value.nfc().flat_map(char::to_lowercase).eq(text.chars()),
+		SmartOperator::IsNot(_) => !value.nfc().flat_map(char::to_lowercase).eq(text.chars()),
+		SmartOperator::Contains(_) => find_match(value, text),
+		SmartOperator::NotContains(_) => !find_match(value, text),
+		SmartOperator::StartsWith(_) => find_match_at_start(value, text),
+		SmartOperator::EndsWith(_) => {
+			let value: String = value.nfc().flat_map(char::to_lowercase).collect();
+			value.ends_with(text)
+		}
+		SmartOperator::IsSet => !value.is_empty(),
+		SmartOperator::IsNotSet => value.is_empty(),
+		_ => false,
+	}
+}
+
+fn match_bool(value: Option<bool>, operator: &SmartOperator) -> bool {
+	let value = value.unwrap_or(false);
+	match operator {
+		SmartOperator::Is(SmartValue::Bool(expected)) => value == *expected,
+		SmartOperator::IsNot(SmartValue::Bool(expected)) => value != *expected,
+		SmartOperator::IsSet => value,
+		SmartOperator::IsNotSet => !value,
+		_ => false,
+	}
+}
+
+fn match_number(value: Option<f64>, operator: &SmartOperator, now: i64) -> bool {
+	match (operator, value) {
+		(SmartOperator::IsSet, value) => value.is_some(),
+		(SmartOperator::IsNotSet, value) => value.is_none(),
+		(SmartOperator::IsNot(SmartValue::Number(n)), value) => value != Some(*n),
+		(SmartOperator::NotInLast(ms), value) => match value {
+			Some(value) => value < (now - ms) as f64,
+			None => true,
+		},
+		(_, None) => false,
+		(SmartOperator::Is(SmartValue::Number(n)), Some(value)) => value == *n,
+		(SmartOperator::GreaterThan(n), Some(value)) => value > *n,
+		(SmartOperator::LessThan(n), Some(value)) => value < *n,
+		(SmartOperator::AtLeast(n), Some(value)) => value >= *n,
+		(SmartOperator::AtMost(n), Some(value)) => value <= *n,
+		(SmartOperator::InRange(from, to), Some(value)) => value >= *from && value <= *to,
+		(SmartOperator::InLast(ms), Some(value)) => value >= (now - ms) as f64,
+		_ => false,
+	}
+}
+
+fn validate_operator(
+	field: &str,
+	field_type: &TrackField,
+	operator: &SmartOperator,
+) -> UniResult<()> {
+	let valid = match (field_type, operator) {
+		(_, SmartOperator::IsSet | SmartOperator::IsNotSet) => true,
+		(TrackField::String, SmartOperator::Is(SmartValue::String(_))) => true,
+		(TrackField::String, SmartOperator::IsNot(SmartValue::String(_))) => true,
+		(
+			TrackField::String,
+			SmartOperator::Contains(_)
+			| SmartOperator::NotContains(_)
+			| SmartOperator::StartsWith(_)
+			| SmartOperator::EndsWith(_),
+		) => true,
+		(TrackField::Bool, SmartOperator::Is(SmartValue::Bool(_))) => true,
+		(TrackField::Bool, SmartOperator::IsNot(SmartValue::Bool(_))) => true,
+		(TrackField::String | TrackField::Bool, _) => false,
+		(_, SmartOperator::Is(SmartValue::Number(_))) => true,
+		(_, SmartOperator::IsNot(SmartValue::Number(_))) => true,
+		(
+			_,
+			SmartOperator::GreaterThan(_)
+			| SmartOperator::LessThan(_)
+			| SmartOperator::AtLeast(_)
+			| SmartOperator::AtMost(_)
+			| SmartOperator::InRange(_, _),
+		) => true,
+		(TrackField::I64, SmartOperator::InLast(_) | SmartOperator::NotInLast(_)) => true,
+		_ => false,
+	};
+	if !valid {
+		throw!("Operator {:?} cannot be used with field {field}", operator);
+	}
+	Ok(())
+}
+
+fn compile_group<'a>(
+	library: &Library,
+	group: &'a SmartRuleGroup,
+	parents: &mut Vec<String>,
+) -> UniResult<Matcher<'a>> {
+	let mut matchers = Vec::new();
+	for rule in &group.rules {
+		let matcher = match rule {
+			SmartRule::Group(group) => compile_group(library, group, parents)?,
+			SmartRule::Condition(condition) if condition.field == "playlist" => {
+				let (id, is_member) = match &condition.operator {
+					SmartOperator::Is(SmartValue::String(id)) => (id, true),
+					SmartOperator::IsNot(SmartValue::String(id)) => (id, false),
+					_ => throw!("Playlist rules must use the is or isNot operator"),
+				};
+				let track_ids = get_tracklist_track_ids(library, id, parents)?;
+				Matcher::Membership {
+					track_ids: track_ids.into_iter().collect(),
+					is_member,
+				}
+			}
+			SmartRule::Condition(condition) => {
+				let field = condition.field.as_str();
+				let field_type = match get_track_field_type(field) {
+					Some(field_type) => field_type,
+					None => throw!("Unknown field {field}"),
+				};
+				validate_operator(field, &field_type, &condition.operator)?;
+				let text = match &condition.operator {
+					SmartOperator::Is(SmartValue::String(s))
+					| SmartOperator::IsNot(SmartValue::String(s))
+					| SmartOperator::EndsWith(s) => s.nfc().flat_map(char::to_lowercase).collect(),
+					SmartOperator::Contains(s)
+					| SmartOperator::NotContains(s)
+					| SmartOperator::StartsWith(s) => s.nfc().collect(),
+					_ => String::new(),
+				};
+				Matcher::Field {
+					field,
+					field_type,
+					operator: &condition.operator,
+					text,
+				}
+			}
+		};
+		matchers.push(matcher);
+	}
+	Ok(Matcher::Group(group.match_, matchers))
+}
+
+/// Track IDs of any tracklist, evaluating smart playlists recursively.
+/// `parents` contains the smart playlists currently being evaluated, so
+/// rules that reference themselves are caught.
+fn get_tracklist_track_ids(
+	library: &Library,
+	id: &str,
+	parents: &mut Vec<String>,
+) -> UniResult<Vec<TrackID>> {
+	match library.get_tracklist(id)? {
+		TrackList::Playlist(playlist) => Ok(playlist.tracks.clone()),
+		TrackList::Smart(smart) => evaluate(library, smart, parents),
+		TrackList::Folder(folder) => {
+			let mut ids: HashSet<TrackID> = HashSet::new();
+			for child in &folder.children {
+				ids.extend(get_tracklist_track_ids(library, child, parents)?);
+			}
+			Ok(ids.into_iter().collect())
+		}
+		TrackList::Special(special) => match special.name {
+			SpecialTrackListName::Root => Ok(library.tracks.keys().cloned().collect()),
+		},
+	}
+}
+
+fn sort_ids(library: &Library, ids: &mut [TrackID], key: &str, field: &TrackField, desc: bool) {
+	let tracks = &library.tracks;
+	ids.sort_by(|id_a, id_b| {
+		let track_a = tracks.get(id_a).expect("Track ID non-existant (1)");
+		let track_b = tracks.get(id_b).expect("Track ID non-existant (2)");
+		let order = compare_track_field(track_a, track_b, key, field);
+		match desc {
+			true => order.reverse(),
+			false => order,
+		}
+	});
+}
+
+/// Sort key that selects tracks randomly. The order is stable for each
+/// playlist, so tracks don't move around every time the playlist is opened.
+const RANDOM_SORT_KEY: &str = "random";
+
+fn random_order(playlist_id: &str, track_id: &str) -> u64 {
+	let mut hasher = DefaultHasher::new();
+	(playlist_id, track_id).hash(&mut hasher);
+	hasher.finish()
+}
+
+fn apply_limit(
+	library: &Library,
+	playlist_id: &str,
+	ids: &mut Vec<TrackID>,
+	limit: &SmartLimit,
+) -> UniResult<()> {
+	let tracks = &library.tracks;
+	if limit.sortKey == RANDOM_SORT_KEY {
+		ids.sort_by_cached_key(|id| random_order(playlist_id, id));
+	} else {
+		let field = match get_track_field_type(&limit.sortKey) {
+			Some(field) => field,
+			None => throw!("Unknown field {}", limit.sortKey),
+		};
+		sort_ids(library, ids, &limit.sortKey, &field, limit.sortDesc);
+	}
+	let mut total = 0.0;
+	let mut count = 0;
+	for id in ids.iter() {
+		let track = tracks.get(id).expect("Track ID non-existant (3)");
+		total += match limit.unit {
+			SmartLimitUnit::Items => 1.0,
+			SmartLimitUnit::Minutes => track.duration / 60.0,
+			SmartLimitUnit::Megabytes => track.size as f64 / 1_000_000.0,
+		};
+		if total > limit.amount {
+			break;
+		}
+		count += 1;
+	}
+	ids.truncate(count);
+	Ok(())
+}
+
+fn evaluate(
+	library: &Library,
+	smart: &SmartPlaylist,
+	parents: &mut Vec<String>,
+) -> UniResult<Vec<TrackID>> {
+	if parents.contains(&smart.id) {
+		throw!("Smart playlist \"{}\" references itself", smart.name);
+	}
+	parents.push(smart.id.clone());
+	let matcher = compile_group(library, &smart.rules, parents)?;
+	parents.pop();
+
+	let now = get_now_timestamp();
+	let mut ids: Vec<TrackID> = library
+		.tracks
+		.iter()
+		.filter(|(id, track)| matcher.matches(id, track, now))
+		.map(|(id, _)| id.clone())
+		.collect();
+	if let Some(limit) = &smart.limit {
+		apply_limit(library, &smart.id, &mut ids, limit)?;
+	}
+	Ok(ids)
+}
+
+/// Rules compiled once, for matching against many tracks
+pub struct TrackMatcher<'a> {
+	matcher: Matcher<'a>,
+	now: i64,
+}
+
+impl<'a> TrackMatcher<'a> {
+	pub fn new(library: &Library, rules: &'a SmartRuleGroup) -> UniResult<Self> {
+		Ok(TrackMatcher {
+			matcher: compile_group(library, rules, &mut Vec::new())?,
+			now: get_now_timestamp(),
+		})
+	}
+	pub fn matches(&self, track_id: &str, track: &Track) -> bool {
+		self.matcher.matches(track_id, track, self.now)
+	}
+}
+
+pub fn get_track_ids(library: &Library, smart: &SmartPlaylist) -> UniResult<Vec<TrackID>> {
+	evaluate(library, smart, &mut Vec::new())
+}
+
+/// Checks that the rules are valid without keeping the result
+pub fn validate(library: &Library, smart: &SmartPlaylist) -> UniResult<()> {
+	if let Some(limit) = &smart.limit {
+		if limit.sortKey != RANDOM_SORT_KEY && get_track_field_type(&limit.sortKey).is_none() {
+			throw!("Unknown field {}", limit.sortKey);
+		}
+	}
+	let mut parents = vec![smart.id.clone()];
+	compile_group(library, &smart.rules, &mut parents)?;
+	Ok(())
+}
+
+/// Whether any `playlist` rule references one of the track lists
+pub fn references_any(group: &SmartRuleGroup, ids: &HashSet<String>) -> bool {
+	group.rules.iter().any(|rule| match rule {
+		SmartRule::Group(group) => references_any(group, ids),
+		SmartRule::Condition(condition) if condition.field == "playlist" => {
+			match &condition.operator {
+				SmartOperator::Is(SmartValue::String(id))
+				| SmartOperator::IsNot(SmartValue::String(id)) => ids.contains(id),
+				_ => false,
+			}
+		}
+		SmartRule::Condition(_) => false,
+	})
+}
+
+#[test]
+fn smart_playlist_rules_test() {
+	use crate::library_types::test_library;
+
+	let library = test_library(serde_json::json!([
+		{ "duration": 60.0, "name": "So What", "genre": "Jazz", "rating": 100 },
+		{ "duration": 60.0, "name": "Blue in Green", "genre": "Jazz", "rating": 60 },
+		{ "duration": 60.0, "name": "Jazz Café", "genre": "Pop", "rating": 80 },
+	]));
+	let rules: SmartRuleGroup = serde_json::from_value(serde_json::json!({
+		"match": "any",
+		"rules": [
+			{ "type": "group", "match": "all", "rules": [
+				{ "type": "condition", "field": "rating", "operator": "atLeast", "value": 80 },
+				{ "type": "condition", "field": "genre", "operator": "is", "value": "jazz" },
+			]},
+			{ "type": "condition", "field": "name", "operator": "contains", "value": "cafe" },
+		],
+	}))
+	.unwrap();
+	let smart = library.new_smart_playlist("Test".to_string(), None, rules, None);
+	assert_eq!(get_track_ids(&library, &smart).unwrap(), vec!["0", "2"]);
+
+	let limit: SmartLimit = serde_json::from_value(serde_json::json!({
+		"amount": 1, "unit": "items", "sortKey": "rating", "sortDesc": false
+	}))
+	.unwrap();
+	let smart = SmartPlaylist {
+		limit: Some(limit),
+		..smart
+	};
+	assert_eq!(get_track_ids(&library, &smart).unwrap(), vec!["2"]);
+
+	let rules: SmartRuleGroup = serde_json::from_value(serde_json::json!({
+		"match": "all",
+		"rules": [
+			{ "type": "group", "match": "any", "rules": [
+				{ "type": "condition", "field": "playlist", "operator": "isNot", "value": "p1" },
+			]},
+		],
+	}))
+	.unwrap();
+	assert!(references_any(&rules, &HashSet::from(["p1".to_string()])));
+	assert!(!references_any(&rules, &HashSet::from(["p2".to_string()])));
+}
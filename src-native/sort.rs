@@ -1,23 +1,27 @@
 use crate::data::Data;
 use crate::library::{get_track_field_type, TrackField};
 use crate::library_types::{Track, TrackList};
-use crate::{page, UniResult};
+use crate::{browse, duplicates, page, UniResult};
 use alphanumeric_sort::compare_str;
 use std::cmp::Ordering;
 use std::time::Instant;
 
-fn get_field_str<'a>(track: &'a Track, sort_key: &str) -> Option<&'a String> {
+pub fn get_field_str<'a>(track: &'a Track, sort_key: &str) -> Option<&'a String> {
 	match sort_key {
 		"file" => Some(&track.file),
 		"name" => Some(&track.name),
 		"importedFrom" => track.importedFrom.as_ref(),
 		"originalId" => track.originalId.as_ref(),
+		"fileType" => track.fileType.as_ref(),
+		"codec" => track.codec.as_ref(),
+		"encoder" => track.encoder.as_ref(),
 		"artist" => Some(&track.artist),
 		"composer" => track.composer.as_ref(),
 		"sortName" => track.sortName.as_ref(),
 		"sortArtist" => track.sortArtist.as_ref(),
 		"sortComposer" => track.sortComposer.as_ref(),
 		"genre" => track.genre.as_ref(),
+		"key" => track.key.as_ref(),
 		"comments" => track.comments.as_ref(),
 		"grouping" => track.grouping.as_ref(),
 		"albumName" => track.albumName.as_ref(),
@@ -28,29 +32,44 @@ fn get_field_str<'a>(track: &'a Track, sort_key: &str) -> Option<&'a String> {
 	}
 }
 
-fn get_field_f64(track: &Track, sort_key: &str) -> Option<f64> {
+pub fn get_field_f64(track: &Track, sort_key: &str) -> Option<f64> {
 	match sort_key {
 		"duration" => Some(track.duration),
 		"bitrate" => Some(track.bitrate),
 		"sampleRate" => Some(track.sampleRate),
 		"bpm" => track.bpm,
+		"silenceStart" => track.silenceStart,
+		"silenceEnd" => track.silenceEnd,
+		"loudness" => track.loudness,
+		"truePeak" => track.truePeak,
+		"albumLoudness" => track.albumLoudness,
 		_ => panic!("Field type not found for {}", sort_key),
 	}
 }
 
-fn get_field_i64(track: &Track, sort_key: &str) -> Option<i64> {
+pub fn get_field_i64(track: &Track, sort_key: &str) -> Option<i64> {
 	match sort_key {
 		"size" => Some(track.size),
 		"dateModified" => Some(track.dateModified),
 		"dateAdded" => Some(track.dateAdded),
 		"dateImported" => track.dateImported,
 		"year" => track.year,
+		"lastPlayed" => track
+			.plays
+			.as_ref()
+			.and_then(|plays| plays.iter().max().copied()),
+		"lastSkipped" => track
+			.skips
+			.as_ref()
+			.and_then(|skips| skips.iter().max().copied()),
 		_ => panic!("Field type not found for {}", sort_key),
 	}
 }
 
-fn get_field_u32(track: &Track, sort_key: &str) -> Option<u32> {
+pub fn get_field_u32(track: &Track, sort_key: &str) -> Option<u32> {
 	match sort_key {
+		"encoderDelay" => track.encoderDelay,
+		"encoderPadding" => track.encoderPadding,
 		"trackNum" => track.trackNum,
 		"trackCount" => track.trackCount,
 		"discNum" => track.discNum,
@@ -61,26 +80,29 @@ fn get_field_u32(track: &Track, sort_key: &str) -> Option<u32> {
 	}
 }
 
-fn get_field_i8(track: &Track, sort_key: &str) -> Option<i8> {
+pub fn get_field_i8(track: &Track, sort_key: &str) -> Option<i8> {
 	match sort_key {
 		"volume" => track.volume,
 		_ => panic!("Field type not found for {}", sort_key),
 	}
 }
 
-fn get_field_u8(track: &Track, sort_key: &str) -> Option<u8> {
+pub fn get_field_u8(track: &Track, sort_key: &str) -> Option<u8> {
 	match sort_key {
 		"rating" => track.rating,
+		"channels" => track.channels,
+		"bitDepth" => track.bitDepth,
 		_ => panic!("Field type not found for {}", sort_key),
 	}
 }
 
-fn get_field_bool(track: &Track, sort_key: &str) -> Option<bool> {
+pub fn get_field_bool(track: &Track, sort_key: &str) -> Option<bool> {
 	match sort_key {
 		"liked" => track.liked,
 		"disliked" => track.disliked,
 		"disabled" => track.disabled,
 		"compilation" => track.compilation,
+		"lossless" => track.lossless,
 		_ => panic!("Field type not found for {}", sort_key),
 	}
 }
@@ -89,6 +111,17 @@ pub fn sort(data: &mut Data, sort_key: &str, desc: bool) -> UniResult<()> {
 	let now = Instant::now();
 
 	if sort_key == "index" {
+		// Browse lists have no order of their own
+		if browse::parse_id(&data.open_playlist_id).is_some() {
+			return Ok(());
+		}
+		// The duplicates list is ordered by group
+		if data.open_playlist_id == duplicates::TRACKLIST_ID {
+			data.open_playlist_track_ids = page::get_track_ids(data)?;
+			data.sort_key = sort_key.to_string();
+			data.sort_desc = true;
+			return Ok(());
+		}
 		// No need to sort for index. Indexes descend from "first to last"
 		// instead of "high to low", so it needs to be reversed
 		let playlist = data
@@ -97,7 +130,7 @@ pub fn sort(data: &mut Data, sort_key: &str, desc: bool) -> UniResult<()> {
 			.get(&data.open_playlist_id)
 			.ok_or("Playlist ID not found")?;
 		match playlist {
-			TrackList::Playlist(_) => {
+			TrackList::Playlist(_) | TrackList::Smart(_) => {
 				data.open_playlist_track_ids = page::get_track_ids(&data)?;
 				data.sort_key = sort_key.to_string();
 				data.sort_desc = true;
@@ -21,10 +21,10 @@ type CacheEntry = (i64, Vec<u8>);
 const IMG_CACHE_TABLE: TableDefinition<&str, CacheEntry> = TableDefinition::new("img_cache");
 
 lazy_static! {
-	static ref CACHE_DB: Arc<RwLock<Option<Database>>> = Arc::new(RwLock::new(None));
+	pub static ref CACHE_DB: Arc<RwLock<Option<Database>>> = Arc::new(RwLock::new(None));
 }
 
-fn init_cache_db(path: String) -> Result<()> {
+pub fn init_cache_db(path: String) -> Result<()> {
 	let cache_db_mutex = CACHE_DB.read().unwrap();
 	if cache_db_mutex.is_none() {
 		drop(cache_db_mutex);
@@ -59,7 +59,7 @@ fn get_modified_timestamp_ms_js(path: String) -> Result<Option<i64>> {
 	Ok(Some(modified_timestamp_ms))
 }
 
-fn get_modified_timestamp_ms(path: &str) -> Result<Option<u128>> {
+pub fn get_modified_timestamp_ms(path: &str) -> Result<Option<u128>> {
 	let file_metadata = match fs::metadata(path) {
 		Ok(file_metadata) => file_metadata,
 		Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
@@ -1,33 +1,57 @@
 use crate::data::Data;
 use crate::library_types::Track;
 use crate::tracks::generate_filename;
+use crate::tracks::properties::AudioProperties;
+use crate::tracks::tag::take_primary_tag;
 use crate::{sys_time_to_timestamp, UniResult};
-use lofty::file::{AudioFile, TaggedFileExt};
+use lofty::file::TaggedFileExt;
+use lofty::probe::Probe;
 use lofty::tag::{Accessor, ItemKey, TagExt};
 use std::fs;
-use std::path::Path;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum FileType {
 	Mp3,
 	M4a,
 	Opus,
+	Flac,
+	Wav,
+	Aiff,
+	Ogg,
 }
 impl FileType {
 	pub fn from_path(path: &Path) -> UniResult<Self> {
 		let ext = path.extension().unwrap_or_default().to_string_lossy();
-		match ext.as_ref() {
+		match ext.to_lowercase().as_ref() {
 			"mp3" => Ok(FileType::Mp3),
 			"m4a" => Ok(FileType::M4a),
 			"opus" => Ok(FileType::Opus),
+			"flac" => Ok(FileType::Flac),
+			"wav" | "wave" => Ok(FileType::Wav),
+			"aif" | "aiff" | "aifc" => Ok(FileType::Aiff),
+			"ogg" | "oga" => Ok(FileType::Ogg),
 			_ => throw!("Unsupported file extension {}", ext),
 		}
 	}
+	/// Detects the type from the file's content, since the extension can be
+	/// wrong
+	pub fn detect(path: &Path) -> UniResult<Self> {
+		match probe(path)?.file_type() {
+			Some(lofty_type) => Self::from_lofty_file_type(lofty_type),
+			None => throw!("Unknown file type: {}", path.to_string_lossy()),
+		}
+	}
 	pub fn from_lofty_file_type(lofty_type: lofty::file::FileType) -> UniResult<Self> {
 		match lofty_type {
 			lofty::file::FileType::Mpeg => Ok(FileType::Mp3),
 			lofty::file::FileType::Mp4 => Ok(FileType::M4a),
 			lofty::file::FileType::Opus => Ok(FileType::Opus),
+			lofty::file::FileType::Flac => Ok(FileType::Flac),
+			lofty::file::FileType::Wav => Ok(FileType::Wav),
+			lofty::file::FileType::Aiff => Ok(FileType::Aiff),
+			lofty::file::FileType::Vorbis => Ok(FileType::Ogg),
 			_ => throw!("Unsupported file type {:?}", lofty_type),
 		}
 	}
@@ -36,6 +60,10 @@ impl FileType {
 			FileType::Mp3 => "mp3",
 			FileType::M4a => "m4a",
 			FileType::Opus => "opus",
+			FileType::Flac => "flac",
+			FileType::Wav => "wav",
+			FileType::Aiff => "aiff",
+			FileType::Ogg => "ogg",
 		}
 	}
 }
@@ -45,6 +73,41 @@ impl std::fmt::Display for FileType {
 	}
 }
 
+/// Opens a file for reading, with the file type detected from its content.
+/// If the content isn't recognized, the extension is used
+pub fn probe(path: &Path) -> UniResult<Probe<BufReader<fs::File>>> {
+	let probe = match Probe::open(path) {
+		Ok(probe) => probe,
+		Err(e) => throw!("File does not exist: {}", e),
+	};
+	match probe.guess_file_type() {
+		Ok(probe) => Ok(probe),
+		Err(e) => throw!("Unable to read file: {}", e),
+	}
+}
+
+/// Audio files in a folder and its subfolders
+pub fn find_audio_files(dir: &Path, files: &mut Vec<PathBuf>) -> UniResult<()> {
+	let entries = match fs::read_dir(dir) {
+		Ok(entries) => entries,
+		Err(err) => throw!("Error reading folder {}: {err}", dir.to_string_lossy()),
+	};
+	for entry in entries.flatten() {
+		let path = entry.path();
+		match entry.file_type() {
+			Ok(file_type) if file_type.is_dir() => {
+				// Unreadable subfolders are skipped
+				let _ = find_audio_files(&path, files);
+			}
+			Ok(file_type) if file_type.is_file() && FileType::from_path(&path).is_ok() => {
+				files.push(path);
+			}
+			_ => {}
+		}
+	}
+	Ok(())
+}
+
 pub fn read_file_metadata(path: &Path) -> UniResult<fs::Metadata> {
 	match std::fs::metadata(path) {
 		Ok(file_md) => Ok(file_md),
@@ -55,36 +118,103 @@ pub fn read_file_metadata(path: &Path) -> UniResult<fs::Metadata> {
 	}
 }
 
+/// A file whose metadata has been read, but that hasn't been copied to the
+/// tracks folder yet
+pub struct ReadFile {
+	path: PathBuf,
+	file_type: FileType,
+	/// Has an empty `file`
+	pub track: Track,
+	/// Tag to write to the copied file, if the original tag was incomplete
+	new_tag: Option<lofty::tag::Tag>,
+	/// Whether the file has no title, so the name is the filename
+	pub untitled: bool,
+}
+
+impl ReadFile {
+	/// Copies the file to a new file in `tracks_dir`
+	pub fn copy_to(self, tracks_dir: &Path, now: i64) -> UniResult<Track> {
+		let mut track = self.track;
+		let tagged = self.new_tag.is_some();
+		// The extension of the original file may not match its content
+		let extension = self.file_type.file_extension();
+		// Another import may pick the same name at the same time, so the
+		// name is reserved by creating the file
+		let (filename, dest_path) = loop {
+			let filename = generate_filename(tracks_dir, &track.artist, &track.name, extension);
+			let dest_path = tracks_dir.join(&filename);
+			match fs::File::create_new(&dest_path) {
+				Ok(_) => break (filename, dest_path),
+				Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+				Err(e) => throw!("Error copying file: {e}"),
+			}
+		};
+
+		if let Err(err) = write_file(&self.path, &dest_path, self.new_tag) {
+			// Don't leave the reserved file behind
+			let _ = fs::remove_file(&dest_path);
+			return Err(err);
+		}
+		println!(
+			"{} -> {}",
+			self.path.to_string_lossy(),
+			dest_path.to_string_lossy()
+		);
+		if tagged {
+			// manually set date_modified because the date_modified doens't seem to
+			// immediately update after tag.write_to_path().
+			track.dateModified = now;
+		}
+		track.file = filename;
+		Ok(track)
+	}
+}
+
+/// Copies a file and writes the new tag to the copy
+fn write_file(path: &Path, dest_path: &Path, new_tag: Option<lofty::tag::Tag>) -> UniResult<()> {
+	if let Err(e) = fs::copy(path, dest_path) {
+		throw!("Error copying file: {e}");
+	}
+	if let Some(tag) = new_tag {
+		if let Err(e) = tag.save_to_path(dest_path, lofty::config::WriteOptions::default()) {
+			throw!("Unable to tag file {}: {e}", dest_path.to_string_lossy());
+		}
+	}
+	Ok(())
+}
+
 pub fn import(data: &Data, track_path: &Path, now: i64) -> UniResult<Track> {
+	read(track_path, now)?.copy_to(&data.paths.tracks_dir, now)
+}
+
+/// Reads a file's metadata, without importing it
+pub fn read(track_path: &Path, now: i64) -> UniResult<ReadFile> {
+	FileType::from_path(track_path)?;
 	let file_md = read_file_metadata(track_path)?;
 
-	let mut date_modified = match file_md.modified() {
+	let date_modified = match file_md.modified() {
 		Ok(sys_time) => sys_time_to_timestamp(&sys_time),
 		Err(_) => now,
 	};
 
-	let probe = match lofty::probe::Probe::open(track_path) {
-		Ok(f) => {
-			let parse_options = lofty::config::ParseOptions::new()
-				.read_properties(true)
-				.parsing_mode(lofty::config::ParsingMode::Strict);
-			f.options(parse_options)
-		}
-		Err(e) => throw!("File does not exist: {}", e),
-	};
-	let mut tagged_file = match probe.read() {
+	let parse_options = lofty::config::ParseOptions::new()
+		.read_properties(true)
+		.parsing_mode(lofty::config::ParsingMode::Strict);
+	let mut tagged_file = match probe(track_path)?.options(parse_options).read() {
 		Ok(f) => f,
 		Err(e) => throw!("Unable to read file: {}", e),
 	};
-	let properties = tagged_file.properties().clone();
+	let file_type = FileType::from_lofty_file_type(tagged_file.file_type())?;
+	let properties = AudioProperties::read(&tagged_file, track_path)?;
 
-	let mut tag_changed = false;
-	let mut default_tag = lofty::tag::Tag::new(tagged_file.primary_tag_type());
-	let tag = match tagged_file.primary_tag_mut() {
+	// Without a primary tag, one is written so the file can be edited later
+	let mut tag_changed = tagged_file.primary_tag().is_none();
+	let mut tag = match take_primary_tag(&mut tagged_file) {
 		Some(tag) => tag,
-		None => &mut default_tag,
+		None => lofty::tag::Tag::new(tagged_file.primary_tag_type()),
 	};
 
+	let untitled = tag.title().is_none();
 	let title = match tag.title() {
 		Some(title) => title.into_owned(),
 		None => {
@@ -99,50 +229,30 @@ pub fn import(data: &Data, track_path: &Path, now: i64) -> UniResult<Track> {
 	};
 	let artist = tag.artist().map(|s| s.into_owned()).unwrap_or_default();
 
-	let tracks_dir = &data.paths.tracks_dir;
-	let extension = match FileType::from_path(track_path)? {
-		FileType::Opus => "opus",
-		FileType::M4a => "m4a",
-		FileType::Mp3 => "mp3",
-	};
-	let filename = generate_filename(tracks_dir, &artist, &title, extension);
-	let dest_path = tracks_dir.join(&filename);
-
-	match fs::copy(track_path, &dest_path) {
-		Ok(_) => (),
-		Err(e) => throw!("Error copying file: {e}"),
-	};
-	println!(
-		"{} -> {}",
-		track_path.to_string_lossy(),
-		dest_path.to_string_lossy()
-	);
-
-	if tag_changed {
-		println!("Writing:::::::");
-		match tag.save_to_path(&dest_path, lofty::config::WriteOptions::default()) {
-			Ok(_) => (),
-			Err(e) => throw!("Unable to tag file {}: {e}", dest_path.to_string_lossy()),
-		};
-		// manually set date_modified because the date_modified doens't seem to
-		// immediately update after tag.write_to_path().
-		date_modified = now;
-	}
-
 	let track = Track {
 		size: file_md.len().try_into().unwrap(),
-		duration: properties.duration().as_secs_f64(),
-		bitrate: (properties.audio_bitrate().expect("Missing bitrate") * 1000).into(), // kbps to bps
-		sampleRate: properties
-			.sample_rate()
-			.expect("Missing sample rate")
-			.into(),
-		file: filename,
+		duration: properties.duration,
+		bitrate: properties.bitrate,
+		sampleRate: properties.sample_rate,
+		file: String::new(),
 		dateModified: date_modified,
 		dateAdded: now,
 		name: title,
 		importedFrom: None,
 		originalId: None,
+		fileType: Some(file_type.file_extension().to_string()),
+		codec: properties.codec,
+		channels: properties.channels,
+		bitDepth: properties.bit_depth,
+		lossless: properties.lossless,
+		encoder: properties.encoder,
+		encoderDelay: properties.encoder_delay,
+		encoderPadding: properties.encoder_padding,
+		silenceStart: None,
+		silenceEnd: None,
+		loudness: None,
+		truePeak: None,
+		albumLoudness: None,
 		artist,
 		composer: tag.get_string(&ItemKey::Composer).map(|s| s.to_string()),
 		sortName: tag
@@ -161,6 +271,7 @@ pub fn import(data: &Data, track_path: &Path, now: i64) -> UniResult<Track> {
 			Some(n) => n.parse().ok(),
 			None => None,
 		},
+		key: tag.get_string(&ItemKey::InitialKey).map(|s| s.to_string()),
 		comments: tag.comment().map(|s| s.into_owned()),
 		grouping: tag
 			.get_string(&ItemKey::ContentGroup)
@@ -194,5 +305,48 @@ pub fn import(data: &Data, track_path: &Path, now: i64) -> UniResult<Track> {
 		skipsImported: None,
 		volume: None,
 	};
-	Ok(track)
+	Ok(ReadFile {
+		path: track_path.to_path_buf(),
+		file_type,
+		track,
+		new_tag: match tag_changed {
+			true => Some(tag),
+			false => None,
+		},
+		untitled,
+	})
+}
+
+#[test]
+fn import_test() {
+	let file_type = |path: &str| FileType::from_path(Path::new(path)).ok();
+	assert!(file_type("a.FLAC") == Some(FileType::Flac));
+	assert!(file_type("a.aif") == Some(FileType::Aiff));
+	assert!(file_type("a.oga") == Some(FileType::Ogg));
+	assert!(file_type("a.txt").is_none());
+	let lofty_type = lofty::file::FileType::from_ext("wav").unwrap();
+	let wav = FileType::from_lofty_file_type(lofty_type).unwrap();
+	assert_eq!(wav.file_extension(), "wav");
+
+	// A WAV file with the wrong extension
+	let path = std::env::temp_dir().join(format!("ferrum-import-{}.m4a", std::process::id()));
+	crate::analysis::write_test_wav(&path, 8000, 1, &[0.0; 2]);
+	assert!(FileType::detect(&path).unwrap() == FileType::Wav);
+	let read_file = read(&path, 0).unwrap();
+	assert_eq!(read_file.track.fileType.as_deref(), Some("wav"));
+	assert_eq!(read_file.track.codec.as_deref(), Some("PCM"));
+	assert_eq!(read_file.track.channels, Some(1));
+	assert_eq!(read_file.track.bitDepth, Some(16));
+	assert_eq!(read_file.track.lossless, Some(true));
+	assert_eq!(read_file.track.encoderDelay, None);
+	// Found in the background later
+	assert_eq!(read_file.track.silenceStart, None);
+	fs::remove_file(&path).unwrap();
+
+	// The reserved file is removed when copying fails
+	let tracks_dir = std::env::temp_dir().join(format!("ferrum-import-{}", std::process::id()));
+	fs::create_dir_all(&tracks_dir).unwrap();
+	assert!(read_file.copy_to(&tracks_dir, 0).is_err());
+	assert_eq!(fs::read_dir(&tracks_dir).unwrap().count(), 0);
+	fs::remove_dir(&tracks_dir).unwrap();
 }
@@ -41,7 +41,11 @@ pub fn update_track_info(
 	if !old_path.exists() {
 		throw!("File does not exist: {}", track.file);
 	}
-	let ext = old_path.extension().unwrap_or_default().to_string_lossy();
+	// Files that were imported with the wrong extension get the right one
+	let ext = match &track.fileType {
+		Some(file_type) => file_type.clone(),
+		None => (old_path.extension().unwrap_or_default().to_string_lossy()).into_owned(),
+	};
 
 	// name
 	match new_info.name.as_ref() {
@@ -202,3 +206,117 @@ pub fn update_track_info(
 
 	Ok(())
 }
+
+/// The fields that `update_track_info` changes, so they can be set back
+pub struct TrackInfo {
+	pub file: String,
+	pub name: String,
+	pub artist: String,
+	pub albumName: Option<String>,
+	pub albumArtist: Option<String>,
+	pub composer: Option<String>,
+	pub grouping: Option<String>,
+	pub genre: Option<String>,
+	pub year: Option<i64>,
+	pub trackNum: Option<u32>,
+	pub trackCount: Option<u32>,
+	pub discNum: Option<u32>,
+	pub discCount: Option<u32>,
+	pub bpm: Option<f64>,
+	pub comments: Option<String>,
+	pub dateModified: i64,
+}
+impl TrackInfo {
+	pub fn from_track(track: &Track) -> Self {
+		TrackInfo {
+			file: track.file.clone(),
+			name: track.name.clone(),
+			artist: track.artist.clone(),
+			albumName: track.albumName.clone(),
+			albumArtist: track.albumArtist.clone(),
+			composer: track.composer.clone(),
+			grouping: track.grouping.clone(),
+			genre: track.genre.clone(),
+			year: track.year,
+			trackNum: track.trackNum,
+			trackCount: track.trackCount,
+			discNum: track.discNum,
+			discCount: track.discCount,
+			bpm: track.bpm,
+			comments: track.comments.clone(),
+			dateModified: track.dateModified,
+		}
+	}
+	/// Sets the fields in the tag, the same way `update_track_info` does
+	pub fn write_to_tag(&self, tag: &mut Tag) -> UniResult<()> {
+		match self.name.as_ref() {
+			"" => tag.remove_title(),
+			value => tag.set_title(value),
+		};
+		match self.artist.as_ref() {
+			"" => tag.remove_artists(),
+			value => tag.set_artist(value),
+		};
+		match &self.albumName {
+			None => tag.remove_album(),
+			Some(value) => tag.set_album(value),
+		};
+		match &self.albumArtist {
+			None => tag.remove_album_artists(),
+			Some(value) => tag.set_album_artist(value),
+		};
+		match &self.composer {
+			None => tag.remove_composers(),
+			Some(value) => tag.set_composer(value),
+		};
+		match &self.grouping {
+			None => tag.remove_groupings(),
+			Some(value) => tag.set_grouping(value),
+		};
+		match &self.genre {
+			None => tag.remove_genres(),
+			Some(value) => tag.set_genre(value),
+		};
+		match self.year {
+			None => tag.remove_year(),
+			Some(value) => tag.set_year(value as i32),
+		};
+		match tag.set_track_info(self.trackNum, self.trackCount) {
+			Ok(()) => {}
+			Err(SetInfoError::NumberRequired) => tag.set_track_info(None, None)?,
+			Err(e) => Err(e)?,
+		}
+		match tag.set_disc_info(self.discNum, self.discCount) {
+			Ok(()) => {}
+			Err(SetInfoError::NumberRequired) => tag.set_disc_info(None, None)?,
+			Err(e) => Err(e)?,
+		};
+		match self.bpm {
+			None => tag.remove_bpm(),
+			Some(value) => tag.set_bpm(value.round() as u16),
+		};
+		match &self.comments {
+			None => tag.remove_comments(),
+			Some(value) => tag.set_comment(value),
+		};
+		Ok(())
+	}
+	pub fn restore(self, track: &mut Track) {
+		track.file = self.file;
+		track.name = self.name;
+		track.artist = self.artist;
+		track.albumName = self.albumName;
+		track.albumArtist = self.albumArtist;
+		track.composer = self.composer;
+		track.grouping = self.grouping;
+		track.genre = self.genre;
+		track.year = self.year;
+		track.trackNum = self.trackNum;
+		track.trackCount = self.trackCount;
+		track.discNum = self.discNum;
+		track.discCount = self.discCount;
+		track.bpm = self.bpm;
+		track.comments = self.comments;
+		track.dateModified = self.dateModified;
+	}
+}
@@ -1,6 +1,7 @@
 use crate::data::Data;
 use crate::data_js::get_data;
 use crate::get_now_timestamp;
+use crate::journal::Change;
 use crate::js::nerr;
 use crate::library_types::{MsSinceUnixEpoch, Track, TrackID};
 use napi::{Env, JsArrayBuffer, JsBuffer, JsObject, Result, Task};
@@ -9,7 +10,8 @@ use std::path::{Path, PathBuf};
 
 pub mod cover;
 pub mod import;
-mod md;
+pub mod md;
+pub mod properties;
 mod tag;
 
 pub use tag::Tag;
@@ -51,6 +53,7 @@ pub fn add_play(track_id: String, env: Env) -> Result<()> {
 		None => track.playCount = Some(1),
 		Some(play_count) => *play_count += 1,
 	}
+	get_data(&env)?.track_changed(&track_id);
 	Ok(())
 }
 
@@ -67,6 +70,7 @@ pub fn add_skip(track_id: String, env: Env) -> Result<()> {
 		None => track.skipCount = Some(1),
 		Some(skip_count) => *skip_count += 1,
 	}
+	get_data(&env)?.track_changed(&track_id);
 	Ok(())
 }
 
@@ -76,6 +80,7 @@ pub fn add_play_time(id: TrackID, start: MsSinceUnixEpoch, dur_ms: i64, env: Env
 	let data: &mut Data = get_data(&env)?;
 	let tracks = &mut data.library.tracks;
 	tracks.get_mut(&id).ok_or(nerr("Track ID not found"))?;
+	data.artists.add_play_time(&id, dur_ms);
 	data.library.playTime.push((id, start, dur_ms));
 	Ok(())
 }
@@ -168,7 +173,8 @@ pub fn import_file(path: String, now: MsSinceUnixEpoch, env: Env) -> Result<()>
 	let data: &mut Data = get_data(&env)?;
 	let id = data.library.generate_id();
 	let track = import::import(&data, Path::new(&path), now)?;
-	data.library.tracks.insert(id, track);
+	data.library.tracks.insert(id.clone(), track);
+	data.track_changed(&id);
 	Ok(())
 }
 
@@ -263,6 +269,7 @@ pub fn remove_image(index: u32, env: Env) -> Result<()> {
 #[allow(dead_code)]
 pub fn update_track_info(track_id: String, info: md::TrackMD, env: Env) -> Result<()> {
 	let data: &mut Data = get_data(&env)?;
+	let change = Change::track_info(data, &track_id)?;
 	let track = id_to_track(&env, &track_id)?;
 
 	let tag = match &mut data.current_tag {
@@ -270,6 +277,8 @@ pub fn update_track_info(track_id: String, info: md::TrackMD, env: Env) -> Resul
 		None => throw!("No tag loaded"),
 	};
 	md::update_track_info(&data.paths.tracks_dir, track, tag, info)?;
+	data.track_changed(&track_id);
+	data.record("Edit Song Info", vec![change]);
 
 	Ok(())
 }
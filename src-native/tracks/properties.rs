@@ -0,0 +1,274 @@
+//! Audio properties of track files, like the codec and bit depth. Tracks
+//! imported before these were recorded can be filled in with
+//! `backfill_audio_properties`.
+
+use super::import::{self, FileType};
+use crate::data::Data;
+use crate::data_js::get_data;
+use crate::library_types::{Track, TrackID};
+use crate::UniResult;
+use lofty::config::ParseOptions;
+use lofty::file::{AudioFile, TaggedFile, TaggedFileExt};
+use lofty::mp4::{Mp4Codec, Mp4File};
+use lofty::tag::ItemKey;
+use napi::{Env, JsObject, Result, Task};
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+/// Samples that MP3 decoders output before the encoded audio
+const MP3_DECODER_DELAY: u32 = 529;
+const ITUNSMPB_KEY: &str = "----:com.apple.iTunes:iTunSMPB";
+
+pub struct AudioProperties {
+	pub duration: f64,
+	/// Bits per second
+	pub bitrate: f64,
+	pub sample_rate: f64,
+	pub codec: Option<String>,
+	pub channels: Option<u8>,
+	pub bit_depth: Option<u8>,
+	pub lossless: Option<bool>,
+	pub encoder: Option<String>,
+	/// Samples to skip at the start for gapless playback
+	pub encoder_delay: Option<u32>,
+	/// Samples to skip at the end for gapless playback
+	pub encoder_padding: Option<u32>,
+}
+
+/// MP4 files can contain different codecs, which the generic properties
+/// don't include
+fn read_mp4_codec(path: &Path) -> Option<&'static str> {
+	let mut file = File::open(path).ok()?;
+	let parse_options = ParseOptions::new().read_tags(false);
+	let mp4_file = Mp4File::read_from(&mut file, parse_options).ok()?;
+	match mp4_file.properties().codec() {
+		Mp4Codec::AAC => Some("AAC"),
+		Mp4Codec::ALAC => Some("ALAC"),
+		Mp4Codec::MP3 => Some("MP3"),
+		Mp4Codec::FLAC => Some("FLAC"),
+		_ => None,
+	}
+}
+
+fn read_codec(path: &Path, file_type: FileType) -> Option<&'static str> {
+	match file_type {
+		FileType::Mp3 => Some("MP3"),
+		FileType::M4a => read_mp4_codec(path),
+		FileType::Opus => Some("Opus"),
+		FileType::Flac => Some("FLAC"),
+		FileType::Wav | FileType::Aiff => Some("PCM"),
+		FileType::Ogg => Some("Vorbis"),
+	}
+}
+
+/// Encoder delay and padding from a LAME or Xing header in the first MP3
+/// frame. The decoder delay is included.
+fn parse_lame_header(bytes: &[u8]) -> Option<(u32, u32)> {
+	let sync = bytes
+		.windows(2)
+		.position(|b| b[0] == 0xff && b[1] & 0xe0 == 0xe0)?;
+	let header = bytes.get(sync..sync + 4)?;
+	let mpeg1 = (header[1] >> 3) & 0b11 == 0b11;
+	let mono = header[3] >> 6 == 0b11;
+	let side_info_size = match (mpeg1, mono) {
+		(true, false) => 32,
+		(true, true) | (false, false) => 17,
+		(false, true) => 9,
+	};
+	let xing = sync + 4 + side_info_size;
+	if !matches!(bytes.get(xing..xing + 4)?, b"Xing" | b"Info") {
+		return None;
+	}
+	let flags = bytes.get(xing + 7)?;
+	let mut lame = xing + 8;
+	// Frame count, byte count, seek table and quality
+	for (flag, size) in [(1, 4), (2, 4), (4, 100), (8, 4)] {
+		if flags & flag != 0 {
+			lame += size;
+		}
+	}
+	let lame = bytes.get(lame..lame + 24)?;
+	if !matches!(&lame[..4], b"LAME" | b"Lavf" | b"Lavc") {
+		return None;
+	}
+	let delay = (u32::from(lame[21]) << 4) | (u32::from(lame[22]) >> 4);
+	let padding = (u32::from(lame[22] & 0x0f) << 8) | u32::from(lame[23]);
+	Some((
+		delay + MP3_DECODER_DELAY,
+		padding.saturating_sub(MP3_DECODER_DELAY),
+	))
+}
+
+fn read_lame_header(path: &Path) -> Option<(u32, u32)> {
+	let mut file = File::open(path).ok()?;
+	let mut id3_header = [0; 10];
+	file.read_exact(&mut id3_header).ok()?;
+	// The first frame is after the ID3v2 tag
+	let mut start = 0;
+	if id3_header.starts_with(b"ID3") {
+		let size = id3_header[6..10]
+			.iter()
+			.fold(0, |size, byte| (size << 7) | u64::from(byte & 0x7f));
+		let footer_size = match id3_header[5] & 0x10 != 0 {
+			true => 10,
+			false => 0,
+		};
+		start = 10 + size + footer_size;
+	}
+	file.seek(SeekFrom::Start(start)).ok()?;
+	let mut bytes = Vec::new();
+	file.take(4096).read_to_end(&mut bytes).ok()?;
+	parse_lame_header(&bytes)
+}
+
+/// Encoder delay and padding from an iTunSMPB value, like
+/// " 00000000 00000840 000001CA 00000000003F1C76 ..."
+fn parse_itunsmpb(value: &str) -> Option<(u32, u32)> {
+	let mut fields = value.split_whitespace().skip(1);
+	let delay = u32::from_str_radix(fields.next()?, 16).ok()?;
+	let padding = u32::from_str_radix(fields.next()?, 16).ok()?;
+	Some((delay, padding))
+}
+
+fn is_lossless(codec: &str) -> bool {
+	matches!(codec, "ALAC" | "FLAC" | "PCM")
+}
+
+impl AudioProperties {
+	/// Reads the properties of a file that was read with its properties and
+	/// tags
+	pub fn read(tagged_file: &TaggedFile, path: &Path) -> UniResult<Self> {
+		let file_type = FileType::from_lofty_file_type(tagged_file.file_type())?;
+		let properties = tagged_file.properties();
+		let bitrate = match properties.audio_bitrate().or(properties.overall_bitrate()) {
+			Some(bitrate) => bitrate,
+			None => throw!("Missing bitrate"),
+		};
+		let sample_rate = match properties.sample_rate() {
+			Some(sample_rate) => sample_rate,
+			None => throw!("Missing sample rate"),
+		};
+		let codec = read_codec(path, file_type);
+		let tag = tagged_file.primary_tag().or(tagged_file.first_tag());
+		let gapless = match file_type {
+			FileType::Mp3 => read_lame_header(path),
+			FileType::M4a => tag
+				.and_then(|tag| tag.get_string(&ItemKey::Unknown(ITUNSMPB_KEY.to_string())))
+				.and_then(parse_itunsmpb),
+			_ => None,
+		};
+		Ok(AudioProperties {
+			duration: properties.duration().as_secs_f64(),
+			bitrate: (bitrate * 1000).into(), // kbps to bps
+			sample_rate: sample_rate.into(),
+			codec: codec.map(str::to_string),
+			channels: properties.channels(),
+			bit_depth: properties.bit_depth(),
+			lossless: codec.map(is_lossless),
+			encoder: tag
+				.and_then(|tag| tag.get_string(&ItemKey::EncoderSoftware))
+				.map(str::to_string),
+			encoder_delay: gapless.map(|(delay, _)| delay),
+			encoder_padding: gapless.map(|(_, padding)| padding),
+		})
+	}
+	pub fn read_from_path(path: &Path) -> UniResult<Self> {
+		let tagged_file = match import::probe(path)?.read() {
+			Ok(tagged_file) => tagged_file,
+			Err(e) => throw!("Unable to read file: {}", e),
+		};
+		Self::read(&tagged_file, path)
+	}
+	pub fn apply_to(self, track: &mut Track) {
+		track.duration = self.duration;
+		track.bitrate = self.bitrate;
+		track.sampleRate = self.sample_rate;
+		track.codec = self.codec;
+		track.channels = self.channels;
+		track.bitDepth = self.bit_depth;
+		track.lossless = self.lossless;
+		track.encoder = self.encoder;
+		track.encoderDelay = self.encoder_delay;
+		track.encoderPadding = self.encoder_padding;
+	}
+}
+
+struct BackfillProperties {
+	files: Vec<(TrackID, PathBuf)>,
+}
+impl Task for BackfillProperties {
+	type Output = Vec<(TrackID, AudioProperties)>;
+	type JsValue = u32;
+	fn compute(&mut self) -> Result<Self::Output> {
+		let properties = self
+			.files
+			.par_iter()
+			.filter_map(|(id, path)| {
+				// Missing or unreadable files are skipped
+				let properties = AudioProperties::read_from_path(path).ok()?;
+				Some((id.clone(), properties))
+			})
+			.collect();
+		Ok(properties)
+	}
+	fn resolve(&mut self, env: Env, output: Self::Output) -> Result<Self::JsValue> {
+		let data: &mut Data = get_data(&env)?;
+		let mut updated = 0;
+		for (id, properties) in output {
+			// The track may have been deleted in the meantime
+			if let Some(track) = data.library.tracks.get_mut(&id) {
+				properties.apply_to(track);
+				data.track_changed(&id);
+				updated += 1;
+			}
+		}
+		Ok(updated)
+	}
+}
+
+/// Reads the audio properties of tracks that don't have a codec recorded,
+/// like tracks imported before it was. Resolves to the number of tracks
+/// that were updated.
+#[napi(
+	js_name = "backfill_audio_properties",
+	ts_return_type = "Promise<number>"
+)]
+#[allow(dead_code)]
+pub fn backfill_audio_properties(env: Env) -> Result<JsObject> {
+	let data: &mut Data = get_data(&env)?;
+	let tracks_dir = &data.paths.tracks_dir;
+	let files = data
+		.library
+		.tracks
+		.iter()
+		.filter(|(_, track)| track.codec.is_none())
+		.map(|(id, track)| (id.clone(), tracks_dir.join(&track.file)))
+		.collect();
+	let task = BackfillProperties { files };
+	env.spawn(task).map(|t| t.promise_object())
+}
+
+#[test]
+fn properties_test() {
+	assert_eq!(
+		parse_itunsmpb(" 00000000 00000840 000001CA 00000000003F1C76 00000000"),
+		Some((2112, 458))
+	);
+	assert_eq!(parse_itunsmpb("invalid"), None);
+
+	// A 128 kbps MPEG-1 stereo frame with an Info and LAME header
+	let mut frame = vec![0xff, 0xfb, 0x90, 0x00];
+	frame.extend([0; 32]);
+	frame.extend(b"Info");
+	frame.extend([0, 0, 0, 0x0f]);
+	frame.extend([0; 4 + 4 + 100 + 4]);
+	frame.extend(b"LAME3.100");
+	frame.extend([0; 12]);
+	// Delay 576 and padding 1000
+	frame.extend([0x24, 0x03, 0xe8]);
+	assert_eq!(parse_lame_header(&frame), Some((576 + 529, 1000 - 529)));
+	frame[36..40].copy_from_slice(b"Abcd");
+	assert_eq!(parse_lame_header(&frame), None);
+}
@@ -1,9 +1,10 @@
+use crate::tracks::import::{self, FileType};
 use crate::{UniError, UniResult};
 use lofty::picture::{MimeType, Picture};
 use lofty::tag::ItemKey;
-use lofty::{file::TaggedFileExt, tag::Accessor, tag::TagExt};
+use lofty::{file::TaggedFile, file::TaggedFileExt, tag::Accessor, tag::TagExt};
 use std::io::Cursor;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 
 pub enum SetInfoError {
 	NumberRequired,
@@ -39,43 +40,43 @@ pub struct ImageRef<'a> {
 	pub data: &'a [u8],
 }
 
+/// Removes the file's primary tag, which is the one that gets written. If the
+/// file only has other tags, like RIFF INFO in WAV files, the first one is
+/// converted to the primary tag type instead.
+pub fn take_primary_tag(tagged_file: &mut TaggedFile) -> Option<lofty::tag::Tag> {
+	let tag_type = tagged_file.primary_tag_type();
+	if let Some(tag) = tagged_file.remove(tag_type) {
+		return Some(tag);
+	}
+	let mut tag = tagged_file.first_tag()?.clone();
+	tag.re_map(tag_type);
+	Some(tag)
+}
+
 pub struct Tag {
 	tag: lofty::tag::Tag,
 }
 impl Tag {
-	pub fn read_from_path(path: &PathBuf) -> UniResult<Tag> {
+	pub fn read_from_path(path: &Path) -> UniResult<Tag> {
 		if !path.exists() {
 			throw!("File does not exist: {}", path.to_string_lossy());
 		}
-		let ext = path.extension().unwrap_or_default().to_string_lossy();
-
-		let tag = match ext.as_ref() {
-			"mp3" | "m4a" | "opus" => {
-				let probe = match lofty::probe::Probe::open(path) {
-					Ok(f) => {
-						let parse_options = lofty::config::ParseOptions::new()
-							.read_properties(false)
-							.parsing_mode(lofty::config::ParsingMode::Strict);
-						f.options(parse_options)
-					}
-					Err(e) => throw!("File does not exist: {}", e),
-				};
-
-				let mut tagged_file = match probe.read() {
-					Ok(f) => f,
-					Err(e) => throw!("Unable to read file: {}", e),
-				};
-
-				let tag = match tagged_file.remove(tagged_file.primary_tag_type()) {
-					Some(t) => t.clone(),
-					None => lofty::tag::Tag::new(tagged_file.primary_tag_type()),
-				};
+		FileType::from_path(path)?;
+		let parse_options = lofty::config::ParseOptions::new()
+			.read_properties(false)
+			.parsing_mode(lofty::config::ParsingMode::Strict);
+		// Detected from the content, so the right tag type is used even if
+		// the extension is wrong
+		let mut tagged_file = match import::probe(path)?.options(parse_options).read() {
+			Ok(f) => f,
+			Err(e) => throw!("Unable to read file: {}", e),
+		};
 
-				Tag { tag }
-			}
-			_ => throw!("Unsupported file extension: {}", ext),
+		let tag = match take_primary_tag(&mut tagged_file) {
+			Some(t) => t,
+			None => lofty::tag::Tag::new(tagged_file.primary_tag_type()),
 		};
-		Ok(tag)
+		Ok(Tag { tag })
 	}
 	pub fn write_to_path(&mut self, path: &Path) -> UniResult<()> {
 		match self
@@ -189,6 +190,31 @@ impl Tag {
 		}
 		assert!(inserted, "Failed to set BPM");
 	}
+	pub fn set_key(&mut self, value: &str) {
+		let inserted = self.tag.insert_text(ItemKey::InitialKey, value.to_string());
+		assert!(inserted, "Failed to set key");
+	}
+	/// Sets ReplayGain tags. Gains are in dB and peaks are linear. Album
+	/// tags are removed if `album` is `None`
+	pub fn set_replay_gain(&mut self, track_gain: f64, track_peak: f64, album: Option<(f64, f64)>) {
+		let gain = |decibels: f64| format!("{decibels:.2} dB");
+		let peak = |linear: f64| format!("{linear:.6}");
+		let items = [
+			(ItemKey::ReplayGainTrackGain, Some(gain(track_gain))),
+			(ItemKey::ReplayGainTrackPeak, Some(peak(track_peak))),
+			(ItemKey::ReplayGainAlbumGain, album.map(|(g, _)| gain(g))),
+			(ItemKey::ReplayGainAlbumPeak, album.map(|(_, p)| peak(p))),
+		];
+		for (key, text) in items {
+			match text {
+				Some(text) => {
+					let inserted = self.tag.insert_text(key, text);
+					assert!(inserted, "Failed to set ReplayGain");
+				}
+				None => self.tag.remove_key(&key),
+			}
+		}
+	}
 	pub fn remove_comments(&mut self) {
 		self.tag.remove_comment()
 	}
@@ -240,4 +266,15 @@ impl Tag {
 	pub fn remove_image(&mut self, index: usize) -> Picture {
 		self.tag.remove_picture(index)
 	}
+	pub fn pictures(&self) -> &[Picture] {
+		self.tag.pictures()
+	}
+	pub fn set_pictures(&mut self, pictures: Vec<Picture>) {
+		while self.tag.picture_count() > 0 {
+			self.tag.remove_picture(0);
+		}
+		for picture in pictures {
+			self.tag.push_picture(picture);
+		}
+	}
 }
@@ -0,0 +1,285 @@
+//! Folders that are watched for new audio files, which are imported
+//! automatically. A file is imported once its size has stopped changing, so
+//! files that are still being downloaded or copied aren't imported early.
+
+use crate::data::Data;
+use crate::data_js::get_data;
+use crate::library::Paths;
+use crate::library_types::Track;
+use crate::tracks::import::{self, FileType};
+use crate::{get_now_timestamp, path_to_json, UniResult};
+use atomicwrites::{AllowOverwrite, AtomicFile};
+use napi::threadsafe_function::{
+	ErrorStrategy, ThreadSafeCallContext, ThreadsafeFunction, ThreadsafeFunctionCallMode,
+};
+use napi::{Env, JsFunction, Result};
+use notify::event::{CreateKind, ModifyKind, RenameMode};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a file's size must stay the same before it's imported
+const SETTLE_TIME: Duration = Duration::from_secs(2);
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+type ImportCallback = ThreadsafeFunction<Vec<Track>, ErrorStrategy::Fatal>;
+
+pub struct WatchFolders {
+	options_path: PathBuf,
+	pub folders: Vec<String>,
+	library_dir: PathBuf,
+	tracks_dir: PathBuf,
+	/// Adds imported tracks on the JS thread and passes their IDs to JS
+	callback: Option<ImportCallback>,
+	/// Stops watching when dropped
+	watcher: Option<RecommendedWatcher>,
+}
+
+impl WatchFolders {
+	pub fn load(paths: &Paths) -> Self {
+		let options_path = paths.local_data_dir.join("watch_folders.json");
+		WatchFolders {
+			folders: path_to_json(options_path.clone()).unwrap_or_default(),
+			options_path,
+			library_dir: paths.library_dir.clone(),
+			tracks_dir: paths.tracks_dir.clone(),
+			callback: None,
+			watcher: None,
+		}
+	}
+	fn save(&self) -> UniResult<()> {
+		let json_str = match serde_json::to_string(&self.folders) {
+			Ok(json_str) => json_str,
+			Err(_) => throw!("Error saving watch_folders.json"),
+		};
+		let af = AtomicFile::new(&self.options_path, AllowOverwrite);
+		match af.write(|f| f.write_all(json_str.as_bytes())) {
+			Ok(_) => Ok(()),
+			Err(_) => throw!("Error writing watch_folders.json"),
+		}
+	}
+	/// Starts watching the folders, or restarts if they changed
+	fn start(&mut self) -> UniResult<()> {
+		self.watcher = None;
+		let callback = match &self.callback {
+			Some(callback) if !self.folders.is_empty() => callback.clone(),
+			_ => return Ok(()),
+		};
+		let (tx, rx) = channel();
+		let mut watcher = match notify::recommended_watcher(tx) {
+			Ok(watcher) => watcher,
+			Err(err) => throw!("Error watching folders: {err}"),
+		};
+		for folder in &self.folders {
+			// Folders that don't exist right now are skipped, like an
+			// unplugged drive
+			if let Err(err) = watcher.watch(Path::new(folder), RecursiveMode::Recursive) {
+				println!("Unable to watch {folder}: {err}");
+			}
+		}
+		let library_dir = self.library_dir.clone();
+		let tracks_dir = self.tracks_dir.clone();
+		thread::spawn(move || wait_for_files(rx, &library_dir, &tracks_dir, callback));
+		self.watcher = Some(watcher);
+		Ok(())
+	}
+}
+
+/// A file that was added and may still be written to
+struct Pending {
+	size: Option<u64>,
+	changed: Instant,
+}
+
+/// Files in `library_dir` are ignored, since imported files are copied there
+fn handle_event(
+	pending: &mut HashMap<PathBuf, Pending>,
+	event: Event,
+	library_dir: &Path,
+	now: Instant,
+) {
+	let is_new = matches!(
+		event.kind,
+		EventKind::Create(CreateKind::File | CreateKind::Any)
+			| EventKind::Modify(ModifyKind::Name(RenameMode::To | RenameMode::Any))
+	);
+	for path in event.paths {
+		if FileType::from_path(&path).is_err() || path.starts_with(library_dir) {
+			continue;
+		}
+		if is_new {
+			pending.insert(
+				path,
+				Pending {
+					size: None,
+					changed: now,
+				},
+			);
+		} else if let Some(file) = pending.get_mut(&path) {
+			file.changed = now;
+		}
+	}
+}
+
+/// Removes and returns the files whose size hasn't changed for `SETTLE_TIME`
+fn take_finished(pending: &mut HashMap<PathBuf, Pending>, now: Instant) -> Vec<PathBuf> {
+	let mut finished = Vec::new();
+	pending.retain(|path, file| {
+		let size = match fs::metadata(path) {
+			Ok(md) if md.is_file() => md.len(),
+			// Moved away or deleted
+			_ => return false,
+		};
+		if file.size != Some(size) {
+			file.size = Some(size);
+			file.changed = now;
+			true
+		} else if now.duration_since(file.changed) >= SETTLE_TIME {
+			finished.push(path.clone());
+			false
+		} else {
+			true
+		}
+	});
+	finished.sort();
+	finished
+}
+
+/// Runs until the watcher is dropped
+fn wait_for_files(
+	rx: Receiver<notify::Result<Event>>,
+	library_dir: &Path,
+	tracks_dir: &Path,
+	callback: ImportCallback,
+) {
+	let mut pending = HashMap::new();
+	loop {
+		match rx.recv_timeout(POLL_INTERVAL) {
+			Ok(Ok(event)) => handle_event(&mut pending, event, library_dir, Instant::now()),
+			Ok(Err(err)) => println!("Watch error: {err}"),
+			Err(RecvTimeoutError::Timeout) => {}
+			Err(RecvTimeoutError::Disconnected) => return,
+		}
+		let finished = take_finished(&mut pending, Instant::now());
+		let tracks = import_files(&finished, tracks_dir);
+		if !tracks.is_empty() {
+			callback.call(tracks, ThreadsafeFunctionCallMode::NonBlocking);
+		}
+	}
+}
+
+/// Reads and copies files into the tracks folder. Files that can't be
+/// imported are skipped
+fn import_files(paths: &[PathBuf], tracks_dir: &Path) -> Vec<Track> {
+	let now = get_now_timestamp();
+	let mut tracks = Vec::new();
+	for path in paths {
+		let result = import::read(path, now).and_then(|file| file.copy_to(tracks_dir, now));
+		match result {
+			Ok(track) => tracks.push(track),
+			Err(err) => println!(
+				"Unable to import {}: {}",
+				path.to_string_lossy(),
+				err.message
+			),
+		}
+	}
+	tracks
+}
+
+/// Adds imported tracks to the library, and returns their IDs
+fn add_tracks(data: &mut Data, tracks: Vec<Track>) -> Vec<String> {
+	let mut ids = Vec::new();
+	for track in tracks {
+		let id = data.library.generate_id();
+		data.library.tracks.insert(id.clone(), track);
+		data.track_changed(&id);
+		ids.push(id);
+	}
+	ids
+}
+
+#[napi(js_name = "get_watch_folders")]
+#[allow(dead_code)]
+pub fn get_watch_folders(env: Env) -> Result<Vec<String>> {
+	let data: &mut Data = get_data(&env)?;
+	Ok(data.watch_folders.folders.clone())
+}
+
+#[napi(js_name = "set_watch_folders")]
+#[allow(dead_code)]
+pub fn set_watch_folders(folders: Vec<String>, env: Env) -> Result<()> {
+	let data: &mut Data = get_data(&env)?;
+	let library_dir = &data.paths.library_dir;
+	for folder in &folders {
+		// Imported files are copied to the library folder, so watching it
+		// would import them again
+		if Path::new(folder).starts_with(library_dir) {
+			throw!("Cannot watch the library folder: {folder}");
+		}
+		if library_dir.starts_with(folder) {
+			throw!("Cannot watch a folder that contains the library folder: {folder}");
+		}
+	}
+	data.watch_folders.folders = folders;
+	data.watch_folders.save()?;
+	data.watch_folders.start()?;
+	Ok(())
+}
+
+/// Starts watching the folders. `callback` is called with the IDs of tracks
+/// that were imported.
+#[napi(
+	js_name = "start_watching",
+	ts_args_type = "callback: (trackIds: Array<string>) => void"
+)]
+#[allow(dead_code)]
+pub fn start_watching(callback: JsFunction, env: Env) -> Result<()> {
+	let data: &mut Data = get_data(&env)?;
+	let mut callback: ImportCallback =
+		callback.create_threadsafe_function(0, |ctx: ThreadSafeCallContext<Vec<Track>>| {
+			let data: &mut Data = get_data(&ctx.env)?;
+			Ok(vec![add_tracks(data, ctx.value)])
+		})?;
+	// Don't keep the app running just because of the watcher
+	callback.unref(&env)?;
+	data.watch_folders.callback = Some(callback);
+	data.watch_folders.start()?;
+	Ok(())
+}
+
+#[test]
+fn watch_folders_test() {
+	let dir = std::env::temp_dir().join(format!("ferrum-watch-{}", std::process::id()));
+	let _ = fs::remove_dir_all(&dir);
+	fs::create_dir_all(&dir).unwrap();
+	let song = dir.join("song.mp3");
+	let other = dir.join("notes.txt");
+	fs::write(&song, b"abc").unwrap();
+	fs::write(&other, b"abc").unwrap();
+
+	let library_dir = dir.join("Library");
+	let mut pending = HashMap::new();
+	let start = Instant::now();
+	let event = Event::new(EventKind::Create(CreateKind::File))
+		.add_path(song.clone())
+		.add_path(other)
+		.add_path(library_dir.join("Tracks").join("song.mp3"));
+	handle_event(&mut pending, event, &library_dir, start);
+	assert_eq!(pending.len(), 1);
+
+	// The size is recorded, then the file is written to
+	assert!(take_finished(&mut pending, start).is_empty());
+	fs::write(&song, b"abcdef").unwrap();
+	let later = start + SETTLE_TIME;
+	assert!(take_finished(&mut pending, later).is_empty());
+	assert!(take_finished(&mut pending, later + SETTLE_TIME / 2).is_empty());
+	assert_eq!(take_finished(&mut pending, later + SETTLE_TIME), vec![song]);
+	assert!(pending.is_empty());
+	fs::remove_dir_all(&dir).unwrap();
+}
@@ -70,8 +70,20 @@ export function init_menu_bar(app: App, main_window: BrowserWindow) {
 			label: 'Edit',
 			submenu: (() => {
 				const menu: MenuItemConstructorOptions[] = [
-					{ role: 'undo' },
-					{ role: 'redo' },
+					{
+						label: 'Undo',
+						accelerator: 'CmdOrCtrl+Z',
+						click: () => {
+							web_contents.send('undo')
+						},
+					},
+					{
+						label: 'Redo',
+						accelerator: is.mac ? 'Shift+CmdOrCtrl+Z' : 'CmdOrCtrl+Y',
+						click: () => {
+							web_contents.send('redo')
+						},
+					},
 					{ type: 'separator' },
 					{ role: 'cut' },
 					{ role: 'copy' },
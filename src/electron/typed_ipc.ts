@@ -96,6 +96,8 @@ type Events = {
 	itunesImport: () => void
 	import: () => void
 	filter: () => void
+	undo: () => void
+	redo: () => void
 
 	playPause: () => void
 	Next: () => void
@@ -1,7 +1,12 @@
 import { writable } from 'svelte/store'
 import { ipc_renderer } from '@/lib/window'
 import type {
+	ArtistSortKey,
+	BrowseField,
+	LibraryIssue,
 	MsSinceUnixEpoch,
+	QueryError,
+	RelinkMatch,
 	TrackID,
 	TrackList,
 	TrackListID,
@@ -25,6 +30,13 @@ call((addon) => addon.load_data(is_dev, local_data_path, library_path))
 
 export const view_as_songs: ViewAs.Songs = 0
 export const view_as_artists: ViewAs.Artists = 1
+export const view_as_albums: ViewAs.Albums = 2
+export const artist_sort_name: ArtistSortKey.Name = 0
+export const artist_sort_play_count: ArtistSortKey.PlayCount = 1
+export const browse_genre: BrowseField.Genre = 0
+export const browse_composer: BrowseField.Composer = 1
+export const browse_grouping: BrowseField.Grouping = 2
+export const browse_decade: BrowseField.Decade = 3
 
 function get_error_message(err: unknown): string {
 	if (typeof err === 'object' && err !== null) {
@@ -132,6 +144,23 @@ export function delete_tracks_in_open(indexes: number[]) {
 	queue.removeDeleted()
 	methods.save()
 }
+function after_undo_or_redo() {
+	track_lists_details_map.refresh()
+	page.refresh_ids_and_keep_selection()
+	pageSelection.clear()
+	queue.removeDeleted()
+	methods.save()
+}
+export function undo() {
+	if (call((addon) => addon.undo()) !== null) {
+		after_undo_or_redo()
+	}
+}
+export function redo() {
+	if (call((addon) => addon.redo()) !== null) {
+		after_undo_or_redo()
+	}
+}
 export type PlaylistInfo = {
 	name: string
 	description: string
@@ -164,28 +193,31 @@ export function move_playlist(
 
 export const paths = call((addon) => addon.get_paths())
 
-export async function import_tracks(paths: string[]) {
-	let err_state = null
-	const now = Date.now()
-	for (const path of paths) {
-		try {
-			inner_addon.import_file(path, now)
-		} catch (err) {
-			if (err_state === 'skip') continue
-			const result = await ipc_renderer.invoke('showMessageBox', false, {
-				type: 'error',
-				message: 'Error importing track ' + path,
-				detail: get_error_message(err),
-				buttons: err_state ? ['OK', 'Skip all errors'] : ['OK'],
-				defaultId: 0,
-			})
-			if (result.response === 1) err_state = 'skip'
-			else err_state = 'skippable'
-		}
-	}
+export async function import_tracks(paths: string[], skip_duplicates = false) {
+	const job = inner_addon.ImportJob.new()
+	const status = await call(() =>
+		job.start(
+			paths,
+			(progress) => {
+				// Show tracks as they're imported
+				if (progress.done % 100 === 0) {
+					page.refresh_ids_and_keep_selection()
+				}
+			},
+			skip_duplicates,
+		),
+	)
 	page.refresh_ids_and_keep_selection()
 	pageSelection.clear()
 	methods.save()
+	analyze_silence()
+	if (status.errors.length > 0) {
+		ipc_renderer.invoke('showMessageBox', false, {
+			type: 'error',
+			message: `${status.errors.length} files could not be imported`,
+			detail: status.errors.join('\n'),
+		})
+	}
 }
 
 export const methods = {
@@ -211,6 +243,75 @@ export const methods = {
 	save: () => {
 		return call((addon) => addon.save())
 	},
+	listBackups: () => {
+		return call((addon) => addon.list_backups())
+	},
+	restoreBackup: (name: string) => {
+		call((addon) => addon.restore_backup(name))
+		track_lists_details_map.refresh()
+		page.refresh_ids_and_keep_selection()
+		pageSelection.clear()
+		queue.removeDeleted()
+	},
+	checkLibrary: () => {
+		return call((addon) => addon.check_library())
+	},
+	fixLibraryIssues: (issues: LibraryIssue[]) => {
+		call((addon) => addon.fix_library_issues(issues))
+		track_lists_details_map.refresh()
+		page.refresh_ids_and_keep_selection()
+		methods.save()
+	},
+	relinkTracks: (search_dir: string) => {
+		return call((addon) => addon.relink_tracks(search_dir))
+	},
+	applyRelinks: (matches: RelinkMatch[]) => {
+		call((addon) => addon.apply_relinks(matches))
+		page.refresh_ids_and_keep_selection()
+		methods.save()
+	},
+	rescanTracks: async (ids: TrackID[]) => {
+		const results = await call((addon) => addon.rescan_tracks(ids))
+		if (results.some((result) => result.changes.length > 0)) {
+			page.refresh_ids_and_keep_selection()
+			methods.save()
+		}
+		return results
+	},
+	analyzeLoudness: async (ids: TrackID[], write_tags: boolean) => {
+		const status = await call((addon) => addon.analyze_loudness(ids, write_tags))
+		page.refresh_ids_and_keep_selection()
+		methods.save()
+		return status
+	},
+	analyzeTempoAndKey: async (ids: TrackID[], overwrite: boolean, write_tags: boolean) => {
+		const results = await call((addon) => addon.analyze_tempo_and_key(ids, overwrite, write_tags))
+		page.refresh_ids_and_keep_selection()
+		methods.save()
+		return results
+	},
+	getWaveform: (id: TrackID, buckets: number) => {
+		return call((addon) => addon.get_waveform(id, buckets))
+	},
+	getFingerprint: (id: TrackID) => {
+		return call((addon) => addon.get_fingerprint(id))
+	},
+	findSimilarTracks: (id: TrackID, min_similarity?: number) => {
+		return call((addon) => addon.find_similar_tracks(id, min_similarity))
+	},
+	mergeTracks: (ids: TrackID[]) => {
+		call((addon) => addon.merge_tracks(ids))
+		page.refresh_ids_and_keep_selection()
+		pageSelection.clear()
+		queue.removeDeleted()
+		methods.save()
+	},
+	getWatchFolders: () => {
+		return call((addon) => addon.get_watch_folders())
+	},
+	setWatchFolders: (folders: string[]) => {
+		call((addon) => addon.set_watch_folders(folders))
+	},
 	addPlay: (id: TrackID) => {
 		call((data) => data.add_play(id))
 		page.refresh_ids_and_keep_selection()
@@ -267,12 +368,13 @@ export const methods = {
 	},
 }
 
+export const filter_error = writable<QueryError | null>(null)
 export const filter = (() => {
 	const { subscribe, set } = writable('')
 	return {
 		subscribe: subscribe,
 		set: (query: string) => {
-			call((data) => data.filter_open_playlist(query))
+			filter_error.set(call((data) => data.filter_open_playlist(query)))
 			page.set(page.get())
 			pageSelection.clear()
 			set(query)
@@ -324,8 +426,26 @@ export const page = (() => {
 			refresh_ids_and_keep_selection()
 			pageSelection.clear()
 		},
-		get_artists() {
-			return call((addon) => addon.get_artists())
+		get_artists(
+			query: string,
+			sort_key: ArtistSortKey,
+			desc: boolean,
+			offset: number,
+			limit: number,
+		) {
+			return call((addon) => addon.get_artists(query, sort_key, desc, offset, limit))
+		},
+		get_albums() {
+			return call((addon) => addon.get_page_albums())
+		},
+		get_browse_items(field: BrowseField) {
+			return call((addon) => addon.get_browse_items(field))
+		},
+		find_duplicates() {
+			return call((addon) => addon.find_duplicates())
+		},
+		find_fingerprint_duplicates(min_similarity?: number) {
+			return call((addon) => addon.find_fingerprint_duplicates(min_similarity))
 		},
 		get_track(index: number) {
 			return call((addon) => addon.get_page_track(index))
@@ -348,3 +468,42 @@ export const page = (() => {
 		},
 	}
 })()
+
+call((addon) =>
+	addon.start_watching((track_ids) => {
+		if (track_ids.length > 0) {
+			page.refresh_ids_and_keep_selection()
+			methods.save()
+			analyze_silence()
+		}
+	}),
+)
+
+// Tracks imported before the codec etc were recorded
+call((addon) => addon.backfill_audio_properties()).then((updated) => {
+	if (updated > 0) {
+		page.refresh_ids_and_keep_selection()
+		methods.save()
+	}
+})
+
+// Finds silence in tracks that haven't been analyzed yet
+function analyze_silence() {
+	call((addon) => addon.analyze_silence()).then((status) => {
+		if (status.analyzed > 0) {
+			page.refresh_ids_and_keep_selection()
+			methods.save()
+		}
+		if (status.errors.length > 0) {
+			console.warn('Unable to detect silence in some tracks:', status.errors)
+		}
+	})
+}
+analyze_silence()
+
+// Fingerprints tracks that don't have one yet
+call((addon) => addon.fingerprint_library()).then((status) => {
+	if (status.errors.length > 0) {
+		console.warn('Unable to fingerprint some tracks:', status.errors)
+	}
+})